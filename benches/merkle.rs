@@ -0,0 +1,107 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use ff::Field;
+use halo2_proofs::pairing::bn256::Fr;
+
+use zkc_state_manager::kvpair::Hash;
+use zkc_state_manager::memory::MemoryMerkleTree;
+use zkc_state_manager::merkle::{MerkleNode, MerkleTree, NodeIndex};
+use zkc_state_manager::poseidon;
+
+// Number of leaves set before benchmarking, so that proofs go through stored nodes.
+const NUM_OF_LEAVES: u64 = 64;
+
+fn populated_tree<const D: usize>() -> MemoryMerkleTree<D> {
+    let mut mt = MemoryMerkleTree::<D>::new();
+    let first_leaf = (1_u64 << D) - 1;
+    for i in 0..NUM_OF_LEAVES {
+        let mut data = [0_u8; 32];
+        data[..8].copy_from_slice(&i.to_le_bytes());
+        mt.update_leaf_data_with_proof(NodeIndex(first_leaf + i), &data)
+            .unwrap();
+    }
+    mt
+}
+
+fn bench_tree<const D: usize>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("merkle_depth_{D}"));
+    let mut mt = populated_tree::<D>();
+    let first_leaf = (1_u64 << D) - 1;
+
+    group.bench_function("get_leaf_with_proof", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % NUM_OF_LEAVES;
+            mt.get_leaf_with_proof(NodeIndex(first_leaf + i)).unwrap()
+        })
+    });
+
+    group.bench_function("set_leaf_with_proof", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % NUM_OF_LEAVES;
+            let (mut leaf, _) = mt.get_leaf_with_proof(NodeIndex(first_leaf + i)).unwrap();
+            leaf.set(&[i as u8; 32]).unwrap();
+            mt.set_leaf_with_proof(&leaf).unwrap()
+        })
+    });
+
+    let (_, proof) = mt.get_leaf_with_proof(NodeIndex(first_leaf)).unwrap();
+    group.bench_function("verify_proof", |b| {
+        b.iter(|| mt.verify_proof(proof.clone()).unwrap())
+    });
+
+    group.finish();
+}
+
+fn bench_merkle(c: &mut Criterion) {
+    bench_tree::<10>(c);
+    bench_tree::<20>(c);
+    bench_tree::<32>(c);
+}
+
+fn bench_poseidon(c: &mut Criterion) {
+    let mut group = c.benchmark_group("poseidon_hash");
+    for num_of_elements in [1, 10, 100] {
+        let data = vec![1_u8; 32 * num_of_elements];
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_of_elements),
+            &data,
+            |b, data| b.iter(|| poseidon::hash(data).unwrap()),
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("poseidon_hash_frs");
+    for num_of_elements in [1, 10, 100] {
+        let frs = vec![Fr::one(); num_of_elements];
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_of_elements),
+            &frs,
+            |b, frs| b.iter(|| poseidon::hash_frs(frs)),
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("merkle_hash_pair");
+    let (a, b) = (Fr::one(), Fr::one());
+    group.bench_function("hash_pair_fr", |bench| {
+        bench.iter(|| poseidon::hash_pair_fr(&a, &b))
+    });
+    let (a, b) = (Hash::from(a), Hash::from(b));
+    group.bench_function("merkle_hash_pair", |bench| {
+        bench.iter(|| poseidon::merkle_hash_pair(&a, &b))
+    });
+    group.bench_function("hash_children", |bench| {
+        bench.iter(|| Hash::hash_children(&a, &b))
+    });
+    // The generic path on the same 64 bytes, with the chunking and the wider hasher.
+    let data = [a.0, b.0].concat();
+    group.bench_function("hash", |bench| {
+        bench.iter(|| poseidon::hash(&data).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_merkle, bench_poseidon);
+criterion_main!(benches);
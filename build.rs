@@ -1,4 +1,4 @@
-use std::{env, path::PathBuf};
+use std::{env, path::PathBuf, process::Command};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -6,5 +6,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .file_descriptor_set_path(out_dir.join("kvpair_descriptor.bin"))
         .compile(&["proto/kvpair.proto"], &["proto"])
         .unwrap();
+
+    // Reported by GetServerInfo.
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if PathBuf::from(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
     Ok(())
 }
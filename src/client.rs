@@ -0,0 +1,280 @@
+//! A typed client of the KVPair gRPC service, which converts the proto responses back into
+//! the crate's types so that consumers don't need to handle bytes and proof encodings.
+
+use std::future::Future;
+
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+
+use crate::kvpair::{ContractId, Hash, MERKLE_TREE_HEIGHT};
+use crate::merkle::{verify_value_with, MerkleProof, NodeIndex};
+use crate::proto::kv_pair_client::KvPairClient;
+use crate::proto::node::NodeData;
+use crate::proto::{
+    BeginReadSessionRequest, DiffRootsRequest, GetLeafRequest, GetRootRequest, Node, Proof,
+    ProofType, SetLeafRequest,
+};
+use crate::Error;
+
+/// Decode the details of the errors caused by an invalid index, e.g. to read the valid leaf range.
+pub use crate::proto::convert::index_error_details_of;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: KvPairClient<Channel>,
+    contract_id: Option<ContractId>,
+    verify: bool,
+    // The token and the pinned root of the read session the reads are served in.
+    read_session: Option<(Vec<u8>, Hash)>,
+}
+
+impl Client {
+    pub fn new(inner: KvPairClient<Channel>) -> Self {
+        Self {
+            inner,
+            contract_id: None,
+            verify: false,
+            read_session: None,
+        }
+    }
+
+    pub async fn connect(
+        dst: impl TryInto<Endpoint, Error = tonic::transport::Error>,
+    ) -> Result<Self, tonic::transport::Error> {
+        Ok(Self::new(KvPairClient::connect(dst).await?))
+    }
+
+    /// Use the given contract instead of the one the server derives from the request.
+    pub fn with_contract_id(mut self, contract_id: ContractId) -> Self {
+        self.contract_id = Some(contract_id);
+        self
+    }
+
+    /// Check that the returned proofs fold to their root before returning them.
+    pub fn with_verification(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn into_inner(self) -> KvPairClient<Channel> {
+        self.inner
+    }
+
+    /// Start a read session on the server and return a client whose reads are all served at
+    /// the root current at this time, until the session expires.
+    pub async fn begin_read_session(&mut self) -> Result<Self, Status> {
+        let response = self
+            .inner
+            .begin_read_session(Request::new(BeginReadSessionRequest {
+                contract_id: self.contract_id.map(Into::into),
+            }))
+            .await?
+            .into_inner();
+        let root = response.root.try_into()?;
+        Ok(Self {
+            read_session: Some((response.read_session, root)),
+            ..self.clone()
+        })
+    }
+
+    /// Run `f` with a client whose reads all observe the same version of the tree, e.g.
+    /// `client.read_session(|mut view| async move { view.get_leaf(index).await }).await`.
+    pub async fn read_session<F, Fut, T>(&mut self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce(Self) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let view = self.begin_read_session().await?;
+        f(view).await
+    }
+
+    /// The current root, or the pinned root in a read session.
+    pub async fn root(&mut self) -> Result<Hash, Status> {
+        if let Some((_, root)) = &self.read_session {
+            return Ok(*root);
+        }
+        let response = self
+            .inner
+            .get_root(Request::new(GetRootRequest {
+                contract_id: self.contract_id.map(Into::into),
+            }))
+            .await?
+            .into_inner();
+        Ok(response.root.try_into()?)
+    }
+
+    /// The leaves whose hash differs between two roots of the contract, as (index, old hash,
+    /// new hash) in index order. Fails with `NOT_FOUND` if either root is unknown.
+    pub async fn diff_roots(
+        &mut self,
+        old_root: Hash,
+        new_root: Hash,
+    ) -> Result<Vec<(NodeIndex, Hash, Hash)>, Status> {
+        let mut stream = self
+            .inner
+            .diff_roots(Request::new(DiffRootsRequest {
+                contract_id: self.contract_id.map(Into::into),
+                old_root: old_root.into(),
+                new_root: new_root.into(),
+                with_data: false,
+            }))
+            .await?
+            .into_inner();
+        let mut changes = vec![];
+        while let Some(response) = stream.message().await? {
+            for change in response.changes {
+                changes.push((
+                    NodeIndex(change.index),
+                    change.old_hash.try_into()?,
+                    change.new_hash.try_into()?,
+                ));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// The data of the leaf and its proof against the current root. Leaves are addressed by
+    /// their index in the tree, e.g. `LeafIndex(3).to_node_index(MERKLE_TREE_HEIGHT)?`.
+    pub async fn get_leaf(
+        &mut self,
+        index: NodeIndex,
+    ) -> Result<(Vec<u8>, MerkleProof<Hash, MERKLE_TREE_HEIGHT>), Status> {
+        let (data, _, proof) = self.get_leaf_with_metadata(index).await?;
+        Ok((data, proof))
+    }
+
+    /// Like `get_leaf`, also returning the metadata of the leaf, which is not covered by the proof.
+    pub async fn get_leaf_with_metadata(
+        &mut self,
+        index: NodeIndex,
+    ) -> Result<
+        (
+            Vec<u8>,
+            Option<Vec<u8>>,
+            MerkleProof<Hash, MERKLE_TREE_HEIGHT>,
+        ),
+        Status,
+    > {
+        let response = self
+            .inner
+            .get_leaf(Request::new(GetLeafRequest {
+                contract_id: self.contract_id.map(Into::into),
+                index: index.into(),
+                hash: None,
+                proof_type: ProofType::ProofV0.into(),
+                root: None,
+                read_session: self.read_session.as_ref().map(|(token, _)| token.clone()),
+                skip_data: false,
+            }))
+            .await?
+            .into_inner();
+        let data = leaf_data(response.node)?;
+        let proof = self.proof(index, response.proof)?;
+        // Empty leaves and leaves set by hash are returned without data.
+        if self.verify
+            && !data.is_empty()
+            && !verify_value_with(&data, &proof, &proof.root, |data| {
+                crate::poseidon::hash(data)
+                    .ok()
+                    .and_then(|hash| hash.try_into().ok())
+            })
+            .map_err(Error::from)?
+        {
+            return Err(Error::InconsistentData(format!(
+                "Data of leaf {index} does not match its proof"
+            ))
+            .into());
+        }
+        Ok((data, response.metadata, proof))
+    }
+
+    /// Update the data of the leaf and return its proof against the new root.
+    pub async fn set_leaf(
+        &mut self,
+        index: NodeIndex,
+        data: Vec<u8>,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Status> {
+        self.set_leaf_with_metadata(index, data, None).await
+    }
+
+    /// Like `set_leaf`, only if the current hash of the leaf is `expected`, e.g. the `source` of
+    /// the proof the data was read with. Otherwise fails with `ABORTED` without writing anything,
+    /// the current hash being returned in the `x-current-leaf-hash` metadata to retry with.
+    pub async fn set_leaf_if(
+        &mut self,
+        index: NodeIndex,
+        data: Vec<u8>,
+        expected: Hash,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Status> {
+        self.send_set_leaf(index, data, None, Some(expected)).await
+    }
+
+    /// Like `set_leaf`, also replacing the metadata of the leaf if given.
+    /// The metadata is not hashed, so it doesn't change the root.
+    pub async fn set_leaf_with_metadata(
+        &mut self,
+        index: NodeIndex,
+        data: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Status> {
+        self.send_set_leaf(index, data, metadata, None).await
+    }
+
+    async fn send_set_leaf(
+        &mut self,
+        index: NodeIndex,
+        data: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+        expected_leaf_hash: Option<Hash>,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Status> {
+        let response = self
+            .inner
+            .set_leaf(Request::new(SetLeafRequest {
+                contract_id: self.contract_id.map(Into::into),
+                index: index.into(),
+                hash: None,
+                data: Some(data),
+                proof_type: ProofType::ProofV0.into(),
+                force: false,
+                metadata,
+                expected_leaf_hash: expected_leaf_hash.map(|hash| hash.0.to_vec()),
+            }))
+            .await?
+            .into_inner();
+        Ok(self.proof(index, response.proof)?)
+    }
+
+    fn proof(
+        &self,
+        index: NodeIndex,
+        proof: Option<Proof>,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Error> {
+        let proof: MerkleProof<Hash, MERKLE_TREE_HEIGHT> = proof
+            .ok_or_else(|| Error::InconsistentData("Proof missing in the response".to_string()))?
+            .try_into()?;
+        if proof.index != index.0 {
+            return Err(Error::InconsistentData(format!(
+                "Proof of leaf {} returned for leaf {index}",
+                proof.index
+            )));
+        }
+        if self.verify {
+            let steps = proof.fold_steps(Hash::hash_children)?;
+            if steps.last().map(|step| step.hash) != Some(proof.root) {
+                return Err(Error::InconsistentData(format!(
+                    "Proof of leaf {index} does not verify"
+                )));
+            }
+        }
+        Ok(proof)
+    }
+}
+
+fn leaf_data(node: Option<Node>) -> Result<Vec<u8>, Error> {
+    match node.and_then(|node| node.node_data) {
+        Some(NodeData::Data(data)) => Ok(data),
+        _ => Err(Error::InconsistentData(
+            "Leaf data missing in the response".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::{oneshot, MutexGuard};
+use tonic::Status;
+
+use crate::kvpair::{Hash, MerkleRecord, MERKLE_TREE_HEIGHT};
+use crate::merkle::{MerkleNode, MerkleProof};
+
+/// The longest coalescing window, which bounds the delay added to the leaf updates.
+pub const MAX_COALESCE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The proof of a coalesced leaf update against the root written by its window.
+pub type CoalescedResult = Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Status>;
+
+/// A leaf update waiting for its coalescing window to be written.
+#[derive(Debug)]
+pub struct PendingUpdate {
+    pub leaf: MerkleRecord,
+    pub force: bool,
+    pub expected: Option<Hash>,
+    pub metadata: Option<Vec<u8>>,
+    pub reply: oneshot::Sender<CoalescedResult>,
+}
+
+/// The leaf updates of a contract received within a window, written together so that a leaf
+/// updated several times is only written once, with its last value. The first update opens the
+/// window, which is written once it has been open for its duration and the previous window of
+/// the contract has been written.
+#[derive(Debug)]
+pub struct CoalesceWindow {
+    duration: Duration,
+    // The updates of the open window in arrival order, empty when no window is open.
+    pending: Mutex<Vec<PendingUpdate>>,
+    // Held while a window is written, so that the windows are written one at a time.
+    flush: tokio::sync::Mutex<()>,
+}
+
+impl CoalesceWindow {
+    /// A window of the given duration, at most `MAX_COALESCE_WINDOW`.
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration: duration.min(MAX_COALESCE_WINDOW),
+            pending: Default::default(),
+            flush: Default::default(),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Add the update to the open window. Returns true if the update opened the window, in
+    /// which case the caller must `close` it after `duration` and write its updates.
+    pub fn push(&self, update: PendingUpdate) -> bool {
+        let mut pending = self.pending.lock().expect("Coalesce window lock");
+        pending.push(update);
+        pending.len() == 1
+    }
+
+    /// Wait for the previous window to be written and close the open one, returning its
+    /// updates and a guard to hold while writing them. The updates received while waiting are
+    /// part of the closed window.
+    pub async fn close(&self) -> (MutexGuard<'_, ()>, Vec<PendingUpdate>) {
+        let guard = self.flush.lock().await;
+        let updates = std::mem::take(&mut *self.pending.lock().expect("Coalesce window lock"));
+        (guard, updates)
+    }
+}
+
+/// The writes of the updates of a window, see `coalesce`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Coalesced {
+    /// The outcome of each update in arrival order, the current hash of its leaf if its
+    /// expected hash did not match.
+    pub outcomes: Vec<Result<(), Hash>>,
+    /// For each updated leaf, the position of the update to write and whether to force it.
+    pub writes: BTreeMap<u64, (usize, bool)>,
+}
+
+impl Coalesced {
+    /// The number of accepted updates which are not written, as a later update of the same leaf
+    /// is written instead.
+    pub fn merged(&self) -> usize {
+        let accepted = self
+            .outcomes
+            .iter()
+            .filter(|outcome| outcome.is_ok())
+            .count();
+        accepted - self.writes.len()
+    }
+}
+
+/// Merge the updates of a window, as if they were applied one after the other in arrival order:
+/// an update with an expected hash is checked against the hash left by the previous accepted
+/// update of its leaf, or the current hash if none, and only the last accepted update of each
+/// leaf is written. It is forced if any of the accepted updates of the leaf is.
+/// `current` must have the current hash of every updated leaf.
+pub fn coalesce(updates: &[PendingUpdate], current: &HashMap<u64, Hash>) -> Coalesced {
+    let mut hashes = current.clone();
+    let mut outcomes = vec![];
+    let mut writes: BTreeMap<u64, (usize, bool)> = BTreeMap::new();
+    for (position, update) in updates.iter().enumerate() {
+        let index = update.leaf.index();
+        let hash = hashes[&index];
+        match update.expected {
+            Some(expected) if expected != hash => {
+                outcomes.push(Err(hash));
+                continue;
+            }
+            _ => outcomes.push(Ok(())),
+        }
+        hashes.insert(index, update.leaf.hash());
+        let force = update.force || writes.get(&index).is_some_and(|(_, force)| *force);
+        writes.insert(index, (position, force));
+    }
+    Coalesced { outcomes, writes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(index: u64, value: u8, force: bool, expected: Option<Hash>) -> PendingUpdate {
+        let (reply, _) = oneshot::channel();
+        PendingUpdate {
+            leaf: MerkleRecord::new_leaf(index, Hash::hash_data(&[value; 32])),
+            force,
+            expected,
+            metadata: None,
+            reply,
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let hash = |value: u8| Hash::hash_data(&[value; 32]);
+        let current = HashMap::from([(7, hash(0)), (8, hash(0))]);
+        let updates = [
+            update(7, 1, false, Some(hash(0))),
+            update(8, 1, true, None),
+            update(7, 2, false, None),
+            // Checked against the pending value of the leaf, not the stored one.
+            update(7, 3, false, Some(hash(0))),
+            update(7, 4, false, Some(hash(2))),
+            update(8, 2, false, None),
+        ];
+        let coalesced = coalesce(&updates, &current);
+        assert_eq!(
+            coalesced.outcomes,
+            vec![Ok(()), Ok(()), Ok(()), Err(hash(2)), Ok(()), Ok(())]
+        );
+        assert_eq!(
+            coalesced.writes,
+            BTreeMap::from([(7, (4, false)), (8, (5, true))])
+        );
+        assert_eq!(coalesced.merged(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_window() {
+        let window = CoalesceWindow::new(Duration::from_secs(10));
+        assert_eq!(window.duration(), MAX_COALESCE_WINDOW);
+        assert!(window.push(update(7, 1, false, None)));
+        assert!(!window.push(update(7, 2, false, None)));
+        let (guard, updates) = window.close().await;
+        assert_eq!(updates.len(), 2);
+        // A new window is opened while the previous one is written, and closed after it.
+        assert!(window.push(update(7, 3, false, None)));
+        assert!(window.flush.try_lock().is_err());
+        drop(guard);
+        let (_, updates) = window.close().await;
+        assert_eq!(updates.len(), 1);
+    }
+}
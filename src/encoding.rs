@@ -0,0 +1,184 @@
+//! Byte encodings of the proofs: the length of the ProofV0 encoding of the `Proof` messages,
+//! and a self-describing encoding recording the hash scheme and the depth of the tree.
+
+use crate::kvpair::Hash;
+use crate::merkle::MerkleProof;
+use crate::Error;
+
+// A hash in the ProofV0 encoding, its 32 bytes prefixed by their length as a u64.
+const ENCODED_HASH_LEN: usize = 8 + 32;
+
+/// The length of the ProofV0 encoding of a proof in a tree with the given depth, i.e. of the
+/// `proof` bytes of a `Proof`. The encoding is bincode, so the assist and each hash are prefixed
+/// by their length as a u64.
+pub const fn proof_len(depth: usize) -> usize {
+    // source, root, assist length, assist and index
+    2 * ENCODED_HASH_LEN + 8 + depth * ENCODED_HASH_LEN + 8
+}
+
+/// The version of the self-describing proof encoding, see `MerkleProof::to_bytes`.
+pub const PROOF_FORMAT_VERSION: u8 = 1;
+
+/// How the parents of a tree are hashed from their children, recorded in self-describing proofs
+/// so that a proof is never verified under another scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProofHashScheme {
+    /// `Hash::hash_children` of the left and the right child.
+    Poseidon = 1,
+    /// `Hash::hash_children` of the children in sorted order, see `SortedPairMerkleTree`.
+    SortedPoseidon = 2,
+}
+
+impl TryFrom<u8> for ProofHashScheme {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ProofHashScheme::Poseidon),
+            2 => Ok(ProofHashScheme::SortedPoseidon),
+            _ => Err(Error::InvalidArgument(format!(
+                "Unknown proof hash scheme {value}"
+            ))),
+        }
+    }
+}
+
+// version, scheme and depth bytes, then the index
+const PROOF_HEADER_LEN: usize = 3 + 8;
+
+impl<const D: usize> MerkleProof<Hash, D> {
+    /// The length of the ProofV0 encoding of this proof, without serializing it.
+    pub fn serialized_len(&self) -> usize {
+        proof_len(self.assist.len())
+    }
+
+    /// Encode the proof with a header carrying the format version, the hash scheme and the
+    /// depth, followed by the index (u64 little endian), the source, the root and the assist
+    /// (32 bytes each, in the order of `assist`).
+    pub fn to_bytes(&self, scheme: ProofHashScheme) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PROOF_HEADER_LEN + 32 * (2 + self.assist.len()));
+        bytes.extend([PROOF_FORMAT_VERSION, scheme as u8, D as u8]);
+        bytes.extend(self.index.to_le_bytes());
+        for hash in [&self.source, &self.root]
+            .into_iter()
+            .chain(self.assist.iter())
+        {
+            bytes.extend(hash.0);
+        }
+        bytes
+    }
+
+    /// Decode a proof encoded by `to_bytes`, checking that it was made for a tree of depth `D`
+    /// hashed with the given scheme.
+    pub fn from_bytes(bytes: &[u8], scheme: ProofHashScheme) -> Result<Self, Error> {
+        if bytes.len() < PROOF_HEADER_LEN {
+            return Err(Error::InvalidArgument(format!(
+                "Proof of {} bytes is shorter than its header",
+                bytes.len()
+            )));
+        }
+        let (header, hashes) = bytes.split_at(PROOF_HEADER_LEN);
+        if header[0] != PROOF_FORMAT_VERSION {
+            return Err(Error::InvalidArgument(format!(
+                "Unsupported proof format version {}, expected {PROOF_FORMAT_VERSION}",
+                header[0]
+            )));
+        }
+        let given = ProofHashScheme::try_from(header[1])?;
+        if given != scheme {
+            return Err(Error::InvalidArgument(format!(
+                "Proof for hash scheme {given:?} given, expected {scheme:?}"
+            )));
+        }
+        if header[2] as usize != D {
+            return Err(Error::InvalidArgument(format!(
+                "Proof for depth {} given, expected {D}",
+                header[2]
+            )));
+        }
+        if hashes.len() != 32 * (2 + D) {
+            return Err(Error::InvalidArgument(format!(
+                "Proof of depth {D} must have {} hashes, {} bytes given",
+                2 + D,
+                hashes.len()
+            )));
+        }
+        let index = u64::from_le_bytes(header[3..].try_into().expect("8 bytes index"));
+        let mut hashes = hashes
+            .chunks(32)
+            .map(Hash::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let assist = hashes.split_off(2);
+        let proof = MerkleProof {
+            source: hashes[0],
+            root: hashes[1],
+            assist,
+            index,
+        };
+        proof
+            .validate_structure()
+            .map_err(|e| Error::InvalidArgument(format!("Proof malformed: {e:?}")))?;
+        Ok(proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvpair::MERKLE_TREE_HEIGHT;
+    use ff::Field;
+    use halo2_proofs::pairing::bn256::Fr;
+    use rand::{thread_rng, Rng};
+
+    fn random_hash() -> Hash {
+        Fr::random(thread_rng()).into()
+    }
+
+    #[test]
+    fn test_self_describing_proof() {
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: random_hash(),
+            root: random_hash(),
+            assist: (0..MERKLE_TREE_HEIGHT).map(|_| random_hash()).collect(),
+            index: thread_rng().gen_range(
+                (1_u64 << MERKLE_TREE_HEIGHT) - 1..(1_u64 << (MERKLE_TREE_HEIGHT + 1)) - 1,
+            ),
+        };
+        let bytes = proof.to_bytes(ProofHashScheme::Poseidon);
+        assert_eq!(bytes[..3], [1, 1, MERKLE_TREE_HEIGHT as u8]);
+        assert_eq!(
+            MerkleProof::from_bytes(&bytes, ProofHashScheme::Poseidon).unwrap(),
+            proof
+        );
+
+        // A proof is only accepted under the scheme and the depth it was made for.
+        assert!(matches!(
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::from_bytes(
+                &bytes,
+                ProofHashScheme::SortedPoseidon
+            ),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            MerkleProof::<Hash, 10>::from_bytes(&bytes, ProofHashScheme::Poseidon),
+            Err(Error::InvalidArgument(_))
+        ));
+        for (position, value) in [(0, 2), (1, 3)] {
+            let mut malformed = bytes.clone();
+            malformed[position] = value;
+            assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::from_bytes(
+                &malformed,
+                ProofHashScheme::Poseidon
+            )
+            .is_err());
+        }
+        for len in [0, 10, bytes.len() - 1] {
+            assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::from_bytes(
+                &bytes[..len],
+                ProofHashScheme::Poseidon
+            )
+            .is_err());
+        }
+    }
+}
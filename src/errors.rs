@@ -1,7 +1,12 @@
+use prost::Message;
 use thiserror::Error;
-use tonic::Status;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
 
-use crate::merkle::MerkleError;
+use crate::kvpair::Hash;
+use crate::merkle::{MerkleError, MerkleErrorCode};
+use crate::proto::convert::index_error_details;
+use crate::proto::{IndexErrorDetails, NodeType};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -11,10 +16,26 @@ pub enum Error {
     Merkle(#[from] MerkleError),
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
+    /// An index of the wrong node type or out of the tree, returned with the valid range.
+    #[error("Invalid argument: {message}")]
+    InvalidIndex {
+        message: String,
+        details: IndexErrorDetails,
+    },
     #[error("Inconsistent data: {0}")]
     InconsistentData(String),
     #[error("Precondition not satisfied: {0}")]
     Precondition(String),
+    #[error("Tree depth mismatch: the contract is stored with depth {stored}, but this server uses depth {expected}")]
+    DepthMismatch { stored: u64, expected: u64 },
+    #[error("Contract is frozen: {0}")]
+    Frozen(String),
+    #[error("Leaf {index} has hash {current:?}, expected {expected:?}")]
+    LeafHashMismatch {
+        index: u64,
+        expected: Hash,
+        current: Hash,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -24,8 +45,35 @@ impl From<Error> for Status {
         use Error::*;
         let s = format!("{error}");
         match error {
-            Mongodb(_) | Merkle(_) | InconsistentData(_) | Precondition(_) => Status::internal(s),
+            Merkle(e) => match (e.code(), e.depth()) {
+                (MerkleErrorCode::InvalidLeafIndex, Some(depth)) => with_details(
+                    Code::Internal,
+                    s,
+                    index_error_details(e.index(), depth, NodeType::NodeLeaf),
+                ),
+                (MerkleErrorCode::InvalidIndex, Some(depth)) => with_details(
+                    Code::Internal,
+                    s,
+                    index_error_details(e.index(), depth, NodeType::NodeUnspecified),
+                ),
+                (MerkleErrorCode::Cancelled, _) => Status::cancelled(s),
+                _ => Status::internal(s),
+            },
+            Mongodb(_) | InconsistentData(_) | Precondition(_) => Status::internal(s),
             InvalidArgument(_) => Status::invalid_argument(s),
+            InvalidIndex { details, .. } => with_details(Code::InvalidArgument, s, details),
+            DepthMismatch { .. } | Frozen(_) => Status::failed_precondition(s),
+            LeafHashMismatch { current, .. } => {
+                let mut metadata = MetadataMap::new();
+                let current = hex::encode(current.0).parse().expect("Valid hex metadata");
+                metadata.insert("x-current-leaf-hash", current);
+                Status::with_metadata(Code::Aborted, s, metadata)
+            }
         }
     }
 }
+
+// Attach the details as the binary details of the status, see `IndexErrorDetails`.
+fn with_details(code: Code, message: String, details: IndexErrorDetails) -> Status {
+    Status::with_details(code, message, details.encode_to_vec().into())
+}
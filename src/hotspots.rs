@@ -0,0 +1,239 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::kvpair::ContractId;
+
+/// The accesses are counted in buckets of this duration, so windows are rounded up to it.
+pub const HOTSPOT_BUCKET: Duration = Duration::from_secs(10);
+/// The longest window the accesses are kept for.
+pub const HOTSPOT_MAX_WINDOW: Duration = Duration::from_secs(600);
+/// The number of leaves counted per contract and bucket.
+pub const HOTSPOT_TOP_K: usize = 64;
+
+/// The most accessed leaves, bounded with the Space-Saving algorithm: once full, a new leaf
+/// replaces the least counted one and inherits its count. Counts are thus overestimated by at
+/// most the count of the evicted leaf, and any leaf with more than `1 / capacity` of the
+/// accesses is always kept.
+#[derive(Debug, Clone, Default)]
+struct TopLeaves {
+    counts: HashMap<u64, u64>,
+}
+
+impl TopLeaves {
+    fn record(&mut self, index: u64) {
+        if let Some(count) = self.counts.get_mut(&index) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() >= HOTSPOT_TOP_K {
+            let (&evicted, &min) = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .expect("Full leaf counts");
+            self.counts.remove(&evicted);
+            count += min;
+        }
+        self.counts.insert(index, count);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    start: Instant,
+    reads: u64,
+    writes: u64,
+    leaves: TopLeaves,
+}
+
+/// The reads and writes of a contract over a window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractAccesses {
+    pub contract_id: ContractId,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// Sliding window counters of the accesses to the contracts and to their leaves, to find the
+/// hot keys when latency spikes. The counts are approximate (see `TopLeaves`), only cover the
+/// requests served by this process and are lost on restart.
+#[derive(Debug, Default)]
+pub struct Hotspots {
+    // The buckets of the last `HOTSPOT_MAX_WINDOW` of each contract, oldest first.
+    contracts: Mutex<HashMap<ContractId, VecDeque<Bucket>>>,
+}
+
+impl Hotspots {
+    /// Count an access to the contract, and to its leaf at `index` if given.
+    pub fn record(&self, contract_id: ContractId, index: Option<u64>, write: bool) {
+        self.record_at(contract_id, index, write, Instant::now())
+    }
+
+    fn record_at(&self, contract_id: ContractId, index: Option<u64>, write: bool, now: Instant) {
+        let mut contracts = self.contracts.lock().expect("Hotspots lock");
+        let buckets = contracts.entry(contract_id).or_default();
+        while matches!(buckets.front(), Some(bucket) if !in_window(bucket, HOTSPOT_MAX_WINDOW, now))
+        {
+            buckets.pop_front();
+        }
+        if !matches!(buckets.back(), Some(bucket) if now < bucket.start + HOTSPOT_BUCKET) {
+            buckets.push_back(Bucket {
+                start: now,
+                reads: 0,
+                writes: 0,
+                leaves: TopLeaves::default(),
+            });
+        }
+        let bucket = buckets.back_mut().expect("Current bucket");
+        if write {
+            bucket.writes += 1;
+        } else {
+            bucket.reads += 1;
+        }
+        if let Some(index) = index {
+            bucket.leaves.record(index);
+        }
+    }
+
+    /// The `limit` most accessed leaves of the contract over the window, as (index, count),
+    /// most accessed first.
+    pub fn top_leaves(
+        &self,
+        contract_id: &ContractId,
+        window: Duration,
+        limit: usize,
+    ) -> Vec<(u64, u64)> {
+        self.top_leaves_at(contract_id, window, limit, Instant::now())
+    }
+
+    fn top_leaves_at(
+        &self,
+        contract_id: &ContractId,
+        window: Duration,
+        limit: usize,
+        now: Instant,
+    ) -> Vec<(u64, u64)> {
+        let contracts = self.contracts.lock().expect("Hotspots lock");
+        let mut counts = HashMap::new();
+        for bucket in contracts.get(contract_id).into_iter().flatten() {
+            if in_window(bucket, window, now) {
+                for (index, count) in bucket.leaves.counts.iter() {
+                    *counts.entry(*index).or_insert(0) += count;
+                }
+            }
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts.truncate(limit);
+        counts
+    }
+
+    /// The accesses of every contract accessed over the window, busiest first.
+    pub fn contracts(&self, window: Duration) -> Vec<ContractAccesses> {
+        self.contracts_at(window, Instant::now())
+    }
+
+    fn contracts_at(&self, window: Duration, now: Instant) -> Vec<ContractAccesses> {
+        let mut contracts = self.contracts.lock().expect("Hotspots lock");
+        contracts.retain(|_, buckets| {
+            buckets
+                .back()
+                .is_some_and(|bucket| in_window(bucket, HOTSPOT_MAX_WINDOW, now))
+        });
+        let mut accesses: Vec<_> = contracts
+            .iter()
+            .map(|(contract_id, buckets)| {
+                let buckets = buckets
+                    .iter()
+                    .filter(|bucket| in_window(bucket, window, now));
+                let (reads, writes) = buckets.fold((0, 0), |(reads, writes), bucket| {
+                    (reads + bucket.reads, writes + bucket.writes)
+                });
+                ContractAccesses {
+                    contract_id: *contract_id,
+                    reads,
+                    writes,
+                }
+            })
+            .filter(|accesses| accesses.reads + accesses.writes > 0)
+            .collect();
+        accesses.sort_by_key(|accesses| std::cmp::Reverse(accesses.reads + accesses.writes));
+        accesses
+    }
+}
+
+// Whether any part of the bucket is within the window ending now.
+fn in_window(bucket: &Bucket, window: Duration, now: Instant) -> bool {
+    now.saturating_duration_since(bucket.start) < window + HOTSPOT_BUCKET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_leaves_eviction() {
+        let mut leaves = TopLeaves::default();
+        for _ in 0..10 {
+            leaves.record(1);
+        }
+        for index in 100..100 + HOTSPOT_TOP_K as u64 * 2 {
+            leaves.record(index);
+        }
+        assert_eq!(leaves.counts.len(), HOTSPOT_TOP_K);
+        // The hot leaf is kept and its count is exact, the others are overestimated.
+        assert_eq!(leaves.counts[&1], 10);
+        let total: u64 = leaves.counts.values().sum();
+        assert_eq!(total, 10 + HOTSPOT_TOP_K as u64 * 2);
+    }
+
+    #[test]
+    fn test_hotspots_window() {
+        let hotspots = Hotspots::default();
+        let (a, b) = (ContractId([1; 32]), ContractId([2; 32]));
+        let start = Instant::now();
+        for _ in 0..3 {
+            hotspots.record_at(a, Some(7), false, start);
+        }
+        hotspots.record_at(a, Some(8), true, start);
+        hotspots.record_at(b, None, false, start);
+
+        let later = start + Duration::from_secs(120);
+        hotspots.record_at(a, Some(8), true, later);
+        hotspots.record_at(a, Some(8), true, later);
+
+        let window = Duration::from_secs(60);
+        assert_eq!(hotspots.top_leaves_at(&a, window, 10, later), vec![(8, 2)]);
+        assert_eq!(
+            hotspots.top_leaves_at(&a, HOTSPOT_MAX_WINDOW, 10, later),
+            vec![(7, 3), (8, 3)]
+        );
+        assert_eq!(
+            hotspots.top_leaves_at(&a, HOTSPOT_MAX_WINDOW, 1, later),
+            vec![(7, 3)]
+        );
+        assert_eq!(
+            hotspots.contracts_at(window, later),
+            vec![ContractAccesses {
+                contract_id: a,
+                reads: 0,
+                writes: 2
+            }]
+        );
+        let contracts = hotspots.contracts_at(HOTSPOT_MAX_WINDOW, later);
+        assert_eq!(contracts.len(), 2);
+        assert_eq!((contracts[0].reads, contracts[0].writes), (3, 3));
+        assert_eq!(contracts[1].contract_id, b);
+
+        // Accesses older than the longest window are dropped.
+        let expired = later + HOTSPOT_MAX_WINDOW + HOTSPOT_BUCKET;
+        assert!(hotspots
+            .contracts_at(HOTSPOT_MAX_WINDOW, expired)
+            .is_empty());
+        assert!(hotspots
+            .top_leaves_at(&a, HOTSPOT_MAX_WINDOW, 10, expired)
+            .is_empty());
+    }
+}
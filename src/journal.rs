@@ -0,0 +1,161 @@
+use std::fmt::Debug;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::kvpair::Hash;
+use crate::merkle::{MerkleError, MerkleErrorCode, NodeIndex};
+
+/// All the writes of one leaf update, recorded before any of them is applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry<H> {
+    /// The index and the new hash of the leaf.
+    pub leaf: (u64, H),
+    /// The new parents from the leaf up to the root, as (index, hash, left, right).
+    pub parents: Vec<(NodeIndex, H, H, H)>,
+    pub root: H,
+}
+
+/// A write-ahead journal holding at most one pending entry.
+/// An entry is opened before the writes of an update and closed after the root is updated,
+/// so an entry still pending at startup belongs to an update interrupted by a crash.
+pub trait Journal<H> {
+    fn open(&mut self, entry: &JournalEntry<H>) -> Result<(), MerkleError>;
+    fn close(&mut self) -> Result<(), MerkleError>;
+    fn pending(&mut self) -> Result<Option<JournalEntry<H>>, MerkleError>;
+}
+
+/// A journal kept in memory, which only protects against errors of the backend.
+impl<H: Clone> Journal<H> for Option<JournalEntry<H>> {
+    fn open(&mut self, entry: &JournalEntry<H>) -> Result<(), MerkleError> {
+        *self = Some(entry.clone());
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), MerkleError> {
+        *self = None;
+        Ok(())
+    }
+
+    fn pending(&mut self) -> Result<Option<JournalEntry<H>>, MerkleError> {
+        Ok(self.clone())
+    }
+}
+
+/// A journal persisted to a file. The entry is written to a temporary file which is synced
+/// and then renamed, so that a crash never leaves a partially written entry.
+#[derive(Debug, Clone)]
+pub struct FileJournal {
+    path: PathBuf,
+}
+
+impl FileJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+}
+
+// The journal errors are reported as `InvalidOther`, their text is logged when tracing.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn journal_error(e: impl Debug) -> MerkleError {
+    #[cfg(feature = "tracing")]
+    tracing::error!(error = ?e, "Journal I/O failed");
+    MerkleError::new(Hash::empty(), 0, MerkleErrorCode::InvalidOther)
+}
+
+impl<H: Serialize + DeserializeOwned> Journal<H> for FileJournal {
+    fn open(&mut self, entry: &JournalEntry<H>) -> Result<(), MerkleError> {
+        let bytes = serde_json::to_vec(entry).map_err(journal_error)?;
+        let tmp_path = self.tmp_path();
+        let mut file = fs::File::create(&tmp_path).map_err(journal_error)?;
+        file.write_all(&bytes).map_err(journal_error)?;
+        file.sync_all().map_err(journal_error)?;
+        fs::rename(&tmp_path, &self.path).map_err(journal_error)
+    }
+
+    fn close(&mut self) -> Result<(), MerkleError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(journal_error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn pending(&mut self) -> Result<Option<JournalEntry<H>>, MerkleError> {
+        match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(journal_error),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(journal_error(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvpair::DEFAULT_HASH_VEC;
+    use crate::memory::MemoryMerkleTree;
+    use crate::merkle::{MerkleNode, MerkleTree};
+
+    #[test]
+    fn test_file_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = FileJournal::new(dir.path().join("journal"));
+        assert_eq!(Journal::<Hash>::pending(&mut journal).unwrap(), None);
+
+        let entry = JournalEntry {
+            leaf: (3, DEFAULT_HASH_VEC[0]),
+            parents: vec![(
+                NodeIndex(1),
+                DEFAULT_HASH_VEC[1],
+                DEFAULT_HASH_VEC[0],
+                DEFAULT_HASH_VEC[0],
+            )],
+            root: DEFAULT_HASH_VEC[2],
+        };
+        journal.open(&entry).unwrap();
+        assert_eq!(journal.pending().unwrap(), Some(entry));
+        Journal::<Hash>::close(&mut journal).unwrap();
+        assert_eq!(Journal::<Hash>::pending(&mut journal).unwrap(), None);
+        // Closing twice is fine, e.g. after a crash between the root update and the close.
+        Journal::<Hash>::close(&mut journal).unwrap();
+    }
+
+    #[test]
+    fn test_recover_memory_merkle_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = FileJournal::new(dir.path().join("journal"));
+        let index = NodeIndex((1 << 10) - 1 + 7);
+
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let (mut leaf, _) = mt.get_leaf_with_proof(index).unwrap();
+        leaf.set(&[1; 32]).unwrap();
+        let entry = mt.journal_entry(&leaf).unwrap();
+        // Crash after the journal entry is written, before any node is updated.
+        journal.open(&entry).unwrap();
+        assert_eq!(mt.get_root_hash(), DEFAULT_HASH_VEC[10]);
+
+        assert!(mt.recover(&mut journal).unwrap());
+        assert_eq!(Journal::<Hash>::pending(&mut journal).unwrap(), None);
+        assert!(!mt.recover(&mut journal).unwrap());
+
+        let mut expected = MemoryMerkleTree::<10>::new();
+        expected
+            .update_leaf_data_with_proof(index, &[1; 32])
+            .unwrap();
+        assert_eq!(mt.get_root_hash(), expected.get_root_hash());
+        let (node, proof) = mt.get_leaf_with_proof(index).unwrap();
+        assert_eq!(node.hash(), Hash::hash_data(&[1; 32]));
+        assert!(mt.verify_proof(proof).unwrap());
+    }
+}
@@ -1,17 +1,18 @@
 use crate::merkle::get_node_type;
-use crate::poseidon::{gen_merkle_hasher, gen_merkle_leaf_hasher};
+use crate::poseidon::{gen_merkle_leaf_hasher, merkle_hash_pair};
 use crate::proto::kv_pair_client::KvPairClient;
 
-use crate::proto::node::NodeData;
 use crate::proto::{
-    GetLeafRequest, GetLeafResponse, GetNonLeafRequest, GetNonLeafResponse, GetRootRequest,
-    GetRootResponse, Node, NodeChildren, NodeType, ProofType, SetLeafRequest, SetLeafResponse,
-    SetNonLeafRequest, SetNonLeafResponse, SetRootRequest, SetRootResponse,
+    GetLeafRequest, GetLeafResponse, GetNonLeafRequest, GetNonLeafResponse, GetRangeProofRequest,
+    GetRangeProofResponse, GetRootRequest, GetRootResponse, NodeType, ProofType, SetLeafRequest,
+    SetLeafResponse, SetNonLeafRequest, SetNonLeafResponse, SetRootRequest, SetRootResponse,
 };
 
 use crate::Error;
 
-use super::merkle::{MerkleError, MerkleErrorCode, MerkleNode, MerkleTree};
+use super::merkle::{
+    MerkleError, MerkleErrorCode, MerkleNode, MerkleTree, MerkleTreeRead, NodeIndex,
+};
 use ff::PrimeField;
 use futures::executor;
 use halo2_proofs::pairing::bn256::Fr;
@@ -19,7 +20,7 @@ use halo2_proofs::pairing::bn256::Fr;
 use mongodb::bson::doc;
 use mongodb::bson::{spec::BinarySubtype, Bson};
 use serde::{
-    de::{Error as SerdeError, Unexpected},
+    de::{Error as SerdeError, SeqAccess, Unexpected, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
 };
 
@@ -43,7 +44,7 @@ lazy_static::lazy_static! {
     };
 }
 
-#[derive(Copy, Debug, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[derive(Copy, Debug, Clone, Eq, PartialEq, std::hash::Hash, Default, Serialize, Deserialize)]
 pub struct ContractId(
     #[serde(serialize_with = "self::serialize_bytes_as_binary")]
     #[serde(deserialize_with = "self::deserialize_u256_from_binary")]
@@ -89,13 +90,72 @@ impl From<[u8; 32]> for ContractId {
 }
 
 /// Note that the hash here must represents a valid field element.
+/// The bytes are little-endian, as produced by `Fr::to_repr`: the first byte is the least
+/// significant one. Use `to_be_bytes` and `from_be_bytes` for big-endian consumers (e.g. the EVM).
+/// It is serialized as a `0x` prefixed hex string in human-readable formats (e.g. JSON),
+/// and as raw bytes otherwise (e.g. bincode). MongoDB documents store hashes as binary with
+/// `serialize_hash_as_binary` and `deserialize_hash_from_binary`.
 /// TODO: Maybe we should wrap Fr instead of [u8; 32] here.
-#[derive(Copy, Debug, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
-pub struct Hash(
-    #[serde(serialize_with = "self::serialize_bytes_as_binary")]
-    #[serde(deserialize_with = "self::deserialize_u256_from_binary")]
-    pub [u8; 32],
-);
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, std::hash::Hash, Default)]
+pub struct Hash(pub [u8; 32]);
+
+impl Serialize for Hash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&format!("0x{}", hex::encode(self.0)))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct HashVisitor;
+
+        impl<'de> Visitor<'de> for HashVisitor {
+            type Value = Hash;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("32 bytes or a 0x prefixed hex string of 32 bytes")
+            }
+
+            fn visit_str<E: SerdeError>(self, v: &str) -> Result<Hash, E> {
+                let bytes = hex::decode(v.strip_prefix("0x").unwrap_or(v))
+                    .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))?;
+                self.visit_bytes(&bytes)
+            }
+
+            fn visit_bytes<E: SerdeError>(self, v: &[u8]) -> Result<Hash, E> {
+                v.try_into()
+                    .map(Hash)
+                    .map_err(|_| E::invalid_length(v.len(), &self))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Hash, A::Error> {
+                let mut bytes = [0u8; 32];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                }
+                Ok(Hash(bytes))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(HashVisitor)
+        } else {
+            deserializer.deserialize_bytes(HashVisitor)
+        }
+    }
+}
 
 // TODO: Maybe use something like protovalidate to automatically validate fields.
 impl TryFrom<&[u8]> for Hash {
@@ -123,7 +183,13 @@ impl TryFrom<[u8; 32]> for Hash {
     type Error = Error;
 
     fn try_from(hash: [u8; 32]) -> Result<Hash, Self::Error> {
-        Ok(Self(hash))
+        let hash = Self(hash);
+        if !hash.is_valid_field_element() {
+            return Err(Error::InvalidArgument(
+                "Hash malformed (must be a canonical field element)".to_string(),
+            ));
+        }
+        Ok(hash)
     }
 }
 
@@ -153,10 +219,7 @@ impl From<Hash> for Vec<u8> {
 
 impl Hash {
     pub fn hash_children(left: &Self, right: &Self) -> Self {
-        let mut hasher = gen_merkle_hasher();
-        let a = Fr::from(*left);
-        let b = Fr::from(*right);
-        hasher.update_exact(&[a, b]).into()
+        merkle_hash_pair(left, right)
     }
 
     pub fn hash_data(data: &[u8]) -> Self {
@@ -185,6 +248,26 @@ impl Hash {
         Self([0u8; 32])
     }
 
+    /// Whether the bytes of this hash are the canonical representation of a field element.
+    pub fn is_valid_field_element(&self) -> bool {
+        Fr::from_repr(self.0).is_some().into()
+    }
+
+    /// The bytes of the field element in big-endian order, e.g. a `uint256` or `bytes32` in
+    /// the EVM. This is the reverse of the native little-endian bytes.
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut bytes = self.0;
+        bytes.reverse();
+        bytes
+    }
+
+    /// The hash of a field element given in big-endian order, the inverse of `to_be_bytes`.
+    /// Fails like `TryFrom<[u8; 32]>` if the bytes are not a canonical field element.
+    pub fn from_be_bytes(mut bytes: [u8; 32]) -> Result<Self, Error> {
+        bytes.reverse();
+        bytes.try_into()
+    }
+
     /// depth start from 0 up to Self::height(). Example 20 height MongoMerkle, root depth=0, leaf depth=20
     pub fn get_default_hash_for_depth(depth: usize) -> Result<Hash, MerkleError> {
         if depth <= MERKLE_TREE_HEIGHT {
@@ -311,6 +394,20 @@ where
     binary.serialize(serializer)
 }
 
+pub fn serialize_hash_as_binary<S>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serialize_bytes_as_binary(&hash.0, serializer)
+}
+
+pub fn deserialize_hash_from_binary<'de, D>(deserializer: D) -> Result<Hash, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_u256_from_binary(deserializer).map(Hash)
+}
+
 pub fn deserialize_bytes_from_binary<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
 where
     D: Deserializer<'de>,
@@ -355,117 +452,20 @@ pub struct MerkleRecord {
     #[serde(serialize_with = "self::serialize_u64_as_binary")]
     #[serde(deserialize_with = "self::deserialize_u64_as_binary")]
     pub index: u64,
+    #[serde(serialize_with = "self::serialize_hash_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_hash_from_binary")]
     pub hash: Hash,
+    #[serde(serialize_with = "self::serialize_hash_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_hash_from_binary")]
     pub left: Hash,
+    #[serde(serialize_with = "self::serialize_hash_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_hash_from_binary")]
     pub right: Hash,
     #[serde(serialize_with = "self::serialize_bytes_as_binary")]
     #[serde(deserialize_with = "self::deserialize_u256_from_binary")]
     pub data: [u8; 32],
 }
 
-impl TryFrom<Node> for MerkleRecord {
-    type Error = Error;
-
-    fn try_from(n: Node) -> Result<Self, Self::Error> {
-        let hash: Hash = n.hash.as_slice().try_into()?;
-        if n.node_type == NodeType::NodeLeaf as i32 {
-            match n.node_data {
-                Some(NodeData::Data(_)) => {
-                    let record = MerkleRecord::new_leaf(n.index, hash);
-                    assert_eq!(record.hash.0.to_vec(), n.hash);
-                    Ok(record)
-                }
-                _ => {
-                    dbg!(&n);
-                    panic!("Invalid node data");
-                }
-            }
-        } else if n.node_type == NodeType::NodeNonLeaf as i32 {
-            match n.node_data {
-                Some(NodeData::Children(children)) => {
-                    let left: Hash = children.left_child_hash.as_slice().try_into()?;
-                    let right: Hash = children.right_child_hash.as_slice().try_into()?;
-                    let record = MerkleRecord::new_non_leaf(n.index, left, right);
-                    assert_eq!(record.hash.0.to_vec(), n.hash);
-                    Ok(record)
-                }
-                _ => {
-                    dbg!(&n);
-                    panic!("Invalid node data");
-                }
-            }
-        } else {
-            Err(Error::InvalidArgument("Invalid node type".to_string()))
-        }
-    }
-}
-
-impl TryFrom<(MerkleRecord, DataHashRecord)> for Node {
-    type Error = Error;
-
-    fn try_from(record: (MerkleRecord, DataHashRecord)) -> Result<Self, Self::Error> {
-        let merkle_record = record.0;
-        let datahash_record = record.1;
-        let node = Self::try_from(merkle_record);
-        if node.is_ok() {
-            return node;
-        }
-
-        if merkle_record.hash != datahash_record.hash {
-            return Err(Error::InvalidArgument("Hash mismatched".to_string()));
-        }
-
-        let node_type = get_node_type(merkle_record.index(), MERKLE_TREE_HEIGHT);
-        if node_type != NodeType::NodeLeaf {
-            return Err(Error::InvalidArgument("Unknown node type".to_string()));
-        }
-        let node_data = { NodeData::Data(datahash_record.data) };
-        Ok(Node {
-            index: merkle_record.index(),
-            hash: merkle_record.hash().into(),
-            node_type: node_type.into(),
-            node_data: Some(node_data),
-        })
-    }
-}
-
-impl TryFrom<MerkleRecord> for Node {
-    type Error = Error;
-
-    fn try_from(merkle_record: MerkleRecord) -> Result<Self, Self::Error> {
-        let index = merkle_record.index();
-        let hash = merkle_record.hash().into();
-        let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
-        if node_type != NodeType::NodeNonLeaf {
-            return Err(Error::InconsistentData("Unknown node type".to_string()));
-        }
-        let node_data = {
-            let left_child_hash = merkle_record
-                .left()
-                .ok_or(Error::InconsistentData(
-                    "Nonleaf node has no children".to_string(),
-                ))?
-                .into();
-            let right_child_hash = merkle_record
-                .right()
-                .ok_or(Error::InconsistentData(
-                    "Nonleaf node has no children".to_string(),
-                ))?
-                .into();
-            NodeData::Children(NodeChildren {
-                left_child_hash,
-                right_child_hash,
-            })
-        };
-        Ok(Node {
-            index,
-            hash,
-            node_type: node_type.into(),
-            node_data: Some(node_data),
-        })
-    }
-}
-
 impl MerkleNode<Hash> for MerkleRecord {
     fn index(&self) -> u64 {
         self.index
@@ -473,8 +473,16 @@ impl MerkleNode<Hash> for MerkleRecord {
     fn hash(&self) -> Hash {
         self.hash
     }
-    fn set(&mut self, data: &[u8]) {
+    fn set(&mut self, data: &[u8]) -> Result<(), MerkleError> {
+        if data.len() != 32 {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                self.index,
+                MerkleErrorCode::InvalidLeafData,
+            ));
+        }
         self.hash = Hash::hash_data(data);
+        Ok(())
     }
     fn right(&self) -> Option<Hash> {
         Some(self.right)
@@ -484,6 +492,12 @@ impl MerkleNode<Hash> for MerkleRecord {
     }
 }
 
+impl From<(u64, Hash)> for MerkleRecord {
+    fn from((index, hash): (u64, Hash)) -> Self {
+        MerkleRecord::new_leaf(index, hash)
+    }
+}
+
 impl MerkleRecord {
     pub fn new(index: u64) -> Self {
         MerkleRecord {
@@ -537,6 +551,8 @@ impl MerkleRecord {
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct DataHashRecord {
+    #[serde(serialize_with = "self::serialize_hash_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_hash_from_binary")]
     pub hash: Hash,
     #[serde(serialize_with = "self::serialize_bytes_as_binary")]
     #[serde(deserialize_with = "self::deserialize_bytes_from_binary")]
@@ -566,12 +582,26 @@ impl MongoMerkle {
             .expect("Connect gRPC server")
     }
 
+    /// Same as `construct`, but with the given client instead of a new connection, e.g. a clone
+    /// of a client shared by the trees of a `TreeManager`.
+    pub fn construct_with_client(
+        client: KvPairClient<Channel>,
+        addr: ContractId,
+        root: Hash,
+    ) -> Self {
+        MongoMerkle {
+            root_hash: root,
+            client,
+            contract_id: addr,
+        }
+    }
+
     pub fn height() -> usize {
         MERKLE_TREE_HEIGHT
     }
     fn empty_leaf(index: u64) -> MerkleRecord {
         let mut leaf = MerkleRecord::new(index);
-        leaf.set([0; 32].as_ref());
+        leaf.set([0; 32].as_ref()).unwrap();
         leaf
     }
 
@@ -605,6 +635,17 @@ impl MongoMerkle {
         index: u64,
         hash: Option<Hash>,
         proof_type: ProofType,
+    ) -> Result<GetLeafResponse, Status> {
+        self.send_get_leaf(index, hash, proof_type, false).await
+    }
+
+    // Like `get_leaf`, without loading the data of the leaf if `skip_data` is set.
+    async fn send_get_leaf(
+        &mut self,
+        index: u64,
+        hash: Option<Hash>,
+        proof_type: ProofType,
+        skip_data: bool,
     ) -> Result<GetLeafResponse, Status> {
         let response = self
             .client
@@ -613,6 +654,9 @@ impl MongoMerkle {
                 hash: hash.map(|h| h.into()),
                 proof_type: proof_type.into(),
                 contract_id: Some(self.contract_id.into()),
+                root: None,
+                read_session: None,
+                skip_data,
             }))
             .await?;
         dbg!(&response);
@@ -635,6 +679,9 @@ impl MongoMerkle {
                 data: Some(leaf_data.0),
                 proof_type,
                 contract_id: Some(self.contract_id.into()),
+                force: false,
+                metadata: None,
+                expected_leaf_hash: None,
             }))
             .await?;
         dbg!(&response);
@@ -642,6 +689,24 @@ impl MongoMerkle {
         Ok(response.into_inner())
     }
 
+    pub async fn get_range_proof(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<GetRangeProofResponse, Status> {
+        let response = self
+            .client
+            .get_range_proof(Request::new(GetRangeProofRequest {
+                contract_id: Some(self.contract_id.into()),
+                start,
+                end,
+                root: None,
+                read_session: None,
+            }))
+            .await?;
+        Ok(response.into_inner())
+    }
+
     pub async fn get_non_leaf(
         &mut self,
         index: u64,
@@ -710,38 +775,69 @@ impl MerkleTree<Hash, MERKLE_TREE_HEIGHT> for MongoMerkle {
         Hash::hash_children(a, b)
     }
 
+    fn get_default_hash(depth: usize) -> Result<Hash, MerkleError> {
+        Hash::get_default_hash_for_depth(depth)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, hash, left, right))
+    )]
     fn set_parent(
         &mut self,
-        index: u64,
+        index: NodeIndex,
         hash: &Hash,
         left: &Hash,
         right: &Hash,
     ) -> Result<(), MerkleError> {
-        self.boundary_check(index)?;
+        self.boundary_check(index.0)?;
         println!("set_node_with_hash {} {:?}", index, hash);
-        executor::block_on(self.set_non_leaf(index, Some(*hash), *left, *right)).map_err(|e| {
-            dbg!(e);
-            MerkleError::new(*hash, index, MerkleErrorCode::InvalidDepth)
-        })?;
+        executor::block_on(self.set_non_leaf(index.0, Some(*hash), *left, *right)).map_err(
+            |e| {
+                dbg!(e);
+                MerkleError::new(*hash, index.0, MerkleErrorCode::InvalidDepth)
+            },
+        )?;
         Ok(())
     }
 
-    fn get_node_with_hash(&mut self, index: u64, hash: &Hash) -> Result<Self::Node, MerkleError> {
-        let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, hash))
+    )]
+    fn get_node_with_hash(
+        &mut self,
+        index: NodeIndex,
+        hash: &Hash,
+    ) -> Result<Self::Node, MerkleError> {
+        let node_type = get_node_type(index.0, MERKLE_TREE_HEIGHT);
         let node = if node_type == NodeType::NodeLeaf {
-            executor::block_on(self.get_leaf(index, Some(*hash), ProofType::ProofEmpty))
-                .map(|x| x.node.unwrap())
+            // The nodes only carry hashes, so the data of the leaf is not loaded.
+            executor::block_on(self.send_get_leaf(
+                index.0,
+                Some(*hash),
+                ProofType::ProofEmpty,
+                true,
+            ))
+            .map(|x| x.node)
         } else {
-            executor::block_on(self.get_non_leaf(index, *hash)).map(|x| x.node.unwrap())
+            executor::block_on(self.get_non_leaf(index.0, *hash)).map(|x| x.node)
         }
-        .and_then(|x| Ok(MerkleRecord::try_from(x)?))
+        .and_then(|x| {
+            let node = x.ok_or(Error::InvalidArgument("Node not returned".to_string()))?;
+            Ok(MerkleRecord::try_from(node)?)
+        })
         .map_err(|e| {
             dbg!(e);
-            MerkleError::new(*hash, index, MerkleErrorCode::InvalidOther)
+            MerkleError::new(*hash, index.0, MerkleErrorCode::InvalidOther)
         })?;
         Ok(node)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(index = leaf.index))
+    )]
     fn set_leaf(&mut self, leaf: &MerkleRecord) -> Result<(), MerkleError> {
         self.boundary_check(leaf.index())?; //should be leaf check?
         executor::block_on(self.set_leaf(leaf.index, Default::default(), ProofType::ProofEmpty))
@@ -753,17 +849,19 @@ impl MerkleTree<Hash, MERKLE_TREE_HEIGHT> for MongoMerkle {
     }
 }
 
-impl Node {
-    /// This corresponds to data in simple_get/simple_set of zkWasm-rust.
-    /// Here we create a Node that has empty vector as its data, although
-    /// technically an empty vector does not hash to the hash in the merkle record.
-    pub fn new_simple_leaf(index: u64, hash: Hash) -> Self {
-        Node {
-            index,
-            hash: hash.into(),
-            node_type: NodeType::NodeLeaf.into(),
-            node_data: Some(NodeData::Data(vec![])),
-        }
+impl MerkleTreeRead<Hash, MERKLE_TREE_HEIGHT> for MongoMerkle {
+    fn read_node_with_hash(
+        &self,
+        index: NodeIndex,
+        hash: &Hash,
+    ) -> Result<Self::Node, MerkleError> {
+        // The client only needs `&mut` to send a request, and clones share its channel.
+        let mut tree = MongoMerkle {
+            root_hash: self.root_hash,
+            contract_id: self.contract_id,
+            client: self.client.clone(),
+        };
+        tree.get_node_with_hash(index, hash)
     }
 }
 
@@ -780,6 +878,43 @@ mod tests {
         r.try_into().unwrap()
     }
 
+    #[test]
+    fn test_hash_serde() {
+        let hash = DEFAULT_HASH_VEC[0];
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"0x{}\"", hex::encode(hash.0)));
+        assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), hash);
+        assert!(serde_json::from_str::<Hash>("\"0x1234\"").is_err());
+
+        // Same layout as the proofs serialized before, the length followed by the bytes.
+        let bytes = bincode::serialize(&hash).unwrap();
+        assert_eq!(bytes[..8], 32_u64.to_le_bytes());
+        assert_eq!(bytes[8..], hash.0);
+        assert_eq!(bincode::deserialize::<Hash>(&bytes).unwrap(), hash);
+
+        // Hashes are always stored as binary in MongoDB.
+        let record = MerkleRecord::new_non_leaf(1, hash, hash);
+        let document = mongodb::bson::to_document(&record).unwrap();
+        assert!(matches!(document.get("hash"), Some(Bson::Binary(_))));
+        assert_eq!(
+            mongodb::bson::from_document::<MerkleRecord>(document).unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn test_hash_be_bytes() {
+        let hash = Hash::from(Fr::from(0x0102));
+        assert_eq!(hash.0[..2], [0x02, 0x01]);
+        let bytes = hash.to_be_bytes();
+        assert_eq!(bytes[30..], [0x01, 0x02]);
+        assert_eq!(Hash::from_be_bytes(bytes).unwrap(), hash);
+
+        let hash = DEFAULT_HASH_VEC[32];
+        assert_eq!(Hash::from_be_bytes(hash.to_be_bytes()).unwrap(), hash);
+        assert!(Hash::from_be_bytes([0xff; 32]).is_err());
+    }
+
     #[test]
     fn show_default_root() {
         for (i, h) in DEFAULT_HASH_VEC.iter().enumerate() {
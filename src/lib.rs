@@ -1,5 +1,11 @@
+pub mod client;
+pub mod coalesce;
+pub mod encoding;
 pub mod errors;
+pub mod hotspots;
+pub mod journal;
 pub mod kvpair;
+pub mod memory;
 pub mod merkle;
 pub mod poseidon;
 pub mod service;
@@ -7,6 +13,8 @@ pub mod service;
 pub mod proto {
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("kvpair_descriptor");
     tonic::include_proto!("kvpair");
+
+    pub mod convert;
 }
 
 use errors::*;
@@ -1,15 +1,24 @@
 use futures::{channel::oneshot, FutureExt};
 use http::Method;
 use tokio::signal;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Server;
 use tonic_web::GrpcWebLayer;
 use tower_http::cors::{Any, CorsLayer};
 
-use zkc_state_manager::proto::{kv_pair_server::KvPairServer, FILE_DESCRIPTOR_SET};
-use zkc_state_manager::service::MongoKvPair;
+use zkc_state_manager::service::{
+    kvpair_server, reflection_service, ApiKeyInterceptor, MongoKvPair,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Log the duration of the tree operations when their spans close.
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .init();
+
     let addr = format!(
         "0.0.0.0:{}",
         std::env::var("KVPAIR_PORT").unwrap_or("50051".to_string())
@@ -17,13 +26,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     .parse()
     .unwrap();
 
-    let reflection_service = tonic_reflection::server::Builder::configure()
-        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
-        .build()
-        .unwrap();
+    // Reflection can be disabled to lock down production deployments.
+    let reflection_service = if std::env::var("KVPAIR_DISABLE_REFLECTION").is_ok() {
+        None
+    } else {
+        Some(reflection_service())
+    };
+
+    // Compression can be disabled on deployments short of CPU rather than bandwidth.
+    let compression = std::env::var("KVPAIR_DISABLE_COMPRESSION").is_err();
 
     let server = MongoKvPair::new().await;
-    let server = KvPairServer::new(server);
+    let server = InterceptedService::new(
+        kvpair_server(server, compression),
+        ApiKeyInterceptor::from_env(),
+    );
 
     println!("Server listening on {}", addr);
     let (send, recv) = oneshot::channel();
@@ -49,7 +66,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .accept_http1(true)
         .layer(GrpcWebLayer::new())
         .layer(cors)
-        .add_service(reflection_service)
+        .add_optional_service(reflection_service)
         .add_service(tonic_web::enable(server))
         .serve_with_shutdown(addr, recv.map(drop))
         .await?;
@@ -0,0 +1,1091 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicBool;
+use std::time::SystemTime;
+
+use crate::kvpair::{Hash, MerkleRecord, DEFAULT_HASH_VEC};
+use crate::merkle::{
+    check_cancelled, Checkpoints, MerkleError, MerkleErrorCode, MerkleNode, MerkleTree,
+    MerkleTreeRead, NodeIndex, VersionId,
+};
+
+/// A merkle tree which keeps all its nodes in memory.
+/// Like the MongoDB backend, nodes are keyed by both their index and their hash,
+/// so that nodes reachable from older roots are never overwritten.
+/// The depth `D` must not exceed `MERKLE_TREE_HEIGHT`, as the default hashes are shared.
+#[derive(Debug, Clone)]
+pub struct MemoryMerkleTree<const D: usize> {
+    root_hash: Hash,
+    records: HashMap<(u64, Hash), MerkleRecord>,
+    // Roots recorded by `checkpoint` with their time, indexed by version.
+    checkpoints: Vec<(Hash, SystemTime)>,
+}
+
+impl<const D: usize> Default for MemoryMerkleTree<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const D: usize> MemoryMerkleTree<D> {
+    /// Create a tree with all leaves empty.
+    pub fn new() -> Self {
+        Self::construct((), DEFAULT_HASH_VEC[D])
+    }
+
+    /// Number of nodes stored, default nodes are not counted.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Delete all the stored nodes which are not reachable from the retained roots, the
+    /// current root or the checkpointed roots, and return the number of deleted nodes.
+    /// Nodes are first marked from all the kept roots and only then swept, so that nodes
+    /// shared between roots are kept. Fails with `Cancelled`, without deleting anything, if
+    /// `cancel` is set during the mark.
+    pub fn compact(
+        &mut self,
+        retain_roots: &[Hash],
+        cancel: &AtomicBool,
+    ) -> Result<u64, MerkleError> {
+        let mut marked = HashSet::new();
+        let mut stack: Vec<(u64, Hash)> = retain_roots
+            .iter()
+            .chain(self.checkpoints.iter().map(|(root, _)| root))
+            .chain(std::iter::once(&self.root_hash))
+            .map(|root| (0, *root))
+            .collect();
+        while let Some(key) = stack.pop() {
+            check_cancelled(cancel, key.0)?;
+            // Nodes which are not stored are default nodes, and so are all their descendants.
+            let record = match self.records.get(&key) {
+                Some(record) => record,
+                None => continue,
+            };
+            if !marked.insert(key) {
+                continue;
+            }
+            let (index, _) = key;
+            if self.leaf_check(index).is_err() {
+                stack.push((index * 2 + 1, record.left));
+                stack.push((index * 2 + 2, record.right));
+            }
+        }
+        let before = self.records.len();
+        self.records.retain(|key, _| marked.contains(key));
+        Ok((before - self.records.len()) as u64)
+    }
+
+    fn get_default_record(index: u64) -> MerkleRecord {
+        let depth = (index + 1).ilog2() as usize;
+        let child_hash = if depth == D {
+            Hash::empty()
+        } else {
+            DEFAULT_HASH_VEC[D - depth - 1]
+        };
+        MerkleRecord {
+            index,
+            hash: DEFAULT_HASH_VEC[D - depth],
+            left: child_hash,
+            right: child_hash,
+            data: [0; 32],
+        }
+    }
+}
+
+impl<const D: usize> MerkleTree<Hash, D> for MemoryMerkleTree<D> {
+    type Id = ();
+    type Root = Hash;
+    type Node = MerkleRecord;
+
+    fn construct(_addr: Self::Id, root: Self::Root) -> Self {
+        MemoryMerkleTree {
+            root_hash: root,
+            records: HashMap::new(),
+            checkpoints: vec![],
+        }
+    }
+
+    fn hash(a: &Hash, b: &Hash) -> Hash {
+        Hash::hash_children(a, b)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, hash, left, right))
+    )]
+    fn set_parent(
+        &mut self,
+        index: NodeIndex,
+        hash: &Hash,
+        left: &Hash,
+        right: &Hash,
+    ) -> Result<(), MerkleError> {
+        self.boundary_check(index.0)?;
+        let mut record = MerkleRecord::new(index.0);
+        record.hash = *hash;
+        record.left = *left;
+        record.right = *right;
+        self.records.insert((index.0, *hash), record);
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(index = leaf.index))
+    )]
+    fn set_leaf(&mut self, leaf: &MerkleRecord) -> Result<(), MerkleError> {
+        self.leaf_check(leaf.index())?;
+        self.records.insert((leaf.index(), leaf.hash()), *leaf);
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, hash))
+    )]
+    fn get_node_with_hash(
+        &mut self,
+        index: NodeIndex,
+        hash: &Hash,
+    ) -> Result<MerkleRecord, MerkleError> {
+        self.read_node_with_hash(index, hash)
+    }
+
+    fn get_root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    fn update_root_hash(&mut self, hash: &Hash) {
+        self.root_hash = *hash;
+    }
+
+    fn get_default_hash(depth: usize) -> Result<Hash, MerkleError> {
+        if depth <= D {
+            Ok(DEFAULT_HASH_VEC[D - depth])
+        } else {
+            Err(MerkleError::new(
+                Hash::empty(),
+                depth as u64,
+                MerkleErrorCode::InvalidDepth,
+            ))
+        }
+    }
+}
+
+impl<const D: usize> MerkleTreeRead<Hash, D> for MemoryMerkleTree<D> {
+    fn read_node_with_hash(
+        &self,
+        index: NodeIndex,
+        hash: &Hash,
+    ) -> Result<MerkleRecord, MerkleError> {
+        self.boundary_check(index.0)?;
+        if let Some(record) = self.records.get(&(index.0, *hash)) {
+            return Ok(*record);
+        }
+        let default_record = Self::get_default_record(index.0);
+        if default_record.hash == *hash {
+            Ok(default_record)
+        } else {
+            Err(MerkleError::new(
+                *hash,
+                index.0,
+                MerkleErrorCode::InvalidHash,
+            ))
+        }
+    }
+}
+
+impl<const D: usize> Checkpoints<Hash, D> for MemoryMerkleTree<D> {
+    fn checkpoint(&mut self) -> Result<VersionId, MerkleError> {
+        self.checkpoints.push((self.root_hash, SystemTime::now()));
+        Ok(VersionId(self.checkpoints.len() as u64 - 1))
+    }
+
+    fn root_of(&self, version: VersionId) -> Option<Hash> {
+        self.checkpoints
+            .get(version.0 as usize)
+            .map(|(root, _)| *root)
+    }
+
+    fn list_checkpoints(
+        &self,
+        start: VersionId,
+        limit: usize,
+    ) -> Result<Vec<(VersionId, Hash, SystemTime)>, MerkleError> {
+        Ok(self
+            .checkpoints
+            .iter()
+            .enumerate()
+            .skip(start.0 as usize)
+            .take(limit)
+            .map(|(version, (root, time))| (VersionId(version as u64), *root, *time))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvpair::MERKLE_TREE_HEIGHT;
+    use crate::merkle::{
+        hash_sorted, verify_value, verify_value_with, CachingMerkleTree, LeafIndex, PrefetchBudget,
+        PrefetchStats, ReadOnlyMerkleTree, SortedPairMerkleTree, TreeManager, Update,
+    };
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_memory_merkle_tree_set_and_get_leaf() {
+        let mut mt = MemoryMerkleTree::<MERKLE_TREE_HEIGHT>::new();
+        let index = NodeIndex(2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1);
+        let (mut leaf, _) = mt.get_leaf_with_proof(index).unwrap();
+        assert_eq!(leaf.hash(), DEFAULT_HASH_VEC[0]);
+        leaf.set(&[1; 32]).unwrap();
+        mt.set_leaf_with_proof(&leaf).unwrap();
+        assert_ne!(mt.get_root_hash(), DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT]);
+        let (node, _) = mt.get_leaf_with_proof(index).unwrap();
+        assert_eq!(node.hash(), Hash::hash_data(&[1; 32]));
+
+        // Truncated data is rejected instead of panicking.
+        let err = mt.update_leaf_data_with_proof(index, &[1; 31]).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafData));
+        assert_eq!(mt.get_leaf_with_proof(index).unwrap().0, node);
+
+        // One leaf and all of its ancestors.
+        assert_eq!(mt.len(), MERKLE_TREE_HEIGHT + 1);
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_verify_proof() {
+        // The hash is not commutative, so the proofs only verify when folded from the leaf up.
+        let mut mt = MemoryMerkleTree::<3>::new();
+        mt.update_leaf_data_with_proof(NodeIndex(8), &[1; 32])
+            .unwrap();
+        mt.update_leaf_data_with_proof(NodeIndex(13), &[2; 32])
+            .unwrap();
+        for index in [7, 8, 13].map(NodeIndex) {
+            let (_, proof) = mt.get_leaf_with_proof(index).unwrap();
+            assert!(mt.verify_proof(proof.clone()).unwrap());
+            let mut reversed = proof;
+            reversed.assist.reverse();
+            assert!(!mt.verify_proof(reversed).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_verify_value() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex(2_u64.pow(10) - 1 + 5);
+        let value = [5; 32];
+        let proof = mt.update_leaf_data_with_proof(index, &value).unwrap();
+        let root = mt.get_root_hash();
+        assert!(verify_value(&value, &proof, &root).unwrap());
+
+        // A valid proof of another value of the leaf.
+        assert!(!verify_value(&[6; 32], &proof, &root).unwrap());
+        // A valid proof against another root.
+        assert!(!verify_value(&value, &proof, &DEFAULT_HASH_VEC[10]).unwrap());
+        let mut tampered = proof.clone();
+        tampered.assist[3] = Hash::empty();
+        assert!(!verify_value(&value, &tampered, &root).unwrap());
+
+        let err = verify_value(&[5; 8], &proof, &root).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafData));
+        let mut truncated = proof.clone();
+        truncated.assist.pop();
+        let err = verify_value(&value, &truncated, &root).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidDepth));
+        let (_, internal) = mt.get_internal_node_with_proof(NodeIndex(5)).unwrap();
+        let err = verify_value(&value, &internal, &root).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafIndex));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_verify_value_with() {
+        let poseidon = |value: &[u8]| {
+            crate::poseidon::hash(value)
+                .ok()
+                .and_then(|hash| hash.try_into().ok())
+        };
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = 2_u64.pow(10) - 1 + 5;
+        let value = [5; 32];
+        let leaf = MerkleRecord::new_leaf(index, poseidon(&value).unwrap());
+        let proof = mt.set_leaf_with_proof(&leaf).unwrap();
+        let root = mt.get_root_hash();
+        assert!(verify_value_with(&value, &proof, &root, poseidon).unwrap());
+        // The value is not hashed with the merkle leaf scheme.
+        assert!(!verify_value(&value, &proof, &root).unwrap());
+        assert!(!verify_value_with(&[6; 32], &proof, &root, poseidon).unwrap());
+
+        let err = verify_value_with(&[5; 8], &proof, &root, poseidon).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafData));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_internal_node_proof() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex((1 << 10) - 1 + 5);
+        mt.update_leaf_data_with_proof(index, &[1; 32]).unwrap();
+
+        let (leaf, proof) = mt.get_leaf_with_proof(index).unwrap();
+        assert_eq!(proof.assist.len(), 10);
+        assert_eq!(proof.source, leaf.hash());
+        assert!(mt.verify_proof(proof).unwrap());
+
+        // The ancestor of the leaf at depth 4.
+        let (node, proof) = mt
+            .get_internal_node_with_proof(NodeIndex((1 << 4) - 1))
+            .unwrap();
+        assert_eq!(proof.assist.len(), 4);
+        assert_ne!(node.hash(), DEFAULT_HASH_VEC[10 - 4]);
+        assert!(mt.verify_proof(proof.clone()).unwrap());
+
+        let mut wrong_source = proof;
+        wrong_source.source = DEFAULT_HASH_VEC[10 - 4];
+        assert!(!mt.verify_proof(wrong_source).unwrap());
+
+        assert!(mt.get_internal_node_with_proof(NodeIndex(0)).is_err());
+        assert!(mt.get_internal_node_with_proof(NodeIndex(1 << 11)).is_err());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_proof_stream() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = (1 << 10) - 1 + 5;
+        mt.update_leaf_data_with_proof(NodeIndex(index), &[1; 32])
+            .unwrap();
+        mt.update_leaf_data_with_proof(NodeIndex(index + 1), &[2; 32])
+            .unwrap();
+
+        let mut hash = Hash::hash_data(&[1; 32]);
+        let mut p = 5;
+        for sibling in mt.proof_stream(NodeIndex(index)) {
+            let sibling = sibling.unwrap();
+            hash = if p % 2 == 1 {
+                Hash::hash_children(&sibling, &hash)
+            } else {
+                Hash::hash_children(&hash, &sibling)
+            };
+            p /= 2;
+        }
+        assert_eq!(hash, mt.get_root_hash());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_checkpoints() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex((1 << 10) - 1 + 5);
+        let empty = mt.checkpoint().unwrap();
+        mt.update_leaf_data_with_proof(index, &[1; 32]).unwrap();
+        let first = mt.checkpoint().unwrap();
+        mt.update_leaf_data_with_proof(index, &[2; 32]).unwrap();
+        assert_eq!((empty, first), (VersionId(0), VersionId(1)));
+        assert_eq!(mt.root_of(empty), Some(DEFAULT_HASH_VEC[10]));
+        assert_eq!(mt.root_of(VersionId(2)), None);
+
+        let current = mt.get_root_hash();
+        let (leaf, proof) = mt.get_leaf_with_proof_at(first, index).unwrap();
+        assert_eq!(leaf.hash(), Hash::hash_data(&[1; 32]));
+        assert_eq!(proof.root, mt.root_of(first).unwrap());
+        assert!(mt.verify_proof(proof).unwrap());
+        let (leaf, _) = mt.get_leaf_with_proof_at(empty, index).unwrap();
+        assert_eq!(leaf.hash(), DEFAULT_HASH_VEC[0]);
+        assert_eq!(mt.get_root_hash(), current);
+
+        let err = mt.get_leaf_with_proof_at(VersionId(2), index).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::UnknownVersion));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_list_checkpoints() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex((1 << 10) - 1);
+        let mut roots = vec![];
+        for i in 0..5 {
+            mt.update_leaf_data_with_proof(index, &[i; 32]).unwrap();
+            mt.checkpoint().unwrap();
+            roots.push(mt.get_root_hash());
+        }
+
+        // Listed page by page, in version order.
+        let first = mt.list_checkpoints(VersionId(0), 2).unwrap();
+        let rest = mt.list_checkpoints(VersionId(2), 10).unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(rest.len(), 3);
+        let listed = first.into_iter().chain(rest).collect::<Vec<_>>();
+        for (i, (version, root, _)) in listed.iter().enumerate() {
+            assert_eq!(*version, VersionId(i as u64));
+            assert_eq!(*root, roots[i]);
+        }
+        assert!(listed.windows(2).all(|w| w[0].2 <= w[1].2));
+        assert!(mt.list_checkpoints(VersionId(5), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_proof_lite() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex((1 << 10) - 1 + 5);
+        mt.update_leaf_data_with_proof(index, &[1; 32]).unwrap();
+        let (_, proof) = mt.get_leaf_with_proof(index).unwrap();
+        let (_, lite) = mt.get_leaf_with_proof_lite(index).unwrap();
+        assert_eq!(lite.clone().with_root(proof.root), proof);
+        assert_eq!(
+            bincode::serialize(&proof).unwrap().len(),
+            bincode::serialize(&lite).unwrap().len() + 32
+        );
+
+        let root = mt.get_root_hash();
+        assert!(mt.verify_proof_against_root(lite.clone(), &root).unwrap());
+        assert!(!mt
+            .verify_proof_against_root(lite, &DEFAULT_HASH_VEC[10])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_locate_mismatch() {
+        let cancel = AtomicBool::new(false);
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = (1 << 10) - 1 + 5;
+        mt.update_leaf_data_with_proof(NodeIndex(index), &[1; 32])
+            .unwrap();
+        mt.update_leaf_data_with_proof(NodeIndex(index + 1), &[2; 32])
+            .unwrap();
+        let root = mt.get_root_hash();
+        assert_eq!(mt.locate_mismatch(&root, &cancel).unwrap(), None);
+        assert_eq!(
+            mt.locate_mismatch(&DEFAULT_HASH_VEC[10], &cancel).unwrap(),
+            None
+        );
+        assert_eq!(
+            mt.locate_mismatch(&Hash::hash_data(&[3; 32]), &cancel)
+                .unwrap(),
+            Some(NodeIndex(0))
+        );
+
+        // Lose the parent of the second leaf, as in an interrupted replication.
+        let parent = index / 2;
+        let parent_hash = mt
+            .get_internal_node_with_proof(NodeIndex(parent))
+            .unwrap()
+            .0
+            .hash();
+        let mut corrupted = mt.clone();
+        corrupted.records.remove(&(parent, parent_hash));
+        assert_eq!(
+            corrupted.locate_mismatch(&root, &cancel).unwrap(),
+            Some(NodeIndex(parent))
+        );
+
+        // A stored node which is not the hash of its children.
+        let mut corrupted = mt.clone();
+        let record = corrupted.records.get_mut(&(parent, parent_hash)).unwrap();
+        record.left = Hash::hash_data(&[4; 32]);
+        assert_eq!(
+            corrupted.locate_mismatch(&root, &cancel).unwrap(),
+            Some(NodeIndex(parent))
+        );
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_compact() {
+        let cancel = AtomicBool::new(false);
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex((1 << 10) - 1 + 5);
+        let next = NodeIndex(index.0 + 1);
+        mt.update_leaf_data_with_proof(index, &[1; 32]).unwrap();
+        mt.update_leaf_data_with_proof(next, &[2; 32]).unwrap();
+        let old_root = mt.get_root_hash();
+        mt.update_leaf_data_with_proof(index, &[3; 32]).unwrap();
+
+        // The 9 common ancestors of both leaves in the first tree are unreachable once the
+        // second leaf is set.
+        assert_eq!(mt.compact(&[old_root], &cancel).unwrap(), 9);
+        let mut old = ReadOnlyMerkleTree::new(mt.clone(), &old_root);
+        let (_, proof) = old.get_leaf_with_proof(index).unwrap();
+        assert!(old.verify_proof(proof).unwrap());
+
+        // The old leaf and its 10 ancestors are only reachable from the old root, while the
+        // other leaf and its parent are shared with the current root.
+        assert_eq!(mt.compact(&[], &cancel).unwrap(), 11);
+        assert_eq!(mt.compact(&[], &cancel).unwrap(), 0);
+        for i in [index, next] {
+            let (_, proof) = mt.get_leaf_with_proof(i).unwrap();
+            assert!(mt.verify_proof(proof).unwrap());
+        }
+        let mut old = ReadOnlyMerkleTree::new(mt.clone(), &old_root);
+        assert!(old.get_leaf_with_proof(index).is_err());
+
+        // A cancelled compaction deletes nothing.
+        mt.update_leaf_data_with_proof(index, &[4; 32]).unwrap();
+        let len = mt.len();
+        cancel.store(true, Ordering::Relaxed);
+        let err = mt.compact(&[], &cancel).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::Cancelled));
+        assert_eq!(mt.len(), len);
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_dangling_child() {
+        // A root whose children were never stored, as in a half-migrated database.
+        let mut mt = MemoryMerkleTree::<3>::new();
+        let (left, right) = (Hash::hash_data(&[1; 32]), Hash::hash_data(&[2; 32]));
+        let root = Hash::hash_children(&left, &right);
+        mt.set_parent(NodeIndex(0), &root, &left, &right).unwrap();
+        mt.update_root_hash(&root);
+        let err = mt.get_leaf_with_proof(NodeIndex(7)).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidHash));
+        assert!(mt
+            .update_leaf_data_with_proof(NodeIndex(7), &[3; 32])
+            .is_err());
+        assert_eq!(mt.get_root_hash(), root);
+    }
+
+    #[test]
+    fn test_caching_memory_merkle_tree() {
+        let mut mt = CachingMerkleTree::new(MemoryMerkleTree::<10>::new());
+        let index = NodeIndex((1 << 10) - 1 + 3);
+        mt.update_leaf_data_with_proof(index, &[1; 32]).unwrap();
+        let old_root = mt.get_root_hash();
+        let (leaf, proof) = mt.get_leaf_with_proof(index).unwrap();
+        assert!(mt.cache_len() > 0);
+
+        // Reads are served from the cache, even if the backend lost its nodes.
+        let records = std::mem::take(&mut mt.get_mut().records);
+        assert_eq!(
+            mt.get_leaf_with_proof(index).unwrap(),
+            (leaf.clone(), proof.clone())
+        );
+        mt.get_mut().records = records;
+
+        let new_proof = mt.update_leaf_data_with_proof(index, &[2; 32]).unwrap();
+        let (new_leaf, proof) = mt.get_leaf_with_proof(index).unwrap();
+        assert_eq!(new_leaf.hash(), Hash::hash_data(&[2; 32]));
+        assert_eq!(proof, new_proof);
+        assert!(mt.verify_proof(proof).unwrap());
+
+        // The nodes of the old root are still cached separately.
+        mt.update_root_hash(&old_root);
+        assert_eq!(mt.get_leaf_with_proof(index).unwrap().0, leaf);
+    }
+
+    #[test]
+    fn test_tree_manager() {
+        let manager: TreeManager<u32, MemoryMerkleTree<10>, Hash, 10> =
+            TreeManager::new(|_: &u32| MemoryMerkleTree::<10>::new())
+                .with_idle_timeout(std::time::Duration::ZERO);
+        let index = NodeIndex((1 << 10) - 1 + 3);
+        let tenant = manager.get(&1);
+        tenant
+            .update_leaf_data_with_proof::<Hash, 10>(index, &[1; 32])
+            .unwrap();
+        assert_eq!(manager.len(), 1);
+
+        // The same tree is returned on the next access, and other keys get trees of their own.
+        assert_eq!(
+            manager.get(&1).get_root_hash::<Hash, 10>(),
+            tenant.get_root_hash::<Hash, 10>()
+        );
+        let other = manager.get(&2);
+        assert_eq!(other.get_root_hash::<Hash, 10>(), DEFAULT_HASH_VEC[10]);
+        assert_eq!(manager.len(), 2);
+
+        // Both trees cache their nodes in the shared cache, under their own keys.
+        let (leaf, _) = tenant.get_leaf_with_proof::<Hash, 10>(index).unwrap();
+        assert_eq!(leaf.hash(), Hash::hash_data(&[1; 32]));
+        let (leaf, _) = other.get_leaf_with_proof::<Hash, 10>(index).unwrap();
+        assert_eq!(leaf.hash(), DEFAULT_HASH_VEC[0]);
+        assert!(!manager.cache().is_empty());
+
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert_eq!(manager.evict_idle(), 2);
+        assert!(manager.is_empty());
+        assert!(manager.cache().is_empty());
+        // Evicted trees are constructed again, here losing the state kept in memory.
+        assert_eq!(
+            manager.get(&1).get_root_hash::<Hash, 10>(),
+            DEFAULT_HASH_VEC[10]
+        );
+    }
+
+    #[test]
+    fn test_tree_manager_constructs_outside_the_lock() {
+        use std::sync::{mpsc, Mutex};
+        // The tree of key 1 is only constructed once the tree of key 2 has been returned.
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let (started, wait_release) = (Mutex::new(started), Mutex::new(wait_release));
+        let manager: TreeManager<u32, MemoryMerkleTree<10>, Hash, 10> =
+            TreeManager::new(move |key: &u32| {
+                if *key == 1 {
+                    started.lock().unwrap().send(()).unwrap();
+                    wait_release
+                        .lock()
+                        .unwrap()
+                        .recv_timeout(Duration::from_secs(5))
+                        .expect("Other trees returned while constructing");
+                }
+                MemoryMerkleTree::<10>::new()
+            });
+        std::thread::scope(|s| {
+            let slow = s.spawn(|| manager.get(&1));
+            wait_started.recv().unwrap();
+            manager.get(&2);
+            release.send(()).unwrap();
+            slow.join().unwrap();
+        });
+        assert_eq!(manager.len(), 2);
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_children_of() {
+        let mut mt = MemoryMerkleTree::<3>::new();
+        mt.update_leaf_data_with_proof(NodeIndex(9), &[9; 32])
+            .unwrap();
+        let root = mt.get_root_hash();
+        let (left, right) = mt.children_of(NodeIndex(0), &root).unwrap();
+        assert_eq!(
+            left,
+            mt.get_internal_node_with_proof(NodeIndex(1))
+                .unwrap()
+                .0
+                .hash()
+        );
+        assert_eq!(right, DEFAULT_HASH_VEC[2]);
+        let parent = mt
+            .get_internal_node_with_proof(NodeIndex(4))
+            .unwrap()
+            .0
+            .hash();
+        let (left, right) = mt.children_of(NodeIndex(4), &parent).unwrap();
+        assert_eq!(left, Hash::hash_data(&[9; 32]));
+        assert_eq!(right, DEFAULT_HASH_VEC[0]);
+
+        let err = mt.children_of(NodeIndex(9), &left).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidIndex));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_contains_leaf() {
+        let mut mt = MemoryMerkleTree::<3>::new();
+        let empty = DEFAULT_HASH_VEC[0];
+        assert!(!mt.contains_leaf(NodeIndex(9), &empty).unwrap());
+        mt.update_leaf_data_with_proof(NodeIndex(9), &[9; 32])
+            .unwrap();
+        assert!(mt.contains_leaf(NodeIndex(9), &empty).unwrap());
+        // The sibling is only known to be empty at the leaf level.
+        assert!(!mt.contains_leaf(NodeIndex(10), &empty).unwrap());
+        assert!(mt.contains_leaf(NodeIndex(3), &empty).is_err());
+
+        // Leaves under the empty right subtree are answered from the root alone.
+        mt.records.retain(|(index, _), _| *index == 0);
+        assert!(!mt.contains_leaf(NodeIndex(13), &empty).unwrap());
+        assert!(mt.contains_leaf(NodeIndex(9), &empty).is_err());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_next_empty_leaf() {
+        let mut mt = MemoryMerkleTree::<3>::new();
+        let empty = DEFAULT_HASH_VEC[0];
+        assert_eq!(mt.next_empty_leaf(&empty).unwrap(), Some(LeafIndex(0)));
+        for index in [7, 8, 9, 11] {
+            mt.update_leaf_data_with_proof(NodeIndex(index), &[1; 32])
+                .unwrap();
+        }
+        assert_eq!(mt.next_empty_leaf(&empty).unwrap(), Some(LeafIndex(3)));
+        mt.update_leaf_data_with_proof(NodeIndex(10), &[1; 32])
+            .unwrap();
+        assert_eq!(mt.next_empty_leaf(&empty).unwrap(), Some(LeafIndex(5)));
+        for index in [12, 13, 14] {
+            mt.update_leaf_data_with_proof(NodeIndex(index), &[1; 32])
+                .unwrap();
+        }
+        assert_eq!(mt.next_empty_leaf(&empty).unwrap(), None);
+
+        // Emptying a leaf again makes it the next one.
+        mt.set_leaf_with_proof(&MerkleRecord::new_leaf(9, empty))
+            .unwrap();
+        assert_eq!(mt.next_empty_leaf(&empty).unwrap(), Some(LeafIndex(2)));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_diff_roots() {
+        let cancel = AtomicBool::new(false);
+        let mut mt = MemoryMerkleTree::<3>::new();
+        let empty_root = mt.get_root_hash();
+        for index in [7, 9, 12] {
+            mt.update_leaf_data_with_proof(NodeIndex(index), &[1; 32])
+                .unwrap();
+        }
+        let old_root = mt.get_root_hash();
+        for index in [9, 13] {
+            mt.update_leaf_data_with_proof(NodeIndex(index), &[2; 32])
+                .unwrap();
+        }
+        let new_root = mt.get_root_hash();
+
+        // Only the paths to the changed leaves are walked.
+        let mut visited = vec![];
+        mt.diff_roots(&old_root, &new_root, &cancel, |index, _, _| {
+            visited.push(index.0);
+            true
+        })
+        .unwrap();
+        assert_eq!(visited, vec![0, 1, 4, 9, 2, 6, 13]);
+
+        let mut leaves = vec![];
+        mt.diff_roots(&empty_root, &old_root, &cancel, |index, old, new| {
+            if index.0 >= 7 {
+                assert_eq!(*old, DEFAULT_HASH_VEC[0]);
+                leaves.push((index.0, *new));
+            }
+            true
+        })
+        .unwrap();
+        let leaf = Hash::hash_data(&[1; 32]);
+        assert_eq!(leaves, vec![(7, leaf), (9, leaf), (12, leaf)]);
+
+        let mut visited = 0;
+        mt.diff_roots(&old_root, &new_root, &cancel, |_, _, _| {
+            visited += 1;
+            visited < 2
+        })
+        .unwrap();
+        assert_eq!(visited, 2);
+
+        // Cancelled midway, e.g. as the client went away: no node is visited after the flag is set.
+        let mut visited = 0;
+        let err = mt
+            .diff_roots(&old_root, &new_root, &cancel, |_, _, _| {
+                visited += 1;
+                if visited == 3 {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                true
+            })
+            .unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::Cancelled));
+        assert_eq!(visited, 3);
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_verify_update_batch() {
+        let mut mt = MemoryMerkleTree::<3>::new();
+        let mut updates = vec![];
+        for (index, value) in [(7, 1), (12, 1), (7, 2)] {
+            let (leaf, proof_before) = mt.get_leaf_with_proof(NodeIndex(index)).unwrap();
+            let proof_after = mt
+                .update_leaf_data_with_proof(NodeIndex(index), &[value; 32])
+                .unwrap();
+            updates.push(Update {
+                index,
+                old_value: leaf.hash(),
+                new_value: proof_after.source,
+                proof_before,
+                proof_after,
+            });
+        }
+        assert!(mt.verify_update_batch(&updates).unwrap());
+        assert!(mt.verify_update_batch(&[]).unwrap());
+
+        let check = |mt: &mut MemoryMerkleTree<3>, updates: &[Update<Hash, 3>], position| {
+            let err = mt.verify_update_batch(updates).unwrap_err();
+            assert_eq!(err.index(), position);
+            err
+        };
+        // The updates don't chain in another order.
+        let swapped = [updates[1].clone(), updates[0].clone()];
+        assert!(matches!(
+            check(&mut mt, &swapped, 1).code(),
+            MerkleErrorCode::RootMismatch
+        ));
+        let mut tampered = updates.clone();
+        tampered[1].new_value = DEFAULT_HASH_VEC[0];
+        assert!(matches!(
+            check(&mut mt, &tampered, 1).code(),
+            MerkleErrorCode::InconsistentProof
+        ));
+        // An update changing a sibling as well as its leaf.
+        let mut tampered = updates.clone();
+        tampered[2].proof_after.assist[2] = Hash::hash_data(&[3; 32]);
+        assert!(matches!(
+            check(&mut mt, &tampered, 2).code(),
+            MerkleErrorCode::InconsistentProof
+        ));
+        let mut tampered = updates.clone();
+        tampered[0].proof_before.root = updates[0].proof_after.root;
+        assert!(matches!(
+            check(&mut mt, &tampered, 0).code(),
+            MerkleErrorCode::InconsistentProof
+        ));
+    }
+
+    #[test]
+    fn test_caching_merkle_tree_prefetch_changes() {
+        let mut mt = CachingMerkleTree::new(MemoryMerkleTree::<3>::new());
+        mt.update_leaf_data_with_proof(NodeIndex(7), &[1; 32])
+            .unwrap();
+        let old_root = mt.get_root_hash();
+        // Written through the inner tree, as by another replica, so the cache doesn't see them.
+        mt.get_mut()
+            .update_leaf_data_with_proof(NodeIndex(9), &[2; 32])
+            .unwrap();
+        mt.get_mut()
+            .update_leaf_data_with_proof(NodeIndex(13), &[2; 32])
+            .unwrap();
+        let new_root = mt.get_root_hash();
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        // The old and new nodes 0 and 1, the new nodes 2, 4 and 6, and the leaves 9 and 13, the
+        // empty subtrees of the old tree being skipped.
+        assert_eq!(
+            mt.prefetch_changes(&old_root, &new_root, 100, deadline)
+                .unwrap(),
+            9
+        );
+        let (leaf, _) = mt.get_leaf_with_proof(NodeIndex(13)).unwrap();
+        assert_eq!(leaf.hash(), Hash::hash_data(&[2; 32]));
+        mt.get_leaf_with_proof(NodeIndex(13)).unwrap();
+        // The nodes 0, 1, 2, 6 and 13 were read, once each.
+        assert_eq!(
+            mt.prefetch_stats(),
+            PrefetchStats {
+                prefetched: 9,
+                used: 5
+            }
+        );
+
+        // The budget and the deadline bound the walk.
+        mt.clear_cache();
+        assert_eq!(
+            mt.prefetch_changes(&old_root, &new_root, 2, deadline)
+                .unwrap(),
+            2
+        );
+        mt.clear_cache();
+        assert_eq!(
+            mt.prefetch_changes(&old_root, &new_root, 100, Instant::now())
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_caching_merkle_tree_prefetch_on_root_update() {
+        let mut mt = CachingMerkleTree::new(MemoryMerkleTree::<3>::new());
+        mt.update_leaf_data_with_proof(NodeIndex(7), &[1; 32])
+            .unwrap();
+        let old_root = mt.get_root_hash();
+        // Written by another replica, this one only learning the new root afterwards.
+        for index in [9, 13] {
+            mt.get_mut()
+                .update_leaf_data_with_proof(NodeIndex(index), &[2; 32])
+                .unwrap();
+        }
+        let new_root = mt.get_root_hash();
+        mt.get_mut().update_root_hash(&old_root);
+
+        let budget = PrefetchBudget {
+            max_nodes: 100,
+            timeout: Duration::from_secs(60),
+        };
+        let mut mt = mt.with_prefetch(Some(budget));
+        mt.update_root_hash(&new_root);
+        assert_eq!(mt.get_root_hash(), new_root);
+        assert_eq!(mt.prefetch_stats().prefetched, 9);
+        mt.get_leaf_with_proof(NodeIndex(13)).unwrap();
+        assert_eq!(mt.prefetch_stats().used, 5);
+
+        // Without a budget, moving the root reads nothing.
+        let mut mt = mt.with_prefetch(None);
+        mt.clear_cache();
+        mt.update_root_hash(&old_root);
+        assert_eq!(mt.prefetch_stats().prefetched, 9);
+        assert_eq!(mt.cache_len(), 0);
+    }
+
+    #[test]
+    fn test_sorted_pair_merkle_tree() {
+        let mut mt = SortedPairMerkleTree::new(MemoryMerkleTree::<2>::new());
+        for (index, value) in [(3, 9), (4, 1), (6, 5)] {
+            mt.update_leaf_data_with_proof(NodeIndex(index), &[value; 32])
+                .unwrap();
+        }
+        let leaf = |value: u8| Hash::hash_data(&[value; 32]);
+        let sorted = |a: &Hash, b: &Hash| hash_sorted(a, b, Hash::hash_children);
+        let root = sorted(
+            &sorted(&leaf(9), &leaf(1)),
+            &sorted(&DEFAULT_HASH_VEC[0], &leaf(5)),
+        );
+        assert_eq!(mt.get_root_hash(), root);
+        assert_eq!(sorted(&leaf(1), &leaf(9)), sorted(&leaf(9), &leaf(1)));
+
+        let (_, proof) = mt.get_leaf_with_proof(NodeIndex(4)).unwrap();
+        assert!(mt.verify_proof(proof.clone()).unwrap());
+        // The position of the leaf is not committed to.
+        let mut swapped = proof;
+        swapped.index = 3;
+        assert!(mt.verify_proof(swapped).unwrap());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_range_proof() {
+        let mut mt = MemoryMerkleTree::<3>::new();
+        for i in 7..15 {
+            mt.update_leaf_data_with_proof(NodeIndex(i), &[i as u8; 32])
+                .unwrap();
+        }
+        let leaf_hashes = |mt: &mut MemoryMerkleTree<3>, start: u64, end: u64| {
+            (start..end)
+                .map(|i| mt.get_leaf_with_proof(NodeIndex(i)).unwrap().0.hash())
+                .collect::<Vec<Hash>>()
+        };
+
+        // A single leaf degenerates to a normal proof.
+        let proof = mt.get_range_proof(NodeIndex(9), NodeIndex(10)).unwrap();
+        let (_, single) = mt.get_leaf_with_proof(NodeIndex(9)).unwrap();
+        assert_eq!(proof.left.len() + proof.right.len(), 3);
+        // Leaf 9 is a left child, its parent 4 a right child and 1 a left child.
+        assert_eq!(proof.left, vec![single.assist[1]]);
+        assert_eq!(proof.right, vec![single.assist[2], single.assist[0]]);
+        let hashes = leaf_hashes(&mut mt, 9, 10);
+        assert!(mt.verify_range_proof(&hashes, &proof).unwrap());
+
+        // The whole bottom level needs no assist.
+        let proof = mt.get_range_proof(NodeIndex(7), NodeIndex(15)).unwrap();
+        assert!(proof.left.is_empty() && proof.right.is_empty());
+        let hashes = leaf_hashes(&mut mt, 7, 15);
+        assert!(mt.verify_range_proof(&hashes, &proof).unwrap());
+
+        // Ranges crossing subtree boundaries.
+        for (start, end) in [(8, 12), (10, 12), (9, 14), (7, 11), (12, 15)] {
+            let proof = mt
+                .get_range_proof(NodeIndex(start), NodeIndex(end))
+                .unwrap();
+            let mut hashes = leaf_hashes(&mut mt, start, end);
+            assert!(mt.verify_range_proof(&hashes, &proof).unwrap());
+            hashes.swap(0, 1);
+            assert!(!mt.verify_range_proof(&hashes, &proof).unwrap());
+            hashes.pop();
+            let err = mt.verify_range_proof(&hashes, &proof).unwrap_err();
+            assert!(matches!(err.code(), MerkleErrorCode::InconsistentProof));
+        }
+
+        for (start, end) in [(9, 9), (10, 9), (6, 8), (8, 16)] {
+            let err = mt
+                .get_range_proof(NodeIndex(start), NodeIndex(end))
+                .unwrap_err();
+            assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafIndex));
+        }
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_assist_order() {
+        let mut mt = MemoryMerkleTree::<3>::new();
+        // Leaf 9 is the left child of 4, which is the right child of 1, a left child of the root.
+        let index = 9;
+        for i in index..15 {
+            mt.update_leaf_data_with_proof(NodeIndex(i), &[i as u8; 32])
+                .unwrap();
+        }
+        let (_, proof) = mt.get_leaf_with_proof(NodeIndex(index)).unwrap();
+        let assist: Vec<Hash> = [2, 3, 10]
+            .into_iter()
+            .map(|i| {
+                mt.get_internal_node_with_proof(NodeIndex(i))
+                    .unwrap()
+                    .0
+                    .hash()
+            })
+            .collect();
+        assert_eq!(proof.assist, assist);
+        assert!(mt.verify_proof(proof.clone()).unwrap());
+        assert!(mt.verify_proof_strict(proof.clone()).unwrap());
+
+        let mut reversed = proof.clone();
+        reversed.assist.reverse();
+        assert!(!mt.verify_proof(reversed.clone()).unwrap());
+        let err = mt.verify_proof_strict(reversed).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InconsistentProof));
+
+        let mut wrong = proof.clone();
+        wrong.source = Hash::hash_data(&[2; 32]);
+        assert!(!mt.verify_proof_strict(wrong).unwrap());
+
+        let mut short = proof;
+        short.assist.pop();
+        let err = mt.verify_proof_strict(short).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidDepth));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_read_only_at_root() {
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let index = NodeIndex((1 << 10) - 1 + 5);
+        mt.update_leaf_data_with_proof(index, &[1; 32]).unwrap();
+        let old_root = mt.get_root_hash();
+        mt.update_leaf_data_with_proof(index, &[2; 32]).unwrap();
+
+        let mut pinned = ReadOnlyMerkleTree::new(mt.clone(), &old_root);
+        assert_eq!(pinned.get_root_hash(), old_root);
+        let (leaf, proof) = pinned.get_leaf_with_proof(index).unwrap();
+        assert_eq!(leaf.hash(), Hash::hash_data(&[1; 32]));
+        assert!(pinned.verify_proof(proof).unwrap());
+
+        let err = pinned
+            .update_leaf_data_with_proof(index, &[3; 32])
+            .unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::ReadOnly));
+        assert_eq!(pinned.get_root_hash(), old_root);
+        let (leaf, _) = pinned.get_leaf_with_proof(index).unwrap();
+        assert_eq!(leaf.hash(), Hash::hash_data(&[1; 32]));
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_build_from_leaves() {
+        let mut naive = MemoryMerkleTree::<10>::new();
+        let mut leaves = vec![];
+        for (i, offset) in [0_u64, 3, 4, 1023].iter().enumerate() {
+            let index = (1 << 10) - 1 + offset;
+            let (mut leaf, _) = naive.get_leaf_with_proof(NodeIndex(index)).unwrap();
+            leaf.set(&[i as u8 + 1; 32]).unwrap();
+            naive.set_leaf_with_proof(&leaf).unwrap();
+            leaves.push(leaf);
+        }
+
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let root = mt.build_from_leaves(leaves).unwrap();
+        assert_eq!(root, naive.get_root_hash());
+        assert_eq!(mt.get_root_hash(), naive.get_root_hash());
+    }
+
+    #[test]
+    fn test_memory_merkle_tree_build_from_leaf_data() {
+        let mut naive = MemoryMerkleTree::<10>::new();
+        let mut leaves = vec![];
+        for (i, offset) in [0_u64, 5, 6, 1000].iter().enumerate() {
+            let index = (1 << 10) - 1 + offset;
+            let data = vec![i as u8 + 1; 32];
+            naive
+                .update_leaf_data_with_proof(NodeIndex(index), &data)
+                .unwrap();
+            leaves.push((NodeIndex(index), data));
+        }
+
+        let mut mt = MemoryMerkleTree::<10>::new();
+        let root = mt.build_from_leaf_data(&leaves).unwrap();
+        assert_eq!(root, naive.get_root_hash());
+        for (index, _) in leaves {
+            let (leaf, proof) = mt.get_leaf_with_proof(index).unwrap();
+            assert_eq!(leaf, naive.get_leaf_with_proof(index).unwrap().0);
+            assert!(mt.verify_proof(proof).unwrap());
+        }
+        assert!(mt
+            .build_from_leaf_data(&[(NodeIndex(0), vec![1; 32])])
+            .is_err());
+    }
+}
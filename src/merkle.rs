@@ -1,8 +1,16 @@
+use crate::journal::{Journal, JournalEntry};
 use crate::kvpair::Hash;
 
+use halo2_proofs::pairing::bn256::Fr;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use serde::{Deserialize, Serialize};
 pub use utils::*;
@@ -11,10 +19,64 @@ pub mod utils {
     use super::*;
     use crate::proto::NodeType;
 
-    pub fn get_offset(index: u64) -> u64 {
-        let height = (index + 1).ilog2();
+    /// The position of the node within its level, e.g. get_offset(3) = 0 and get_offset(6) = 3.
+    /// The last index has no depth, as its level would be the 65th, so it is rejected instead.
+    pub fn get_offset(index: u64) -> Result<u64, MerkleError> {
+        let height = index
+            .checked_add(1)
+            .ok_or_else(|| MerkleError::new(Hash::empty(), index, MerkleErrorCode::InvalidIndex))?
+            .ilog2();
         let full = (1u64 << height) - 1;
-        index - full
+        Ok(index - full)
+    }
+
+    /// The index of a node in the whole tree, i.e. 0 for the root and `2^D - 1` for the first
+    /// leaf of a tree of depth `D`. This is the index taken by the tree and the service.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    pub struct NodeIndex(pub u64);
+
+    /// The position of a leaf among the leaves, i.e. 0 for the first leaf whatever the depth.
+    /// It must be converted with `to_node_index` before being used as an index.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    pub struct LeafIndex(pub u64);
+
+    impl LeafIndex {
+        /// The index of the leaf in a tree of the given depth, failing with `InvalidLeafIndex`
+        /// if the tree has no such leaf.
+        /// The error carries the leaf index, as the node index may not fit in a `u64`.
+        pub fn to_node_index(self, depth: usize) -> Result<NodeIndex, MerkleError> {
+            u32::try_from(depth)
+                .ok()
+                .and_then(|depth| 1u64.checked_shl(depth))
+                .filter(|&leaves| self.0 < leaves)
+                .and_then(|leaves| (leaves - 1).checked_add(self.0))
+                .map(NodeIndex)
+                .ok_or_else(|| {
+                    MerkleError::new(Hash::empty(), self.0, MerkleErrorCode::InvalidLeafIndex)
+                        .with_depth(depth)
+                })
+        }
+    }
+
+    impl NodeIndex {
+        /// The position of the node among the leaves of a tree of the given depth, failing
+        /// with `InvalidLeafIndex` if it is not a leaf.
+        pub fn to_leaf_index(self, depth: usize) -> Result<LeafIndex, MerkleError> {
+            leaf_check(self.0, depth)?;
+            Ok(LeafIndex(get_offset(self.0)?))
+        }
+    }
+
+    impl From<NodeIndex> for u64 {
+        fn from(index: NodeIndex) -> Self {
+            index.0
+        }
+    }
+
+    impl fmt::Display for NodeIndex {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
     }
 
     pub fn get_node_type(index: u64, height: usize) -> NodeType {
@@ -35,7 +97,8 @@ pub mod utils {
                 [0; 32].try_into().unwrap(),
                 index,
                 MerkleErrorCode::InvalidIndex,
-            ))
+            )
+            .with_depth(height))
         } else {
             Ok(())
         }
@@ -59,20 +122,29 @@ pub mod utils {
                 [0; 32].try_into().unwrap(),
                 index,
                 MerkleErrorCode::InvalidLeafIndex,
-            ))
+            )
+            .with_depth(height))
         } else {
             Ok(())
         }
     }
 
-    pub fn get_sibling_index(index: u64) -> u64 {
-        if index % 2 == 1 {
-            index + 1
+    /// The index of the other child of the same parent, the root has no sibling.
+    pub fn get_sibling_index(index: u64) -> Option<u64> {
+        if index == 0 {
+            None
+        } else if index % 2 == 1 {
+            Some(index + 1)
         } else {
-            index - 1
+            Some(index - 1)
         }
     }
 
+    // Error for a root index where a non root index is required, e.g. to get its sibling.
+    pub(crate) fn root_index_error() -> MerkleError {
+        MerkleError::new(Hash::empty(), 0, MerkleErrorCode::InvalidIndex)
+    }
+
     /// get the index from leaf to the root
     /// root index is not included in the result as root index is always 0
     /// Example: Given D=3 and a merkle tree as follows:
@@ -84,6 +156,14 @@ pub mod utils {
     /// get_path(15) = [6, 2]
     pub fn get_path(index: u64, height: usize) -> Result<Vec<u64>, MerkleError> {
         leaf_check(index, height)?;
+        get_node_path(index, height)
+    }
+
+    /// get the index from any node to the root, like get_path but the node can be a non leaf
+    /// node, in which case the path is shorter
+    /// Example: Given D=3, get_node_path(3) = [1, 3]
+    pub fn get_node_path(index: u64, height: usize) -> Result<Vec<u64>, MerkleError> {
+        boundary_check(index, height)?;
         let mut height = (index + 1).ilog2();
         let round = height;
         let full = (1u64 << height) - 1;
@@ -101,6 +181,26 @@ pub mod utils {
         assert!(p == 0);
         Ok(path)
     }
+
+    /// get the deepest node which is an ancestor of both leaves,
+    /// i.e. the node where the paths of the two leaves merge
+    /// Example: Given D=3 and a merkle tree as follows:
+    /// 0
+    /// 1 2
+    /// 3 4 5 6
+    /// 7 8 9 10 11 12 13 14
+    /// lowest_common_ancestor(7, 8) = 3
+    /// lowest_common_ancestor(7, 10) = 1
+    /// lowest_common_ancestor(7, 14) = 0
+    pub fn lowest_common_ancestor(a: u64, b: u64, height: usize) -> Result<u64, MerkleError> {
+        leaf_check(a, height)?;
+        leaf_check(b, height)?;
+        // The offsets of the ancestors are the common bit-prefix of the offsets of the leaves.
+        let (offset_a, offset_b) = (get_offset(a)?, get_offset(b)?);
+        let levels = u64::BITS - (offset_a ^ offset_b).leading_zeros();
+        let depth = height as u32 - levels;
+        Ok((1u64 << depth) - 1 + (offset_a >> levels))
+    }
 }
 
 /*
@@ -115,6 +215,24 @@ pub enum MerkleErrorCode {
     InvalidDepth,
     InvalidIndex,
     InvalidOther,
+    InconsistentProof,
+    /// A stored non leaf node without child hashes, e.g. from a corrupted database.
+    MissingChild,
+    /// A mutation through a read-only handle, see `ReadOnlyMerkleTree`.
+    ReadOnly,
+    /// A version which has not been checkpointed, see `Checkpoints`.
+    UnknownVersion,
+    /// A node whose hash is not the hash of its children, see `set_verified_node`.
+    HashMismatch,
+    /// Proofs expected to share a root which don't, see `verify_proofs_common_root`.
+    RootMismatch,
+    /// Leaf data of the wrong length for the node type, see `MerkleNode::set`.
+    InvalidLeafData,
+    /// The same index given twice to a batch write, where the intended value is ambiguous,
+    /// see `build_from_leaves` and `apply_changes`.
+    DuplicateIndex,
+    /// A walk stopped by its cancellation flag, see `check_cancelled`.
+    Cancelled,
 }
 
 #[derive(Debug)]
@@ -122,6 +240,8 @@ pub struct MerkleError {
     source: Hash,
     index: u64,
     code: MerkleErrorCode,
+    // The depth of the tree, for index errors, so that the valid range can be reported.
+    depth: Option<usize>,
 }
 
 impl MerkleError {
@@ -130,8 +250,28 @@ impl MerkleError {
             source,
             index,
             code,
+            depth: None,
         }
     }
+
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// The depth of the tree the index was checked against, set by `leaf_check` and
+    /// `boundary_check`.
+    pub fn depth(&self) -> Option<usize> {
+        self.depth
+    }
+
+    pub fn code(&self) -> &MerkleErrorCode {
+        &self.code
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
 }
 
 impl fmt::Display for MerkleError {
@@ -149,19 +289,825 @@ impl Error for MerkleError {}
 pub trait MerkleNode<H: Debug + Clone + PartialEq> {
     fn hash(&self) -> H;
     fn index(&self) -> u64;
-    fn set(&mut self, data: &[u8]);
+    /// Set the hash of a leaf from its data, failing with `InvalidLeafData` if the data doesn't
+    /// have the length expected by the node type.
+    fn set(&mut self, data: &[u8]) -> Result<(), MerkleError>;
     fn left(&self) -> Option<H>; // hash of left child
     fn right(&self) -> Option<H>; // hash of right child
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MerkleProof<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
     pub source: H,
     pub root: H, // last is root
+    /// Sibling hashes ordered from the root down: `assist[0]` is the sibling of the child of
+    /// the root on the path, and the last entry is the sibling of `source`. Whether `source` is
+    /// a left or right child at each level is given by the bits of `index`, not by the assist.
+    pub assist: Vec<H>,
+    pub index: u64,
+}
+
+// The hashes of the subtrees with only `empty_leaf` leaves, indexed by depth (0 is the root).
+fn empty_subtree_hashes<H: Clone>(
+    empty_leaf: &H,
+    depth: usize,
+    hash: impl Fn(&H, &H) -> H,
+) -> Vec<H> {
+    let mut empty = vec![empty_leaf.clone()];
+    for _ in 0..depth {
+        let below = &empty[empty.len() - 1];
+        empty.push(hash(below, below));
+    }
+    empty.reverse();
+    empty
+}
+
+// Fold the source with the sibling hashes given from the source up to the root.
+fn fold_assist<'a, H: Clone + 'a>(
+    source: &H,
+    index: u64,
+    siblings: impl Iterator<Item = &'a H>,
+    hash: impl Fn(&H, &H) -> H,
+) -> Result<H, MerkleError> {
+    let mut p = get_offset(index)?;
+    Ok(siblings.fold(source.clone(), |acc, x| {
+        let (left, right) = if p % 2 == 1 { (x, &acc) } else { (&acc, x) };
+        p /= 2;
+        hash(left, right)
+    }))
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> MerkleProof<H, D> {
+    /// Number of levels folded from the source up to the root, i.e. the length of the assist.
+    /// A well-formed leaf proof has `depth() == D`, while proofs of internal nodes are shorter.
+    pub fn depth(&self) -> usize {
+        self.assist.len()
+    }
+
+    /// Whether the index is within the tree and the depth of the proof matches the depth of
+    /// the node at that index. Truncated or padded proofs are not well-formed.
+    pub fn is_well_formed(&self) -> bool {
+        boundary_check(self.index, D).is_ok() && (self.index + 1).ilog2() as usize == self.depth()
+    }
+}
+
+/// Verify that the proof folds to `expected_root` and that it is a proof of `value`, i.e. that
+/// its source is the leaf hash of `value` (`Hash::hash_data`, as set by `MerkleRecord::set`).
+/// Checking the fold alone accepts a valid proof of any other value of the leaf.
+/// Fails with `InvalidLeafData` if the value is not 32 bytes, with `InvalidLeafIndex` if the
+/// proof is not of a leaf and with `InvalidDepth` if its assist doesn't have one hash per level.
+/// Note that the service hashes the data of `SetLeaf` with `poseidon::hash` instead, use
+/// `verify_value_with` to check the values read from the service.
+pub fn verify_value<const D: usize>(
+    value: &[u8],
+    proof: &MerkleProof<Hash, D>,
+    expected_root: &Hash,
+) -> Result<bool, MerkleError> {
+    verify_value_with(value, proof, expected_root, |value| {
+        (value.len() == 32).then(|| Hash::hash_data(value))
+    })
+}
+
+/// Like `verify_value`, with the leaf hash of `value` computed by `hash_leaf`, which returns
+/// `None` if the value can't be hashed, failing with `InvalidLeafData`.
+pub fn verify_value_with<const D: usize>(
+    value: &[u8],
+    proof: &MerkleProof<Hash, D>,
+    expected_root: &Hash,
+    hash_leaf: impl FnOnce(&[u8]) -> Option<Hash>,
+) -> Result<bool, MerkleError> {
+    let leaf_hash = hash_leaf(value).ok_or_else(|| {
+        MerkleError::new(Hash::empty(), proof.index, MerkleErrorCode::InvalidLeafData)
+    })?;
+    leaf_check(proof.index, D)?;
+    if !proof.is_well_formed() {
+        return Err(MerkleError::new(
+            Hash::empty(),
+            proof.index,
+            MerkleErrorCode::InvalidDepth,
+        ));
+    }
+    if proof.source != leaf_hash || proof.root != *expected_root {
+        return Ok(false);
+    }
+    let root = fold_assist(
+        &proof.source,
+        proof.index,
+        proof.assist.iter().rev(),
+        Hash::hash_children,
+    )?;
+    Ok(root == *expected_root)
+}
+
+/// A `MerkleProof` without its root, for clients which verify proofs against a root they
+/// already know, e.g. many proofs against the root they just fetched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProofLite<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
+    pub source: H,
     pub assist: Vec<H>,
     pub index: u64,
 }
 
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> MerkleProofLite<H, D> {
+    pub fn with_root(self, root: H) -> MerkleProof<H, D> {
+        MerkleProof {
+            source: self.source,
+            root,
+            assist: self.assist,
+            index: self.index,
+        }
+    }
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> From<MerkleProof<H, D>>
+    for MerkleProofLite<H, D>
+{
+    fn from(proof: MerkleProof<H, D>) -> Self {
+        MerkleProofLite {
+            source: proof.source,
+            assist: proof.assist,
+            index: proof.index,
+        }
+    }
+}
+
+/// A best-effort proof returned while some nodes can't be read, e.g. during a partial backend
+/// outage, see `MerkleTree::try_get_leaf_with_proof`. The assist has the same order as in
+/// `MerkleProof`, with `None` for the levels whose sibling couldn't be read. Once a node on the
+/// path is missing, the walk can't go further down, so all the levels below it are `None`, and
+/// so is `source` if the node itself was not reached.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartialProof<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
+    pub source: Option<H>,
+    pub root: H,
+    pub assist: Vec<Option<H>>,
+    pub index: u64,
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> PartialProof<H, D> {
+    /// Whether every level was read, i.e. the proof can be verified as a `MerkleProof`.
+    pub fn is_complete(&self) -> bool {
+        self.source.is_some() && self.assist.iter().all(Option::is_some)
+    }
+
+    /// The positions in the assist of the levels which couldn't be authenticated.
+    pub fn missing_levels(&self) -> Vec<usize> {
+        self.assist
+            .iter()
+            .enumerate()
+            .filter_map(|(level, sibling)| sibling.is_none().then_some(level))
+            .collect()
+    }
+
+    /// The full proof, if no level is missing.
+    pub fn into_proof(self) -> Option<MerkleProof<H, D>> {
+        Some(MerkleProof {
+            source: self.source?,
+            root: self.root,
+            assist: self.assist.into_iter().collect::<Option<_>>()?,
+            index: self.index,
+        })
+    }
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> From<MerkleProof<H, D>>
+    for PartialProof<H, D>
+{
+    fn from(proof: MerkleProof<H, D>) -> Self {
+        PartialProof {
+            source: Some(proof.source),
+            root: proof.root,
+            assist: proof.assist.into_iter().map(Some).collect(),
+            index: proof.index,
+        }
+    }
+}
+
+/// A proof for the contiguous range of leaves `[start, end)`, e.g. an array committed by a
+/// circuit. Given the hashes of all the leaves in the range, the root is recomputed level by
+/// level, and only the nodes just outside of the range at each level are needed, i.e. the left
+/// frontier of `start` and the right frontier of `end - 1`. For a single leaf this is the
+/// assist of a normal proof split in two, and the whole bottom level needs no assist at all.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleRangeProof<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
+    pub root: H,
+    /// Index of the first leaf of the range.
+    pub start: u64,
+    /// Index of the leaf after the last leaf of the range.
+    pub end: u64,
+    /// Left siblings of the leftmost node of each level which is a right child, from the
+    /// leaves up to the root.
+    pub left: Vec<H>,
+    /// Right siblings of the rightmost node of each level which is a left child, from the
+    /// leaves up to the root.
+    pub right: Vec<H>,
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> MerkleRangeProof<H, D> {
+    fn range_check(start: u64, end: u64) -> Result<(), MerkleError> {
+        leaf_check(start, D)?;
+        if end <= start {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                end,
+                MerkleErrorCode::InvalidLeafIndex,
+            ));
+        }
+        leaf_check(end - 1, D)
+    }
+
+    /// Build the range proof from the proofs of the first and the last leaves of the range,
+    /// which must be against the same root.
+    pub fn from_boundary_proofs(
+        start: u64,
+        end: u64,
+        first: &MerkleProof<H, D>,
+        last: &MerkleProof<H, D>,
+    ) -> Result<Self, MerkleError> {
+        Self::range_check(start, end)?;
+        for (proof, index) in [(first, start), (last, end - 1)] {
+            if proof.index != index || proof.root != first.root || !proof.is_well_formed() {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    proof.index,
+                    MerkleErrorCode::InconsistentProof,
+                ));
+            }
+        }
+        // The leftmost node of each level is the ancestor of `start`, and we need its sibling
+        // when it is a right child. Symmetrically for `end - 1` on the right.
+        let frontier = |proof: &MerkleProof<H, D>, is_left: bool| {
+            let mut index = proof.index;
+            let mut hashes = vec![];
+            for sibling in proof.assist.iter().rev() {
+                if (index % 2 == 0) == is_left {
+                    hashes.push(sibling.clone());
+                }
+                index = (index - 1) / 2;
+            }
+            hashes
+        };
+        Ok(MerkleRangeProof {
+            root: first.root.clone(),
+            start,
+            end,
+            left: frontier(first, true),
+            right: frontier(last, false),
+        })
+    }
+
+    /// Recompute the root from the hashes of all the leaves in the range, in order.
+    /// Fails if the number of leaf hashes or frontier hashes does not match the range.
+    pub fn compute_root(&self, leaves: &[H], hash: impl Fn(&H, &H) -> H) -> Result<H, MerkleError> {
+        Self::range_check(self.start, self.end)?;
+        let inconsistent =
+            |index| MerkleError::new(Hash::empty(), index, MerkleErrorCode::InconsistentProof);
+        if leaves.len() as u64 != self.end - self.start {
+            return Err(inconsistent(self.start));
+        }
+        let mut left = self.left.iter();
+        let mut right = self.right.iter();
+        let (mut first, mut last) = (self.start, self.end - 1);
+        let mut level = leaves.to_vec();
+        for _ in 0..D {
+            let mut nodes = Vec::with_capacity(level.len() + 2);
+            if first % 2 == 0 {
+                nodes.push(left.next().ok_or_else(|| inconsistent(first))?.clone());
+                first -= 1;
+            }
+            nodes.append(&mut level);
+            if last % 2 == 1 {
+                nodes.push(right.next().ok_or_else(|| inconsistent(last))?.clone());
+                last += 1;
+            }
+            level = nodes
+                .chunks(2)
+                .map(|pair| hash(&pair[0], &pair[1]))
+                .collect();
+            first = (first - 1) / 2;
+            last = (last - 1) / 2;
+        }
+        if left.next().is_some() || right.next().is_some() {
+            return Err(inconsistent(0));
+        }
+        Ok(level.remove(0))
+    }
+
+    /// Whether the leaf hashes of the range, in order, recompute the root of this proof.
+    pub fn verify(&self, leaves: &[H], hash: impl Fn(&H, &H) -> H) -> Result<bool, MerkleError> {
+        Ok(self.compute_root(leaves, hash)? == self.root)
+    }
+}
+
+/// One level of the fold of a proof, see `MerkleProof::fold_steps`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoldStep<H> {
+    /// Index of the node computed by this step.
+    pub index: u64,
+    /// Whether the node folded so far is the right child, i.e. the sibling is on the left.
+    pub is_right: bool,
+    pub left: H,
+    pub right: H,
+    pub hash: H,
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> MerkleProof<H, D> {
+    /// The intermediate hashes of the fold done by `verify_proof`, from the source up to the
+    /// root, e.g. to find out why a proof does not verify. The last step computes the root.
+    pub fn fold_steps(&self, hash: impl Fn(&H, &H) -> H) -> Result<Vec<FoldStep<H>>, MerkleError> {
+        if !self.is_well_formed() {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                self.index,
+                MerkleErrorCode::InvalidDepth,
+            ));
+        }
+        let mut index = self.index;
+        let mut acc = self.source.clone();
+        let mut steps = Vec::with_capacity(self.depth());
+        for sibling in self.assist.iter().rev() {
+            let is_right = index % 2 == 0;
+            let (left, right) = if is_right {
+                (sibling.clone(), acc)
+            } else {
+                (acc, sibling.clone())
+            };
+            acc = hash(&left, &right);
+            index = (index - 1) / 2;
+            steps.push(FoldStep {
+                index,
+                is_right,
+                left,
+                right,
+                hash: acc.clone(),
+            });
+        }
+        Ok(steps)
+    }
+}
+
+impl<const D: usize> MerkleProof<Hash, D> {
+    /// Check that this proof is well-formed without doing the hash fold, i.e.
+    /// the assist has exactly `D` entries, the index is a leaf index and all the hashes are
+    /// canonical field elements. This is much cheaper than `verify_proof` and can be used to
+    /// reject malformed proofs early.
+    pub fn validate_structure(&self) -> Result<(), MerkleError> {
+        if self.depth() != D {
+            return Err(MerkleError::new(
+                self.source,
+                self.depth() as u64,
+                MerkleErrorCode::InvalidDepth,
+            ));
+        }
+        leaf_check(self.index, D)?;
+        for hash in [&self.source, &self.root]
+            .into_iter()
+            .chain(self.assist.iter())
+        {
+            if !hash.is_valid_field_element() {
+                return Err(MerkleError::new(
+                    *hash,
+                    self.index,
+                    MerkleErrorCode::InvalidHash,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A leaf update as claimed by a server, e.g. an entry of an audit log: the hash of the leaf
+/// before and after the update, with its proofs against the roots before and after.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Update<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
+    pub index: u64,
+    pub old_value: H,
+    pub new_value: H,
+    pub proof_before: MerkleProof<H, D>,
+    pub proof_after: MerkleProof<H, D>,
+}
+
+/// A compressed form of several proofs against the same root.
+/// Sibling hashes which can be recomputed from the other leaves in the batch
+/// are dropped, and the shared ones are only stored once.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleBatchProof<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
+    pub root: H,
+    /// (leaf index, leaf hash) of the proved leaves, ordered by index.
+    pub leaves: Vec<(u64, H)>,
+    /// (node index, node hash) of the nodes needed to recompute the root, ordered by index.
+    pub assist: Vec<(u64, H)>,
+}
+
+impl<H: Debug + Clone + PartialEq + Serialize, const D: usize> MerkleBatchProof<H, D> {
+    /// Combine single proofs against the same root into a batch proof.
+    /// Fails if the roots differ or if two proofs imply different hashes for the same node
+    /// (which usually means one of them is stale). Being a read, several proofs of the same
+    /// leaf are deduplicated, so the leaf appears once in the batch.
+    pub fn from_single_proofs(proofs: &[MerkleProof<H, D>]) -> Result<Self, MerkleError> {
+        let root = match proofs.first() {
+            Some(proof) => proof.root.clone(),
+            None => {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    0,
+                    MerkleErrorCode::InconsistentProof,
+                ))
+            }
+        };
+        let mut nodes: BTreeMap<u64, H> = BTreeMap::new();
+        let mut derivable: BTreeSet<u64> = BTreeSet::new();
+        let mut leaves: BTreeMap<u64, H> = BTreeMap::new();
+        for proof in proofs {
+            if proof.root != root || proof.assist.len() != D {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    proof.index,
+                    MerkleErrorCode::InconsistentProof,
+                ));
+            }
+            let path = get_path(proof.index, D)?;
+            Self::insert_node(&mut nodes, proof.index, &proof.source)?;
+            leaves.insert(proof.index, proof.source.clone());
+            for (node, sibling_hash) in path.iter().zip(proof.assist.iter()) {
+                derivable.insert(*node);
+                let sibling = get_sibling_index(*node).ok_or_else(root_index_error)?;
+                Self::insert_node(&mut nodes, sibling, sibling_hash)?;
+            }
+        }
+        let assist = nodes
+            .into_iter()
+            .filter(|(index, _)| !derivable.contains(index))
+            .collect();
+        Ok(MerkleBatchProof {
+            root,
+            leaves: leaves.into_iter().collect(),
+            assist,
+        })
+    }
+
+    fn insert_node(nodes: &mut BTreeMap<u64, H>, index: u64, hash: &H) -> Result<(), MerkleError> {
+        match nodes.get(&index) {
+            Some(existing) if existing != hash => Err(MerkleError::new(
+                Hash::empty(),
+                index,
+                MerkleErrorCode::InconsistentProof,
+            )),
+            Some(_) => Ok(()),
+            None => {
+                nodes.insert(index, hash.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Expand this batch proof back into one proof per leaf.
+    /// The pair hash function of the tree is needed to recompute the shared path nodes,
+    /// e.g. `batch.to_single_proofs(MongoMerkle::hash)`.
+    pub fn to_single_proofs(
+        &self,
+        hash: impl Fn(&H, &H) -> H,
+    ) -> Result<Vec<MerkleProof<H, D>>, MerkleError> {
+        let mut nodes: BTreeMap<u64, H> = BTreeMap::new();
+        for (index, node_hash) in self.assist.iter().chain(self.leaves.iter()) {
+            Self::insert_node(&mut nodes, *index, node_hash)?;
+        }
+        // All the leaves are at the same depth, so we can compute the path nodes level by level.
+        let mut level: BTreeSet<u64> = self.leaves.iter().map(|(index, _)| *index).collect();
+        for _ in 0..D {
+            let mut parents = BTreeSet::new();
+            for index in level {
+                let sibling = get_sibling_index(index).ok_or_else(root_index_error)?;
+                let (left, right) = if index % 2 == 1 {
+                    (index, sibling)
+                } else {
+                    (sibling, index)
+                };
+                let missing =
+                    || MerkleError::new(Hash::empty(), sibling, MerkleErrorCode::InconsistentProof);
+                let left_hash = nodes.get(&left).ok_or_else(missing)?;
+                let right_hash = nodes.get(&right).ok_or_else(missing)?;
+                let parent_hash = hash(left_hash, right_hash);
+                let parent = (index - 1) / 2;
+                Self::insert_node(&mut nodes, parent, &parent_hash)?;
+                parents.insert(parent);
+            }
+            level = parents;
+        }
+        if !self.leaves.is_empty() && nodes.get(&0) != Some(&self.root) {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                0,
+                MerkleErrorCode::InconsistentProof,
+            ));
+        }
+        self.leaves
+            .iter()
+            .map(|(index, source)| {
+                let assist = get_path(*index, D)?
+                    .into_iter()
+                    .map(|node| {
+                        let sibling = get_sibling_index(node).ok_or_else(root_index_error)?;
+                        Ok(nodes[&sibling].clone())
+                    })
+                    .collect::<Result<_, MerkleError>>()?;
+                Ok(MerkleProof {
+                    source: source.clone(),
+                    root: self.root.clone(),
+                    assist,
+                    index: *index,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The hash and the child hashes of a stored node, which is all a proof walk needs from it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeView<H> {
+    pub hash: H,
+    pub left: Option<H>,
+    pub right: Option<H>,
+}
+
+impl<H: Debug + Clone + PartialEq> NodeView<H> {
+    pub fn of(node: &impl MerkleNode<H>) -> Self {
+        NodeView {
+            hash: node.hash(),
+            left: node.left(),
+            right: node.right(),
+        }
+    }
+}
+
+/// Fail with `Cancelled` at the node at `index` if the flag is set. The long walks over a tree
+/// (`diff_trees`, `MerkleTree::locate_mismatch`, `MemoryMerkleTree::compact`) check it at
+/// each node, so that e.g. the walk of a request stops soon after the client went away.
+pub fn check_cancelled(cancel: &AtomicBool, index: u64) -> Result<(), MerkleError> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(MerkleError::new(
+            Hash::empty(),
+            index,
+            MerkleErrorCode::Cancelled,
+        ));
+    }
+    Ok(())
+}
+
+/// Walk down the trees of `old_root` and `new_root` together, only where their hashes differ,
+/// and call `visit(index, old, new)` on each node which differs, parents first and left
+/// subtrees before right ones, so that the leaves which differ come in index order. Subtrees
+/// equal in both trees are not read, and neither are the empty subtrees: the children of a node
+/// whose hash is `defaults[depth]` are `defaults[depth + 1]`. `visit` returns false to stop the
+/// walk, e.g. once a budget is spent. The nodes are read with `get_node(index, hash)` as in
+/// `generate_proof`. `defaults` has the hashes of the empty subtrees from the root (depth 0) to
+/// the leaves (depth `D`). The walk fails with `Cancelled` once `cancel` is set.
+pub fn diff_trees<H, const D: usize>(
+    old_root: H,
+    new_root: H,
+    defaults: &[H],
+    cancel: &AtomicBool,
+    mut get_node: impl FnMut(NodeIndex, &H) -> Result<NodeView<H>, MerkleError>,
+    mut visit: impl FnMut(NodeIndex, &H, &H) -> bool,
+) -> Result<(), MerkleError>
+where
+    H: Debug + Clone + PartialEq,
+{
+    if defaults.len() != D + 1 {
+        return Err(MerkleError::new(
+            Hash::empty(),
+            defaults.len() as u64,
+            MerkleErrorCode::InvalidDepth,
+        ));
+    }
+    let mut children = |index: u64, depth: usize, hash: &H| -> Result<(H, H), MerkleError> {
+        if *hash == defaults[depth] {
+            return Ok((defaults[depth + 1].clone(), defaults[depth + 1].clone()));
+        }
+        match get_node(NodeIndex(index), hash)? {
+            NodeView {
+                left: Some(left),
+                right: Some(right),
+                ..
+            } => Ok((left, right)),
+            _ => Err(MerkleError::new(
+                Hash::empty(),
+                index,
+                MerkleErrorCode::MissingChild,
+            )),
+        }
+    };
+    // The pairs of nodes to compare as (index, depth, old, new), the next one last.
+    let mut stack = vec![(0, 0, old_root, new_root)];
+    while let Some((index, depth, old, new)) = stack.pop() {
+        if old == new {
+            continue;
+        }
+        check_cancelled(cancel, index)?;
+        if !visit(NodeIndex(index), &old, &new) {
+            return Ok(());
+        }
+        if depth == D {
+            continue;
+        }
+        let (old_left, old_right) = children(index, depth, &old)?;
+        let (new_left, new_right) = children(index, depth, &new)?;
+        stack.push((2 * index + 2, depth + 1, old_right, new_right));
+        stack.push((2 * index + 1, depth + 1, old_left, new_left));
+    }
+    Ok(())
+}
+
+/// Walk down from `root` to the node at `index` (any non root node) and return the proof of
+/// that node, reading each node on the path and its sibling with `get_node(index, hash)`.
+/// This is the walk of `MerkleTree::get_internal_node_with_proof` without a backend, e.g. for
+/// services which already hold the nodes in memory.
+pub fn generate_proof<H, const D: usize>(
+    root: H,
+    index: NodeIndex,
+    mut get_node: impl FnMut(NodeIndex, &H) -> Result<NodeView<H>, MerkleError>,
+) -> Result<MerkleProof<H, D>, MerkleError>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+{
+    let NodeIndex(index) = index;
+    boundary_check(index, D)?;
+    if index == 0 {
+        return Err(MerkleError::new(
+            Hash::empty(),
+            index,
+            MerkleErrorCode::InvalidIndex,
+        ));
+    }
+    // We push the search from the top
+    let mut acc = 0;
+    let mut acc_node = get_node(NodeIndex(acc), &root)?;
+    let mut assist = Vec::with_capacity(D);
+    for child in get_node_path(index, D)? {
+        let (hash, sibling_hash) = match (acc_node.left, acc_node.right) {
+            // left child
+            (Some(left), Some(right)) if (acc + 1) * 2 == child + 1 => (left, right),
+            (Some(left), Some(right)) => (right, left),
+            _ => {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    acc,
+                    MerkleErrorCode::MissingChild,
+                ))
+            }
+        };
+        let sibling = get_sibling_index(child).ok_or_else(root_index_error)?;
+        assist.push(get_node(NodeIndex(sibling), &sibling_hash)?.hash);
+        acc = child;
+        acc_node = get_node(NodeIndex(acc), &hash)?;
+    }
+    Ok(MerkleProof {
+        source: acc_node.hash,
+        root,
+        assist,
+        index,
+    })
+}
+
+/// Same walk as `generate_proof`, but the nodes which can't be read (or stored without child
+/// hashes) are marked as missing in the returned `PartialProof` instead of failing the walk.
+/// Only an invalid index is an error.
+pub fn generate_partial_proof<H, const D: usize>(
+    root: H,
+    index: NodeIndex,
+    mut get_node: impl FnMut(NodeIndex, &H) -> Result<NodeView<H>, MerkleError>,
+) -> Result<PartialProof<H, D>, MerkleError>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+{
+    let NodeIndex(index) = index;
+    boundary_check(index, D)?;
+    if index == 0 {
+        return Err(MerkleError::new(
+            Hash::empty(),
+            index,
+            MerkleErrorCode::InvalidIndex,
+        ));
+    }
+    let path = get_node_path(index, D)?;
+    let mut acc = 0;
+    let mut acc_node = get_node(NodeIndex(acc), &root).ok();
+    let mut assist = Vec::with_capacity(path.len());
+    for child in path.iter().copied() {
+        let children = acc_node
+            .as_ref()
+            .and_then(|node| node.left.clone().zip(node.right.clone()));
+        let (left, right) = match children {
+            Some(children) => children,
+            None => {
+                // Nothing below a missing node can be read.
+                assist.resize(path.len(), None);
+                return Ok(PartialProof {
+                    source: None,
+                    root,
+                    assist,
+                    index,
+                });
+            }
+        };
+        let (hash, sibling_hash) = if (acc + 1) * 2 == child + 1 {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        let sibling = get_sibling_index(child).ok_or_else(root_index_error)?;
+        assist.push(
+            get_node(NodeIndex(sibling), &sibling_hash)
+                .ok()
+                .map(|node| node.hash),
+        );
+        acc = child;
+        acc_node = get_node(NodeIndex(acc), &hash).ok();
+    }
+    Ok(PartialProof {
+        source: acc_node.map(|node| node.hash),
+        root,
+        assist,
+        index,
+    })
+}
+
+/// The new parents of a leaf set to `leaf_hash` given its current proof, from the parent of
+/// the leaf up to the root, as (index, hash, left, right). This is the recomputation done by
+/// `MerkleTree::set_leaf_with_proof` without a backend.
+pub fn update_path<H, const D: usize>(
+    proof: &MerkleProof<H, D>,
+    leaf_hash: &H,
+    hash: impl Fn(&H, &H) -> H,
+) -> Result<Vec<(NodeIndex, H, H, H)>, MerkleError>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+{
+    if proof.depth() != D || !proof.is_well_formed() {
+        return Err(MerkleError::new(
+            Hash::empty(),
+            proof.index,
+            MerkleErrorCode::InvalidDepth,
+        ));
+    }
+    let mut child = proof.index;
+    let mut acc = leaf_hash.clone();
+    let mut parents = Vec::with_capacity(D);
+    for sibling in proof.assist.iter().rev() {
+        let (left, right) = if child % 2 == 1 {
+            (acc, sibling.clone())
+        } else {
+            (sibling.clone(), acc)
+        };
+        acc = hash(&left, &right);
+        child = (child - 1) / 2;
+        parents.push((NodeIndex(child), acc.clone(), left, right));
+    }
+    Ok(parents)
+}
+
+/// Write the leaf and the new hashes of its path up to the root, given the current proof of
+/// the leaf. Returns the proof against the new root.
+fn write_leaf_path<T, H, const D: usize>(
+    tree: &mut T,
+    leaf: &T::Node,
+    mut proof: MerkleProof<H, D>,
+) -> Result<MerkleProof<H, D>, MerkleError>
+where
+    T: MerkleTree<H, D> + ?Sized,
+    H: Debug + Clone + PartialEq + Serialize,
+{
+    let hash = leaf.hash();
+    let parents = update_path(&proof, &hash, T::hash)?;
+    tree.set_leaf(leaf)?;
+    for (index, hash, left, right) in parents.iter() {
+        tree.set_parent(*index, hash, left, right)?;
+    }
+    let root = parents
+        .last()
+        .map_or(hash.clone(), |(_, root, _, _)| root.clone());
+    tree.update_root_hash(&root);
+    proof.source = hash;
+    proof.root = root;
+    Ok(proof)
+}
+
+/// The (left, right) child hashes of a non leaf node, or a `MissingChild` error carrying the
+/// index of the node if it has none.
+pub fn node_children<H: Debug + Clone + PartialEq>(
+    node: &impl MerkleNode<H>,
+    index: u64,
+) -> Result<(H, H), MerkleError> {
+    match (node.left(), node.right()) {
+        (Some(left), Some(right)) => Ok((left, right)),
+        _ => Err(MerkleError::new(
+            Hash::empty(),
+            index,
+            MerkleErrorCode::MissingChild,
+        )),
+    }
+}
+
 pub trait MerkleTree<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
     type Node: MerkleNode<H>;
     type Id;
@@ -171,14 +1117,28 @@ pub trait MerkleTree<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
     /// If the root is None then the default root with all leafs are empty is used.
     fn construct(addr: Self::Id, id: Self::Root) -> Self;
 
+    /// The hash of a parent from its children, `a` being the left child and `b` the right one.
+    /// The order matters, e.g. `Hash::hash_children` hashes `left || right`. For trees hashing
+    /// pairs regardless of their order, see `SortedPairMerkleTree`.
     fn hash(a: &H, b: &H) -> H;
-    fn set_parent(&mut self, index: u64, hash: &H, left: &H, right: &H) -> Result<(), MerkleError>;
+    fn set_parent(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+        left: &H,
+        right: &H,
+    ) -> Result<(), MerkleError>;
     fn set_leaf(&mut self, leaf: &Self::Node) -> Result<(), MerkleError>;
-    fn get_node_with_hash(&mut self, index: u64, hash: &H) -> Result<Self::Node, MerkleError>;
+    fn get_node_with_hash(&mut self, index: NodeIndex, hash: &H)
+        -> Result<Self::Node, MerkleError>;
 
     fn get_root_hash(&self) -> H;
     fn update_root_hash(&mut self, hash: &H);
 
+    /// The hash of a node at the given depth in a tree with all leaves empty.
+    /// depth start from 0 (the root) up to D (the leaves).
+    fn get_default_hash(depth: usize) -> Result<H, MerkleError>;
+
     fn boundary_check(&self, index: u64) -> Result<(), MerkleError> {
         boundary_check(index, D)
     }
@@ -187,7 +1147,7 @@ pub trait MerkleTree<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
         leaf_check(index, D)
     }
 
-    fn get_sibling_index(&self, index: u64) -> u64 {
+    fn get_sibling_index(&self, index: u64) -> Option<u64> {
         get_sibling_index(index)
     }
 
@@ -201,98 +1161,1892 @@ pub trait MerkleTree<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
     /// get_path(7) = [3, 1]
     /// get_path(15) = [6, 2]
     fn get_path(&self, index: u64) -> Result<[u64; D], MerkleError> {
-        Ok(get_path(index, D)?.try_into().unwrap())
+        get_path(index, D)?
+            .try_into()
+            .map_err(|_| MerkleError::new(Hash::empty(), index, MerkleErrorCode::InvalidDepth))
     }
 
+    /// The (left, right) child hashes of the non leaf node with the given index and hash.
+    /// Fails with `MissingChild` if the stored node has no child hashes.
+    fn children_of(&mut self, index: NodeIndex, hash: &H) -> Result<(H, H), MerkleError> {
+        self.boundary_check(index.0)?;
+        if get_node_type(index.0, D) != crate::proto::NodeType::NodeNonLeaf {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                index.0,
+                MerkleErrorCode::InvalidIndex,
+            ));
+        }
+        let node = self.get_node_with_hash(index, hash)?;
+        node_children(&node, index.0)
+    }
+
+    /// Whether the leaf is populated, i.e. its hash is not `empty_leaf`, without building its
+    /// proof. The walk from the root stops at the first ancestor which is the root of an empty
+    /// subtree, so leaves of sparse regions are answered without reaching the leaf level.
+    fn contains_leaf(&mut self, index: NodeIndex, empty_leaf: &H) -> Result<bool, MerkleError> {
+        let path = get_path(index.0, D)?;
+        let empty = empty_subtree_hashes(empty_leaf, D, Self::hash);
+        let (mut parent, mut hash) = (0, self.get_root_hash());
+        for (depth, child) in path.into_iter().enumerate() {
+            if hash == empty[depth] {
+                return Ok(false);
+            }
+            let node = self.get_node_with_hash(NodeIndex(parent), &hash)?;
+            let (left, right) = node_children(&node, parent)?;
+            hash = if child % 2 == 1 { left } else { right };
+            parent = child;
+        }
+        Ok(hash != *empty_leaf)
+    }
+
+    /// The lowest leaf which is not populated, i.e. whose hash is `empty_leaf`, or `None` if the
+    /// tree is full. The tree is walked depth first from the left, and the walk stops at the
+    /// first root of an empty subtree, whose leftmost leaf is the result. Fullness is not
+    /// tracked, so the populated subtrees left of the result are walked down to their leaves:
+    /// the cost is the number of populated nodes before the first empty leaf, not `2^D`.
+    fn next_empty_leaf(&mut self, empty_leaf: &H) -> Result<Option<LeafIndex>, MerkleError> {
+        let empty = empty_subtree_hashes(empty_leaf, D, Self::hash);
+        // The nodes to visit as (index, hash, depth), the next one last.
+        let mut stack = vec![(0, self.get_root_hash(), 0)];
+        while let Some((index, hash, depth)) = stack.pop() {
+            if hash == empty[depth] {
+                let leaf = ((index + 1) << (D - depth)) - 1;
+                return Ok(Some(LeafIndex(get_offset(leaf)?)));
+            }
+            if depth == D {
+                continue;
+            }
+            let node = self.get_node_with_hash(NodeIndex(index), &hash)?;
+            let (left, right) = node_children(&node, index)?;
+            stack.push((2 * index + 2, right, depth + 1));
+            stack.push((2 * index + 1, left, depth + 1));
+        }
+        Ok(None)
+    }
+
+    /// Visit the nodes which differ between the trees of two roots of this tree, see
+    /// `diff_trees`. Both roots must have been current at some point, as nodes are read by hash.
+    fn diff_roots(
+        &mut self,
+        old_root: &H,
+        new_root: &H,
+        cancel: &AtomicBool,
+        visit: impl FnMut(NodeIndex, &H, &H) -> bool,
+    ) -> Result<(), MerkleError> {
+        let defaults = (0..=D)
+            .map(Self::get_default_hash)
+            .collect::<Result<Vec<_>, _>>()?;
+        diff_trees::<H, D>(
+            old_root.clone(),
+            new_root.clone(),
+            &defaults,
+            cancel,
+            |index, hash| Ok(NodeView::of(&self.get_node_with_hash(index, hash)?)),
+            visit,
+        )
+    }
+
+    /// Store a trusted internal node, e.g. from a verified subtree, without recomputing it from
+    /// the leaves. This is `set_parent` which first checks that `hash` is the hash of `left` and
+    /// `right`, so that a node inconsistent with its children can never be stored.
+    fn set_verified_node(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+        left: &H,
+        right: &H,
+    ) -> Result<(), MerkleError> {
+        self.boundary_check(index.0)?;
+        if get_node_type(index.0, D) != crate::proto::NodeType::NodeNonLeaf {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                index.0,
+                MerkleErrorCode::InvalidIndex,
+            ));
+        }
+        if Self::hash(left, right) != *hash {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                index.0,
+                MerkleErrorCode::HashMismatch,
+            ));
+        }
+        self.set_parent(index, hash, left, right)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), fields(depth = D)))]
     fn get_leaf_with_proof(
         &mut self,
-        index: u64,
+        index: NodeIndex,
     ) -> Result<(Self::Node, MerkleProof<H, D>), MerkleError> {
-        self.leaf_check(index)?;
-        let paths = self.get_path(index)?.to_vec();
-        // We push the search from the top
-        let hash = self.get_root_hash();
-        let mut acc = 0;
-        let mut acc_node = self.get_node_with_hash(acc, &hash)?;
-        let assist: Vec<H> = paths
-            .into_iter()
-            .map(|child| {
-                let (hash, sibling_hash) = if (acc + 1) * 2 == child + 1 {
-                    // left child
-                    (acc_node.left().unwrap(), acc_node.right().unwrap())
-                } else {
-                    assert!((acc + 1) * 2 == child);
-                    (acc_node.right().unwrap(), acc_node.left().unwrap())
-                };
-                let sibling = self.get_sibling_index(child);
-                let sibling_node = self.get_node_with_hash(sibling, &sibling_hash)?;
-                acc = child;
-                acc_node = self.get_node_with_hash(acc, &hash)?;
-                Ok(sibling_node.hash())
-            })
-            .collect::<Result<Vec<H>, _>>()?;
-        let hash = acc_node.hash();
-        Ok((
-            acc_node,
-            MerkleProof {
-                source: hash,
-                root: self.get_root_hash(),
-                assist,
-                index,
-            },
-        ))
+        self.leaf_check(index.0)?;
+        self.get_internal_node_with_proof(index)
+    }
+
+    /// Same as `get_leaf_with_proof`, but serves a best-effort `PartialProof` when some nodes
+    /// can't be read instead of failing, so that a degraded backend can still serve reads which
+    /// the client evaluates. The leaf is `None` if it couldn't be read.
+    fn try_get_leaf_with_proof(
+        &mut self,
+        index: NodeIndex,
+    ) -> Result<(Option<Self::Node>, PartialProof<H, D>), MerkleError> {
+        self.leaf_check(index.0)?;
+        let root = self.get_root_hash();
+        let mut node = None;
+        let proof = generate_partial_proof(root, index, |i, hash| {
+            let n = self.get_node_with_hash(i, hash)?;
+            let view = NodeView::of(&n);
+            if i == index {
+                node = Some(n);
+            }
+            Ok(view)
+        })?;
+        Ok((node, proof))
+    }
+
+    /// Get a node at any non root index with a proof up to the root, e.g. the root of a subtree.
+    /// The assist has one entry per level above the node, so its length is the depth of the
+    /// node instead of `D`. For leaves this is the same as `get_leaf_with_proof`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self), fields(depth = D, reads))
+    )]
+    fn get_internal_node_with_proof(
+        &mut self,
+        index: NodeIndex,
+    ) -> Result<(Self::Node, MerkleProof<H, D>), MerkleError> {
+        let root = self.get_root_hash();
+        let mut node = None;
+        let proof = generate_proof(root, index, |i, hash| {
+            let n = self.get_node_with_hash(i, hash)?;
+            let view = NodeView::of(&n);
+            if i == index {
+                node = Some(n);
+            }
+            Ok(view)
+        })?;
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("reads", 1 + 2 * proof.assist.len());
+        let node = node.ok_or_else(root_index_error)?;
+        Ok((node, proof))
     }
 
+    /// Set the leaf and return its proof against the new root. If the leaf hash is unchanged,
+    /// nothing is written and the current proof is returned, see `force_set_leaf_with_proof`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(index = leaf.index(), depth = D, unchanged))
+    )]
     fn set_leaf_with_proof(&mut self, leaf: &Self::Node) -> Result<MerkleProof<H, D>, MerkleError> {
+        let (_, proof) = self.get_leaf_with_proof(NodeIndex(leaf.index()))?;
+        let unchanged = proof.source == leaf.hash();
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("unchanged", unchanged);
+        if unchanged && proof.depth() == D {
+            return Ok(proof);
+        }
+        write_leaf_path(self, leaf, proof)
+    }
+
+    /// Same as `set_leaf_with_proof`, but the leaf and its path are written even if the leaf
+    /// hash is unchanged, e.g. to repair the stored nodes.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(index = leaf.index(), depth = D))
+    )]
+    fn force_set_leaf_with_proof(
+        &mut self,
+        leaf: &Self::Node,
+    ) -> Result<MerkleProof<H, D>, MerkleError> {
+        let (_, proof) = self.get_leaf_with_proof(NodeIndex(leaf.index()))?;
+        write_leaf_path(self, leaf, proof)
+    }
+
+    /// All the writes needed to set the leaf, computed without writing anything.
+    fn journal_entry(&mut self, leaf: &Self::Node) -> Result<JournalEntry<H>, MerkleError> {
         let index = leaf.index();
-        let mut hash = leaf.hash();
-        let (_, mut proof) = self.get_leaf_with_proof(index)?;
-        proof.source = hash.clone();
-        let mut p = get_offset(index);
-        self.set_leaf(leaf)?;
-        for i in 0..D {
-            let cur_hash = hash;
-            let depth = D - i - 1;
-            let (left, right) = if p % 2 == 1 {
-                (&proof.assist[depth], &cur_hash)
-            } else {
-                (&cur_hash, &proof.assist[depth])
-            };
-            hash = Self::hash(left, right);
-            p /= 2;
-            let index = p + (1 << depth) - 1;
-            self.set_parent(index, &hash, left, right)?;
+        let (_, proof) = self.get_leaf_with_proof(NodeIndex(index))?;
+        let parents = update_path(&proof, &leaf.hash(), Self::hash)?;
+        let root = parents
+            .last()
+            .map_or(leaf.hash(), |(_, root, _, _)| root.clone());
+        Ok(JournalEntry {
+            leaf: (index, leaf.hash()),
+            parents,
+            root,
+        })
+    }
+
+    /// Apply all the writes of the entry, this is idempotent.
+    fn apply_journal_entry(&mut self, entry: &JournalEntry<H>) -> Result<(), MerkleError>
+    where
+        Self::Node: From<(u64, H)>,
+    {
+        let (index, hash) = entry.leaf.clone();
+        self.set_leaf(&Self::Node::from((index, hash)))?;
+        for (index, hash, left, right) in entry.parents.iter() {
+            self.set_parent(*index, hash, left, right)?;
+        }
+        self.update_root_hash(&entry.root);
+        Ok(())
+    }
+
+    /// Same as `set_leaf_with_proof`, but all the writes are recorded in the journal before
+    /// being applied, so that an update interrupted by a crash can be completed by `recover`.
+    fn set_leaf_with_journal(
+        &mut self,
+        leaf: &Self::Node,
+        journal: &mut impl Journal<H>,
+    ) -> Result<MerkleProof<H, D>, MerkleError>
+    where
+        Self::Node: From<(u64, H)>,
+    {
+        let entry = self.journal_entry(leaf)?;
+        journal.open(&entry)?;
+        self.apply_journal_entry(&entry)?;
+        journal.close()?;
+        let mut child = leaf.index();
+        let mut assist = vec![];
+        for (index, _, left, right) in entry.parents.iter() {
+            assist.push(if child % 2 == 1 { right } else { left }.clone());
+            child = index.0;
+        }
+        assist.reverse();
+        Ok(MerkleProof {
+            source: leaf.hash(),
+            root: entry.root,
+            assist,
+            index: leaf.index(),
+        })
+    }
+
+    /// Complete the update pending in the journal, if any. This should be called at startup
+    /// before any other update. Returns whether there was a pending update.
+    fn recover(&mut self, journal: &mut impl Journal<H>) -> Result<bool, MerkleError>
+    where
+        Self::Node: From<(u64, H)>,
+    {
+        match journal.pending()? {
+            Some(entry) => {
+                self.apply_journal_entry(&entry)?;
+                journal.close()?;
+                Ok(true)
+            }
+            None => Ok(false),
         }
-        self.update_root_hash(&hash);
-        proof.root = hash;
-        Ok(proof)
     }
 
     fn update_leaf_data_with_proof(
         &mut self,
-        index: u64,
+        index: NodeIndex,
         data: &[u8],
     ) -> Result<MerkleProof<H, D>, MerkleError> {
         let (mut leaf, _) = self.get_leaf_with_proof(index)?;
-        leaf.set(data);
+        leaf.set(data)?;
         self.set_leaf_with_proof(&leaf)
     }
 
-    fn verify_proof(&mut self, proof: MerkleProof<H, D>) -> Result<bool, MerkleError> {
-        let init = proof.source;
-        let mut p = get_offset(proof.index);
-        let hash = proof.assist.to_vec().iter().fold(init, |acc, x| {
-            let (left, right) = if p % 2 == 1 { (x, &acc) } else { (&acc, x) };
-            p /= 2;
-            Self::hash(left, right)
-        });
+    /// Build the whole tree bottom-up from the given leaves, all the other leaves are empty.
+    /// Each internal node is hashed once, which is much cheaper than calling
+    /// `set_leaf_with_proof` for each leaf. Only nodes which differ from the default nodes
+    /// are persisted. Returns the new root hash.
+    ///
+    /// Fails with `DuplicateIndex` if two leaves have the same index, even with the same hash,
+    /// and nothing is written then.
+    fn build_from_leaves(
+        &mut self,
+        leaves: impl IntoIterator<Item = Self::Node>,
+    ) -> Result<H, MerkleError> {
+        let leaves: Vec<_> = leaves.into_iter().collect();
+        let mut indexes = BTreeSet::new();
+        for leaf in leaves.iter() {
+            self.leaf_check(leaf.index())?;
+            if !indexes.insert(leaf.index()) {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    leaf.index(),
+                    MerkleErrorCode::DuplicateIndex,
+                ));
+            }
+        }
+        let mut level: BTreeMap<u64, H> = BTreeMap::new();
+        let default_leaf = Self::get_default_hash(D)?;
+        for leaf in leaves {
+            if leaf.hash() != default_leaf {
+                self.set_leaf(&leaf)?;
+                level.insert(leaf.index(), leaf.hash());
+            }
+        }
+        for depth in (1..=D).rev() {
+            let default_child = Self::get_default_hash(depth)?;
+            let default_parent = Self::get_default_hash(depth - 1)?;
+            let mut parents = BTreeMap::new();
+            for index in level.keys() {
+                let parent = (index - 1) / 2;
+                if parents.contains_key(&parent) {
+                    continue;
+                }
+                let left = level.get(&(parent * 2 + 1)).unwrap_or(&default_child);
+                let right = level.get(&(parent * 2 + 2)).unwrap_or(&default_child);
+                let hash = Self::hash(left, right);
+                if hash != default_parent {
+                    self.set_parent(NodeIndex(parent), &hash, left, right)?;
+                }
+                parents.insert(parent, hash);
+            }
+            level = parents;
+        }
+        let root = match level.remove(&0) {
+            Some(root) => root,
+            None => Self::get_default_hash(0)?,
+        };
+        self.update_root_hash(&root);
+        Ok(root)
+    }
+
+    /// Same as `build_from_leaves`, but from the `(index, data)` of the leaves, e.g. a genesis
+    /// state. Each leaf is created from the empty leaf at its index and set to its data.
+    /// Duplicate indexes fail with `DuplicateIndex` as well.
+    fn build_from_leaf_data(&mut self, leaves: &[(NodeIndex, Vec<u8>)]) -> Result<H, MerkleError> {
+        let default_leaf = Self::get_default_hash(D)?;
+        let nodes = leaves
+            .iter()
+            .map(|(index, data)| {
+                self.leaf_check(index.0)?;
+                let mut leaf = self.get_node_with_hash(*index, &default_leaf)?;
+                leaf.set(data)?;
+                Ok(leaf)
+            })
+            .collect::<Result<Vec<_>, MerkleError>>()?;
+        self.build_from_leaves(nodes)
+    }
+
+    /// Apply a changelog of `(index, hash)` pairs, e.g. the nodes written by updates on another
+    /// replica of this tree. The parent of each changed node must be changed as well, up to the
+    /// root, and each changed parent must be the hash of its children, which are taken from the
+    /// changelog or else from the current tree. Nothing is written if the changelog is
+    /// inconsistent, or if it has the same index twice (`DuplicateIndex`). Returns the new
+    /// root hash.
+    fn apply_changes(&mut self, changes: &[(u64, H)]) -> Result<H, MerkleError>
+    where
+        Self::Node: From<(u64, H)>,
+    {
+        let mut nodes = BTreeMap::new();
+        for (index, hash) in changes {
+            self.boundary_check(*index)?;
+            if nodes.insert(*index, hash.clone()).is_some() {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    *index,
+                    MerkleErrorCode::DuplicateIndex,
+                ));
+            }
+        }
+        if nodes.is_empty() {
+            return Ok(self.get_root_hash());
+        }
+        if !nodes.contains_key(&0) {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                0,
+                MerkleErrorCode::InconsistentProof,
+            ));
+        }
+
+        // Parents come before their children in index order, so the old hash of each changed
+        // node is known from its parent by the time we reach it.
+        let mut old_hashes = BTreeMap::from([(0, self.get_root_hash())]);
+        let mut parents = vec![];
+        for (&index, hash) in nodes.iter() {
+            if index != 0 && !nodes.contains_key(&((index - 1) / 2)) {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    index,
+                    MerkleErrorCode::InconsistentProof,
+                ));
+            }
+            if leaf_check(index, D).is_ok() {
+                continue;
+            }
+            let old_node = self.get_node_with_hash(NodeIndex(index), &old_hashes[&index])?;
+            let mut children = vec![];
+            for (child, old_hash) in [
+                (index * 2 + 1, old_node.left()),
+                (index * 2 + 2, old_node.right()),
+            ] {
+                let old_hash = old_hash.ok_or(MerkleError::new(
+                    Hash::empty(),
+                    index,
+                    MerkleErrorCode::MissingChild,
+                ))?;
+                let old_hash = self.get_node_with_hash(NodeIndex(child), &old_hash)?.hash();
+                children.push(nodes.get(&child).cloned().unwrap_or(old_hash.clone()));
+                old_hashes.insert(child, old_hash);
+            }
+            let (left, right) = (children[0].clone(), children[1].clone());
+            if Self::hash(&left, &right) != *hash {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    index,
+                    MerkleErrorCode::InconsistentProof,
+                ));
+            }
+            parents.push((index, hash.clone(), left, right));
+        }
+
+        for (index, hash) in nodes.iter() {
+            if leaf_check(*index, D).is_ok() {
+                self.set_leaf(&Self::Node::from((*index, hash.clone())))?;
+            }
+        }
+        for (index, hash, left, right) in parents.iter().rev() {
+            self.set_parent(NodeIndex(*index), hash, left, right)?;
+        }
+        let root = nodes[&0].clone();
+        self.update_root_hash(&root);
+        Ok(root)
+    }
+
+    /// The assist is ordered from the root down, so the fold starts from its last entry.
+    /// Proofs of internal nodes have fewer assist entries and fold fewer levels.
+    fn verify_proof(&mut self, proof: MerkleProof<H, D>) -> Result<bool, MerkleError> {
+        // Folded in place from the leaf up, without copying the assist.
+        let hash = fold_assist(
+            &proof.source,
+            proof.index,
+            proof.assist.iter().rev(),
+            Self::hash,
+        )?;
         Ok(proof.root == hash)
     }
+
+    /// Get a proof for the leaves `[start, end)`, see `MerkleRangeProof`.
+    fn get_range_proof(
+        &mut self,
+        start: NodeIndex,
+        end: NodeIndex,
+    ) -> Result<MerkleRangeProof<H, D>, MerkleError> {
+        MerkleRangeProof::<H, D>::range_check(start.0, end.0)?;
+        let (_, first) = self.get_leaf_with_proof(start)?;
+        let (_, last) = self.get_leaf_with_proof(NodeIndex(end.0 - 1))?;
+        MerkleRangeProof::from_boundary_proofs(start.0, end.0, &first, &last)
+    }
+
+    fn verify_range_proof(
+        &mut self,
+        leaf_hashes: &[H],
+        proof: &MerkleRangeProof<H, D>,
+    ) -> Result<bool, MerkleError> {
+        proof.verify(leaf_hashes, Self::hash)
+    }
+
+    /// Open a read-only handle pinned at the given (possibly historical) root, so that all the
+    /// reads through it are consistent with each other, see `ReadOnlyMerkleTree`.
+    fn open_at_root(addr: Self::Id, root: Self::Root) -> ReadOnlyMerkleTree<Self, H, D>
+    where
+        Self: Sized,
+    {
+        ReadOnlyMerkleTree::construct(addr, root)
+    }
+
+    /// Get a proof of the leaf up to its ancestor at depth `up_to_depth` instead of the root,
+    /// for clients which already trust the hash of that ancestor, e.g. from a prior verification.
+    /// The assist only has the `D - up_to_depth` levels below the ancestor, and the `root` of
+    /// the proof is the hash of the ancestor. Check it with `verify_partial_proof`.
+    fn get_partial_proof(
+        &mut self,
+        index: NodeIndex,
+        up_to_depth: usize,
+    ) -> Result<MerkleProof<H, D>, MerkleError> {
+        if up_to_depth > D {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                index.0,
+                MerkleErrorCode::InvalidDepth,
+            ));
+        }
+        let (_, mut proof) = self.get_leaf_with_proof(index)?;
+        proof.assist.drain(..up_to_depth);
+        proof.root = fold_assist(
+            &proof.source,
+            proof.index,
+            proof.assist.iter().rev(),
+            Self::hash,
+        )?;
+        Ok(proof)
+    }
+
+    /// Verify a proof from `get_partial_proof` against the trusted hash of the ancestor of the
+    /// leaf at depth `up_to_depth`. Proofs of another depth or of another ancestor are rejected,
+    /// so that a truncated proof is never accepted in place of a full one.
+    fn verify_partial_proof(
+        &mut self,
+        proof: &MerkleProof<H, D>,
+        trusted: &H,
+        up_to_depth: usize,
+    ) -> Result<bool, MerkleError> {
+        leaf_check(proof.index, D)?;
+        if up_to_depth > D || proof.depth() != D - up_to_depth || proof.root != *trusted {
+            return Ok(false);
+        }
+        let root = fold_assist(
+            &proof.source,
+            proof.index,
+            proof.assist.iter().rev(),
+            Self::hash,
+        )?;
+        Ok(root == *trusted)
+    }
+
+    /// Same as `get_leaf_with_proof`, but the proof does not carry the root.
+    fn get_leaf_with_proof_lite(
+        &mut self,
+        index: NodeIndex,
+    ) -> Result<(Self::Node, MerkleProofLite<H, D>), MerkleError> {
+        let (node, proof) = self.get_leaf_with_proof(index)?;
+        Ok((node, proof.into()))
+    }
+
+    /// Verify a proof without root against a root known by the caller.
+    fn verify_proof_against_root(
+        &mut self,
+        proof: MerkleProofLite<H, D>,
+        root: &H,
+    ) -> Result<bool, MerkleError> {
+        self.verify_proof(proof.with_root(root.clone()))
+    }
+
+    /// Same as `verify_proof`, but also rejects malformed proofs instead of returning false:
+    /// fails with `InvalidDepth` if the assist length does not match the depth of the index,
+    /// and with `InconsistentProof` if the assist only reconstructs the root when reversed,
+    /// i.e. it was serialized from the leaf up instead of from the root down.
+    fn verify_proof_strict(&mut self, proof: MerkleProof<H, D>) -> Result<bool, MerkleError> {
+        if !proof.is_well_formed() {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                proof.index,
+                MerkleErrorCode::InvalidDepth,
+            ));
+        }
+        let root = fold_assist(
+            &proof.source,
+            proof.index,
+            proof.assist.iter().rev(),
+            Self::hash,
+        )?;
+        if root == proof.root {
+            return Ok(true);
+        }
+        let reversed = fold_assist(&proof.source, proof.index, proof.assist.iter(), Self::hash)?;
+        if reversed == proof.root {
+            return Err(MerkleError::new(
+                Hash::empty(),
+                proof.index,
+                MerkleErrorCode::InconsistentProof,
+            ));
+        }
+        Ok(false)
+    }
+
+    /// Verify proofs which claim to be against the same root, e.g. the proofs of one batch
+    /// response, and return that root. Fails at the index of the first offending proof, with
+    /// `RootMismatch` if its root is not the root of the first proof, or with
+    /// `InconsistentProof` if it does not verify. An empty batch fails with `InvalidOther`.
+    fn verify_proofs_common_root(
+        &mut self,
+        proofs: &[MerkleProof<H, D>],
+    ) -> Result<H, MerkleError> {
+        let root = match proofs.first() {
+            Some(proof) => proof.root.clone(),
+            None => {
+                return Err(MerkleError::new(
+                    Hash::empty(),
+                    0,
+                    MerkleErrorCode::InvalidOther,
+                ))
+            }
+        };
+        for proof in proofs {
+            let error = |code| MerkleError::new(Hash::empty(), proof.index, code);
+            if proof.root != root {
+                return Err(error(MerkleErrorCode::RootMismatch));
+            }
+            let computed = fold_assist(
+                &proof.source,
+                proof.index,
+                proof.assist.iter().rev(),
+                Self::hash,
+            )?;
+            if computed != root {
+                return Err(error(MerkleErrorCode::InconsistentProof));
+            }
+        }
+        Ok(root)
+    }
+
+    /// Verify that the updates form a chain of state transitions, without the tree: each update
+    /// only changes its leaf from `old_value` to `new_value` (both proofs have the same index and
+    /// siblings, and fold to their roots), and the root before each update is the root after
+    /// the previous one. As each proof is bound to its root, the siblings of overlapping paths
+    /// are then consistent. Returns true for a valid chain, otherwise fails at the position of
+    /// the first inconsistent update in `updates`, with `RootMismatch` if it doesn't start from
+    /// the root left by the previous update, or with `InconsistentProof`. Proofs of indexes
+    /// which are not leaves fail with `InvalidLeafIndex`.
+    fn verify_update_batch(&mut self, updates: &[Update<H, D>]) -> Result<bool, MerkleError> {
+        let mut previous_root = None;
+        for (position, update) in updates.iter().enumerate() {
+            let error = |code| MerkleError::new(Hash::empty(), position as u64, code);
+            let (before, after) = (&update.proof_before, &update.proof_after);
+            self.leaf_check(update.index)?;
+            if before.index != update.index
+                || after.index != update.index
+                || before.source != update.old_value
+                || after.source != update.new_value
+                || before.assist != after.assist
+            {
+                return Err(error(MerkleErrorCode::InconsistentProof));
+            }
+            for proof in [before, after] {
+                let root = fold_assist(
+                    &proof.source,
+                    proof.index,
+                    proof.assist.iter().rev(),
+                    Self::hash,
+                )?;
+                if proof.assist.len() != D || root != proof.root {
+                    return Err(error(MerkleErrorCode::InconsistentProof));
+                }
+            }
+            if previous_root.is_some_and(|root| root != before.root) {
+                return Err(error(MerkleErrorCode::RootMismatch));
+            }
+            previous_root = Some(&after.root);
+        }
+        Ok(true)
+    }
+
+    /// Check that the whole tree under the expected root is stored and consistent, e.g. on a
+    /// replica after replication. Returns the index of the shallowest node which is either
+    /// missing or whose hash is not the hash of its children, or `None` if there is none.
+    /// Subtrees whose hash is the default hash are not visited, so this reads each stored
+    /// node of the tree once. Fails with `Cancelled` once `cancel` is set.
+    fn locate_mismatch(
+        &mut self,
+        expected_root: &H,
+        cancel: &AtomicBool,
+    ) -> Result<Option<NodeIndex>, MerkleError> {
+        // Breadth first, so that the first mismatch found is the shallowest one.
+        let mut queue = VecDeque::from([(0, expected_root.clone())]);
+        while let Some((index, hash)) = queue.pop_front() {
+            check_cancelled(cancel, index)?;
+            let node = match self.get_node_with_hash(NodeIndex(index), &hash) {
+                Ok(node) if node.hash() == hash => node,
+                _ => return Ok(Some(NodeIndex(index))),
+            };
+            if leaf_check(index, D).is_ok() {
+                continue;
+            }
+            let (left, right) = match node_children(&node, index) {
+                Ok(children) => children,
+                Err(_) => return Ok(Some(NodeIndex(index))),
+            };
+            if Self::hash(&left, &right) != hash {
+                return Ok(Some(NodeIndex(index)));
+            }
+            let default_child = Self::get_default_hash((index + 1).ilog2() as usize + 1)?;
+            for (child, child_hash) in [(index * 2 + 1, left), (index * 2 + 2, right)] {
+                if child_hash != default_child {
+                    queue.push_back((child, child_hash));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stream the assist of a leaf proof from the leaf up to the root, so that consumers can
+    /// fold the proof incrementally without holding the whole assist vector, see `ProofStream`.
+    fn proof_stream(&mut self, index: NodeIndex) -> ProofStream<'_, Self, H, D>
+    where
+        Self: Sized,
+    {
+        ProofStream {
+            tree: self,
+            index: index.0,
+            level: 0,
+            _hash: PhantomData,
+        }
+    }
+
+    /// Verify the proofs as they are consumed, e.g. from a streamed export, yielding each proof
+    /// with whether it verifies, see `VerifyStream`.
+    fn verify_stream<I>(&mut self, proofs: I) -> VerifyStream<'_, Self, I::IntoIter, H, D>
+    where
+        Self: Sized,
+        I: IntoIterator<Item = MerkleProof<H, D>>,
+    {
+        VerifyStream {
+            tree: self,
+            proofs: proofs.into_iter(),
+            _hash: PhantomData,
+        }
+    }
+}
+
+/// Proofs paired with the result of `MerkleTree::verify_proof`, verified one at a time as they
+/// are pulled, so that only one proof is held at once. Proofs which can't be folded, e.g. of an
+/// index out of the tree, are yielded as not verified.
+pub struct VerifyStream<'a, T, I, H, const D: usize> {
+    tree: &'a mut T,
+    proofs: I,
+    _hash: PhantomData<H>,
+}
+
+impl<'a, T, I, H, const D: usize> Iterator for VerifyStream<'a, T, I, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+    I: Iterator<Item = MerkleProof<H, D>>,
+{
+    type Item = (MerkleProof<H, D>, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let proof = self.proofs.next()?;
+        let verified = self.tree.verify_proof(proof.clone()).unwrap_or(false);
+        Some((proof, verified))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.proofs.size_hint()
+    }
+}
+
+/// Sibling hashes of a leaf from the leaf level up to the root.
+/// Only constant memory is held: each level is found by walking down from the root again,
+/// which costs O(D^2) node fetches instead of the O(D) of `get_leaf_with_proof`.
+/// The stream ends after the first error.
+pub struct ProofStream<'a, T, H, const D: usize> {
+    tree: &'a mut T,
+    index: u64,
+    // Number of sibling hashes already yielded.
+    level: usize,
+    _hash: PhantomData<H>,
+}
+
+impl<'a, T, H, const D: usize> ProofStream<'a, T, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    // The ancestor of the leaf at the given depth.
+    fn ancestor(&self, depth: usize) -> Result<u64, MerkleError> {
+        Ok((1 << depth) - 1 + (get_offset(self.index)? >> (D - depth)))
+    }
+
+    fn sibling_hash(&mut self, depth: usize) -> Result<H, MerkleError> {
+        let root = self.tree.get_root_hash();
+        let mut node = self.tree.get_node_with_hash(NodeIndex(0), &root)?;
+        for d in 1..=depth {
+            let child = self.ancestor(d)?;
+            let (hash, sibling_hash) = if child % 2 == 1 {
+                (node.left(), node.right())
+            } else {
+                (node.right(), node.left())
+            };
+            let missing = || MerkleError::new(Hash::empty(), child, MerkleErrorCode::MissingChild);
+            if d == depth {
+                let sibling_hash = sibling_hash.ok_or_else(missing)?;
+                let sibling = get_sibling_index(child).ok_or_else(root_index_error)?;
+                return Ok(self
+                    .tree
+                    .get_node_with_hash(NodeIndex(sibling), &sibling_hash)?
+                    .hash());
+            }
+            node = self
+                .tree
+                .get_node_with_hash(NodeIndex(child), &hash.ok_or_else(missing)?)?;
+        }
+        unreachable!("depth of a leaf is at least 1")
+    }
+}
+
+impl<'a, T, H, const D: usize> Iterator for ProofStream<'a, T, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    type Item = Result<H, MerkleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.level >= D {
+            return None;
+        }
+        let result = leaf_check(self.index, D).and_then(|_| self.sibling_hash(D - self.level));
+        self.level = if result.is_ok() { self.level + 1 } else { D };
+        Some(result)
+    }
+}
+
+/// A merkle tree handle pinned at the root it was constructed with.
+/// Reads resolve against that root, and all the mutations fail with `MerkleErrorCode::ReadOnly`
+/// without touching the underlying tree.
+pub struct ReadOnlyMerkleTree<T, H, const D: usize> {
+    inner: T,
+    _hash: PhantomData<H>,
+}
+
+impl<T, H, const D: usize> ReadOnlyMerkleTree<T, H, D> {
+    /// Pin an existing handle at the given root, e.g. a root read before some updates.
+    pub fn new(mut tree: T, root: &H) -> Self
+    where
+        H: Debug + Clone + PartialEq + Serialize,
+        T: MerkleTree<H, D>,
+    {
+        tree.update_root_hash(root);
+        ReadOnlyMerkleTree {
+            inner: tree,
+            _hash: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn read_only_error(index: u64) -> MerkleError {
+        MerkleError::new(Hash::empty(), index, MerkleErrorCode::ReadOnly)
+    }
+}
+
+impl<T, H, const D: usize> MerkleTree<H, D> for ReadOnlyMerkleTree<T, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    type Node = T::Node;
+    type Id = T::Id;
+    type Root = T::Root;
+
+    fn construct(addr: Self::Id, root: Self::Root) -> Self {
+        ReadOnlyMerkleTree {
+            inner: T::construct(addr, root),
+            _hash: PhantomData,
+        }
+    }
+
+    fn hash(a: &H, b: &H) -> H {
+        T::hash(a, b)
+    }
+
+    fn set_parent(
+        &mut self,
+        index: NodeIndex,
+        _hash: &H,
+        _left: &H,
+        _right: &H,
+    ) -> Result<(), MerkleError> {
+        Err(Self::read_only_error(index.0))
+    }
+
+    fn set_leaf(&mut self, leaf: &Self::Node) -> Result<(), MerkleError> {
+        Err(Self::read_only_error(leaf.index()))
+    }
+
+    fn get_node_with_hash(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+    ) -> Result<Self::Node, MerkleError> {
+        self.inner.get_node_with_hash(index, hash)
+    }
+
+    fn get_root_hash(&self) -> H {
+        self.inner.get_root_hash()
+    }
+
+    // The root only moves after a successful write, which never happens on this handle.
+    fn update_root_hash(&mut self, _hash: &H) {}
+
+    fn get_default_hash(depth: usize) -> Result<H, MerkleError> {
+        T::get_default_hash(depth)
+    }
+}
+
+/// A decorator caching the nodes read from the inner tree, so that repeated reads (e.g. the
+/// upper levels of consecutive proofs) only hit the backend once. Nodes are keyed by index and
+/// hash, so reads at a historical root never alias the current nodes. Writes drop the entry of
+/// the written node, so that the next read returns what was written.
+pub struct CachingMerkleTree<T, H, const D: usize>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    inner: T,
+    cache: HashMap<(u64, H), T::Node>,
+    // The prefetched nodes which have not been read since, see `prefetch_changes`.
+    prefetched: HashSet<(u64, H)>,
+    prefetch_stats: PrefetchStats,
+    // The budget of the prefetch run when the root moves, see `with_prefetch`.
+    prefetch: Option<PrefetchBudget>,
+}
+
+/// The number of nodes read into the cache by `CachingMerkleTree::prefetch_changes`, and how
+/// many of them were read afterwards, so `used / prefetched` is the hit rate of the prefetch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    pub prefetched: u64,
+    pub used: u64,
+}
+
+/// The time budget of a prefetch if `KVPAIR_PREFETCH_TIMEOUT_MS` is not set.
+pub const DEFAULT_PREFETCH_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The bounds of the prefetch run by `CachingMerkleTree` when its root moves, see
+/// `CachingMerkleTree::with_prefetch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchBudget {
+    pub max_nodes: usize,
+    pub timeout: Duration,
+}
+
+impl PrefetchBudget {
+    /// The budget set by `KVPAIR_PREFETCH_MAX_NODES` and `KVPAIR_PREFETCH_TIMEOUT_MS`, or `None`
+    /// if the node budget is not set, which disables the prefetch.
+    pub fn from_env() -> Result<Option<Self>, crate::errors::Error> {
+        let parse = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|n| {
+                    n.parse::<u64>().map_err(|_| {
+                        crate::errors::Error::InvalidArgument(format!("Invalid {name}"))
+                    })
+                })
+                .transpose()
+        };
+        let max_nodes = match parse("KVPAIR_PREFETCH_MAX_NODES")? {
+            Some(max_nodes) => max_nodes as usize,
+            None => return Ok(None),
+        };
+        let timeout = parse("KVPAIR_PREFETCH_TIMEOUT_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_PREFETCH_TIMEOUT);
+        Ok(Some(PrefetchBudget { max_nodes, timeout }))
+    }
+}
+
+impl<T, H, const D: usize> CachingMerkleTree<T, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    pub fn new(inner: T) -> Self {
+        CachingMerkleTree {
+            inner,
+            cache: HashMap::new(),
+            prefetched: HashSet::new(),
+            prefetch_stats: PrefetchStats::default(),
+            prefetch: None,
+        }
+    }
+
+    /// Prefetch the changes between the old and the new root within `budget` (see
+    /// `prefetch_changes`) whenever `update_root_hash` moves the root, e.g. on a read replica
+    /// learning the roots written by another one, typically with `PrefetchBudget::from_env()`.
+    /// Only meant for trees which are read, as the writes of a tree move its root as well.
+    pub fn with_prefetch(mut self, budget: Option<PrefetchBudget>) -> Self {
+        self.prefetch = budget;
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Writes through the inner tree are not seen by the cache, see `clear_cache`.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.prefetched.clear();
+    }
+
+    pub fn prefetch_stats(&self) -> PrefetchStats {
+        self.prefetch_stats
+    }
+}
+
+impl<T, H, const D: usize> CachingMerkleTree<T, H, D>
+where
+    H: Debug + Clone + PartialEq + Eq + std::hash::Hash + Serialize,
+    T: MerkleTree<H, D>,
+    T::Node: Clone,
+{
+    /// Read into the cache the nodes which differ between the trees of `old_root` and
+    /// `new_root` (see `diff_trees`), e.g. when the root moved after writes on another replica,
+    /// so that the first reads of the changed paths hit the cache. The walk stops once about
+    /// `max_nodes` nodes have been read or at `deadline`, whichever comes first, the upper
+    /// levels being read first. Returns the number of nodes read, which are counted with their
+    /// later reads in `prefetch_stats`.
+    pub fn prefetch_changes(
+        &mut self,
+        old_root: &H,
+        new_root: &H,
+        max_nodes: usize,
+        deadline: Instant,
+    ) -> Result<usize, MerkleError> {
+        let defaults = (0..=D)
+            .map(T::get_default_hash)
+            .collect::<Result<Vec<_>, _>>()?;
+        let read = Cell::new(0);
+        let within_budget = || read.get() < max_nodes && Instant::now() < deadline;
+        // The leaves are not read by the walk, only compared.
+        let mut leaves = vec![];
+        // The walk is bounded by the budget instead.
+        let cancel = AtomicBool::new(false);
+        diff_trees::<H, D>(
+            old_root.clone(),
+            new_root.clone(),
+            &defaults,
+            &cancel,
+            |index, hash| {
+                let (node, fetched) = self.prefetch_node(index, hash)?;
+                read.set(read.get() + fetched as usize);
+                Ok(NodeView::of(&node))
+            },
+            |index, _, new| {
+                if leaf_check(index.0, D).is_ok() {
+                    leaves.push((index, new.clone()));
+                }
+                within_budget()
+            },
+        )?;
+        for (index, hash) in leaves {
+            if !within_budget() {
+                break;
+            }
+            let (_, fetched) = self.prefetch_node(index, &hash)?;
+            read.set(read.get() + fetched as usize);
+        }
+        Ok(read.get())
+    }
+
+    // Read the node into the cache, returning whether it was read from the inner tree.
+    fn prefetch_node(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+    ) -> Result<(T::Node, bool), MerkleError> {
+        let key = (index.0, hash.clone());
+        if let Some(node) = self.cache.get(&key) {
+            return Ok((node.clone(), false));
+        }
+        let node = self.inner.get_node_with_hash(index, hash)?;
+        self.cache.insert(key.clone(), node.clone());
+        self.prefetched.insert(key);
+        self.prefetch_stats.prefetched += 1;
+        Ok((node, true))
+    }
+}
+
+impl<T, H, const D: usize> MerkleTree<H, D> for CachingMerkleTree<T, H, D>
+where
+    H: Debug + Clone + PartialEq + Eq + std::hash::Hash + Serialize,
+    T: MerkleTree<H, D>,
+    T::Node: Clone,
+{
+    type Node = T::Node;
+    type Id = T::Id;
+    type Root = T::Root;
+
+    fn construct(addr: Self::Id, root: Self::Root) -> Self {
+        Self::new(T::construct(addr, root))
+    }
+
+    fn hash(a: &H, b: &H) -> H {
+        T::hash(a, b)
+    }
+
+    fn set_parent(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+        left: &H,
+        right: &H,
+    ) -> Result<(), MerkleError> {
+        let key = (index.0, hash.clone());
+        self.cache.remove(&key);
+        self.prefetched.remove(&key);
+        self.inner.set_parent(index, hash, left, right)
+    }
+
+    fn set_leaf(&mut self, leaf: &Self::Node) -> Result<(), MerkleError> {
+        let key = (leaf.index(), leaf.hash());
+        self.cache.remove(&key);
+        self.prefetched.remove(&key);
+        self.inner.set_leaf(leaf)
+    }
+
+    fn get_node_with_hash(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+    ) -> Result<Self::Node, MerkleError> {
+        let key = (index.0, hash.clone());
+        if let Some(node) = self.cache.get(&key) {
+            if self.prefetched.remove(&key) {
+                self.prefetch_stats.used += 1;
+            }
+            return Ok(node.clone());
+        }
+        let node = self.inner.get_node_with_hash(index, hash)?;
+        self.cache.insert(key, node.clone());
+        Ok(node)
+    }
+
+    fn get_root_hash(&self) -> H {
+        self.inner.get_root_hash()
+    }
+
+    fn update_root_hash(&mut self, hash: &H) {
+        let old_root = self.inner.get_root_hash();
+        self.inner.update_root_hash(hash);
+        if let Some(budget) = self.prefetch {
+            if old_root != *hash {
+                // Best effort, the nodes which were not read are read when first needed.
+                let deadline = Instant::now() + budget.timeout;
+                let _ = self.prefetch_changes(&old_root, hash, budget.max_nodes, deadline);
+            }
+        }
+    }
+
+    fn get_default_hash(depth: usize) -> Result<H, MerkleError> {
+        T::get_default_hash(depth)
+    }
+}
+
+/// Hash the smaller of the two children first (`min(a, b) || max(a, b)`), so that the parent
+/// does not depend on the order of its children. For `Hash`, this compares the bytes of the
+/// hashes as OpenZeppelin's `MerkleProof` compares its `bytes32`.
+pub fn hash_sorted<H: Ord>(a: &H, b: &H, hash: impl Fn(&H, &H) -> H) -> H {
+    if a <= b {
+        hash(a, b)
+    } else {
+        hash(b, a)
+    }
+}
+
+/// A decorator hashing the children of each node in sorted order with `hash_sorted`, for
+/// compatibility with systems using commutative pair hashes. The inner tree must store the
+/// hashes given to `set_parent` as is, e.g. `MemoryMerkleTree`, as a backend computing the
+/// hashes of the parents itself would reject them. The empty subtrees have the same hashes
+/// in both conventions, as their children are equal.
+pub struct SortedPairMerkleTree<T, H, const D: usize>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    inner: T,
+    _hash: PhantomData<H>,
+}
+
+impl<T, H, const D: usize> SortedPairMerkleTree<T, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    pub fn new(inner: T) -> Self {
+        SortedPairMerkleTree {
+            inner,
+            _hash: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, H, const D: usize> MerkleTree<H, D> for SortedPairMerkleTree<T, H, D>
+where
+    H: Debug + Clone + PartialEq + Ord + Serialize,
+    T: MerkleTree<H, D>,
+{
+    type Node = T::Node;
+    type Id = T::Id;
+    type Root = T::Root;
+
+    fn construct(addr: Self::Id, root: Self::Root) -> Self {
+        Self::new(T::construct(addr, root))
+    }
+
+    fn hash(a: &H, b: &H) -> H {
+        hash_sorted(a, b, T::hash)
+    }
+
+    fn set_parent(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+        left: &H,
+        right: &H,
+    ) -> Result<(), MerkleError> {
+        self.inner.set_parent(index, hash, left, right)
+    }
+
+    fn set_leaf(&mut self, leaf: &Self::Node) -> Result<(), MerkleError> {
+        self.inner.set_leaf(leaf)
+    }
+
+    fn get_node_with_hash(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+    ) -> Result<Self::Node, MerkleError> {
+        self.inner.get_node_with_hash(index, hash)
+    }
+
+    fn get_root_hash(&self) -> H {
+        self.inner.get_root_hash()
+    }
+
+    fn update_root_hash(&mut self, hash: &H) {
+        self.inner.update_root_hash(hash)
+    }
+
+    fn get_default_hash(depth: usize) -> Result<H, MerkleError> {
+        T::get_default_hash(depth)
+    }
+}
+
+/// A commitment over the ordered roots of several trees, e.g. the sub-trees of a global state.
+/// The roots are the leaves of a binary tree of the smallest power of two width, padded on the
+/// right with `Hash::empty()`, whose parents are `Hash::hash_children` as in the trees. The
+/// commitment is `Hash::hash_children(top, count)`, the number of roots being a field element,
+/// so that padded and unpadded lists of roots don't share a commitment. See `prove_root`.
+pub fn commit_roots(roots: &[Hash]) -> Hash {
+    let levels = roots_levels(roots);
+    commit_roots_top(&levels[levels.len() - 1][0], roots.len() as u64)
+}
+
+/// The proof that `roots[index]` is committed by `commit_roots(roots)`, `None` if the index is
+/// out of the roots.
+pub fn prove_root(roots: &[Hash], index: usize) -> Option<RootsProof> {
+    let root = *roots.get(index)?;
+    let levels = roots_levels(roots);
+    let mut assist: Vec<Hash> = levels[..levels.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(height, level)| level[(index >> height) ^ 1])
+        .collect();
+    assist.reverse();
+    Some(RootsProof {
+        root,
+        index: index as u64,
+        count: roots.len() as u64,
+        assist,
+        commitment: commit_roots_top(&levels[levels.len() - 1][0], roots.len() as u64),
+    })
+}
+
+// The levels of the tree over the roots, from the padded roots up to the top.
+fn roots_levels(roots: &[Hash]) -> Vec<Vec<Hash>> {
+    let mut level = roots.to_vec();
+    level.resize(roots.len().next_power_of_two(), Hash::empty());
+    let mut levels = vec![level];
+    while levels[levels.len() - 1].len() > 1 {
+        let next = levels[levels.len() - 1]
+            .chunks(2)
+            .map(|pair| Hash::hash_children(&pair[0], &pair[1]))
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+fn commit_roots_top(top: &Hash, count: u64) -> Hash {
+    Hash::hash_children(top, &Fr::from(count).into())
+}
+
+/// A proof that `root` is the root at `index` of the `count` roots committed by `commitment`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RootsProof {
+    pub root: Hash,
+    pub index: u64,
+    pub count: u64,
+    /// Sibling hashes ordered from the top down, as the assist of `MerkleProof`.
+    pub assist: Vec<Hash>,
+    pub commitment: Hash,
+}
+
+impl RootsProof {
+    /// Check that the root folds to the commitment. The width of the tree follows from the
+    /// count, so that the proof can't claim a root at another position.
+    pub fn verify(&self) -> bool {
+        let depth = match self.count.checked_next_power_of_two() {
+            Some(width) => width.trailing_zeros() as usize,
+            None => return false,
+        };
+        if self.index >= self.count || self.assist.len() != depth {
+            return false;
+        }
+        let top = self
+            .assist
+            .iter()
+            .rev()
+            .enumerate()
+            .fold(self.root, |acc, (height, sibling)| {
+                if (self.index >> height) & 1 == 0 {
+                    Hash::hash_children(&acc, sibling)
+                } else {
+                    Hash::hash_children(sibling, &acc)
+                }
+            });
+        commit_roots_top(&top, self.count) == self.commitment
+    }
+}
+
+/// A stable handle to a checkpointed root, numbered from 0 in checkpoint order,
+/// which clients can use like a block height instead of the root hash.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash, Serialize, Deserialize,
+)]
+pub struct VersionId(pub u64);
+
+/// Merkle trees which record their roots at checkpoints, so that they can be read at the
+/// versions of these checkpoints later on. The mapping is stored along with the tree.
+pub trait Checkpoints<H: Debug + Clone + PartialEq + Serialize, const D: usize>:
+    MerkleTree<H, D>
+{
+    /// Record the current root as a new version.
+    fn checkpoint(&mut self) -> Result<VersionId, MerkleError>;
+
+    /// The root recorded at the given version, if any.
+    fn root_of(&self, version: VersionId) -> Option<H>;
+
+    /// The checkpoints from the given version on, in version order, with the time they were
+    /// recorded at. At most `limit` checkpoints are returned, so that stores with many
+    /// checkpoints are listed page by page, starting each page after the last version returned.
+    fn list_checkpoints(
+        &self,
+        start: VersionId,
+        limit: usize,
+    ) -> Result<Vec<(VersionId, H, SystemTime)>, MerkleError>;
+
+    fn must_root_of(&self, version: VersionId) -> Result<H, MerkleError> {
+        self.root_of(version).ok_or(MerkleError::new(
+            Hash::empty(),
+            version.0,
+            MerkleErrorCode::UnknownVersion,
+        ))
+    }
+
+    /// Same as `get_leaf_with_proof`, but at the root of the given version.
+    fn get_leaf_with_proof_at(
+        &mut self,
+        version: VersionId,
+        index: NodeIndex,
+    ) -> Result<(Self::Node, MerkleProof<H, D>), MerkleError> {
+        let root = self.must_root_of(version)?;
+        let current = self.get_root_hash();
+        self.update_root_hash(&root);
+        let result = self.get_leaf_with_proof(index);
+        self.update_root_hash(&current);
+        result
+    }
+}
+
+/// The reads of a tree which only need shared access, for the backends which can fetch their
+/// nodes through `&self`.
+pub trait MerkleTreeRead<H: Debug + Clone + PartialEq + Serialize, const D: usize>:
+    MerkleTree<H, D>
+{
+    /// Same as `get_node_with_hash`, through `&self`.
+    fn read_node_with_hash(&self, index: NodeIndex, hash: &H) -> Result<Self::Node, MerkleError>;
+
+    /// Same as `get_leaf_with_proof`, through `&self`.
+    fn read_leaf_with_proof(
+        &self,
+        index: NodeIndex,
+    ) -> Result<(Self::Node, MerkleProof<H, D>), MerkleError> {
+        self.leaf_check(index.0)?;
+        let mut node = None;
+        let proof = generate_proof(self.get_root_hash(), index, |i, hash| {
+            let n = self.read_node_with_hash(i, hash)?;
+            let view = NodeView::of(&n);
+            if i == index {
+                node = Some(n);
+            }
+            Ok(view)
+        })?;
+        let node = node.ok_or_else(root_index_error)?;
+        Ok((node, proof))
+    }
+}
+
+/// A handle to a merkle tree which can be cloned and shared between threads or async tasks.
+/// Reads (the root hash, and proofs for the trees implementing `MerkleTreeRead`) take a read
+/// lock and may run concurrently, while updates are serialized under the write lock.
+pub struct SharedMerkleTree<T> {
+    inner: Arc<RwLock<T>>,
+}
+
+impl<T> Clone for SharedMerkleTree<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> SharedMerkleTree<T> {
+    pub fn new(tree: T) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(tree)),
+        }
+    }
+
+    /// Run `f` with shared access to the tree.
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let tree = self.inner.read().expect("Merkle tree lock poisoned");
+        f(&tree)
+    }
+
+    /// Run `f` with exclusive access to the tree.
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut tree = self.inner.write().expect("Merkle tree lock poisoned");
+        f(&mut tree)
+    }
+
+    pub fn get_root_hash<H, const D: usize>(&self) -> H
+    where
+        H: Debug + Clone + PartialEq + Serialize,
+        T: MerkleTree<H, D>,
+    {
+        self.read(|tree| tree.get_root_hash())
+    }
+
+    pub fn get_leaf_with_proof<H, const D: usize>(
+        &self,
+        index: NodeIndex,
+    ) -> Result<(T::Node, MerkleProof<H, D>), MerkleError>
+    where
+        H: Debug + Clone + PartialEq + Serialize,
+        T: MerkleTreeRead<H, D>,
+    {
+        self.read(|tree| tree.read_leaf_with_proof(index))
+    }
+
+    pub fn set_leaf_with_proof<H, const D: usize>(
+        &self,
+        leaf: &T::Node,
+    ) -> Result<MerkleProof<H, D>, MerkleError>
+    where
+        H: Debug + Clone + PartialEq + Serialize,
+        T: MerkleTree<H, D>,
+    {
+        self.write(|tree| tree.set_leaf_with_proof(leaf))
+    }
+
+    pub fn update_leaf_data_with_proof<H, const D: usize>(
+        &self,
+        index: NodeIndex,
+        data: &[u8],
+    ) -> Result<MerkleProof<H, D>, MerkleError>
+    where
+        H: Debug + Clone + PartialEq + Serialize,
+        T: MerkleTree<H, D>,
+    {
+        self.write(|tree| tree.update_leaf_data_with_proof(index, data))
+    }
+}
+
+/// The number of nodes kept by a `SharedNodeCache` unless given, e.g. about 200 MB of
+/// `MerkleRecord`s.
+pub const DEFAULT_NODE_CACHE_CAPACITY: usize = 1 << 20;
+
+/// A node cache shared between several trees, keyed by the key of the tree, the index and the
+/// hash of the node, so that trees never read each other's entries. Once it holds `capacity`
+/// nodes, the least recently used node is dropped for each new one, so that the nodes of many
+/// trees and of their past roots don't grow the memory without bound. Clones share the cache.
+pub struct SharedNodeCache<K, H, N> {
+    nodes: Arc<Mutex<NodeLru<K, H, N>>>,
+}
+
+impl<K, H, N> Clone for SharedNodeCache<K, H, N> {
+    fn clone(&self) -> Self {
+        Self {
+            nodes: Arc::clone(&self.nodes),
+        }
+    }
+}
+
+impl<K, H, N> Default for SharedNodeCache<K, H, N> {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_NODE_CACHE_CAPACITY)
+    }
+}
+
+impl<K, H, N> SharedNodeCache<K, H, N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache of at most `capacity` nodes, at least one.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            nodes: Arc::new(Mutex::new(NodeLru {
+                capacity: capacity.max(1),
+                nodes: HashMap::new(),
+                uses: BTreeMap::new(),
+                tick: 0,
+            })),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().nodes.is_empty()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, NodeLru<K, H, N>> {
+        self.nodes.lock().expect("Node cache lock poisoned")
+    }
+}
+
+impl<K, H, N> SharedNodeCache<K, H, N>
+where
+    K: Clone + Eq + std::hash::Hash,
+    H: Clone + Eq + std::hash::Hash,
+{
+    /// Drop all the nodes cached for the tree with the given key.
+    pub fn remove_tree(&self, key: &K) {
+        let mut lru = self.lock();
+        let keys: Vec<_> = lru
+            .nodes
+            .keys()
+            .filter(|(k, _, _)| k == key)
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            lru.remove(key);
+        }
+    }
+}
+
+// The nodes of a `SharedNodeCache`, with the tick of their last use.
+struct NodeLru<K, H, N> {
+    capacity: usize,
+    nodes: HashMap<(K, u64, H), (N, u64)>,
+    // The keys of the nodes by the tick of their last use, least recently used first.
+    uses: BTreeMap<u64, (K, u64, H)>,
+    tick: u64,
+}
+
+impl<K, H, N> NodeLru<K, H, N>
+where
+    K: Clone + Eq + std::hash::Hash,
+    H: Clone + Eq + std::hash::Hash,
+{
+    fn get(&mut self, key: &(K, u64, H)) -> Option<&N> {
+        self.tick += 1;
+        let (node, used) = self.nodes.get_mut(key)?;
+        self.uses.remove(used);
+        *used = self.tick;
+        self.uses.insert(self.tick, key.clone());
+        Some(node)
+    }
+
+    fn insert(&mut self, key: (K, u64, H), node: N) {
+        self.remove(&key);
+        if self.nodes.len() >= self.capacity {
+            if let Some((_, evicted)) = self.uses.pop_first() {
+                self.nodes.remove(&evicted);
+            }
+        }
+        self.tick += 1;
+        self.uses.insert(self.tick, key.clone());
+        self.nodes.insert(key, (node, self.tick));
+    }
+
+    fn remove(&mut self, key: &(K, u64, H)) {
+        if let Some((_, used)) = self.nodes.remove(key) {
+            self.uses.remove(&used);
+        }
+    }
+}
+
+/// Same as `CachingMerkleTree`, but caching the nodes in a cache shared with other trees, under
+/// the key of this tree.
+pub struct SharedCachingMerkleTree<K, T, H, const D: usize>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    inner: T,
+    key: K,
+    cache: SharedNodeCache<K, H, T::Node>,
+}
+
+impl<K, T, H, const D: usize> SharedCachingMerkleTree<K, T, H, D>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    pub fn new(key: K, inner: T, cache: SharedNodeCache<K, H, T::Node>) -> Self {
+        SharedCachingMerkleTree { inner, key, cache }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K, T, H, const D: usize> MerkleTree<H, D> for SharedCachingMerkleTree<K, T, H, D>
+where
+    K: Clone + Eq + std::hash::Hash,
+    H: Debug + Clone + PartialEq + Eq + std::hash::Hash + Serialize,
+    T: MerkleTree<H, D>,
+    T::Node: Clone,
+{
+    type Node = T::Node;
+    /// The key of the tree in the cache and the id of the inner tree, with a cache of its own.
+    type Id = (K, T::Id);
+    type Root = T::Root;
+
+    fn construct((key, addr): Self::Id, root: Self::Root) -> Self {
+        Self::new(key, T::construct(addr, root), SharedNodeCache::new())
+    }
+
+    fn hash(a: &H, b: &H) -> H {
+        T::hash(a, b)
+    }
+
+    fn set_parent(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+        left: &H,
+        right: &H,
+    ) -> Result<(), MerkleError> {
+        self.cache
+            .lock()
+            .remove(&(self.key.clone(), index.0, hash.clone()));
+        self.inner.set_parent(index, hash, left, right)
+    }
+
+    fn set_leaf(&mut self, leaf: &Self::Node) -> Result<(), MerkleError> {
+        self.cache
+            .lock()
+            .remove(&(self.key.clone(), leaf.index(), leaf.hash()));
+        self.inner.set_leaf(leaf)
+    }
+
+    fn get_node_with_hash(
+        &mut self,
+        index: NodeIndex,
+        hash: &H,
+    ) -> Result<Self::Node, MerkleError> {
+        let key = (self.key.clone(), index.0, hash.clone());
+        if let Some(node) = self.cache.lock().get(&key) {
+            return Ok(node.clone());
+        }
+        // The lock is not held while reading the backend, so that the other trees aren't blocked.
+        let node = self.inner.get_node_with_hash(index, hash)?;
+        self.cache.lock().insert(key, node.clone());
+        Ok(node)
+    }
+
+    fn get_root_hash(&self) -> H {
+        self.inner.get_root_hash()
+    }
+
+    fn update_root_hash(&mut self, hash: &H) {
+        self.inner.update_root_hash(hash)
+    }
+
+    fn get_default_hash(depth: usize) -> Result<H, MerkleError> {
+        T::get_default_hash(depth)
+    }
+}
+
+impl<K, T, H, const D: usize> MerkleTreeRead<H, D> for SharedCachingMerkleTree<K, T, H, D>
+where
+    K: Clone + Eq + std::hash::Hash,
+    H: Debug + Clone + PartialEq + Eq + std::hash::Hash + Serialize,
+    T: MerkleTreeRead<H, D>,
+    T::Node: Clone,
+{
+    fn read_node_with_hash(&self, index: NodeIndex, hash: &H) -> Result<Self::Node, MerkleError> {
+        let key = (self.key.clone(), index.0, hash.clone());
+        if let Some(node) = self.cache.lock().get(&key) {
+            return Ok(node.clone());
+        }
+        let node = self.inner.read_node_with_hash(index, hash)?;
+        self.cache.lock().insert(key, node.clone());
+        Ok(node)
+    }
+}
+
+/// A tree handed out by a `TreeManager`.
+pub type ManagedMerkleTree<K, T, H, const D: usize> =
+    SharedMerkleTree<SharedCachingMerkleTree<K, T, H, D>>;
+
+struct ManagedEntry<K, T, H, const D: usize>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    tree: ManagedMerkleTree<K, T, H, D>,
+    last_used: Instant,
+}
+
+/// A registry of independent trees keyed by `K`, e.g. one tree per tenant, which constructs
+/// each tree on its first access. All the trees cache their nodes in the same
+/// `SharedNodeCache`, and share the resources captured by the construct function, e.g. a
+/// single `KvPairClient` for `MongoMerkle::construct_with_client`, whose clones share their
+/// connection.
+pub struct TreeManager<K, T, H, const D: usize>
+where
+    H: Debug + Clone + PartialEq + Serialize,
+    T: MerkleTree<H, D>,
+{
+    trees: Mutex<HashMap<K, ManagedEntry<K, T, H, D>>>,
+    cache: SharedNodeCache<K, H, T::Node>,
+    construct: Box<dyn Fn(&K) -> T + Send + Sync>,
+    idle_timeout: Option<Duration>,
+}
+
+impl<K, T, H, const D: usize> TreeManager<K, T, H, D>
+where
+    K: Clone + Eq + std::hash::Hash,
+    H: Debug + Clone + PartialEq + Eq + std::hash::Hash + Serialize,
+    T: MerkleTree<H, D>,
+{
+    pub fn new(construct: impl Fn(&K) -> T + Send + Sync + 'static) -> Self {
+        TreeManager {
+            trees: Mutex::new(HashMap::new()),
+            cache: SharedNodeCache::new(),
+            construct: Box::new(construct),
+            idle_timeout: None,
+        }
+    }
+
+    /// Let `evict_idle` drop the trees which were not accessed for `timeout`. Trees are kept
+    /// until then by default. An evicted tree is constructed again on its next access, so the
+    /// construct function must restore its state (e.g. its current root) from the backend.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep at most `capacity` nodes in the cache shared by the trees, see `SharedNodeCache`.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache = SharedNodeCache::with_capacity(capacity);
+        self
+    }
+
+    /// The tree with the given key, constructed if it is not live yet. The tree is constructed
+    /// without holding the lock of the registry, so that constructing a tree which reads its
+    /// backend doesn't block the accesses to the other trees. If two accesses construct the
+    /// same tree concurrently, the first one inserted is kept.
+    pub fn get(&self, key: &K) -> ManagedMerkleTree<K, T, H, D> {
+        if let Some(entry) = self.lock().get_mut(key) {
+            entry.last_used = Instant::now();
+            return entry.tree.clone();
+        }
+        let tree = SharedMerkleTree::new(SharedCachingMerkleTree::new(
+            key.clone(),
+            (self.construct)(key),
+            self.cache.clone(),
+        ));
+        let mut trees = self.lock();
+        let entry = trees.entry(key.clone()).or_insert(ManagedEntry {
+            tree,
+            last_used: Instant::now(),
+        });
+        entry.last_used = Instant::now();
+        entry.tree.clone()
+    }
+
+    /// Drop the trees idle for longer than the idle timeout along with their cached nodes, and
+    /// return their number. Handles returned by `get` before keep working, but are no longer
+    /// shared with the next accesses.
+    pub fn evict_idle(&self) -> usize {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return 0,
+        };
+        let mut trees = self.lock();
+        let idle: Vec<K> = trees
+            .iter()
+            .filter(|(_, entry)| entry.last_used.elapsed() > timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in idle.iter() {
+            trees.remove(key);
+            self.cache.remove_tree(key);
+        }
+        idle.len()
+    }
+
+    /// Number of live trees.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn cache(&self) -> &SharedNodeCache<K, H, T::Node> {
+        &self.cache
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<K, ManagedEntry<K, T, H, D>>> {
+        self.trees.lock().expect("Tree manager lock poisoned")
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::merkle::{MerkleError, MerkleNode, MerkleTree};
+    use crate::journal::{Journal, JournalEntry};
+    use crate::kvpair::Hash;
+    use crate::merkle::{
+        commit_roots, generate_partial_proof, generate_proof, get_offset, get_path,
+        get_sibling_index, lowest_common_ancestor, prove_root, update_path, FoldStep, LeafIndex,
+        MerkleBatchProof, MerkleError, MerkleErrorCode, MerkleNode, MerkleProof, MerkleTree,
+        MerkleTreeRead, NodeIndex, NodeView, SharedMerkleTree, SharedNodeCache,
+        DEFAULT_NODE_CACHE_CAPACITY,
+    };
     struct MerkleAsArray {
         data: [u64; 127], // 2^7-1 and depth = 6
     }
@@ -323,9 +3077,12 @@ mod tests {
         fn hash(&self) -> u64 {
             self.value
         }
-        fn set(&mut self, value: &[u8]) {
-            let v: [u8; 8] = value.clone().try_into().unwrap();
+        fn set(&mut self, value: &[u8]) -> Result<(), MerkleError> {
+            let v: [u8; 8] = value.try_into().map_err(|_| {
+                MerkleError::new(Hash::empty(), self.index, MerkleErrorCode::InvalidLeafData)
+            })?;
             self.value = u64::from_le_bytes(v);
+            Ok(())
         }
         fn right(&self) -> Option<u64> {
             Some(0)
@@ -335,6 +3092,12 @@ mod tests {
         }
     }
 
+    impl From<(u64, u64)> for MerkleU64Node {
+        fn from((index, value): (u64, u64)) -> Self {
+            MerkleU64Node { value, index }
+        }
+    }
+
     impl MerkleTree<u64, 6> for MerkleAsArray {
         type Id = String;
         type Root = String;
@@ -350,27 +3113,27 @@ mod tests {
         }
         fn update_root_hash(&mut self, _h: &u64) {}
 
+        fn get_default_hash(_depth: usize) -> Result<u64, MerkleError> {
+            Ok(0)
+        }
+
         fn get_node_with_hash(
             &mut self,
-            index: u64,
-            _hash: &u64,
+            index: NodeIndex,
+            hash: &u64,
         ) -> Result<Self::Node, MerkleError> {
-            self.boundary_check(index)?;
-            Ok(MerkleU64Node {
-                value: self.data[index as usize],
-                index,
-            })
+            self.read_node_with_hash(index, hash)
         }
 
         fn set_parent(
             &mut self,
-            index: u64,
+            index: NodeIndex,
             hash: &u64,
             _left: &u64,
             _right: &u64,
         ) -> Result<(), MerkleError> {
-            self.boundary_check(index)?;
-            self.data[index as usize] = *hash;
+            self.boundary_check(index.0)?;
+            self.data[index.0 as usize] = *hash;
             Ok(())
         }
         fn set_leaf(&mut self, leaf: &Self::Node) -> Result<(), MerkleError> {
@@ -380,10 +3143,162 @@ mod tests {
         }
     }
 
+    impl MerkleTreeRead<u64, 6> for MerkleAsArray {
+        fn read_node_with_hash(
+            &self,
+            index: NodeIndex,
+            _hash: &u64,
+        ) -> Result<Self::Node, MerkleError> {
+            self.boundary_check(index.0)?;
+            Ok(MerkleU64Node {
+                value: self.data[index.0 as usize],
+                index: index.0,
+            })
+        }
+    }
+
+    #[test]
+    fn test_lowest_common_ancestor() {
+        assert_eq!(lowest_common_ancestor(7, 8, 3).unwrap(), 3);
+        assert_eq!(lowest_common_ancestor(8, 7, 3).unwrap(), 3);
+        assert_eq!(lowest_common_ancestor(7, 10, 3).unwrap(), 1);
+        assert_eq!(lowest_common_ancestor(7, 14, 3).unwrap(), 0);
+        assert_eq!(lowest_common_ancestor(9, 9, 3).unwrap(), 9);
+        assert!(lowest_common_ancestor(3, 8, 3).is_err());
+        for a in 7..15 {
+            for b in 7..15 {
+                let lca = lowest_common_ancestor(a, b, 3).unwrap();
+                let common = get_path(a, 3)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|i| get_path(b, 3).unwrap().contains(i))
+                    .last()
+                    .unwrap_or(0);
+                assert_eq!(lca, common);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fold_steps() {
+        let proof = MerkleProof::<u64, 3> {
+            source: 1,
+            root: 0,
+            assist: vec![100, 20, 3],
+            index: 9,
+        };
+        let steps = proof.fold_steps(|a, b| a * 2 + b).unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                FoldStep {
+                    index: 4,
+                    is_right: false,
+                    left: 1,
+                    right: 3,
+                    hash: 5
+                },
+                FoldStep {
+                    index: 1,
+                    is_right: true,
+                    left: 20,
+                    right: 5,
+                    hash: 45
+                },
+                FoldStep {
+                    index: 0,
+                    is_right: false,
+                    left: 45,
+                    right: 100,
+                    hash: 190
+                },
+            ]
+        );
+
+        let mut short = proof;
+        short.assist.pop();
+        assert!(short.fold_steps(|a, b| a + b).is_err());
+    }
+
+    #[test]
+    fn test_sibling_index() {
+        assert_eq!(get_sibling_index(0), None);
+        assert_eq!(get_sibling_index(1), Some(2));
+        assert_eq!(get_sibling_index(2), Some(1));
+        assert_eq!(get_sibling_index(7), Some(8));
+        assert_eq!(get_sibling_index(14), Some(13));
+    }
+
+    #[test]
+    fn test_leaf_and_node_index() {
+        let index = LeafIndex(3).to_node_index(6).unwrap();
+        assert_eq!(index, NodeIndex(2_u64.pow(6) - 1 + 3));
+        assert_eq!(index.to_leaf_index(6).unwrap(), LeafIndex(3));
+        assert_eq!(LeafIndex(0).to_node_index(0).unwrap(), NodeIndex(0));
+        assert_eq!(
+            LeafIndex(63).to_node_index(6).unwrap(),
+            NodeIndex(2_u64.pow(7) - 2)
+        );
+        let err = LeafIndex(64).to_node_index(6).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafIndex));
+        assert_eq!(err.depth(), Some(6));
+        // The first leaf index and the leaf count overflow for large depths.
+        assert_eq!(
+            LeafIndex(u64::MAX >> 1).to_node_index(63).unwrap(),
+            NodeIndex(u64::MAX - 1)
+        );
+        assert!(LeafIndex(0).to_node_index(64).is_err());
+        assert!(LeafIndex(u64::MAX).to_node_index(63).is_err());
+        assert!(LeafIndex(0).to_node_index(usize::MAX).is_err());
+        // An offset mistaken for an index is an internal node.
+        assert!(NodeIndex(3).to_leaf_index(6).is_err());
+        assert!(NodeIndex(2_u64.pow(7) - 1).to_leaf_index(6).is_err());
+    }
+
+    #[test]
+    fn test_offset() {
+        assert_eq!(get_offset(0).unwrap(), 0);
+        assert_eq!(get_offset(3).unwrap(), 0);
+        assert_eq!(get_offset(6).unwrap(), 3);
+        assert_eq!(get_offset(u32::MAX as u64).unwrap(), 1 << 31);
+        assert_eq!(get_offset(u64::MAX - 1).unwrap(), (1 << 63) - 1);
+        let err = get_offset(u64::MAX).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidIndex));
+
+        // A proof with a hostile index is rejected instead of panicking.
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let proof = MerkleProof::<u64, 6> {
+            source: 0,
+            root: 0,
+            assist: vec![0; 6],
+            index: u64::MAX,
+        };
+        assert!(mt.verify_proof(proof).is_err());
+    }
+
+    #[test]
+    fn test_set_verified_node() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        mt.set_verified_node(NodeIndex(1), &5, &2, &3).unwrap();
+        assert_eq!(mt.data[1], 5);
+
+        let err = mt.set_verified_node(NodeIndex(2), &5, &2, &2).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::HashMismatch));
+        assert_eq!(mt.data[2], 0);
+
+        // Leaves are set with set_leaf, and the index must be in the tree.
+        let err = mt.set_verified_node(NodeIndex(63), &5, &2, &3).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidIndex));
+        let err = mt
+            .set_verified_node(NodeIndex(127), &5, &2, &3)
+            .unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidIndex));
+    }
+
     #[test]
     fn test_merkle_path() {
         let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
-        let (mut leaf, _) = mt.get_leaf_with_proof(2_u64.pow(6) - 1).unwrap();
+        let (mut leaf, _) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6) - 1)).unwrap();
         leaf.value = 1;
         let _proof = mt.set_leaf_with_proof(&leaf).unwrap();
 
@@ -392,7 +3307,7 @@ mod tests {
         mt.debug();
         assert_eq!(root, 1_u64);
 
-        let (mut leaf, _) = mt.get_leaf_with_proof(2_u64.pow(6) + 2).unwrap();
+        let (mut leaf, _) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6) + 2)).unwrap();
         leaf.value = 2;
         let _proof = mt.set_leaf_with_proof(&leaf).unwrap();
 
@@ -401,11 +3316,658 @@ mod tests {
         mt.debug();
         assert_eq!(root, 3_u64);
 
-        let (mut leaf, _) = mt.get_leaf_with_proof(2_u64.pow(6) + 4).unwrap();
+        let (mut leaf, _) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6) + 4)).unwrap();
         leaf.value = 3;
         let _proof = mt.set_leaf_with_proof(&leaf).unwrap();
         /* two leaves hash needs to be 3 */
         let root = mt.get_root_hash();
         assert_eq!(root, 6_u64);
     }
+
+    #[test]
+    fn test_generate_proof_without_backend() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let index = NodeIndex(2_u64.pow(6) + 2);
+        let (mut leaf, old_proof) = mt.get_leaf_with_proof(index).unwrap();
+        leaf.value = 3;
+        let parents = update_path(&old_proof, &3, MerkleAsArray::hash).unwrap();
+        mt.set_leaf_with_proof(&leaf).unwrap();
+        assert_eq!(parents.len(), 6);
+        assert_eq!(parents.last().unwrap().0, NodeIndex(0));
+        for (index, hash, left, right) in parents {
+            assert_eq!(mt.data[index.0 as usize], hash);
+            assert_eq!(left + right, hash);
+        }
+
+        // Nodes only known through a closure, e.g. mirrored by another service.
+        let data = mt.data;
+        let get_node = |NodeIndex(index): NodeIndex, _hash: &u64| {
+            let child = |i: u64| data.get(i as usize).copied();
+            Ok(NodeView {
+                hash: data[index as usize],
+                left: child(index * 2 + 1),
+                right: child(index * 2 + 2),
+            })
+        };
+        let proof = generate_proof::<u64, 6>(data[0], index, get_node).unwrap();
+        assert_eq!(proof, mt.get_leaf_with_proof(index).unwrap().1);
+        let proof = generate_proof::<u64, 6>(data[0], NodeIndex(4), get_node).unwrap();
+        assert_eq!(
+            proof,
+            mt.get_internal_node_with_proof(NodeIndex(4)).unwrap().1
+        );
+        assert!(generate_proof::<u64, 6>(data[0], NodeIndex(0), get_node).is_err());
+
+        let missing = |NodeIndex(index): NodeIndex, hash: &u64| {
+            Ok(NodeView {
+                hash: *hash + index,
+                left: None,
+                right: None,
+            })
+        };
+        let err = generate_proof::<u64, 6>(data[0], index, missing).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::MissingChild));
+    }
+
+    #[test]
+    fn test_generate_partial_proof() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let index = NodeIndex(2_u64.pow(6) + 2);
+        let (mut leaf, _) = mt.get_leaf_with_proof(index).unwrap();
+        leaf.value = 3;
+        let proof = mt.set_leaf_with_proof(&leaf).unwrap();
+        let data = mt.data;
+        let get_node = |unavailable: u64| {
+            move |NodeIndex(index): NodeIndex, _hash: &u64| {
+                if index == unavailable {
+                    return Err(MerkleError::new(
+                        Hash::empty(),
+                        index,
+                        MerkleErrorCode::InvalidOther,
+                    ));
+                }
+                let child = |i: u64| data.get(i as usize).copied();
+                Ok(NodeView {
+                    hash: data[index as usize],
+                    left: child(index * 2 + 1),
+                    right: child(index * 2 + 2),
+                })
+            }
+        };
+
+        // All nodes available: the partial proof is the full proof.
+        let partial = generate_partial_proof::<u64, 6>(data[0], index, get_node(u64::MAX)).unwrap();
+        assert!(partial.is_complete());
+        assert_eq!(partial.clone().into_proof().unwrap(), proof);
+        assert_eq!(partial, proof.clone().into());
+
+        // A missing sibling only hides its own level.
+        let path = get_path(index.0, 6).unwrap();
+        let sibling = get_sibling_index(path[2]).unwrap();
+        let partial = generate_partial_proof::<u64, 6>(data[0], index, get_node(sibling)).unwrap();
+        assert_eq!(partial.missing_levels(), vec![2]);
+        assert_eq!(partial.source, Some(3));
+        assert!(partial.into_proof().is_none());
+
+        // A missing node on the path hides all the levels below it.
+        let partial = generate_partial_proof::<u64, 6>(data[0], index, get_node(path[3])).unwrap();
+        // The sibling at its level is still read from its parent.
+        assert_eq!(partial.missing_levels(), vec![4, 5]);
+        assert_eq!(
+            partial.assist[..4],
+            proof.assist[..4]
+                .iter()
+                .map(|h| Some(*h))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(partial.source, None);
+
+        let partial = generate_partial_proof::<u64, 6>(data[0], index, get_node(0)).unwrap();
+        assert_eq!(partial.missing_levels(), (0..6).collect::<Vec<_>>());
+
+        // Index errors still fail.
+        assert!(
+            generate_partial_proof::<u64, 6>(data[0], NodeIndex(0), get_node(u64::MAX)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_set_unchanged_leaf() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let index = NodeIndex(2_u64.pow(6) - 1);
+        let (mut leaf, _) = mt.get_leaf_with_proof(index).unwrap();
+        leaf.value = 1;
+        mt.set_leaf_with_proof(&leaf).unwrap();
+        assert_eq!(mt.get_root_hash(), 1);
+
+        // Tamper with the stored root to see whether the path is written again.
+        mt.data[0] = 42;
+        let proof = mt.set_leaf_with_proof(&leaf).unwrap();
+        assert_eq!((proof.source, proof.root), (1, 42));
+        assert_eq!(mt.get_root_hash(), 42);
+
+        let proof = mt.force_set_leaf_with_proof(&leaf).unwrap();
+        assert_eq!((proof.source, proof.root), (1, 1));
+        assert_eq!(mt.get_root_hash(), 1);
+    }
+
+    #[test]
+    fn test_commit_roots() {
+        let roots: Vec<Hash> = (0..5_u8).map(|i| Hash::hash_data(&[i; 32])).collect();
+        let commitment = commit_roots(&roots);
+        for index in 0..roots.len() {
+            let proof = prove_root(&roots, index).unwrap();
+            assert_eq!(proof.commitment, commitment);
+            assert_eq!(proof.assist.len(), 3);
+            assert!(proof.verify());
+
+            let mut wrong_index = proof.clone();
+            wrong_index.index ^= 1;
+            assert!(!wrong_index.verify());
+            let mut wrong_root = proof.clone();
+            wrong_root.root = Hash::empty();
+            assert!(!wrong_root.verify());
+        }
+        assert!(prove_root(&roots, roots.len()).is_none());
+
+        // The padding is not a root: the commitment binds the number of roots.
+        let mut padded = roots.clone();
+        padded.push(Hash::empty());
+        assert_ne!(commit_roots(&padded), commitment);
+        let mut past_the_end = prove_root(&roots, 4).unwrap();
+        past_the_end.index = 5;
+        past_the_end.root = Hash::empty();
+        assert!(!past_the_end.verify());
+
+        // A single root is its own tree.
+        let proof = prove_root(&roots[..1], 0).unwrap();
+        assert!(proof.assist.is_empty());
+        assert!(proof.verify());
+        assert_ne!(commit_roots(&[]), commit_roots(&[Hash::empty()]));
+    }
+
+    #[test]
+    fn test_set_wrong_leaf_data_length() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let index = NodeIndex(2_u64.pow(6) - 1);
+        for data in [&[1_u8; 7][..], &[1; 9], &[]] {
+            let err = mt.update_leaf_data_with_proof(index, data).unwrap_err();
+            assert!(matches!(err.code(), MerkleErrorCode::InvalidLeafData));
+            assert_eq!(err.index(), index.0);
+        }
+        assert_eq!(mt.get_root_hash(), 0);
+        mt.update_leaf_data_with_proof(index, &1_u64.to_le_bytes())
+            .unwrap();
+        assert_eq!(mt.get_root_hash(), 1);
+    }
+
+    #[test]
+    fn test_verify_proofs_common_root() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let first = 2_u64.pow(6) - 1;
+        let (mut leaf, _) = mt.get_leaf_with_proof(NodeIndex(first)).unwrap();
+        leaf.value = 1;
+        mt.set_leaf_with_proof(&leaf).unwrap();
+        let mut proofs: Vec<_> = (first..first + 3)
+            .map(|index| mt.get_leaf_with_proof(NodeIndex(index)).unwrap().1)
+            .collect();
+        assert_eq!(mt.verify_proofs_common_root(&proofs).unwrap(), 1);
+
+        // A proof of the same leaf against another root.
+        leaf.value = 2;
+        let stale = mt.set_leaf_with_proof(&leaf).unwrap();
+        proofs.insert(1, stale);
+        let err = mt.verify_proofs_common_root(&proofs).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::RootMismatch));
+        assert_eq!(err.index(), first);
+
+        let mut forged = proofs[2].clone();
+        forged.source = 5;
+        let err = mt
+            .verify_proofs_common_root(&[proofs[0].clone(), forged])
+            .unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InconsistentProof));
+        assert_eq!(err.index(), first + 1);
+
+        let err = mt.verify_proofs_common_root(&[]).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidOther));
+    }
+
+    #[test]
+    fn test_batch_proof_round_trip() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let indexes = [2_u64.pow(6) - 1, 2_u64.pow(6), 2_u64.pow(6) + 5].map(NodeIndex);
+        for (i, index) in indexes.iter().enumerate() {
+            let (mut leaf, _) = mt.get_leaf_with_proof(*index).unwrap();
+            leaf.value = i as u64 + 1;
+            mt.set_leaf_with_proof(&leaf).unwrap();
+        }
+        let proofs = indexes
+            .iter()
+            .map(|index| mt.get_leaf_with_proof(*index).unwrap().1)
+            .collect::<Vec<_>>();
+        let batch = MerkleBatchProof::from_single_proofs(&proofs).unwrap();
+        assert_eq!(batch.leaves.len(), 3);
+        // The two adjacent leaves share all their assist nodes above the leaf level.
+        assert!(batch.assist.len() < proofs.iter().map(|p| p.assist.len()).sum());
+        let expanded = batch.to_single_proofs(MerkleAsArray::hash).unwrap();
+        assert_eq!(expanded, proofs);
+
+        // Proving a leaf twice is harmless for a read, the duplicate is dropped.
+        let mut duplicated = proofs.clone();
+        duplicated.push(proofs[1].clone());
+        assert_eq!(
+            MerkleBatchProof::from_single_proofs(&duplicated).unwrap(),
+            batch
+        );
+    }
+
+    #[test]
+    fn test_batch_proof_rejects_stale_proof() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let (_, stale) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6) - 1)).unwrap();
+        let (mut leaf, _) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6))).unwrap();
+        leaf.value = 1;
+        mt.set_leaf_with_proof(&leaf).unwrap();
+        // The stale proof still has the old value of its sibling leaf in its assist.
+        let (_, mut fresh) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6))).unwrap();
+        // Pretend the proofs share the same root so that only the node hashes disagree.
+        fresh.root = stale.root;
+        assert!(MerkleBatchProof::from_single_proofs(&[stale, fresh]).is_err());
+    }
+
+    #[test]
+    fn test_shared_merkle_tree() {
+        let mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let shared = SharedMerkleTree::new(mt);
+        let handles = (0..4_u64)
+            .map(|i| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    let index = NodeIndex(2_u64.pow(6) - 1 + i);
+                    let (mut leaf, _) = shared.get_leaf_with_proof::<u64, 6>(index).unwrap();
+                    leaf.value = i + 1;
+                    shared.set_leaf_with_proof::<u64, 6>(&leaf).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(shared.get_root_hash::<u64, 6>(), 10_u64);
+
+        // Proofs are read under the read lock, so they don't wait for the other readers.
+        let index = NodeIndex(2_u64.pow(6) - 1);
+        let leaf = shared.read(|_| {
+            std::thread::scope(|s| {
+                s.spawn(|| shared.get_leaf_with_proof::<u64, 6>(index).unwrap().0)
+                    .join()
+                    .unwrap()
+            })
+        });
+        assert_eq!(leaf.value, 1);
+    }
+
+    #[test]
+    fn test_shared_node_cache_lru() {
+        let cache = SharedNodeCache::<u32, u64, u64>::with_capacity(2);
+        cache.lock().insert((1, 7, 0), 10);
+        cache.lock().insert((2, 7, 0), 20);
+        // Using the first node makes the second one the least recently used.
+        assert_eq!(cache.lock().get(&(1, 7, 0)), Some(&10));
+        cache.lock().insert((1, 8, 0), 30);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lock().get(&(2, 7, 0)), None);
+        assert_eq!(cache.lock().get(&(1, 7, 0)), Some(&10));
+
+        cache.remove_tree(&1);
+        assert!(cache.is_empty());
+        assert_eq!(
+            SharedNodeCache::<u32, u64, u64>::new().capacity(),
+            DEFAULT_NODE_CACHE_CAPACITY
+        );
+    }
+
+    #[test]
+    fn test_validate_proof_structure() {
+        let proof = MerkleProof::<Hash, 2> {
+            source: Hash::get_default_hash_for_depth(32).unwrap(),
+            root: Hash::get_default_hash_for_depth(30).unwrap(),
+            assist: vec![
+                Hash::get_default_hash_for_depth(31).unwrap(),
+                Hash::get_default_hash_for_depth(32).unwrap(),
+            ],
+            index: 3,
+        };
+        assert!(proof.validate_structure().is_ok());
+
+        let mut truncated = proof.clone();
+        truncated.assist.pop();
+        assert!(truncated.validate_structure().is_err());
+
+        let mut non_leaf = proof.clone();
+        non_leaf.index = 1;
+        assert!(non_leaf.validate_structure().is_err());
+
+        let mut non_canonical = proof;
+        non_canonical.assist[0] = Hash([0xff; 32]);
+        assert!(non_canonical.validate_structure().is_err());
+    }
+
+    #[test]
+    fn test_build_from_leaves() {
+        let indexes = [2_u64.pow(6) - 1, 2_u64.pow(6) + 2, 2_u64.pow(7) - 2];
+        let mut naive = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        for (i, index) in indexes.iter().enumerate() {
+            let (mut leaf, _) = naive.get_leaf_with_proof(NodeIndex(*index)).unwrap();
+            leaf.value = i as u64 + 1;
+            naive.set_leaf_with_proof(&leaf).unwrap();
+        }
+
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let leaves = indexes.iter().enumerate().map(|(i, index)| MerkleU64Node {
+            value: i as u64 + 1,
+            index: *index,
+        });
+        let root = mt.build_from_leaves(leaves).unwrap();
+        assert_eq!(root, naive.get_root_hash());
+        assert_eq!(mt.data, naive.data);
+
+        // A leaf given twice is ambiguous, and nothing is written.
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let leaves = [indexes[0], indexes[1], indexes[0]].map(|index| MerkleU64Node {
+            value: index,
+            index,
+        });
+        let err = mt.build_from_leaves(leaves).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::DuplicateIndex));
+        assert_eq!(err.index(), indexes[0]);
+        assert_eq!(mt.data, [0; 127]);
+    }
+
+    #[test]
+    fn test_proof_depth() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let (_, proof) = mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6) - 1)).unwrap();
+        assert_eq!(proof.depth(), 6);
+        assert!(proof.is_well_formed());
+
+        let (_, proof) = mt.get_internal_node_with_proof(NodeIndex(2)).unwrap();
+        assert_eq!(proof.depth(), 1);
+        assert!(proof.is_well_formed());
+
+        let mut truncated = proof.clone();
+        truncated.assist.pop();
+        assert!(!truncated.is_well_formed());
+
+        let mut out_of_bound = proof;
+        out_of_bound.index = u64::MAX;
+        assert!(!out_of_bound.is_well_formed());
+    }
+
+    #[test]
+    fn test_partial_proof() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        for offset in [0_u64, 9, 10, 33] {
+            mt.update_leaf_data_with_proof(
+                NodeIndex(2_u64.pow(6) - 1 + offset),
+                &(offset + 1).to_le_bytes(),
+            )
+            .unwrap();
+        }
+        let index = NodeIndex(2_u64.pow(6) - 1 + 10);
+        let (_, full) = mt.get_leaf_with_proof(index).unwrap();
+        for depth in 0..=6 {
+            let proof = mt.get_partial_proof(index, depth).unwrap();
+            assert_eq!(proof.depth(), 6 - depth);
+            assert_eq!(proof.assist, full.assist[depth..]);
+            // The root of the proof is the ancestor at that depth.
+            let ancestor = (1 << depth) - 1 + (10 >> (6 - depth));
+            assert_eq!(proof.root, mt.data[ancestor]);
+            assert!(mt.verify_partial_proof(&proof, &proof.root, depth).unwrap());
+        }
+        assert_eq!(mt.get_partial_proof(index, 0).unwrap(), full);
+
+        let proof = mt.get_partial_proof(index, 2).unwrap();
+        // Not a proof against another ancestor, nor at another depth.
+        assert!(!mt
+            .verify_partial_proof(&proof, &(proof.root + 1), 2)
+            .unwrap());
+        assert!(!mt.verify_partial_proof(&proof, &proof.root, 3).unwrap());
+        let mut tampered = proof.clone();
+        tampered.source += 1;
+        assert!(!mt.verify_partial_proof(&tampered, &proof.root, 2).unwrap());
+
+        let err = mt.get_partial_proof(index, 7).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::InvalidDepth));
+    }
+
+    #[test]
+    fn test_verify_stream() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        for offset in [0_u64, 5, 40] {
+            mt.update_leaf_data_with_proof(
+                NodeIndex(2_u64.pow(6) - 1 + offset),
+                &(offset + 1).to_le_bytes(),
+            )
+            .unwrap();
+        }
+        let mut proofs: Vec<_> = [0_u64, 5, 40]
+            .iter()
+            .map(|offset| {
+                mt.get_leaf_with_proof(NodeIndex(2_u64.pow(6) - 1 + offset))
+                    .unwrap()
+                    .1
+            })
+            .collect();
+        proofs[1].source += 1;
+        proofs.push(MerkleProof {
+            index: u64::MAX,
+            ..proofs[0].clone()
+        });
+        let verified: Vec<_> = mt
+            .verify_stream(proofs.clone())
+            .map(|(proof, verified)| (proof.index, verified))
+            .collect();
+        assert_eq!(
+            verified,
+            vec![
+                (proofs[0].index, true),
+                (proofs[1].index, false),
+                (proofs[2].index, true),
+                (u64::MAX, false)
+            ]
+        );
+
+        // Proofs are only verified as they are pulled, so an endless stream can be consumed.
+        let proof = proofs[0].clone();
+        assert!(mt
+            .verify_stream(std::iter::repeat(proof))
+            .take(3)
+            .all(|(_, verified)| verified));
+    }
+
+    #[test]
+    fn test_proof_stream() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        for (i, offset) in [0_u64, 3, 17, 63].iter().enumerate() {
+            mt.update_leaf_data_with_proof(
+                NodeIndex(2_u64.pow(6) - 1 + offset),
+                &(1_u64 << i).to_le_bytes(),
+            )
+            .unwrap();
+        }
+        for index in [2_u64.pow(6) - 1, 2_u64.pow(6) + 2, 2_u64.pow(7) - 2].map(NodeIndex) {
+            let (_, proof) = mt.get_leaf_with_proof(index).unwrap();
+            let mut streamed = mt
+                .proof_stream(index)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            streamed.reverse();
+            assert_eq!(streamed, proof.assist);
+        }
+
+        let mut stream = mt.proof_stream(NodeIndex(0));
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn test_missing_children() {
+        // A tree whose stored non leaf nodes have lost their child hashes.
+        struct ChildlessNode {
+            index: u64,
+            hash: u64,
+        }
+
+        impl MerkleNode<u64> for ChildlessNode {
+            fn index(&self) -> u64 {
+                self.index
+            }
+            fn hash(&self) -> u64 {
+                self.hash
+            }
+            fn set(&mut self, _value: &[u8]) -> Result<(), MerkleError> {
+                Ok(())
+            }
+            fn right(&self) -> Option<u64> {
+                None
+            }
+            fn left(&self) -> Option<u64> {
+                None
+            }
+        }
+
+        struct ChildlessTree;
+
+        impl MerkleTree<u64, 2> for ChildlessTree {
+            type Id = ();
+            type Root = ();
+            type Node = ChildlessNode;
+            fn construct(_addr: Self::Id, _id: Self::Root) -> Self {
+                ChildlessTree
+            }
+            fn hash(a: &u64, b: &u64) -> u64 {
+                a + b
+            }
+            fn get_root_hash(&self) -> u64 {
+                1
+            }
+            fn update_root_hash(&mut self, _h: &u64) {}
+            fn get_default_hash(_depth: usize) -> Result<u64, MerkleError> {
+                Ok(0)
+            }
+            fn set_parent(
+                &mut self,
+                _index: NodeIndex,
+                _hash: &u64,
+                _left: &u64,
+                _right: &u64,
+            ) -> Result<(), MerkleError> {
+                Ok(())
+            }
+            fn set_leaf(&mut self, _leaf: &Self::Node) -> Result<(), MerkleError> {
+                Ok(())
+            }
+            fn get_node_with_hash(
+                &mut self,
+                index: NodeIndex,
+                hash: &u64,
+            ) -> Result<Self::Node, MerkleError> {
+                Ok(ChildlessNode {
+                    index: index.0,
+                    hash: *hash,
+                })
+            }
+        }
+
+        let mut mt = ChildlessTree::construct((), ());
+        let err = mt.get_leaf_with_proof(NodeIndex(3)).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::MissingChild));
+        assert_eq!(err.index, 0);
+        let err = mt.children_of(NodeIndex(1), &1).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::MissingChild));
+        assert_eq!(err.index, 1);
+        let err = mt
+            .set_leaf_with_proof(&ChildlessNode { index: 4, hash: 1 })
+            .unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::MissingChild));
+        let stream: Vec<_> = mt.proof_stream(NodeIndex(5)).collect();
+        assert_eq!(stream.len(), 1);
+        assert!(matches!(
+            stream[0].as_ref().unwrap_err().code(),
+            MerkleErrorCode::MissingChild
+        ));
+    }
+
+    #[test]
+    fn test_set_leaf_with_journal() {
+        struct CrashingJournal(Option<JournalEntry<u64>>);
+
+        impl Journal<u64> for CrashingJournal {
+            fn open(&mut self, entry: &JournalEntry<u64>) -> Result<(), MerkleError> {
+                self.0 = Some(entry.clone());
+                Err(MerkleError::new(
+                    Hash::empty(),
+                    0,
+                    MerkleErrorCode::InvalidOther,
+                ))
+            }
+            fn close(&mut self) -> Result<(), MerkleError> {
+                self.0 = None;
+                Ok(())
+            }
+            fn pending(&mut self) -> Result<Option<JournalEntry<u64>>, MerkleError> {
+                Ok(self.0.clone())
+            }
+        }
+
+        let index = 2_u64.pow(6) + 2;
+        let leaf = MerkleU64Node { value: 3, index };
+        let mut expected = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let expected_proof = expected.set_leaf_with_proof(&leaf).unwrap();
+
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let mut journal = None;
+        let proof = mt.set_leaf_with_journal(&leaf, &mut journal).unwrap();
+        assert_eq!(proof, expected_proof);
+        assert_eq!(mt.data, expected.data);
+        assert!(journal.is_none());
+
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        let mut journal = CrashingJournal(None);
+        assert!(mt.set_leaf_with_journal(&leaf, &mut journal).is_err());
+        assert_eq!(mt.get_root_hash(), 0);
+        assert!(mt.recover(&mut journal).unwrap());
+        assert_eq!(mt.data, expected.data);
+        assert!(!mt.recover(&mut journal).unwrap());
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
+        mt.update_leaf_data_with_proof(NodeIndex(2_u64.pow(6) - 1), &1_u64.to_le_bytes())
+            .unwrap();
+        let mut replica = MerkleAsArray { data: mt.data };
+        mt.update_leaf_data_with_proof(NodeIndex(2_u64.pow(6) + 2), &2_u64.to_le_bytes())
+            .unwrap();
+        let changes: Vec<(u64, u64)> = (0..127)
+            .filter(|i| mt.data[*i] != replica.data[*i])
+            .map(|i| (i as u64, mt.data[i]))
+            .collect();
+
+        let mut bad_root = changes.clone();
+        bad_root[0].1 += 1;
+        assert!(replica.apply_changes(&bad_root).is_err());
+        let missing_parent = changes[1..].to_vec();
+        assert!(replica.apply_changes(&missing_parent).is_err());
+        let mut duplicated = changes.clone();
+        duplicated.push(changes[0]);
+        let err = replica.apply_changes(&duplicated).unwrap_err();
+        assert!(matches!(err.code(), MerkleErrorCode::DuplicateIndex));
+
+        let root = replica.apply_changes(&changes).unwrap();
+        assert_eq!(root, mt.get_root_hash());
+        assert_eq!(replica.data, mt.data);
+    }
 }
@@ -1,8 +1,9 @@
-use ff::PrimeField;
+use ff::{Field, PrimeField};
 use halo2_proofs::pairing::bn256::Fr;
 use poseidon::Poseidon;
 
 use crate::errors::Error;
+use crate::kvpair::Hash;
 
 pub const PREFIX_CHALLENGE: u64 = 0u64;
 pub const PREFIX_POINT: u64 = 1u64;
@@ -59,13 +60,33 @@ pub fn gen_merkle_leaf_hasher() -> Poseidon<Fr, 3, 2> {
     Poseidon::<Fr, 3, 2>::new(8, 57)
 }
 
-pub fn hash_field_elements(frs: &[Fr]) -> <Fr as PrimeField>::Repr {
-    dbg!(frs);
+/// Hash field elements with the POSEIDON_HASHER, without any conversion from bytes.
+pub fn hash_frs(frs: &[Fr]) -> Fr {
     let mut hasher = gen_poseidon_hasher();
     hasher.update(frs);
-    let hash = hasher.squeeze().to_repr();
-    dbg!(&hash);
-    hash
+    hasher.squeeze()
+}
+
+lazy_static::lazy_static! {
+    // Generating the round constants costs more than hashing a pair, so the pair hashes clone
+    // a fresh hasher instead.
+    static ref MERKLE_HASHER: Poseidon<Fr, 3, 2> = gen_merkle_hasher();
+}
+
+/// Hash two children in the merkle tree with the MERKLE_HASHER.
+pub fn hash_pair_fr(a: &Fr, b: &Fr) -> Fr {
+    let mut hasher = MERKLE_HASHER.clone();
+    hasher.update_exact(&[*a, *b])
+}
+
+/// Hash two children in the merkle tree, as `hash_pair_fr` of their field elements.
+/// This is the hash of the parents in the tree, see `Hash::hash_children`.
+pub fn merkle_hash_pair(a: &Hash, b: &Hash) -> Hash {
+    hash_pair_fr(&Fr::from(*a), &Fr::from(*b)).into()
+}
+
+pub fn hash_field_elements(frs: &[Fr]) -> <Fr as PrimeField>::Repr {
+    hash_frs(frs).to_repr()
 }
 
 /// Hash data from an array of 32 bytes. Since we will split each 32 bytes to
@@ -90,40 +111,83 @@ pub fn hash_with_padding(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr
             Fr::from_repr(f).unwrap()
         })
         .collect::<Vec<Fr>>();
-    dbg!(&frs);
     Ok(hash_field_elements(&frs))
 }
 
 /// Hash data from an array of 32 bytes. Each 32 bytes must be a valid field element.
 pub fn hash(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr, Error> {
-    dbg!(data_to_hash);
     let num_of_bytes: usize = 32;
     if data_to_hash.len() % num_of_bytes != 0 {
         return Err(Error::InvalidArgument(
             "Invalid data to hash, must be an array of field elements".to_string(),
         ));
     }
-    let frs = data_to_hash
+    hash_iter(data_to_hash.chunks(num_of_bytes).map(|x| {
+        let v = x.try_into().unwrap();
+        let f = Fr::from_repr(v);
+        if f.is_none().into() {
+            return Err(Error::InvalidArgument(
+                "Invalid data to hash, must be an array of field elements".to_string(),
+            ));
+        }
+        Ok(f.unwrap())
+    }))
+}
+
+/// Hash field elements with the POSEIDON_HASHER as they are produced by `frs`, without collecting
+/// them. The elements are absorbed a rate (8 elements) at a time, which gives the same result as
+/// absorbing them all at once. The first error of `frs` is returned.
+pub fn hash_iter(
+    frs: impl Iterator<Item = Result<Fr, Error>>,
+) -> Result<<Fr as PrimeField>::Repr, Error> {
+    const RATE: usize = 8;
+    let mut hasher = gen_poseidon_hasher();
+    let mut buffer = [Fr::zero(); RATE];
+    let mut len = 0;
+    for fr in frs {
+        buffer[len] = fr?;
+        len += 1;
+        if len == RATE {
+            hasher.update(&buffer);
+            len = 0;
+        }
+    }
+    hasher.update(&buffer[..len]);
+    Ok(hasher.squeeze().to_repr())
+}
+
+/// Hash a 64 bytes leaf value, i.e. two field elements, in the same way as zkWasm's
+/// MERKLE_LEAF_HASHER. Each 32 bytes must be a valid field element, and the two field
+/// elements are absorbed in order with `update_exact`.
+pub fn hash_leaf_value(data: &[u8]) -> Result<Hash, Error> {
+    let num_of_bytes: usize = 32;
+    if data.len() != 2 * num_of_bytes {
+        return Err(Error::InvalidArgument(
+            "Invalid leaf value to hash, must be two field elements".to_string(),
+        ));
+    }
+    let frs = data
         .chunks(num_of_bytes)
         .map(|x| {
-            let v = x.try_into().unwrap();
-            let f = Fr::from_repr(v);
+            let f = Fr::from_repr(x.try_into().unwrap());
             if f.is_none().into() {
                 return Err(Error::InvalidArgument(
-                    "Invalid data to hash, must be an array of field elements".to_string(),
+                    "Invalid leaf value to hash, must be two field elements".to_string(),
                 ));
             }
             Ok(f.unwrap())
         })
         .collect::<Result<Vec<Fr>, _>>()?;
-    Ok(hash_field_elements(&frs))
+    let mut hasher = gen_merkle_leaf_hasher();
+    Ok(hasher.update_exact(&[frs[0], frs[1]]).into())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ff::PrimeField;
+    use ff::{Field, PrimeField};
     use halo2_proofs::pairing::bn256::Fr;
+    use rand::thread_rng;
 
     #[test]
     fn test_merkle_leaf_hash_zero() {
@@ -166,4 +230,76 @@ mod tests {
         let result2 = hash_with_padding(&[0; 32]).expect("Hash succeeded");
         assert_eq!(result, result2);
     }
+
+    #[test]
+    fn test_hash_frs_equivalent() {
+        let frs = (0..10)
+            .map(|_| Fr::random(thread_rng()))
+            .collect::<Vec<_>>();
+        let mut hasher = super::gen_poseidon_hasher();
+        hasher.update(&frs);
+        let result = hasher.squeeze();
+        assert_eq!(hash_frs(&frs), result);
+
+        let bytes = frs.iter().flat_map(|f| f.to_repr()).collect::<Vec<_>>();
+        assert_eq!(hash(&bytes).expect("Hash succeeded"), result.to_repr());
+    }
+
+    #[test]
+    fn test_hash_iter_equivalent() {
+        // Cover empty input and lengths around multiples of the rate.
+        for n in [0, 1, 7, 8, 9, 16, 17] {
+            let frs = (0..n).map(|_| Fr::random(thread_rng())).collect::<Vec<_>>();
+            let result = hash_iter(frs.iter().cloned().map(Ok)).expect("Hash succeeded");
+            assert_eq!(result, hash_field_elements(&frs));
+        }
+
+        let error = hash_iter(
+            [
+                Ok(Fr::zero()),
+                Err(Error::InvalidArgument("bad".to_string())),
+            ]
+            .into_iter(),
+        );
+        assert!(matches!(error, Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_hash_pair_fr_equivalent() {
+        let (a, b) = (Fr::random(thread_rng()), Fr::random(thread_rng()));
+        let mut hasher = super::gen_merkle_hasher();
+        let result = hasher.update_exact(&[a, b]);
+        assert_eq!(hash_pair_fr(&a, &b), result);
+        assert_eq!(merkle_hash_pair(&a.into(), &b.into()), Hash::from(result));
+        assert_eq!(
+            Hash::hash_children(&a.into(), &b.into()),
+            Hash::from(result)
+        );
+        // The reused hasher is not affected by the previous hashes.
+        assert_eq!(hash_pair_fr(&a, &b), result);
+    }
+
+    #[test]
+    fn test_hash_leaf_value_empty_leaf() {
+        // The default leaf hash is pinned by the upstream root in kvpair tests.
+        let result = hash_leaf_value(&[0; 64]).expect("Hash succeeded");
+        assert_eq!(result, crate::kvpair::DEFAULT_HASH_VEC[0]);
+    }
+
+    #[test]
+    fn test_hash_leaf_value_equivalent() {
+        // Hash::hash_data splits 32 bytes into two 16 bytes halves padded with zeros.
+        let value = [0x42_u8; 32];
+        let mut data = [0_u8; 64];
+        data[..16].copy_from_slice(&value[..16]);
+        data[32..48].copy_from_slice(&value[16..]);
+        let result = hash_leaf_value(&data).expect("Hash succeeded");
+        assert_eq!(result, Hash::hash_data(&value));
+    }
+
+    #[test]
+    fn test_hash_leaf_value_invalid() {
+        assert!(hash_leaf_value(&[0; 32]).is_err());
+        assert!(hash_leaf_value(&[0xff; 64]).is_err());
+    }
 }
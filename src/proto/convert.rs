@@ -0,0 +1,569 @@
+//! Conversions between the protobuf messages and the internal types.
+//! Bytes coming from the wire are only validated here (hash length and canonical field
+//! elements, node indices and proof structure), so that handlers never need to slice bytes
+//! or unwrap on malformed input.
+
+use super::node::NodeData;
+use super::{
+    DescribeIndexResponse, FoldStep, IndexErrorDetails, Node, NodeChildren, NodeType, Proof,
+    ProofType, RangeProof,
+};
+use crate::kvpair::{DataHashRecord, Hash, MerkleRecord, MERKLE_TREE_HEIGHT};
+use crate::merkle::{self, get_node_type, MerkleNode, MerkleProof, MerkleRangeProof};
+use crate::Error;
+use prost::Message;
+
+const FIRST_LEAF_INDEX: u64 = (1 << MERKLE_TREE_HEIGHT) - 1;
+const LAST_LEAF_INDEX: u64 = (1 << (MERKLE_TREE_HEIGHT + 1)) - 2;
+
+/// Check that the index is a node of the tree.
+pub fn node_index(index: u64) -> Result<u64, Error> {
+    match get_node_type(index, MERKLE_TREE_HEIGHT) {
+        NodeType::NodeInvalid => Err(Error::InvalidIndex {
+            message: format!(
+                "Index {index} out of the bound of a tree with depth {MERKLE_TREE_HEIGHT}, \
+                 nodes are [0, {LAST_LEAF_INDEX}]"
+            ),
+            details: index_error_details(index, MERKLE_TREE_HEIGHT, NodeType::NodeUnspecified),
+        }),
+        _ => Ok(index),
+    }
+}
+
+/// Check that the index is a leaf of the tree.
+pub fn leaf_index(index: u64) -> Result<u64, Error> {
+    match get_node_type(index, MERKLE_TREE_HEIGHT) {
+        NodeType::NodeLeaf => Ok(index),
+        _ => Err(Error::InvalidIndex {
+            message: format!(
+                "Index {index} is not a leaf of a tree with depth {MERKLE_TREE_HEIGHT}, \
+                 leaves are [{FIRST_LEAF_INDEX}, {LAST_LEAF_INDEX}]"
+            ),
+            details: index_error_details(index, MERKLE_TREE_HEIGHT, NodeType::NodeLeaf),
+        }),
+    }
+}
+
+/// Check that the index is a non leaf node of the tree.
+pub fn non_leaf_index(index: u64) -> Result<u64, Error> {
+    match get_node_type(index, MERKLE_TREE_HEIGHT) {
+        NodeType::NodeNonLeaf => Ok(index),
+        _ => Err(Error::InvalidIndex {
+            message: format!(
+                "Index {index} is not a non leaf node of a tree with depth {MERKLE_TREE_HEIGHT}, \
+                 non leaf nodes are [0, {}]",
+                FIRST_LEAF_INDEX - 1
+            ),
+            details: index_error_details(index, MERKLE_TREE_HEIGHT, NodeType::NodeNonLeaf),
+        }),
+    }
+}
+
+/// Describe an index of the wrong node type (`NodeUnspecified` for any node) in a tree of the
+/// given depth, with the valid leaf range and the leaf closest to the index.
+pub fn index_error_details(index: u64, depth: usize, expected: NodeType) -> IndexErrorDetails {
+    let first_leaf_index = (1 << depth) - 1;
+    let last_leaf_index = 2 * first_leaf_index;
+    IndexErrorDetails {
+        index,
+        depth: depth as u32,
+        first_leaf_index,
+        last_leaf_index,
+        node_type: get_node_type(index, depth).into(),
+        expected_node_type: expected.into(),
+        nearest_leaf_index: index.clamp(first_leaf_index, last_leaf_index),
+    }
+}
+
+/// The index error details attached to a status by the server, if any.
+pub fn index_error_details_of(status: &tonic::Status) -> Option<IndexErrorDetails> {
+    if status.details().is_empty() {
+        return None;
+    }
+    IndexErrorDetails::decode(status.details()).ok()
+}
+
+/// Check that the proof type is known, as prost keeps unknown enum values as raw integers.
+pub fn proof_type(value: i32) -> Result<ProofType, Error> {
+    ProofType::from_i32(value)
+        .ok_or_else(|| Error::InvalidArgument(format!("Unknown proof type {value}")))
+}
+
+/// Serialize the proof if the requested proof type has a payload.
+pub fn proof_of_type(
+    value: i32,
+    proof: &MerkleProof<Hash, MERKLE_TREE_HEIGHT>,
+) -> Result<Option<Proof>, Error> {
+    match proof_type(value)? {
+        ProofType::ProofV0 => Ok(Some(proof.try_into()?)),
+        ProofType::ProofUnspecified | ProofType::ProofEmpty => Ok(None),
+    }
+}
+
+/// The type, depth and offset within its level of the node with the given index.
+pub fn describe_index(index: u64) -> DescribeIndexResponse {
+    let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
+    let (depth, offset) = match node_type {
+        NodeType::NodeInvalid => (None, None),
+        _ => (Some((index + 1).ilog2()), merkle::get_offset(index).ok()),
+    };
+    DescribeIndexResponse {
+        index,
+        node_type: node_type.into(),
+        depth,
+        offset,
+        stored_depth: None,
+    }
+}
+
+impl TryFrom<&MerkleProof<Hash, MERKLE_TREE_HEIGHT>> for Proof {
+    type Error = Error;
+
+    fn try_from(proof: &MerkleProof<Hash, MERKLE_TREE_HEIGHT>) -> Result<Self, Self::Error> {
+        let bytes = bincode::serialize(proof)
+            .map_err(|e| Error::InconsistentData(format!("Failed to serialize proof: {e}")))?;
+        Ok(Proof {
+            proof_type: ProofType::ProofV0.into(),
+            proof: bytes,
+        })
+    }
+}
+
+impl TryFrom<Proof> for MerkleProof<Hash, MERKLE_TREE_HEIGHT> {
+    type Error = Error;
+
+    fn try_from(proof: Proof) -> Result<Self, Self::Error> {
+        if proof.proof_type != ProofType::ProofV0 as i32 {
+            return Err(Error::InvalidArgument(format!(
+                "Unsupported proof type {}",
+                proof.proof_type
+            )));
+        }
+        let proof: Self = bincode::deserialize(&proof.proof)
+            .map_err(|e| Error::InvalidArgument(format!("Proof malformed: {e}")))?;
+        proof
+            .validate_structure()
+            .map_err(|e| Error::InvalidArgument(format!("Proof malformed: {e:?}")))?;
+        Ok(proof)
+    }
+}
+
+impl From<merkle::FoldStep<Hash>> for FoldStep {
+    fn from(step: merkle::FoldStep<Hash>) -> Self {
+        FoldStep {
+            index: step.index,
+            is_right: step.is_right,
+            left: step.left.into(),
+            right: step.right.into(),
+            hash: step.hash.into(),
+        }
+    }
+}
+
+impl From<MerkleRangeProof<Hash, MERKLE_TREE_HEIGHT>> for RangeProof {
+    fn from(proof: MerkleRangeProof<Hash, MERKLE_TREE_HEIGHT>) -> Self {
+        RangeProof {
+            root: proof.root.into(),
+            start: proof.start,
+            end: proof.end,
+            left: proof.left.into_iter().map(Into::into).collect(),
+            right: proof.right.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TryFrom<RangeProof> for MerkleRangeProof<Hash, MERKLE_TREE_HEIGHT> {
+    type Error = Error;
+
+    fn try_from(proof: RangeProof) -> Result<Self, Self::Error> {
+        let hashes = |hashes: Vec<Vec<u8>>| {
+            hashes
+                .iter()
+                .map(|hash| Hash::try_from(hash.as_slice()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(MerkleRangeProof {
+            root: proof.root.as_slice().try_into()?,
+            start: leaf_index(proof.start)?,
+            end: leaf_index(proof.end.saturating_sub(1))? + 1,
+            left: hashes(proof.left)?,
+            right: hashes(proof.right)?,
+        })
+    }
+}
+
+impl TryFrom<Node> for MerkleRecord {
+    type Error = Error;
+
+    fn try_from(n: Node) -> Result<Self, Self::Error> {
+        let hash = Hash::try_from(n.hash)?;
+        let record = match (NodeType::from_i32(n.node_type), n.node_data) {
+            // Leaves read with `skip_data` come without data.
+            (Some(NodeType::NodeLeaf), Some(NodeData::Data(_)) | None) => {
+                MerkleRecord::new_leaf(leaf_index(n.index)?, hash)
+            }
+            (Some(NodeType::NodeNonLeaf), Some(NodeData::Children(children))) => {
+                let left = Hash::try_from(children.left_child_hash)?;
+                let right = Hash::try_from(children.right_child_hash)?;
+                let record = MerkleRecord::new_non_leaf(non_leaf_index(n.index)?, left, right);
+                if record.hash != hash {
+                    return Err(Error::InvalidArgument(
+                        "Node hash mismatched with its children".to_string(),
+                    ));
+                }
+                record
+            }
+            (Some(NodeType::NodeLeaf), _) | (Some(NodeType::NodeNonLeaf), _) => {
+                return Err(Error::InvalidArgument(
+                    "Node data mismatched with node type".to_string(),
+                ))
+            }
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "Invalid node type {}",
+                    n.node_type
+                )))
+            }
+        };
+        Ok(record)
+    }
+}
+
+impl TryFrom<(MerkleRecord, DataHashRecord)> for Node {
+    type Error = Error;
+
+    fn try_from(record: (MerkleRecord, DataHashRecord)) -> Result<Self, Self::Error> {
+        let merkle_record = record.0;
+        let datahash_record = record.1;
+        let node = Self::try_from(merkle_record);
+        if node.is_ok() {
+            return node;
+        }
+
+        if merkle_record.hash != datahash_record.hash {
+            return Err(Error::InvalidArgument("Hash mismatched".to_string()));
+        }
+
+        let node_type = get_node_type(merkle_record.index(), MERKLE_TREE_HEIGHT);
+        if node_type != NodeType::NodeLeaf {
+            return Err(Error::InvalidArgument("Unknown node type".to_string()));
+        }
+        let node_data = { NodeData::Data(datahash_record.data) };
+        Ok(Node {
+            index: merkle_record.index(),
+            hash: merkle_record.hash().into(),
+            node_type: node_type.into(),
+            node_data: Some(node_data),
+        })
+    }
+}
+
+impl TryFrom<MerkleRecord> for Node {
+    type Error = Error;
+
+    fn try_from(merkle_record: MerkleRecord) -> Result<Self, Self::Error> {
+        let index = merkle_record.index();
+        let hash = merkle_record.hash().into();
+        let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
+        if node_type != NodeType::NodeNonLeaf {
+            return Err(Error::InconsistentData("Unknown node type".to_string()));
+        }
+        let node_data = {
+            let left_child_hash = merkle_record
+                .left()
+                .ok_or(Error::InconsistentData(
+                    "Nonleaf node has no children".to_string(),
+                ))?
+                .into();
+            let right_child_hash = merkle_record
+                .right()
+                .ok_or(Error::InconsistentData(
+                    "Nonleaf node has no children".to_string(),
+                ))?
+                .into();
+            NodeData::Children(NodeChildren {
+                left_child_hash,
+                right_child_hash,
+            })
+        };
+        Ok(Node {
+            index,
+            hash,
+            node_type: node_type.into(),
+            node_data: Some(node_data),
+        })
+    }
+}
+
+impl Node {
+    /// A leaf without its data, as returned by `GetLeaf` with `skip_data`.
+    pub fn new_leaf_without_data(index: u64, hash: Hash) -> Self {
+        Node {
+            index,
+            hash: hash.into(),
+            node_type: NodeType::NodeLeaf.into(),
+            node_data: None,
+        }
+    }
+
+    /// This corresponds to data in simple_get/simple_set of zkWasm-rust.
+    /// Here we create a Node that has empty vector as its data, although
+    /// technically an empty vector does not hash to the hash in the merkle record.
+    pub fn new_simple_leaf(index: u64, hash: Hash) -> Self {
+        Node {
+            index,
+            hash: hash.into(),
+            node_type: NodeType::NodeLeaf.into(),
+            node_data: Some(NodeData::Data(vec![])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::proof_len;
+    use ff::Field;
+    use halo2_proofs::pairing::bn256::Fr;
+    use rand::{thread_rng, Rng};
+
+    const NUM_OF_SAMPLES: usize = 100;
+
+    fn random_hash() -> Hash {
+        Fr::random(thread_rng()).into()
+    }
+
+    fn random_index(depth: u32) -> u64 {
+        thread_rng().gen_range((1_u64 << depth) - 1..(1_u64 << (depth + 1)) - 1)
+    }
+
+    #[test]
+    fn test_index_validation() {
+        assert_eq!(leaf_index(FIRST_LEAF_INDEX).unwrap(), FIRST_LEAF_INDEX);
+        assert_eq!(leaf_index(LAST_LEAF_INDEX).unwrap(), LAST_LEAF_INDEX);
+        assert_eq!(non_leaf_index(0).unwrap(), 0);
+        assert_eq!(node_index(LAST_LEAF_INDEX).unwrap(), LAST_LEAF_INDEX);
+        for index in [LAST_LEAF_INDEX + 1, u32::MAX as u64 * 2, u64::MAX] {
+            match leaf_index(index) {
+                Err(Error::InvalidIndex { message, details }) => {
+                    assert!(message.contains(&format!("[{FIRST_LEAF_INDEX}, {LAST_LEAF_INDEX}]")));
+                    assert_eq!(details.node_type, NodeType::NodeInvalid as i32);
+                    assert_eq!(details.nearest_leaf_index, LAST_LEAF_INDEX);
+                }
+                result => panic!("Unexpected {:?}", result),
+            }
+            assert!(node_index(index).is_err());
+        }
+        match non_leaf_index(FIRST_LEAF_INDEX) {
+            Err(Error::InvalidIndex { message, .. }) => {
+                assert!(message.contains(&format!("[0, {}]", FIRST_LEAF_INDEX - 1)))
+            }
+            result => panic!("Unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_index_error_details() {
+        // An offset passed as an index, i.e. an internal node.
+        let status = tonic::Status::from(leaf_index(3).unwrap_err());
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let details = index_error_details_of(&status).unwrap();
+        assert_eq!(
+            details,
+            IndexErrorDetails {
+                index: 3,
+                depth: MERKLE_TREE_HEIGHT as u32,
+                first_leaf_index: FIRST_LEAF_INDEX,
+                last_leaf_index: LAST_LEAF_INDEX,
+                node_type: NodeType::NodeNonLeaf.into(),
+                expected_node_type: NodeType::NodeLeaf.into(),
+                nearest_leaf_index: FIRST_LEAF_INDEX,
+            }
+        );
+
+        // The errors of the tree checks carry the depth of the tree.
+        let error = merkle::leaf_check(0, 10).unwrap_err();
+        let details = index_error_details_of(&Error::from(error).into()).unwrap();
+        assert_eq!(details.depth, 10);
+        assert_eq!(details.first_leaf_index, (1 << 10) - 1);
+        assert_eq!(details.last_leaf_index, (1 << 11) - 2);
+
+        assert!(index_error_details_of(&tonic::Status::internal("")).is_none());
+    }
+
+    #[test]
+    fn test_hash_round_trip() {
+        for _ in 0..NUM_OF_SAMPLES {
+            let hash = random_hash();
+            let bytes: Vec<u8> = hash.into();
+            assert_eq!(Hash::try_from(bytes).unwrap(), hash);
+        }
+        assert!(matches!(
+            Hash::try_from(vec![0; 31]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            Hash::try_from(vec![0xff; 32]),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_non_leaf_node_round_trip() {
+        for _ in 0..NUM_OF_SAMPLES {
+            let depth = thread_rng().gen_range(0..MERKLE_TREE_HEIGHT as u32);
+            let record =
+                MerkleRecord::new_non_leaf(random_index(depth), random_hash(), random_hash());
+            let node = Node::try_from(record).unwrap();
+            assert_eq!(MerkleRecord::try_from(node).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_leaf_node_round_trip() {
+        for _ in 0..NUM_OF_SAMPLES {
+            let data = vec![1_u8; 32];
+            let hash: Hash = crate::poseidon::hash(&data).unwrap().try_into().unwrap();
+            let record = MerkleRecord::new_leaf(random_index(MERKLE_TREE_HEIGHT as u32), hash);
+            let node = Node::try_from((record, DataHashRecord::new(hash, data))).unwrap();
+            assert_eq!(MerkleRecord::try_from(node).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_malformed_node() {
+        let record = MerkleRecord::new_non_leaf(0, random_hash(), random_hash());
+        let node = Node::try_from(record).unwrap();
+
+        let mut wrong_hash = node.clone();
+        wrong_hash.hash = random_hash().into();
+        let mut short_hash = node.clone();
+        short_hash.hash.pop();
+        let mut no_data = node.clone();
+        no_data.node_data = None;
+        let mut wrong_type = node.clone();
+        wrong_type.node_type = NodeType::NodeLeaf.into();
+        let mut unknown_type = node.clone();
+        unknown_type.node_type = 42;
+        let mut leaf = node.clone();
+        leaf.index = random_index(MERKLE_TREE_HEIGHT as u32);
+        let mut out_of_bound = node;
+        out_of_bound.index = u64::MAX;
+
+        for node in [
+            wrong_hash,
+            short_hash,
+            no_data,
+            wrong_type,
+            unknown_type,
+            leaf,
+            out_of_bound,
+        ] {
+            assert!(matches!(
+                MerkleRecord::try_from(node),
+                Err(Error::InvalidArgument(_) | Error::InvalidIndex { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unknown_enum_values() {
+        let invalid_argument = |result: Result<_, Error>| match result {
+            Err(Error::InvalidArgument(message)) => message,
+            _ => panic!("Unknown enum value accepted"),
+        };
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: random_hash(),
+            root: random_hash(),
+            assist: (0..MERKLE_TREE_HEIGHT).map(|_| random_hash()).collect(),
+            index: random_index(MERKLE_TREE_HEIGHT as u32),
+        };
+        let node =
+            Node::try_from(MerkleRecord::new_non_leaf(0, random_hash(), random_hash())).unwrap();
+        for value in [-1, 3, 42, i32::MAX] {
+            let message = invalid_argument(proof_of_type(value, &proof).map(|_| ()));
+            assert!(message.contains(&value.to_string()), "{message}");
+
+            let mut message = Proof::try_from(&proof).unwrap();
+            message.proof_type = value;
+            let message = invalid_argument(MerkleProof::try_from(message).map(|_| ()));
+            assert!(message.contains(&value.to_string()), "{message}");
+
+            let mut unknown_type = node.clone();
+            unknown_type.node_type = value;
+            let message = invalid_argument(MerkleRecord::try_from(unknown_type).map(|_| ()));
+            assert!(message.contains(&value.to_string()), "{message}");
+        }
+    }
+
+    #[test]
+    fn test_describe_index() {
+        let root = describe_index(0);
+        assert_eq!(root.node_type, NodeType::NodeNonLeaf as i32);
+        assert_eq!((root.depth, root.offset), (Some(0), Some(0)));
+        let leaf = describe_index((1 << MERKLE_TREE_HEIGHT) + 2);
+        assert_eq!(leaf.node_type, NodeType::NodeLeaf as i32);
+        assert_eq!(
+            (leaf.depth, leaf.offset),
+            (Some(MERKLE_TREE_HEIGHT as u32), Some(3))
+        );
+        for index in [LAST_LEAF_INDEX + 1, u64::MAX] {
+            let invalid = describe_index(index);
+            assert_eq!(invalid.node_type, NodeType::NodeInvalid as i32);
+            assert_eq!((invalid.depth, invalid.offset), (None, None));
+        }
+    }
+
+    #[test]
+    fn test_proof_round_trip() {
+        for _ in 0..NUM_OF_SAMPLES {
+            let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+                source: random_hash(),
+                root: random_hash(),
+                assist: (0..MERKLE_TREE_HEIGHT).map(|_| random_hash()).collect(),
+                index: random_index(MERKLE_TREE_HEIGHT as u32),
+            };
+            let message = proof_of_type(ProofType::ProofV0.into(), &proof)
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.proof.len(), proof.serialized_len());
+            assert_eq!(message.proof.len(), proof_len(MERKLE_TREE_HEIGHT));
+            assert_eq!(MerkleProof::try_from(message).unwrap(), proof);
+            assert!(proof_of_type(ProofType::ProofEmpty.into(), &proof)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_malformed_proof() {
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: random_hash(),
+            root: random_hash(),
+            assist: (0..MERKLE_TREE_HEIGHT).map(|_| random_hash()).collect(),
+            index: random_index(MERKLE_TREE_HEIGHT as u32),
+        };
+        let message = Proof::try_from(&proof).unwrap();
+
+        let mut truncated = message.clone();
+        truncated.proof.truncate(truncated.proof.len() / 2);
+        let mut wrong_type = message;
+        wrong_type.proof_type = ProofType::ProofEmpty.into();
+        let mut short_assist = proof.clone();
+        short_assist.assist.pop();
+        let mut non_leaf = proof;
+        non_leaf.index = 0;
+
+        for message in [
+            truncated,
+            wrong_type,
+            Proof::try_from(&short_assist).unwrap(),
+            Proof::try_from(&non_leaf).unwrap(),
+        ] {
+            assert!(matches!(
+                MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(message),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+}
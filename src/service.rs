@@ -1,45 +1,578 @@
 use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
-use crate::kvpair::{u256_to_bson, MERKLE_TREE_HEIGHT};
-use crate::merkle::{get_offset, get_path, get_sibling_index, leaf_check, MerkleNode, MerkleProof};
+use crate::coalesce::{coalesce, CoalesceWindow, Coalesced, PendingUpdate};
+use crate::hotspots::{Hotspots, HOTSPOT_MAX_WINDOW, HOTSPOT_TOP_K};
+use crate::kvpair::{u256_to_bson, DEFAULT_HASH_VEC, MERKLE_TREE_HEIGHT};
+use crate::merkle::{
+    check_cancelled, get_offset, get_path, get_sibling_index, leaf_check, root_index_error,
+    MerkleError, MerkleErrorCode, MerkleNode, MerkleProof, MerkleRangeProof,
+};
 use crate::Error;
 
 use super::kvpair::{hash_to_bson, u64_to_bson, ContractId, DataHashRecord, Hash, MerkleRecord};
-use mongodb::bson::{doc, to_bson, Document};
-use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use futures::Stream;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{doc, Binary, Bson, Document};
+use mongodb::error::{ErrorKind, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
 use mongodb::options::{
-    Acknowledgment, CreateIndexOptions, FindOneOptions, InsertOneOptions, ReadConcern,
-    ReplaceOptions, TransactionOptions, UpdateModifications, UpdateOptions, WriteConcern,
+    Acknowledgment, ClientOptions, CreateIndexOptions, FindOneOptions, InsertOneOptions,
+    ReadConcern, ReadPreference, ReplaceOptions, SelectionCriteria, TransactionOptions,
+    UpdateModifications, UpdateOptions, WriteConcern,
 };
 use mongodb::results::{InsertOneResult, UpdateResult};
 use mongodb::{Client, ClientSession, Collection, IndexModel};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::{oneshot, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::Bytes;
+use tonic::metadata::MetadataValue;
 use tonic::{Request, Response, Status};
+use tonic_reflection::server::{ServerReflection, ServerReflectionServer};
 
-use super::proto::kv_pair_server::KvPair;
-use super::proto::Proof;
+use super::proto::convert::{
+    describe_index, leaf_index, non_leaf_index, proof_of_type, proof_type,
+};
+use super::proto::kv_pair_server::{KvPair, KvPairServer};
 use super::proto::ProofType;
 use super::proto::*;
 
+/// Data of at least this many bytes (128 field elements) is hashed on the blocking thread pool,
+/// see `MongoKvPair::with_blocking_hash_threshold`.
+pub const DEFAULT_BLOCKING_HASH_THRESHOLD: usize = 4096;
+
+/// Maximum size of the metadata attached to a leaf, which is meant for small bookkeeping data
+/// such as an owner or a timestamp.
+pub const MAX_LEAF_METADATA_LEN: usize = 1024;
+
+/// How long a read session pins its root, see `MongoKvPair::with_read_session_ttl`.
+pub const DEFAULT_READ_SESSION_TTL: Duration = Duration::from_secs(60);
+
+/// The window and the number of leaves returned by `GetHotspots` when not given.
+pub const DEFAULT_HOTSPOT_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_HOTSPOT_LIMIT: usize = 10;
+
+/// How long the freeze of a contract read by a server is reused, so a freeze or an unfreeze
+/// takes up to this long to apply on the other servers.
+pub const FREEZE_CACHE_TTL: Duration = Duration::from_secs(1);
+/// The default number of contracts whose freeze is cached, see
+/// `MerkleServiceBuilder::cache_capacity`.
+pub const DEFAULT_FREEZE_CACHE_CAPACITY: usize = 4096;
+
+/// The number of changed leaves per message of `DiffRoots`.
+pub const DIFF_ROOTS_BATCH: usize = 256;
+
+/// The stream of the responses of `DiffRoots`.
+pub type DiffRootsResponseStream =
+    Pin<Box<dyn Stream<Item = std::result::Result<DiffRootsResponse, Status>> + Send>>;
+
 #[derive(Copy, Clone, Debug)]
 pub struct MongoKvPairTestConfig {
     pub contract_id: ContractId,
 }
 
+/// Where the data of a contract is stored. Contracts without a placement are stored in the
+/// default database of the MongoDB server specified by `MONGODB_URI`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MongoPlacement {
+    pub uri: String,
+    #[serde(default = "default_database_name")]
+    pub database: String,
+}
+
+fn default_database_name() -> String {
+    MongoCollection::<(), ()>::get_database_name()
+}
+
+/// The storage of the trees served by a `MongoKvPair`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MerkleBackend {
+    /// The default database of the MongoDB server at the uri.
+    Mongo { uri: String },
+}
+
+/// The configuration of a `MongoKvPair`, validated as a whole by `build` before connecting to
+/// the backend. `from_env` reads it from the environment variables described in the README.
+#[derive(Clone, Debug)]
+pub struct MerkleServiceBuilder {
+    backend: MerkleBackend,
+    depth: usize,
+    empty_leaf: Hash,
+    cache_capacity: usize,
+    read_only: bool,
+    max_concurrent_reads: Option<usize>,
+    max_concurrent_writes: Option<usize>,
+    max_concurrent_hashes: Option<usize>,
+    blocking_hash_threshold: usize,
+    read_session_ttl: Duration,
+    self_check_contracts: Vec<ContractId>,
+    coalesce_windows: Vec<(ContractId, Duration)>,
+    placements: Vec<(ContractId, MongoPlacement)>,
+}
+
+impl Default for MerkleServiceBuilder {
+    fn default() -> Self {
+        Self {
+            backend: MerkleBackend::Mongo {
+                uri: "mongodb://localhost:27017".to_string(),
+            },
+            depth: MERKLE_TREE_HEIGHT,
+            empty_leaf: DEFAULT_HASH_VEC[0],
+            cache_capacity: DEFAULT_FREEZE_CACHE_CAPACITY,
+            read_only: false,
+            max_concurrent_reads: None,
+            max_concurrent_writes: None,
+            max_concurrent_hashes: None,
+            blocking_hash_threshold: DEFAULT_BLOCKING_HASH_THRESHOLD,
+            read_session_ttl: DEFAULT_READ_SESSION_TTL,
+            self_check_contracts: vec![],
+            coalesce_windows: vec![],
+            placements: vec![],
+        }
+    }
+}
+
+impl MerkleServiceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The configuration set by the environment variables `MONGODB_URI`, `KVPAIR_READ_ONLY`,
+    /// `KVPAIR_MAX_CONCURRENT_READS`, `KVPAIR_MAX_CONCURRENT_WRITES`,
+    /// `KVPAIR_MAX_CONCURRENT_HASHES`, `KVPAIR_BLOCKING_HASH_THRESHOLD`,
+    /// `KVPAIR_READ_SESSION_TTL_SECS`, `KVPAIR_FREEZE_CACHE_CAPACITY`,
+    /// `KVPAIR_SELF_CHECK_CONTRACTS`, `KVPAIR_WRITE_COALESCING` and `MONGODB_PLACEMENTS`, with
+    /// the defaults of `new` for the unset ones.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut builder = Self::new();
+        if let Ok(uri) = std::env::var("MONGODB_URI") {
+            builder = builder.backend(MerkleBackend::Mongo { uri });
+        }
+        builder = builder.read_only(std::env::var("KVPAIR_READ_ONLY").is_ok());
+        let limit = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|n| {
+                    n.parse()
+                        .map_err(|_| Error::InvalidArgument(format!("Invalid {name}")))
+                })
+                .transpose()
+        };
+        builder = builder
+            .concurrency_limits(
+                limit("KVPAIR_MAX_CONCURRENT_READS")?,
+                limit("KVPAIR_MAX_CONCURRENT_WRITES")?,
+            )
+            .hash_concurrency_limit(limit("KVPAIR_MAX_CONCURRENT_HASHES")?);
+        if let Some(threshold) = limit("KVPAIR_BLOCKING_HASH_THRESHOLD")? {
+            builder = builder.blocking_hash_threshold(threshold);
+        }
+        if let Some(secs) = limit("KVPAIR_READ_SESSION_TTL_SECS")? {
+            builder = builder.read_session_ttl(Duration::from_secs(secs as u64));
+        }
+        if let Some(capacity) = limit("KVPAIR_FREEZE_CACHE_CAPACITY")? {
+            builder = builder.cache_capacity(capacity);
+        }
+        let parse_contract_id = |contract_id: &str| -> Option<ContractId> {
+            let contract_id: [u8; 32] = hex::decode(contract_id).ok()?.try_into().ok()?;
+            Some(contract_id.into())
+        };
+        // Contracts whose leaf updates are self checked, as hex encoded contract ids separated by commas.
+        if let Ok(contracts) = std::env::var("KVPAIR_SELF_CHECK_CONTRACTS") {
+            for contract_id in contracts.split(',').filter(|id| !id.is_empty()) {
+                let contract_id = parse_contract_id(contract_id).ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "Invalid contract id {contract_id} to self check"
+                    ))
+                })?;
+                builder = builder.self_check(contract_id);
+            }
+        }
+        // Coalescing windows of the leaf updates, as hex encoded contract ids and windows in
+        // milliseconds separated by commas, e.g. "<contract id>:50,<contract id>:100".
+        if let Ok(windows) = std::env::var("KVPAIR_WRITE_COALESCING") {
+            for window in windows.split(',').filter(|window| !window.is_empty()) {
+                let (contract_id, millis) = window
+                    .split_once(':')
+                    .and_then(|(contract_id, millis)| {
+                        Some((parse_contract_id(contract_id)?, millis.parse().ok()?))
+                    })
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!("Invalid write coalescing window {window}"))
+                    })?;
+                builder = builder.write_coalescing(contract_id, Duration::from_millis(millis));
+            }
+        }
+        // Placements are passed as a json object from hex encoded contract ids to placements, e.g.
+        // {"<contract id>": {"uri": "mongodb://localhost:27018", "database": "tenant"}}
+        if let Ok(placements) = std::env::var("MONGODB_PLACEMENTS") {
+            let placements: HashMap<String, MongoPlacement> = serde_json::from_str(&placements)
+                .map_err(|e| Error::InvalidArgument(format!("Invalid MONGODB_PLACEMENTS: {e}")))?;
+            for (contract_id, placement) in placements {
+                let contract_id = parse_contract_id(&contract_id).ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "Invalid contract id {contract_id} in placements"
+                    ))
+                })?;
+                builder = builder.placement(contract_id, placement);
+            }
+        }
+        Ok(builder)
+    }
+
+    pub fn backend(mut self, backend: MerkleBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// The depth of the trees, which must be `MERKLE_TREE_HEIGHT` for the MongoDB backend.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// The hash of the empty leaves, which must be `DEFAULT_HASH_VEC[0]` for the MongoDB
+    /// backend, as the hashes of the empty subtrees are derived from it.
+    pub fn empty_leaf(mut self, empty_leaf: Hash) -> Self {
+        self.empty_leaf = empty_leaf;
+        self
+    }
+
+    /// The number of contracts whose freeze is cached, at least one. Beyond it, the expired
+    /// freezes and then the oldest one are dropped for each new contract.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// See `MongoKvPair::with_read_only`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// See `MongoKvPair::with_concurrency_limits`.
+    pub fn concurrency_limits(mut self, reads: Option<usize>, writes: Option<usize>) -> Self {
+        self.max_concurrent_reads = reads;
+        self.max_concurrent_writes = writes;
+        self
+    }
+
+    /// See `MongoKvPair::with_hash_concurrency_limit`.
+    pub fn hash_concurrency_limit(mut self, hashes: Option<usize>) -> Self {
+        self.max_concurrent_hashes = hashes;
+        self
+    }
+
+    /// See `MongoKvPair::with_blocking_hash_threshold`.
+    pub fn blocking_hash_threshold(mut self, bytes: usize) -> Self {
+        self.blocking_hash_threshold = bytes;
+        self
+    }
+
+    /// See `MongoKvPair::with_read_session_ttl`.
+    pub fn read_session_ttl(mut self, ttl: Duration) -> Self {
+        self.read_session_ttl = ttl;
+        self
+    }
+
+    /// See `MongoKvPair::with_self_check`.
+    pub fn self_check(mut self, contract_id: ContractId) -> Self {
+        self.self_check_contracts.push(contract_id);
+        self
+    }
+
+    /// See `MongoKvPair::with_write_coalescing`.
+    pub fn write_coalescing(mut self, contract_id: ContractId, window: Duration) -> Self {
+        self.coalesce_windows.push((contract_id, window));
+        self
+    }
+
+    /// See `MongoKvPair::with_placement`.
+    pub fn placement(mut self, contract_id: ContractId, placement: MongoPlacement) -> Self {
+        self.placements.push((contract_id, placement));
+        self
+    }
+
+    /// Check that the configuration is supported, without connecting to the backend.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.cache_capacity == 0 {
+            return Err(Error::InvalidArgument(
+                "The cache capacity must be at least one".to_string(),
+            ));
+        }
+        match self.backend {
+            MerkleBackend::Mongo { .. } => {
+                if self.depth != MERKLE_TREE_HEIGHT {
+                    return Err(Error::InvalidArgument(format!(
+                        "The MongoDB backend only supports trees of depth {MERKLE_TREE_HEIGHT}, not {}",
+                        self.depth
+                    )));
+                }
+                if self.empty_leaf != DEFAULT_HASH_VEC[0] {
+                    return Err(Error::InvalidArgument(
+                        "The MongoDB backend only supports the empty leaf DEFAULT_HASH_VEC[0]"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration, then connect to the backend.
+    pub async fn build(self) -> Result<MongoKvPair, Error> {
+        self.validate()?;
+        let MerkleBackend::Mongo { uri } = &self.backend;
+        let client = MongoKvPair::connect(
+            uri,
+            &MongoCollection::<(), ()>::get_database_name(),
+            self.read_only,
+        )
+        .await?;
+        let mut server = MongoKvPair::new_with_client(client)
+            .with_read_only(self.read_only)
+            .with_concurrency_limits(self.max_concurrent_reads, self.max_concurrent_writes)
+            .with_hash_concurrency_limit(self.max_concurrent_hashes)
+            .with_blocking_hash_threshold(self.blocking_hash_threshold)
+            .with_read_session_ttl(self.read_session_ttl);
+        server.freeze_cache_capacity = self.cache_capacity;
+        for contract_id in self.self_check_contracts {
+            server = server.with_self_check(contract_id);
+        }
+        for (contract_id, window) in self.coalesce_windows {
+            server = server.with_write_coalescing(contract_id, window);
+        }
+        for (contract_id, placement) in self.placements {
+            server = server.with_placement(contract_id, placement).await?;
+        }
+        Ok(server)
+    }
+}
+
+/// Authenticate requests with a shared API key, passed either as a bearer token in the
+/// `authorization` header or in the `x-api-key` header. All requests are accepted when no key
+/// is configured, which is the default for local deployments.
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeyInterceptor {
+    api_key: Option<String>,
+}
+
+impl ApiKeyInterceptor {
+    pub fn new(api_key: Option<String>) -> Self {
+        Self { api_key }
+    }
+
+    /// Read the API key from the environment variable `KVPAIR_API_KEY`.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("KVPAIR_API_KEY").ok())
+    }
+}
+
+impl tonic::service::Interceptor for ApiKeyInterceptor {
+    fn call(&mut self, request: Request<()>) -> std::result::Result<Request<()>, Status> {
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key,
+            None => return Ok(request),
+        };
+        let metadata = request.metadata();
+        let given = metadata
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .or_else(|| metadata.get("x-api-key").and_then(|v| v.to_str().ok()))
+            .ok_or_else(|| Status::unauthenticated("API key not found"))?;
+        if bool::from(given.as_bytes().ct_eq(api_key.as_bytes())) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("Invalid API key"))
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MongoKvPair {
     client: Client,
+    // Clients to the MongoDB servers used by contract placements, keyed by uri.
+    clients: HashMap<String, Client>,
+    placements: HashMap<ContractId, MongoPlacement>,
     test_config: Option<MongoKvPairTestConfig>,
+    // Reject all the mutating requests, and read from secondaries when possible.
+    read_only: bool,
+    // Bound the number of requests using the storage concurrently, unbounded if not set.
+    read_permits: Option<Arc<Semaphore>>,
+    write_permits: Option<Arc<Semaphore>>,
+    // Bound the number of hash computations, which only use the CPU.
+    hash_permits: Option<Arc<Semaphore>>,
+    // Data of at least this many bytes is hashed on the blocking thread pool.
+    blocking_hash_threshold: usize,
+    // The roots pinned by the open read sessions, keyed by session token.
+    read_sessions: Arc<Mutex<HashMap<Vec<u8>, ReadSession>>>,
+    read_session_ttl: Duration,
+    // Access counters of the contracts and leaves served by this process.
+    hotspots: Arc<Hotspots>,
+    // The contracts whose leaf updates are verified before the root is updated.
+    self_check_contracts: HashSet<ContractId>,
+    self_check_failures: Arc<AtomicU64>,
+    // The coalescing windows of the leaf updates of the contracts, see `with_write_coalescing`.
+    coalesce_windows: HashMap<ContractId, Arc<CoalesceWindow>>,
+    coalesced_updates: Arc<AtomicU64>,
+    // The leaf updates not written as the leaf hash was unchanged.
+    skipped_updates: Arc<AtomicU64>,
+    // The freeze of the contracts read within `FREEZE_CACHE_TTL`, with the time it was read.
+    freezes: Arc<Mutex<HashMap<ContractId, (Instant, Option<Freeze>)>>>,
+    freeze_cache_capacity: usize,
+    // The locks serializing the leaf updates of the contracts, see `lock_leaves`.
+    leaf_locks: Arc<Mutex<HashMap<ContractId, Weak<tokio::sync::Mutex<()>>>>>,
+    // The time spent waiting for the read and write permits, see `permit_wait`.
+    permit_wait: Arc<PermitWait>,
+}
+
+/// The time spent by the requests queued for a storage permit, see
+/// `MongoKvPair::with_concurrency_limits`. Requests are only counted while the limits are set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PermitWaitStats {
+    /// The requests which got a permit, after waiting `total` altogether and `max` at most.
+    pub acquired: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// The requests which failed with `RESOURCE_EXHAUSTED` as they would have waited past their
+    /// deadline.
+    pub shed: u64,
+}
+
+/// The state of the configured MongoDB servers, see `MongoKvPair::health_check`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Health {
+    /// How far the most lagging secondary is behind the primary, over all the configured
+    /// servers. `None` if no server is a replica set with a primary and a secondary, or the
+    /// replica set status can't be read.
+    pub replication_lag: Option<Duration>,
+}
+
+// The MongoDB error codes of `replSetGetStatus` on a standalone server, and without the
+// `clusterMonitor` role.
+const NO_REPLICATION_ENABLED: i32 = 76;
+const UNAUTHORIZED: i32 = 13;
+
+// How far the most lagging secondary is behind the primary, from the optimes of the members
+// in `replSetGetStatus`.
+async fn replication_lag(client: &Client) -> Result<Option<Duration>, Error> {
+    let status = match client
+        .database("admin")
+        .run_command(doc! { "replSetGetStatus": 1 }, None)
+        .await
+    {
+        Ok(status) => status,
+        Err(e) => match *e.kind {
+            ErrorKind::Command(ref c)
+                if [NO_REPLICATION_ENABLED, UNAUTHORIZED].contains(&c.code) =>
+            {
+                return Ok(None)
+            }
+            _ => return Err(e.into()),
+        },
+    };
+    let members = match status.get_array("members") {
+        Ok(members) => members,
+        Err(_) => return Ok(None),
+    };
+    // The optimes in milliseconds of the members in the given state, 1 being primary and 2
+    // secondary.
+    let optimes = |state: i32| {
+        members
+            .iter()
+            .filter_map(Bson::as_document)
+            .filter(move |member| member.get_i32("state") == Ok(state))
+            .filter_map(|member| member.get_datetime("optimeDate").ok())
+            .map(|optime| optime.timestamp_millis())
+    };
+    Ok(optimes(1)
+        .max()
+        .zip(optimes(2).min())
+        .map(|(primary, secondary)| {
+            Duration::from_millis(primary.saturating_sub(secondary).max(0) as u64)
+        }))
+}
+
+// The counters behind `PermitWaitStats`, in nanoseconds.
+#[derive(Debug, Default)]
+struct PermitWait {
+    acquired: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl PermitWait {
+    fn record(&self, waited: Duration, acquired: bool) {
+        if !acquired {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let waited = waited.as_nanos() as u64;
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(waited, Ordering::Relaxed);
+        self.max.fetch_max(waited, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> PermitWaitStats {
+        PermitWaitStats {
+            acquired: self.acquired.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A contract frozen with `FreezeContract`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Freeze {
+    /// Frozen for all requests, or only for writes.
+    pub full: bool,
+    pub reason: String,
+}
+
+impl Freeze {
+    /// Fail with `Error::Frozen` if the freeze applies to the request.
+    pub fn check(&self, write: bool) -> Result<(), Error> {
+        if write || self.full {
+            return Err(Error::Frozen(self.reason.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ReadSession {
+    contract_id: ContractId,
+    root: Hash,
+    expires: Instant,
 }
 
 #[derive(Debug)]
 pub struct MongoCollection<T, R> {
     merkle_collection: Collection<T>,
     datahash_collection: Collection<R>,
+    // The metadata of the leaves, keyed by leaf index, see `set_leaf_metadata`.
+    leaf_metadata_collection: Collection<Document>,
     session: Option<ClientSession>,
+    // Attached to the MongoDB operations so that they show up in the profiler and currentOp.
+    comment: Option<String>,
+    // Verify the proofs of the leaf updates, counting the failures here, see `with_self_check`.
+    self_check: Option<Arc<AtomicU64>>,
+    // Count the leaf updates skipped as the leaf is unchanged, see `with_skipped_updates`.
+    skipped_updates: Option<Arc<AtomicU64>>,
 }
 
 impl<T, R> MongoCollection<T, R> {
-    fn get_database_name() -> String {
+    pub fn get_database_name() -> String {
         "zkwasm-mongo-merkle".to_string()
     }
 
@@ -51,8 +584,13 @@ impl<T, R> MongoCollection<T, R> {
         format!("DATAHASH_{}", hex::encode(contract_id.0))
     }
 
+    fn get_leaf_metadata_collection_name(contract_id: &ContractId) -> String {
+        format!("LEAFMETA_{}", hex::encode(contract_id.0))
+    }
+
     pub async fn new(
         client: Client,
+        database_name: &str,
         contract_id: &ContractId,
         with_session: bool,
     ) -> Result<Self, mongodb::error::Error> {
@@ -67,11 +605,13 @@ impl<T, R> MongoCollection<T, R> {
         } else {
             None
         };
-        let database = client.clone().database(Self::get_database_name().as_str());
+        let database = client.clone().database(database_name);
         let merkle_collection_name = Self::get_merkle_collection_name(contract_id);
         let merkle_collection = database.collection::<T>(merkle_collection_name.as_str());
         let datahash_collection_name = Self::get_data_collection_name(contract_id);
         let datahash_collection = database.collection::<R>(datahash_collection_name.as_str());
+        let leaf_metadata_collection = database
+            .collection::<Document>(Self::get_leaf_metadata_collection_name(contract_id).as_str());
         if std::env::var("MONGODB_CREATE_INDEXES").is_ok() {
             merkle_collection
                 .create_indexes(
@@ -95,14 +635,50 @@ impl<T, R> MongoCollection<T, R> {
                 )
                 .await?;
         }
-        dbg!(merkle_collection_name, datahash_collection_name);
         Ok(Self {
             merkle_collection,
             datahash_collection,
+            leaf_metadata_collection,
             session,
+            comment: None,
+            self_check: None,
+            skipped_updates: None,
         })
     }
 
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Verify that the current proof of an updated leaf folds to the current root, and that the
+    /// new proof folds to the new root, before the root is updated. A failed check fails the
+    /// update with an internal error, leaving the root unchanged, and is counted in `failures`.
+    /// This costs two folds of the proof per update.
+    pub fn with_self_check(mut self, failures: Arc<AtomicU64>) -> Self {
+        self.self_check = Some(failures);
+        self
+    }
+
+    /// Count in `skipped` the leaf updates of `set_leaf_and_get_proof` which write nothing as
+    /// the leaf hash is unchanged.
+    pub fn with_skipped_updates(mut self, skipped: Arc<AtomicU64>) -> Self {
+        self.skipped_updates = Some(skipped);
+        self
+    }
+
+    fn find_one_options(&self, options: impl Into<Option<FindOneOptions>>) -> FindOneOptions {
+        let mut options = options.into().unwrap_or_default();
+        if options.comment.is_none() {
+            options.comment = self.comment.clone();
+        }
+        options
+    }
+
+    fn comment_bson(&self) -> Option<Bson> {
+        self.comment.clone().map(Bson::String)
+    }
+
     pub async fn commit(&mut self) -> Result<(), mongodb::error::Error> {
         if let Some(mut session) = self.session.take() {
             // A "TransientTransactionError" label indicates that the entire transaction can be retried
@@ -129,7 +705,8 @@ impl<T, R> MongoCollection<T, R> {
     pub async fn drop(&self) -> Result<(), mongodb::error::Error> {
         let options = mongodb::options::DropCollectionOptions::builder().build();
         self.merkle_collection.drop(options.clone()).await?;
-        self.datahash_collection.drop(options).await?;
+        self.datahash_collection.drop(options.clone()).await?;
+        self.leaf_metadata_collection.drop(options).await?;
         Ok(())
     }
 }
@@ -145,6 +722,7 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         filter: impl Into<Option<Document>>,
         options: impl Into<Option<FindOneOptions>>,
     ) -> Result<Option<MerkleRecord>, mongodb::error::Error> {
+        let options = self.find_one_options(options);
         let result = match self.session.as_mut() {
             Some(session) => {
                 self.merkle_collection
@@ -161,6 +739,10 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         doc: impl Borrow<MerkleRecord>,
         options: impl Into<Option<InsertOneOptions>>,
     ) -> Result<InsertOneResult, mongodb::error::Error> {
+        let mut options = options.into().unwrap_or_default();
+        if options.comment.is_none() {
+            options.comment = self.comment_bson();
+        }
         let result = match self.session.as_mut() {
             Some(session) => {
                 self.merkle_collection
@@ -178,6 +760,10 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         replacement: impl Borrow<MerkleRecord>,
         options: impl Into<Option<ReplaceOptions>>,
     ) -> Result<UpdateResult, mongodb::error::Error> {
+        let mut options = options.into().unwrap_or_default();
+        if options.comment.is_none() {
+            options.comment = self.comment_bson();
+        }
         let result = match self.session.as_mut() {
             Some(session) => {
                 self.merkle_collection
@@ -199,6 +785,10 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         update: impl Into<UpdateModifications>,
         options: impl Into<Option<UpdateOptions>>,
     ) -> Result<UpdateResult, mongodb::error::Error> {
+        let mut options = options.into().unwrap_or_default();
+        if options.comment.is_none() {
+            options.comment = self.comment_bson();
+        }
         let result = match self.session.as_mut() {
             Some(session) => {
                 self.merkle_collection
@@ -214,12 +804,15 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         Ok(result)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, hash))
+    )]
     pub async fn get_merkle_record(
         &mut self,
         index: u64,
         hash: &Hash,
     ) -> Result<Option<MerkleRecord>, Error> {
-        dbg!(index, hash);
         let mut filter = doc! {};
         filter.insert("index", u64_to_bson(index));
         filter.insert("hash", hash_to_bson(hash));
@@ -228,7 +821,6 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
             return Ok(record);
         }
         let default_record = MerkleRecord::get_default_record(index)?;
-        dbg!(&default_record, hash);
         if default_record.hash == *hash {
             Ok(Some(default_record))
         } else {
@@ -245,10 +837,92 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         record.ok_or(Error::Precondition("Merkle record not found".to_string()))
     }
 
+    // Read the given fields of a document which is not a merkle record, e.g. the metadata, or
+    // all its fields if no projection is given.
+    async fn find_one_document(
+        &mut self,
+        id: mongodb::bson::oid::ObjectId,
+        projection: impl Into<Option<Document>>,
+    ) -> Result<Option<Document>, mongodb::error::Error> {
+        let filter = doc! {"_id": id};
+        let options = self.find_one_options(
+            FindOneOptions::builder()
+                .projection(projection.into())
+                .build(),
+        );
+        let collection = self.merkle_collection.clone_with_type::<Document>();
+        match self.session.as_mut() {
+            Some(session) => {
+                collection
+                    .find_one_with_session(filter, options, session)
+                    .await
+            }
+            _ => collection.find_one(filter, options).await,
+        }
+    }
+
+    /// The depth of the tree saved along with the current root, None if there is no root yet or
+    /// it was saved before the depth was recorded.
+    pub async fn get_stored_depth(&mut self) -> Result<Option<u64>, Error> {
+        let document = self
+            .find_one_document(Self::get_current_root_object_id(), doc! {"depth": 1})
+            .await?;
+        Ok(document.as_ref().and_then(stored_depth))
+    }
+
+    // Check the depth of the tree saved along with the current root matches ours.
+    pub async fn check_depth(&mut self) -> Result<(), Error> {
+        check_stored_depth(self.get_stored_depth().await?)
+    }
+
+    /// The document with the contract level metadata, e.g. whether the contract is frozen.
+    pub fn get_metadata_object_id() -> mongodb::bson::oid::ObjectId {
+        mongodb::bson::oid::ObjectId::from_bytes([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+    }
+
+    /// Freeze the contract with the given mode and reason, or unfreeze it if freeze is None.
+    pub async fn set_freeze(&mut self, freeze: Option<(FreezeMode, &str)>) -> Result<(), Error> {
+        let filter = doc! {"_id": Self::get_metadata_object_id()};
+        let update = match freeze {
+            Some((mode, reason)) => doc! {
+                "$set": {"freeze": {"mode": mode as i32, "reason": reason}},
+            },
+            None => doc! {"$unset": {"freeze": ""}},
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.update_one_merkle_record(filter, update, options)
+            .await?;
+        Ok(())
+    }
+
+    /// The freeze of the contract, None if it is not frozen.
+    pub async fn get_freeze(&mut self) -> Result<Option<Freeze>, Error> {
+        let document = self
+            .find_one_document(Self::get_metadata_object_id(), doc! {"freeze": 1})
+            .await?;
+        Ok(document
+            .as_ref()
+            .and_then(|d| d.get_document("freeze").ok())
+            .map(|freeze| Freeze {
+                full: freeze.get_i32("mode") == Ok(FreezeMode::FreezeFull as i32),
+                reason: freeze.get_str("reason").unwrap_or_default().to_string(),
+            }))
+    }
+
     pub async fn get_root_merkle_record(&mut self) -> Result<Option<MerkleRecord>, Error> {
-        let filter = doc! {"_id": Self::get_current_root_object_id()};
-        let record = self.find_one_merkle_record(filter, None).await?;
-        dbg!(&record);
+        // The depth is checked on the root document itself, so the root is read in one round trip.
+        let document = self
+            .find_one_document(Self::get_current_root_object_id(), None)
+            .await?;
+        let record = match document {
+            Some(document) => {
+                check_stored_depth(stored_depth(&document))?;
+                mongodb::bson::from_document::<MerkleRecord>(document)
+                    .map(Some)
+                    .map_err(|e| Error::InconsistentData(format!("Invalid root record: {e}")))?
+            }
+            None => None,
+        };
         if record.is_some() {
             return Ok(record);
         }
@@ -272,8 +946,7 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         match result {
             Some(result) => Ok(result),
             None => {
-                let result = self.insert_one_merkle_record(record, None).await?;
-                dbg!(&record, &result);
+                self.insert_one_merkle_record(record, None).await?;
                 Ok(*record)
             }
         }
@@ -297,29 +970,45 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         let update = doc! {
             "$set": {
                 "index": u64_to_bson(0),
-                "hash": to_bson(&record.hash).unwrap(),
-                "left": to_bson(&record.left).unwrap(),
-                "right": to_bson(&record.right).unwrap(),
-                "data": u256_to_bson(&record.data)
+                "hash": hash_to_bson(&record.hash),
+                "left": hash_to_bson(&record.left),
+                "right": hash_to_bson(&record.right),
+                "data": u256_to_bson(&record.data),
+                "depth": MERKLE_TREE_HEIGHT as i64
             },
         };
         let options = UpdateOptions::builder().upsert(true).build();
-        let result = self
-            .update_one_merkle_record(filter, update, options)
+        self.update_one_merkle_record(filter, update, options)
             .await?;
-        dbg!(&result);
         Ok(*record)
     }
 
     pub async fn get_leaf_and_proof(
         &mut self,
         index: u64,
+    ) -> Result<(MerkleRecord, MerkleProof<Hash, MERKLE_TREE_HEIGHT>), Error> {
+        self.get_leaf_and_proof_at(index, None).await
+    }
+
+    /// Same as `get_leaf_and_proof`, but walks the tree from the given root if any.
+    /// As nodes are never overwritten, any root which has been current at some point can be used.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, root), fields(depth = MERKLE_TREE_HEIGHT, reads))
+    )]
+    pub async fn get_leaf_and_proof_at(
+        &mut self,
+        index: u64,
+        root: Option<&Hash>,
     ) -> Result<(MerkleRecord, MerkleProof<Hash, MERKLE_TREE_HEIGHT>), Error> {
         leaf_check(index, MERKLE_TREE_HEIGHT)?;
         let paths = get_path(index, MERKLE_TREE_HEIGHT)?;
         // We push the search from the top
         let mut acc = 0;
-        let mut acc_node = self.must_get_root_merkle_record().await?;
+        let mut acc_node = match root {
+            Some(root) => self.must_get_merkle_record(0, root).await?,
+            None => self.must_get_root_merkle_record().await?,
+        };
         let root_hash = acc_node.hash;
         let mut assist = Vec::with_capacity(MERKLE_TREE_HEIGHT);
         for child in paths {
@@ -327,16 +1016,18 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
             let is_right_child = (acc + 1) * 2 == child;
             assert!(is_left_child || is_right_child);
             let (hash, sibling_hash) = if is_left_child {
-                (acc_node.left().unwrap(), acc_node.right().unwrap())
+                (acc_node.left, acc_node.right)
             } else {
-                (acc_node.right().unwrap(), acc_node.left().unwrap())
+                (acc_node.right, acc_node.left)
             };
-            let sibling = get_sibling_index(child);
+            let sibling = get_sibling_index(child).ok_or_else(root_index_error)?;
             let sibling_node = self.must_get_merkle_record(sibling, &sibling_hash).await?;
             acc = child;
             acc_node = self.must_get_merkle_record(acc, &hash).await?;
             assist.push(sibling_node.hash());
         }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("reads", 1 + 2 * assist.len());
         let hash = acc_node.hash();
         Ok((
             acc_node,
@@ -349,16 +1040,45 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         ))
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(index = leaf.index, depth = MERKLE_TREE_HEIGHT))
+    )]
+    /// Set the leaf and return its proof against the new root. If the leaf hash is unchanged,
+    /// nothing is written and the current proof is returned, unless `force` is set.
+    /// If `expected` is given, the leaf is only set if its current hash is `expected`, as read
+    /// for the path of the update, so that read-modify-writes don't overwrite each other. The
+    /// check and the write only happen without another update in between if the updates of the
+    /// contract are serialized, as `MongoKvPair` does with a lock per contract.
     pub async fn set_leaf_and_get_proof(
         &mut self,
         leaf: &MerkleRecord,
+        force: bool,
+        expected: Option<&Hash>,
     ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Error> {
         let index = leaf.index();
         let mut hash = leaf.hash();
         let (_, mut proof) = self.get_leaf_and_proof(index).await?;
+        self.check_proof(&proof, "current")?;
+        if let Some(expected) = expected {
+            if proof.source != *expected {
+                return Err(Error::LeafHashMismatch {
+                    index,
+                    expected: *expected,
+                    current: proof.source,
+                });
+            }
+        }
+        if proof.source == hash && !force {
+            if let Some(skipped) = &self.skipped_updates {
+                skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            return Ok(proof);
+        }
         proof.source = hash;
-        let mut p = get_offset(index);
+        let mut p = get_offset(index)?;
         self.insert_merkle_record(leaf).await?;
+        let mut root = None;
         for i in 0..MERKLE_TREE_HEIGHT {
             let cur_hash = hash;
             let depth = MERKLE_TREE_HEIGHT - i - 1;
@@ -374,17 +1094,97 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
             assert_eq!(record.hash, hash);
             self.insert_merkle_record(&record).await?;
             if index == 0 {
-                self.update_root_merkle_record(&record).await?;
+                root = Some(record);
             }
         }
+        proof.root = hash;
+        // Nodes are never overwritten, so the new nodes are unreachable until the root is updated.
+        self.check_proof(&proof, "new")?;
+        if let Some(root) = root {
+            self.update_root_merkle_record(&root).await?;
+        }
         Ok(proof)
     }
 
+    /// The next leaves whose hash differs between two roots, as (index, old hash, new hash) in
+    /// index order, at most `limit` of them. The walk is `merkle::diff_trees` over the stored
+    /// nodes: it descends only where the hashes differ and doesn't read the empty subtrees, so
+    /// it reads about the depth times the number of changed leaves. `stack` holds the pairs of
+    /// nodes left to compare as (index, depth, old hash, new hash), starting with the roots at
+    /// index 0, and is empty once the walk is done. Fails with `Cancelled` once `cancel` is set.
+    pub async fn next_changed_leaves(
+        &mut self,
+        stack: &mut Vec<(u64, usize, Hash, Hash)>,
+        limit: usize,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<(u64, Hash, Hash)>, Error> {
+        let mut leaves = vec![];
+        while leaves.len() < limit {
+            let (index, depth, old, new) = match stack.pop() {
+                Some(pair) => pair,
+                None => break,
+            };
+            if old == new {
+                continue;
+            }
+            check_cancelled(cancel, index)?;
+            if depth == MERKLE_TREE_HEIGHT {
+                leaves.push((index, old, new));
+                continue;
+            }
+            let (old_left, old_right) = self.diff_children(index, depth, &old).await?;
+            let (new_left, new_right) = self.diff_children(index, depth, &new).await?;
+            stack.push((2 * index + 2, depth + 1, old_right, new_right));
+            stack.push((2 * index + 1, depth + 1, old_left, new_left));
+        }
+        Ok(leaves)
+    }
+
+    // The child hashes of a node, without reading the nodes of the empty subtrees.
+    async fn diff_children(
+        &mut self,
+        index: u64,
+        depth: usize,
+        hash: &Hash,
+    ) -> Result<(Hash, Hash), Error> {
+        if *hash == DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - depth] {
+            let child = DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - depth - 1];
+            return Ok((child, child));
+        }
+        let record = self.must_get_merkle_record(index, hash).await?;
+        match (record.left(), record.right()) {
+            (Some(left), Some(right)) => Ok((left, right)),
+            _ => Err(MerkleError::new(Hash::empty(), index, MerkleErrorCode::MissingChild).into()),
+        }
+    }
+
+    // Check that the proof folds to its root if self checks are enabled.
+    fn check_proof(
+        &self,
+        proof: &MerkleProof<Hash, MERKLE_TREE_HEIGHT>,
+        what: &str,
+    ) -> Result<(), Error> {
+        let failures = match &self.self_check {
+            Some(failures) => failures,
+            None => return Ok(()),
+        };
+        let steps = proof.fold_steps(Hash::hash_children)?;
+        if steps.last().map(|step| step.hash) != Some(proof.root) {
+            failures.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::InconsistentData(format!(
+                "Self check failed: the {what} proof of leaf {} does not fold to its root",
+                proof.index
+            )));
+        }
+        Ok(())
+    }
+
     pub async fn find_one_datahash_record(
         &mut self,
         filter: impl Into<Option<Document>>,
         options: impl Into<Option<FindOneOptions>>,
     ) -> Result<Option<DataHashRecord>, mongodb::error::Error> {
+        let options = self.find_one_options(options);
         let result = match self.session.as_mut() {
             Some(session) => {
                 self.datahash_collection
@@ -401,6 +1201,10 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         doc: impl Borrow<DataHashRecord>,
         options: impl Into<Option<InsertOneOptions>>,
     ) -> Result<InsertOneResult, mongodb::error::Error> {
+        let mut options = options.into().unwrap_or_default();
+        if options.comment.is_none() {
+            options.comment = self.comment_bson();
+        }
         let result = match self.session.as_mut() {
             Some(session) => {
                 self.datahash_collection
@@ -418,14 +1222,11 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
     ) -> Result<DataHashRecord, Error> {
         let mut filter = doc! {};
         filter.insert("hash", hash_to_bson(&record.hash));
-        dbg!(&record.hash, &filter);
         let result = self.find_one_datahash_record(filter, None).await?;
-        dbg!(&result);
         match result {
             Some(result) => Ok(result),
             None => {
-                let result = self.insert_one_datahash_record(record, None).await?;
-                dbg!(&record, &result);
+                self.insert_one_datahash_record(record, None).await?;
                 Ok(record.clone())
             }
         }
@@ -435,7 +1236,6 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         &mut self,
         hash: &Hash,
     ) -> Result<Option<DataHashRecord>, Error> {
-        dbg!(hash);
         if *hash == Hash::empty() {
             return Ok(Some(DataHashRecord::empty()));
         }
@@ -449,24 +1249,128 @@ impl MongoCollection<MerkleRecord, DataHashRecord> {
         let record = self.get_datahash_record(hash).await?;
         record.ok_or(Error::Precondition("Datahash record not found".to_string()))
     }
+
+    /// Replace the metadata of the leaf. The metadata is keyed by the index only and is not
+    /// part of the leaf hash, so it is not covered by any proof and is not versioned with the
+    /// roots: reading a leaf at a previous root returns the latest metadata.
+    pub async fn set_leaf_metadata(&mut self, index: u64, metadata: &[u8]) -> Result<(), Error> {
+        let filter = doc! {"_id": u64_to_bson(index)};
+        let update = doc! {"$set": {"metadata": Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: metadata.to_vec(),
+        })}};
+        let options = UpdateOptions::builder()
+            .upsert(true)
+            .comment(self.comment_bson())
+            .build();
+        match self.session.as_mut() {
+            Some(session) => {
+                self.leaf_metadata_collection
+                    .update_one_with_session(filter, update, options, session)
+                    .await?
+            }
+            _ => {
+                self.leaf_metadata_collection
+                    .update_one(filter, update, options)
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    /// The metadata last set for the leaf, if any.
+    pub async fn get_leaf_metadata(&mut self, index: u64) -> Result<Option<Vec<u8>>, Error> {
+        let filter = doc! {"_id": u64_to_bson(index)};
+        let options = self.find_one_options(None);
+        let document = match self.session.as_mut() {
+            Some(session) => {
+                self.leaf_metadata_collection
+                    .find_one_with_session(filter, options, session)
+                    .await?
+            }
+            _ => {
+                self.leaf_metadata_collection
+                    .find_one(filter, options)
+                    .await?
+            }
+        };
+        Ok(document.and_then(|d| d.get_binary_generic("metadata").ok().cloned()))
+    }
+}
+
+// The depth of the tree saved in the root document.
+fn stored_depth(root: &Document) -> Option<u64> {
+    root.get_i64("depth").ok().map(|depth| depth as u64)
+}
+
+// Roots saved before the depth was recorded are assumed to have the right depth.
+fn check_stored_depth(stored: Option<u64>) -> Result<(), Error> {
+    match stored {
+        Some(stored) if stored != MERKLE_TREE_HEIGHT as u64 => Err(Error::DepthMismatch {
+            stored,
+            expected: MERKLE_TREE_HEIGHT as u64,
+        }),
+        _ => Ok(()),
+    }
 }
 
 impl MongoKvPair {
+    /// A server configured by the environment variables, see `MerkleServiceBuilder::from_env`.
     pub async fn new() -> Self {
-        let mongodb_uri: String =
-            std::env::var("MONGODB_URI").unwrap_or("mongodb://localhost:27017".to_string());
-        let client = Client::with_uri_str(&mongodb_uri).await.unwrap();
+        MerkleServiceBuilder::from_env()
+            .expect("Read the service configuration")
+            .build()
+            .await
+            .expect("Connect to mongodb")
+    }
+
+    async fn connect(uri: &str, database_name: &str, read_only: bool) -> Result<Client, Error> {
+        let mut options = ClientOptions::parse(uri).await?;
+        if read_only {
+            options.selection_criteria = Some(SelectionCriteria::ReadPreference(
+                ReadPreference::SecondaryPreferred {
+                    options: Default::default(),
+                },
+            ));
+        }
+        let client = Client::with_options(options)?;
         // Eagerly connect to mongodb server to fail faster.
         let _ = client
             .list_database_names(
                 doc! {
-                    "name": MongoCollection::<(), ()>::get_database_name(),
+                    "name": database_name,
                 },
                 None,
             )
-            .await
-            .expect("List databases");
-        MongoKvPair::new_with_client(client)
+            .await?;
+        Ok(client)
+    }
+
+    /// Store the data of the given contract with the given placement instead of the default
+    /// database. Clients are shared between placements with the same uri.
+    pub async fn with_placement(
+        mut self,
+        contract_id: ContractId,
+        placement: MongoPlacement,
+    ) -> Result<Self, Error> {
+        let client = match self.clients.get(&placement.uri) {
+            Some(client) => client.clone(),
+            None => Self::connect(&placement.uri, &placement.database, self.read_only).await?,
+        };
+        self.clients.insert(placement.uri.clone(), client);
+        self.placements.insert(contract_id, placement);
+        Ok(self)
+    }
+
+    /// Check that all the configured MongoDB servers are reachable, and report how far their
+    /// secondaries lag behind, which bounds the staleness of the reads of a read-only replica.
+    pub async fn health_check(&self) -> Result<Health, Error> {
+        let mut health = Health::default();
+        for client in std::iter::once(&self.client).chain(self.clients.values()) {
+            client.list_database_names(None, None).await?;
+            health.replication_lag = health.replication_lag.max(replication_lag(client).await?);
+        }
+        Ok(health)
     }
 
     pub async fn new_with_test_config(test_config: Option<MongoKvPairTestConfig>) -> Self {
@@ -478,29 +1382,505 @@ impl MongoKvPair {
     fn new_with_client(client: Client) -> Self {
         Self {
             client,
+            clients: HashMap::new(),
+            placements: HashMap::new(),
             test_config: None,
+            read_only: false,
+            read_permits: None,
+            write_permits: None,
+            hash_permits: None,
+            blocking_hash_threshold: DEFAULT_BLOCKING_HASH_THRESHOLD,
+            read_sessions: Default::default(),
+            read_session_ttl: DEFAULT_READ_SESSION_TTL,
+            hotspots: Default::default(),
+            self_check_contracts: HashSet::new(),
+            self_check_failures: Default::default(),
+            coalesce_windows: HashMap::new(),
+            coalesced_updates: Default::default(),
+            skipped_updates: Default::default(),
+            freezes: Default::default(),
+            freeze_cache_capacity: DEFAULT_FREEZE_CACHE_CAPACITY,
+            leaf_locks: Default::default(),
+            permit_wait: Default::default(),
         }
     }
 
-    pub async fn new_collection<T, R>(
-        &self,
-        contract_id: &ContractId,
-        with_session: bool,
-    ) -> Result<MongoCollection<T, R>, Error> {
-        Ok(MongoCollection::new(self.client.clone(), contract_id, with_session).await?)
+    /// Bound the number of reading and writing requests using the MongoDB connection pools
+    /// concurrently, so that bursts queue here instead of exhausting the pools. Requests which
+    /// would wait past their deadline (from the `grpc-timeout` header) fail with
+    /// `RESOURCE_EXHAUSTED`. `health_check` is not bounded. `None` means unbounded.
+    pub fn with_concurrency_limits(mut self, reads: Option<usize>, writes: Option<usize>) -> Self {
+        self.read_permits = reads.map(|n| Arc::new(Semaphore::new(n)));
+        self.write_permits = writes.map(|n| Arc::new(Semaphore::new(n)));
+        self
     }
 
-    pub async fn drop_test_collection(&self) -> Result<(), Error> {
-        if let Some(test_config) = &self.test_config {
-            let collection = self
-                .new_collection::<MerkleRecord, DataHashRecord>(&test_config.contract_id, false)
-                .await?;
-            collection.drop().await?;
+    /// The time spent by the reading and writing requests queued for a permit, see
+    /// `with_concurrency_limits`.
+    pub fn permit_wait(&self) -> PermitWaitStats {
+        self.permit_wait.stats()
+    }
+
+    /// Same as `with_concurrency_limits`, but for the stateless hash computations
+    /// (`ComputeHash` and `ComputePairHash`), which are bounded separately as they only use the CPU.
+    pub fn with_hash_concurrency_limit(mut self, hashes: Option<usize>) -> Self {
+        self.hash_permits = hashes.map(|n| Arc::new(Semaphore::new(n)));
+        self
+    }
+
+    /// Hash data of at least `bytes` bytes (in `PoseidonHash`, `ComputeHash` and `SetLeaf`) on
+    /// the blocking thread pool, so that large hashes don't stall the other requests served by
+    /// the same runtime thread. Smaller data is hashed inline, as handing it off to another
+    /// thread costs more than hashing a few field elements.
+    pub fn with_blocking_hash_threshold(mut self, bytes: usize) -> Self {
+        self.blocking_hash_threshold = bytes;
+        self
+    }
+
+    // Run the hash of data of the given length off the runtime if it is over the threshold.
+    async fn hash_data<T, F>(&self, len: usize, hash: F) -> Result<T, Status>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if len < self.blocking_hash_threshold {
+            return Ok(hash());
         }
-        Ok(())
+        tokio::task::spawn_blocking(hash)
+            .await
+            .map_err(|e| Status::internal(format!("Hash task failed: {e}")))
     }
 
-    // Validate the contract id passed from http request or gRPC request parameter.
+    /// Verify the leaf updates of the contract before updating its root, see
+    /// `MongoCollection::with_self_check`.
+    pub fn with_self_check(mut self, contract_id: ContractId) -> Self {
+        self.self_check_contracts.insert(contract_id);
+        self
+    }
+
+    /// The number of leaf updates refused because their self check failed.
+    pub fn self_check_failures(&self) -> u64 {
+        self.self_check_failures.load(Ordering::Relaxed)
+    }
+
+    /// Coalesce the leaf updates of the contract received within `window` (at most
+    /// `MAX_COALESCE_WINDOW`), so that a leaf updated several times in a window is only written
+    /// once, with its last value, see `CoalesceWindow`. This adds up to the window to the latency
+    /// of the updates, plus the time to write the previous window, as each update is answered
+    /// once its window is written, with a proof against the root written by the window. The
+    /// proof is that of the leaf in this root, so the leaf of an update overwritten later in the
+    /// window has the later value. Expected leaf hashes are checked in arrival order against the
+    /// hash left by the previous updates of the window.
+    pub fn with_write_coalescing(mut self, contract_id: ContractId, window: Duration) -> Self {
+        self.coalesce_windows
+            .insert(contract_id, Arc::new(CoalesceWindow::new(window)));
+        self
+    }
+
+    /// The number of leaf updates which were not written as a later update of the same leaf in
+    /// their coalescing window was written instead.
+    pub fn coalesced_updates(&self) -> u64 {
+        self.coalesced_updates.load(Ordering::Relaxed)
+    }
+
+    /// The number of leaf updates which wrote nothing as the leaf hash was unchanged, also
+    /// returned by `GetServerInfo`.
+    pub fn skipped_unchanged_updates(&self) -> u64 {
+        self.skipped_updates.load(Ordering::Relaxed)
+    }
+
+    // Add the leaf update to the coalescing window of the contract and wait for the window to be
+    // written. The update opening the window writes it from a task of its own, so that the
+    // window is written even if this request is cancelled.
+    async fn set_leaf_coalesced(
+        &self,
+        contract_id: ContractId,
+        window: Arc<CoalesceWindow>,
+        leaf: MerkleRecord,
+        force: bool,
+        expected: Option<Hash>,
+        metadata: Option<Vec<u8>>,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Status> {
+        let (reply, result) = oneshot::channel();
+        let update = PendingUpdate {
+            leaf,
+            force,
+            expected,
+            metadata,
+            reply,
+        };
+        if window.push(update) {
+            let server = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(window.duration()).await;
+                let (_guard, updates) = window.close().await;
+                server.flush_coalesced(&contract_id, updates).await;
+            });
+        }
+        result
+            .await
+            .map_err(|_| Status::internal("Coalesced leaf update dropped"))?
+    }
+
+    // Write the updates of a closed coalescing window and answer each of them.
+    async fn flush_coalesced(&self, contract_id: &ContractId, updates: Vec<PendingUpdate>) {
+        match self.write_coalesced(contract_id, &updates).await {
+            Ok((coalesced, proofs)) => {
+                for (update, outcome) in updates.into_iter().zip(coalesced.outcomes) {
+                    let index = update.leaf.index();
+                    let result = match (outcome, update.expected) {
+                        (Err(current), Some(expected)) => Err(Error::LeafHashMismatch {
+                            index,
+                            expected,
+                            current,
+                        }
+                        .into()),
+                        _ => Ok(proofs[&index].clone()),
+                    };
+                    // The request may have been cancelled.
+                    let _ = update.reply.send(result);
+                }
+            }
+            Err(error) => {
+                let status = Status::from(error);
+                for update in updates {
+                    let _ = update.reply.send(Err(status.clone()));
+                }
+            }
+        }
+    }
+
+    // Write the last accepted update of each leaf of the window, returning the proofs of the
+    // updated leaves against the new root.
+    async fn write_coalesced(
+        &self,
+        contract_id: &ContractId,
+        updates: &[PendingUpdate],
+    ) -> Result<
+        (
+            Coalesced,
+            HashMap<u64, MerkleProof<Hash, MERKLE_TREE_HEIGHT>>,
+        ),
+        Error,
+    > {
+        let _leaves = self.lock_leaves(contract_id).await;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?
+            .with_comment("coalesced");
+        if self.self_check_contracts.contains(contract_id) {
+            collection = collection.with_self_check(self.self_check_failures.clone());
+        }
+        let mut current = HashMap::new();
+        for update in updates {
+            let index = update.leaf.index();
+            if !current.contains_key(&index) {
+                let (_, proof) = collection.get_leaf_and_proof(index).await?;
+                current.insert(index, proof.source);
+            }
+        }
+        let coalesced = coalesce(updates, &current);
+        let mut root = None;
+        for (position, force) in coalesced.writes.values() {
+            let proof = collection
+                .set_leaf_and_get_proof(&updates[*position].leaf, *force, None)
+                .await?;
+            root = Some(proof.root);
+        }
+        // Written even if the leaf is unchanged, as in `handle_set_leaf`.
+        let mut metadata = BTreeMap::new();
+        for (update, outcome) in updates.iter().zip(coalesced.outcomes.iter()) {
+            if let (Ok(()), Some(data)) = (outcome, update.metadata.as_ref()) {
+                metadata.insert(update.leaf.index(), data);
+            }
+        }
+        for (index, data) in metadata {
+            collection.set_leaf_metadata(index, data).await?;
+        }
+        collection.commit().await?;
+        let mut proofs = HashMap::new();
+        for index in coalesced.writes.keys() {
+            let (_, proof) = collection
+                .get_leaf_and_proof_at(*index, root.as_ref())
+                .await?;
+            proofs.insert(*index, proof);
+        }
+        self.coalesced_updates
+            .fetch_add(coalesced.merged() as u64, Ordering::Relaxed);
+        Ok((coalesced, proofs))
+    }
+
+    /// Set the leaves of the contract in order, each only if its current hash is the expected one
+    /// when given, the current hash being the one left by the previous leaves of the batch. If
+    /// any expectation fails, nothing is written and the first mismatch is returned as
+    /// `Error::LeafHashMismatch`. Returns the proofs of the leaves against the new root.
+    pub async fn set_leaves(
+        &self,
+        contract_id: &ContractId,
+        leaves: &[(MerkleRecord, Option<Hash>)],
+    ) -> Result<Vec<MerkleProof<Hash, MERKLE_TREE_HEIGHT>>, Status> {
+        self.check_writable()?;
+        self.check_freeze(contract_id, true, "batch").await?;
+        let _leaves = self.lock_leaves(contract_id).await;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?
+            .with_comment("batch");
+        if self.self_check_contracts.contains(contract_id) {
+            collection = collection.with_self_check(self.self_check_failures.clone());
+        }
+        let mut current = HashMap::new();
+        for (leaf, expected) in leaves {
+            let index = leaf.index();
+            if !current.contains_key(&index) {
+                let (_, proof) = collection.get_leaf_and_proof(index).await?;
+                current.insert(index, proof.source);
+            }
+            if let Some(expected) = expected {
+                if current[&index] != *expected {
+                    return Err(Error::LeafHashMismatch {
+                        index,
+                        expected: *expected,
+                        current: current[&index],
+                    }
+                    .into());
+                }
+            }
+            current.insert(index, leaf.hash());
+        }
+        let mut root = None;
+        for (leaf, _) in leaves {
+            let proof = collection.set_leaf_and_get_proof(leaf, false, None).await?;
+            root = Some(proof.root);
+        }
+        collection.commit().await.map_err(Error::from)?;
+        let mut proofs = vec![];
+        for (leaf, _) in leaves {
+            let (_, proof) = collection
+                .get_leaf_and_proof_at(leaf.index(), root.as_ref())
+                .await?;
+            proofs.push(proof);
+        }
+        Ok(proofs)
+    }
+
+    /// Serve the reads presenting a session token at the root pinned by `BeginReadSession` for
+    /// `ttl`, after which the reads fail with `FAILED_PRECONDITION`. Sessions are kept in the
+    /// memory of this server, so all the reads of a session must be sent to the same server.
+    pub fn with_read_session_ttl(mut self, ttl: Duration) -> Self {
+        self.read_session_ttl = ttl;
+        self
+    }
+
+    /// The roots pinned by the open read sessions of the contract, which must be kept by any
+    /// pruning of the merkle records.
+    pub fn read_session_roots(&self, contract_id: &ContractId) -> Vec<Hash> {
+        let now = Instant::now();
+        self.read_sessions
+            .lock()
+            .expect("Read sessions lock")
+            .values()
+            .filter(|session| session.contract_id == *contract_id && session.expires > now)
+            .map(|session| session.root)
+            .collect()
+    }
+
+    // Pin the given root of the contract for the ttl, returning the session token.
+    fn begin_read_session(&self, contract_id: ContractId, root: Hash) -> Vec<u8> {
+        let token = rand::random::<[u8; 16]>().to_vec();
+        let now = Instant::now();
+        let mut sessions = self.read_sessions.lock().expect("Read sessions lock");
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            token.clone(),
+            ReadSession {
+                contract_id,
+                root,
+                expires: now + self.read_session_ttl,
+            },
+        );
+        token
+    }
+
+    // The root to read at, either given explicitly or pinned by a read session of the contract,
+    // the current root if neither is given.
+    fn read_root(
+        &self,
+        contract_id: &ContractId,
+        root: Option<Vec<u8>>,
+        read_session: Option<Vec<u8>>,
+    ) -> Result<Option<Hash>, Status> {
+        match (root, read_session) {
+            (Some(_), Some(_)) => Err(Status::invalid_argument(
+                "Only one of root and read session can be given",
+            )),
+            (Some(root), None) => Ok(Some(Hash::try_from(root.as_slice())?)),
+            (None, Some(token)) => {
+                let sessions = self.read_sessions.lock().expect("Read sessions lock");
+                match sessions.get(&token) {
+                    Some(session)
+                        if session.contract_id == *contract_id
+                            && session.expires > Instant::now() =>
+                    {
+                        Ok(Some(session.root))
+                    }
+                    _ => Err(Status::failed_precondition(
+                        "Read session not found, it may have expired",
+                    )),
+                }
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    // Wait for the other leaf updates of the contract to be done, and hold off the next ones until
+    // the guard is dropped, so that an expected leaf hash is checked and the leaf written with no
+    // other update in between. Only the updates served by this process are serialized.
+    async fn lock_leaves(&self, contract_id: &ContractId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.leaf_locks.lock().unwrap();
+            match locks.get(contract_id).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    // Drop the locks of the contracts no longer updated.
+                    locks.retain(|_, lock| lock.strong_count() > 0);
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(*contract_id, Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    // Wait for a permit to use the storage, must be held until the storage is no longer used.
+    async fn acquire_permit<T>(
+        &self,
+        request: &Request<T>,
+        write: bool,
+    ) -> Result<Option<OwnedSemaphorePermit>, Status> {
+        let permits = if write {
+            &self.write_permits
+        } else {
+            &self.read_permits
+        };
+        if permits.is_none() {
+            return Ok(None);
+        }
+        let start = Instant::now();
+        let permit = acquire_permit(permits, request).await;
+        let shed =
+            matches!(&permit, Err(status) if status.code() == tonic::Code::ResourceExhausted);
+        if permit.is_ok() || shed {
+            self.permit_wait.record(start.elapsed(), !shed);
+        }
+        permit
+    }
+
+    /// Reject all the mutating requests with `FAILED_PRECONDITION`, used by replicas which only
+    /// serve reads. Note that the read preference of the MongoDB clients is only changed when
+    /// read-only mode is enabled with the environment variable `KVPAIR_READ_ONLY`.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Fail with `Error::Frozen` if the contract is frozen for the request. Called before taking a
+    // permit, so that the rejected requests neither hold nor wait for one, with the freeze
+    // read at most once per `FREEZE_CACHE_TTL`.
+    async fn check_freeze(
+        &self,
+        contract_id: &ContractId,
+        write: bool,
+        request_id: &str,
+    ) -> Result<(), Error> {
+        let cached = self
+            .freezes
+            .lock()
+            .expect("Freezes lock")
+            .get(contract_id)
+            .filter(|(read, _)| read.elapsed() < FREEZE_CACHE_TTL)
+            .map(|(_, freeze)| freeze.clone());
+        let freeze = match cached {
+            Some(freeze) => freeze,
+            None => {
+                let mut collection = self
+                    .new_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+                    .await?
+                    .with_comment(request_id);
+                let freeze = collection.get_freeze().await?;
+                self.cache_freeze(contract_id, freeze.clone());
+                freeze
+            }
+        };
+        match freeze {
+            Some(freeze) => freeze.check(write),
+            None => Ok(()),
+        }
+    }
+
+    // Cache the freeze read for the contract, dropping the expired freezes, and then the oldest
+    // one, when the cache is full.
+    fn cache_freeze(&self, contract_id: &ContractId, freeze: Option<Freeze>) {
+        let mut freezes = self.freezes.lock().expect("Freezes lock");
+        if !freezes.contains_key(contract_id) && freezes.len() >= self.freeze_cache_capacity {
+            freezes.retain(|_, (read, _)| read.elapsed() < FREEZE_CACHE_TTL);
+            if freezes.len() >= self.freeze_cache_capacity {
+                let oldest = freezes
+                    .iter()
+                    .min_by_key(|(_, (read, _))| *read)
+                    .map(|(contract_id, _)| *contract_id);
+                if let Some(oldest) = oldest {
+                    freezes.remove(&oldest);
+                }
+            }
+        }
+        freezes.insert(*contract_id, (Instant::now(), freeze));
+    }
+
+    // Must be called by mutating requests before touching the storage.
+    fn check_writable(&self) -> Result<(), Status> {
+        if self.read_only {
+            return Err(Status::failed_precondition(
+                "Server is in read-only mode, mutating requests are not allowed",
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn new_collection<T, R>(
+        &self,
+        contract_id: &ContractId,
+        with_session: bool,
+    ) -> Result<MongoCollection<T, R>, Error> {
+        let (client, database_name) = match self.placements.get(contract_id) {
+            Some(placement) => (
+                self.clients[&placement.uri].clone(),
+                placement.database.clone(),
+            ),
+            None => (
+                self.client.clone(),
+                MongoCollection::<T, R>::get_database_name(),
+            ),
+        };
+        Ok(
+            MongoCollection::new(client, &database_name, contract_id, with_session)
+                .await?
+                .with_skipped_updates(self.skipped_updates.clone()),
+        )
+    }
+
+    pub async fn drop_test_collection(&self) -> Result<(), Error> {
+        if let Some(test_config) = &self.test_config {
+            let collection = self
+                .new_collection::<MerkleRecord, DataHashRecord>(&test_config.contract_id, false)
+                .await?;
+            collection.drop().await?;
+        }
+        Ok(())
+    }
+
+    // Validate the contract id passed from http request or gRPC request parameter.
     // TODO: This function does nothing yet.
     fn validate_contract_id<T>(
         &self,
@@ -523,7 +1903,6 @@ impl MongoKvPair {
             .map_err(|e| Status::unauthenticated(format!("Invalid Contract id: {e}")))?
             .try_into()
             .map_err(|e| Status::unauthenticated(format!("Invalid Contract id: {e}")))?;
-        dbg!(&contract_id);
         self.validate_contract_id(request, &contract_id)?;
         Ok(contract_id)
     }
@@ -569,47 +1948,219 @@ impl MongoKvPair {
     }
 }
 
-#[tonic::async_trait]
-impl KvPair for MongoKvPair {
-    async fn get_root(
+// The next batch of the changes of `DiffRoots`, with the data of the leaves if asked for.
+async fn diff_roots_batch(
+    collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+    stack: &mut Vec<(u64, usize, Hash, Hash)>,
+    with_data: bool,
+    cancel: &AtomicBool,
+) -> Result<Vec<LeafChange>, Error> {
+    let mut changes = vec![];
+    for (index, old_hash, new_hash) in collection
+        .next_changed_leaves(stack, DIFF_ROOTS_BATCH, cancel)
+        .await?
+    {
+        let (mut old_data, mut new_data) = (None, None);
+        if with_data {
+            old_data = collection
+                .get_datahash_record(&old_hash)
+                .await?
+                .map(|record| record.data);
+            new_data = collection
+                .get_datahash_record(&new_hash)
+                .await?
+                .map(|record| record.data);
+        }
+        changes.push(LeafChange {
+            index,
+            old_hash: old_hash.into(),
+            new_hash: new_hash.into(),
+            old_data,
+            new_data,
+        });
+    }
+    Ok(changes)
+}
+
+// Set the flag when dropped, e.g. along with the response stream of a request whose client went
+// away, so that the walk serving it stops at the next node instead of at the next message.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// Use the request id passed by the client in the `x-request-id` header, or generate a new one.
+fn get_request_id<T>(request: &Request<T>) -> String {
+    request
+        .metadata()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+// Wait for a permit if the permits are bounded, failing if the wait would exceed the deadline.
+async fn acquire_permit<T>(
+    permits: &Option<Arc<Semaphore>>,
+    request: &Request<T>,
+) -> Result<Option<OwnedSemaphorePermit>, Status> {
+    let permits = match permits {
+        Some(permits) => permits.clone(),
+        None => return Ok(None),
+    };
+    let acquire = permits.acquire_owned();
+    let permit = match get_request_timeout(request) {
+        Some(timeout) => tokio::time::timeout(timeout, acquire)
+            .await
+            .map_err(|_| Status::resource_exhausted("Too many concurrent requests"))?,
+        None => acquire.await,
+    };
+    permit
+        .map(Some)
+        .map_err(|_| Status::unavailable("Server is shutting down"))
+}
+
+// Parse the deadline set by the client in the `grpc-timeout` header, e.g. `100m` for 100ms.
+fn get_request_timeout<T>(request: &Request<T>) -> Option<Duration> {
+    let timeout = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    let (value, unit) = timeout.split_at(timeout.len().checked_sub(1)?);
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value * 60 * 60)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+// Return the request id in the response metadata, also add it to the error message so that
+// clients can report it.
+fn attach_request_id<T>(
+    result: std::result::Result<Response<T>, Status>,
+    request_id: &str,
+) -> std::result::Result<Response<T>, Status> {
+    let value: MetadataValue<_> = request_id.parse().expect("Valid request id");
+    match result {
+        Ok(mut response) => {
+            response.metadata_mut().insert("x-request-id", value);
+            Ok(response)
+        }
+        Err(status) => {
+            let mut metadata = status.metadata().clone();
+            metadata.insert("x-request-id", value);
+            Err(Status::with_details_and_metadata(
+                status.code(),
+                format!("{} (request id: {})", status.message(), request_id),
+                Bytes::copy_from_slice(status.details()),
+                metadata,
+            ))
+        }
+    }
+}
+
+/// The gRPC reflection service describing the KVPair service, so that tools such as grpcurl
+/// can list and call its methods without the proto files.
+pub fn reflection_service() -> ServerReflectionServer<impl ServerReflection> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()
+        .expect("Valid file descriptor set")
+}
+
+/// The KVPair gRPC service of `server`. With `compression`, replies are gzip compressed for the
+/// clients advertising gzip in `grpc-accept-encoding` and gzip compressed requests are accepted,
+/// while the other clients still get uncompressed replies.
+/// tonic 0.9 only ships gzip and compresses all the methods of a service alike, zstd and a
+/// per-method opt-in need tonic 0.10.
+pub fn kvpair_server(server: MongoKvPair, compression: bool) -> KvPairServer<MongoKvPair> {
+    let server = KvPairServer::new(server);
+    if compression {
+        server
+            .send_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Gzip)
+    } else {
+        server
+    }
+}
+
+// Request handlers, the request id is used to correlate logs, errors and MongoDB operations.
+impl MongoKvPair {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_get_root(
         &self,
         request: Request<GetRootRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<GetRootResponse>, Status> {
-        dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
-        let mut collection = self.new_collection(&contract_id, false).await?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let _permit = self.acquire_permit(&request, false).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
         let record = collection.must_get_root_merkle_record().await?;
         Ok(Response::new(GetRootResponse {
             root: record.hash().into(),
         }))
     }
 
-    async fn set_root(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_set_root(
         &self,
         request: Request<SetRootRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<SetRootResponse>, Status> {
-        dbg!(&request);
+        self.check_writable()?;
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, true, request_id).await?;
+        let _permit = self.acquire_permit(&request, true).await?;
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let hash: Hash = request.hash.as_slice().try_into()?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let hash: Hash = request.hash.try_into()?;
         let record = collection.must_get_merkle_record(0, &hash).await?;
-        dbg!(&record);
         collection.update_root_merkle_record(&record).await?;
         Ok(Response::new(SetRootResponse {
             root: record.hash.into(),
         }))
     }
 
-    async fn get_leaf(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_get_leaf(
         &self,
         request: Request<GetLeafRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<GetLeafResponse>, Status> {
-        dbg!(&request);
+        // Reject indices out of the tree and unknown proof types before any storage access.
+        let index = leaf_index(request.get_ref().index)?;
+        proof_type(request.get_ref().proof_type)?;
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let _permit = self.acquire_permit(&request, false).await?;
+        self.hotspots.record(contract_id, Some(index), false);
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let index = request.index;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let root = self.read_root(&contract_id, request.root, request.read_session)?;
         let proof_v0 = ProofType::ProofV0 as i32;
         let (mut record, proof) = match (request.hash.as_ref(), request.proof_type) {
             // Get merkle records in a faster way
@@ -619,64 +2170,101 @@ impl KvPair for MongoKvPair {
                 (record, None)
             }
             (_, _) => {
-                let (record, proof) = collection.get_leaf_and_proof(index).await?;
+                let (record, proof) = collection
+                    .get_leaf_and_proof_at(index, root.as_ref())
+                    .await?;
                 if request.hash.is_some() {
-                    let hash: Hash = request.hash.unwrap().as_slice().try_into()?;
+                    let hash: Hash = request.hash.unwrap().try_into()?;
                     if hash != proof.source {
-                        return Err(
-                            Error::InvalidArgument("Leaf not in current root".to_string()).into(),
-                        );
+                        return Err(Error::InvalidArgument(format!(
+                            "Leaf not in {} root",
+                            if root.is_some() {
+                                "the given"
+                            } else {
+                                "current"
+                            }
+                        ))
+                        .into());
                     }
                 }
-                let proof_bytes = if request.proof_type == proof_v0 {
-                    Some(Proof {
-                        proof_type: request.proof_type,
-                        proof: bincode::serialize(&proof).unwrap(),
-                    })
-                } else {
-                    None
-                };
-                dbg!(&record, &proof_bytes);
+                let proof_bytes = proof_of_type(request.proof_type, &proof)?;
                 (record, proof_bytes)
             }
         };
         // We now use [0u8; 32] to represent empty node hash, since
         if record.hash == Hash::get_default_hash_for_depth(MERKLE_TREE_HEIGHT).unwrap() {
-            record.hash = [0u8; 32].try_into().unwrap();
+            record.hash = Hash::empty();
         }
-        let datahash_record = collection.get_datahash_record(&record.hash()).await?;
-        dbg!(&record, &proof, &datahash_record);
-        let node = match datahash_record {
-            Some(datahash_record) => (record, datahash_record).try_into()?,
-            // If the datahash record corresponding to this hash does not exists,
-            // then we assume the actual data is stored inline to the merkle record.
-            None => Node::new_simple_leaf(record.index(), record.hash()),
+        let node = if request.skip_data {
+            Node::new_leaf_without_data(record.index(), record.hash())
+        } else {
+            match collection.get_datahash_record(&record.hash()).await? {
+                Some(datahash_record) => (record, datahash_record).try_into()?,
+                // If the datahash record corresponding to this hash does not exists,
+                // then we assume the actual data is stored inline to the merkle record.
+                None => Node::new_simple_leaf(record.index(), record.hash()),
+            }
         };
-        dbg!(&node);
+        let metadata = collection.get_leaf_metadata(index).await?;
         collection.commit().await.map_err(Error::from)?;
         Ok(Response::new(GetLeafResponse {
             node: Some(node),
             proof,
+            metadata,
         }))
     }
 
-    async fn set_leaf(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_set_leaf(
         &self,
         request: Request<SetLeafRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<SetLeafResponse>, Status> {
-        dbg!(&request);
+        let index = leaf_index(request.get_ref().index)?;
+        proof_type(request.get_ref().proof_type)?;
+        self.check_writable()?;
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, true, request_id).await?;
+        let _permit = self.acquire_permit(&request, true).await?;
+        self.hotspots.record(contract_id, Some(index), true);
         let request = request.into_inner();
         // TODO: Should use session here
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let index = request.index;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        if self.self_check_contracts.contains(&contract_id) {
+            collection = collection.with_self_check(self.self_check_failures.clone());
+        }
+        let expected_leaf_hash = request
+            .expected_leaf_hash
+            .map(|hash| Hash::try_from(hash.as_slice()))
+            .transpose()?;
+        if let Some(metadata) = request.metadata.as_ref() {
+            if metadata.len() > MAX_LEAF_METADATA_LEN {
+                return Err(Error::InvalidArgument(format!(
+                    "Leaf metadata of {} bytes exceeds {MAX_LEAF_METADATA_LEN} bytes",
+                    metadata.len()
+                ))
+                .into());
+            }
+        }
 
         let (merkle_record, node): (MerkleRecord, Node) = match (request.data, request.hash) {
             (Some(data), hash) => {
-                let hash = if let Some(hash) = hash {
-                    hash.try_into()?
+                let (data, hash) = if let Some(hash) = hash {
+                    (data, hash.try_into()?)
                 } else {
-                    crate::poseidon::hash(&data)?.try_into().unwrap()
+                    let (data, hash) = self
+                        .hash_data(data.len(), move || {
+                            let hash = crate::poseidon::hash(&data);
+                            (data, hash)
+                        })
+                        .await?;
+                    (data, hash?.try_into()?)
                 };
                 let merkle_record = MerkleRecord::new_leaf(index, hash);
 
@@ -702,84 +2290,537 @@ impl KvPair for MongoKvPair {
             }
         };
 
-        dbg!(&merkle_record);
-        let proof = collection.set_leaf_and_get_proof(&merkle_record).await?;
-        let proof = if request.proof_type == ProofType::ProofV0 as i32 {
-            Some(Proof {
-                proof_type: request.proof_type,
-                proof: bincode::serialize(&proof).unwrap(),
-            })
-        } else {
-            None
-        };
+        if let Some(window) = self.coalesce_windows.get(&contract_id) {
+            let proof = self
+                .set_leaf_coalesced(
+                    contract_id,
+                    window.clone(),
+                    merkle_record,
+                    request.force,
+                    expected_leaf_hash,
+                    request.metadata,
+                )
+                .await?;
+            let proof = proof_of_type(request.proof_type, &proof)?;
+            return Ok(Response::new(SetLeafResponse {
+                node: Some(node),
+                proof,
+            }));
+        }
+        // Held until the leaf and its metadata are written.
+        let _leaves = self.lock_leaves(&contract_id).await;
+        let proof = collection
+            .set_leaf_and_get_proof(&merkle_record, request.force, expected_leaf_hash.as_ref())
+            .await?;
+        // Written even if the leaf is unchanged, as the metadata is not part of its hash.
+        if let Some(metadata) = request.metadata.as_ref() {
+            collection.set_leaf_metadata(index, metadata).await?;
+        }
+        let proof = proof_of_type(request.proof_type, &proof)?;
         collection.commit().await.map_err(Error::from)?;
-        dbg!(&node);
         Ok(Response::new(SetLeafResponse {
             node: Some(node),
             proof,
         }))
     }
 
-    async fn get_non_leaf(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_get_range_proof(
+        &self,
+        request: Request<GetRangeProofRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<GetRangeProofResponse>, Status> {
+        // Reject ranges out of the tree before any storage access.
+        let start = leaf_index(request.get_ref().start)?;
+        let end = request.get_ref().end;
+        if end <= start {
+            return Err(
+                Error::InvalidArgument(format!("Empty leaf range [{start}, {end})")).into(),
+            );
+        }
+        let last = leaf_index(end - 1)?;
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let _permit = self.acquire_permit(&request, false).await?;
+        self.hotspots.record(contract_id, None, false);
+        let request = request.into_inner();
+        let root = self.read_root(&contract_id, request.root, request.read_session)?;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let (_, first_proof) = collection
+            .get_leaf_and_proof_at(start, root.as_ref())
+            .await?;
+        // Pin the second walk to the root of the first one, which may have been the current root.
+        let (_, last_proof) = collection
+            .get_leaf_and_proof_at(last, Some(&first_proof.root))
+            .await?;
+        let proof =
+            MerkleRangeProof::from_boundary_proofs(start, request.end, &first_proof, &last_proof)
+                .map_err(Error::from)?;
+        Ok(Response::new(GetRangeProofResponse {
+            proof: Some(proof.into()),
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_begin_read_session(
+        &self,
+        request: Request<BeginReadSessionRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<BeginReadSessionResponse>, Status> {
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let _permit = self.acquire_permit(&request, false).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let root = collection.must_get_root_merkle_record().await?.hash();
+        let read_session = self.begin_read_session(contract_id, root);
+        Ok(Response::new(BeginReadSessionResponse {
+            root: root.into(),
+            read_session,
+            ttl_ms: self.read_session_ttl.as_millis() as u64,
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_get_non_leaf(
         &self,
         request: Request<GetNonLeafRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<GetNonLeafResponse>, Status> {
-        dbg!(&request);
+        let index = non_leaf_index(request.get_ref().index)?;
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let _permit = self.acquire_permit(&request, false).await?;
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let index = request.index;
-        let hash: Hash = request.hash.as_slice().try_into()?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let hash: Hash = request.hash.try_into()?;
         let record = collection.must_get_merkle_record(index, &hash).await?;
-        dbg!(&record);
         let node = record.try_into()?;
-        dbg!(&node);
         Ok(Response::new(GetNonLeafResponse { node: Some(node) }))
     }
 
-    async fn set_non_leaf(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_set_non_leaf(
         &self,
         request: Request<SetNonLeafRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<SetNonLeafResponse>, Status> {
-        dbg!(&request);
+        let index = non_leaf_index(request.get_ref().index)?;
+        self.check_writable()?;
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, true, request_id).await?;
+        let _permit = self.acquire_permit(&request, true).await?;
         let request = request.into_inner();
         // TODO: Should use session here
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let index = request.index;
-        let left: Hash = request.left_child_hash.as_slice().try_into()?;
-        let right: Hash = request.right_child_hash.as_slice().try_into()?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let left: Hash = request.left_child_hash.try_into()?;
+        let right: Hash = request.right_child_hash.try_into()?;
         if let Some(hash) = request.hash {
-            Hash::validate_children(&hash.as_slice().try_into()?, &left, &right)?;
+            Hash::validate_children(&hash.try_into()?, &left, &right)?;
         }
         let record = collection.insert_non_leaf_node(index, left, right).await?;
-        dbg!(&record);
         let node = record.try_into()?;
-        dbg!(&node);
         Ok(Response::new(SetNonLeafResponse { node: Some(node) }))
     }
 
-    async fn poseidon_hash(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_poseidon_hash(
         &self,
         request: Request<PoseidonHashRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<PoseidonHashResponse>, Status> {
-        dbg!(&request);
         let _contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
         let request = request.into_inner();
         // TODO: Should use session here
         let data_to_hash = request.data;
-        let hash = crate::poseidon::hash(&data_to_hash)?;
+        let hash = self
+            .hash_data(data_to_hash.len(), move || {
+                crate::poseidon::hash(&data_to_hash)
+            })
+            .await??;
         Ok(Response::new(PoseidonHashResponse { hash: hash.into() }))
     }
 
-    async fn data_hash_record(
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_compute_hash(
+        &self,
+        request: Request<ComputeHashRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<ComputeHashResponse>, Status> {
+        let _permit = acquire_permit(&self.hash_permits, &request).await?;
+        let request = request.into_inner();
+        let len = request.data.len();
+        let hash: Hash = match HashScheme::from_i32(request.scheme) {
+            Some(HashScheme::HashSchemeFieldElements) => self
+                .hash_data(len, move || crate::poseidon::hash(&request.data))
+                .await??
+                .try_into()?,
+            Some(HashScheme::HashSchemePadded) => self
+                .hash_data(len, move || {
+                    crate::poseidon::hash_with_padding(&request.data)
+                })
+                .await??
+                .try_into()?,
+            Some(HashScheme::HashSchemeLeafValue) => {
+                crate::poseidon::hash_leaf_value(&request.data)?
+            }
+            Some(HashScheme::HashSchemeMerkleLeaf) if request.data.len() == 32 => {
+                Hash::hash_data(&request.data)
+            }
+            Some(HashScheme::HashSchemeMerkleLeaf) => {
+                return Err(Status::invalid_argument(
+                    "Merkle leaf scheme requires exactly 32 bytes",
+                ))
+            }
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "Invalid hash scheme, given {}",
+                    request.scheme
+                )))
+            }
+        };
+        Ok(Response::new(ComputeHashResponse { hash: hash.into() }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_compute_pair_hash(
+        &self,
+        request: Request<ComputePairHashRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<ComputePairHashResponse>, Status> {
+        let _permit = acquire_permit(&self.hash_permits, &request).await?;
+        let request = request.into_inner();
+        let left: Hash = request.left.try_into()?;
+        let right: Hash = request.right.try_into()?;
+        Ok(Response::new(ComputePairHashResponse {
+            hash: Hash::hash_children(&left, &right).into(),
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_get_server_info(
+        &self,
+        request: Request<GetServerInfoRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<GetServerInfoResponse>, Status> {
+        let mut features = vec![];
+        if cfg!(feature = "tracing") {
+            features.push("tracing".to_string());
+        }
+        Ok(Response::new(GetServerInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: env!("GIT_HASH").to_string(),
+            proof_types: vec![ProofType::ProofEmpty.into(), ProofType::ProofV0.into()],
+            features,
+            backend: "mongodb".to_string(),
+            depth: MERKLE_TREE_HEIGHT as u32,
+            read_only: self.read_only,
+            skipped_unchanged_updates: self.skipped_unchanged_updates(),
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_get_hotspots(
+        &self,
+        request: Request<GetHotspotsRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<GetHotspotsResponse>, Status> {
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let request = request.into_inner();
+        let window = match request.window_secs {
+            0 => DEFAULT_HOTSPOT_WINDOW,
+            secs => Duration::from_secs(secs.into()).min(HOTSPOT_MAX_WINDOW),
+        };
+        let limit = match request.limit {
+            0 => DEFAULT_HOTSPOT_LIMIT,
+            limit => (limit as usize).min(HOTSPOT_TOP_K),
+        };
+        let leaves = self
+            .hotspots
+            .top_leaves(&contract_id, window, limit)
+            .into_iter()
+            .map(|(index, count)| LeafAccesses { index, count })
+            .collect();
+        let contracts = self
+            .hotspots
+            .contracts(window)
+            .into_iter()
+            .map(|accesses| ContractAccesses {
+                contract_id: accesses.contract_id.into(),
+                reads: accesses.reads,
+                writes: accesses.writes,
+            })
+            .collect();
+        Ok(Response::new(GetHotspotsResponse {
+            leaves,
+            contracts,
+            window_secs: window.as_secs() as u32,
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_diff_roots(
+        &self,
+        request: Request<DiffRootsRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<DiffRootsResponseStream>, Status> {
+        let old_root = Hash::try_from(request.get_ref().old_root.as_slice())?;
+        let new_root = Hash::try_from(request.get_ref().new_root.as_slice())?;
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let permit = self.acquire_permit(&request, false).await?;
+        let request = request.into_inner();
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        // Roots are not stored with their depth, so both are assumed to have the depth of the
+        // current root.
+        collection.check_depth().await?;
+        for root in [&old_root, &new_root] {
+            if collection.get_merkle_record(0, root).await?.is_none() {
+                return Err(Status::not_found(format!(
+                    "Root {} not found",
+                    hex::encode(root.0)
+                )));
+            }
+        }
+
+        // The walk runs in a task of its own, so that the changes are sent as they are found,
+        // and is cancelled when the response stream is dropped, i.e. when the client goes away.
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let guard = CancelOnDrop(cancel.clone());
+        let walk = async move {
+            let _permit = permit;
+            let mut stack = vec![(0, 0, old_root, new_root)];
+            while !stack.is_empty() {
+                let batch =
+                    diff_roots_batch(&mut collection, &mut stack, request.with_data, &cancel);
+                let response = match batch.await {
+                    // Only when the last pairs of nodes left are equal.
+                    Ok(changes) if changes.is_empty() => continue,
+                    Ok(changes) => Ok(DiffRootsResponse { changes }),
+                    Err(e) => Err(Status::from(e)),
+                };
+                let failed = response.is_err();
+                if tx.send(response).await.is_err() || failed {
+                    break;
+                }
+            }
+        };
+        // The walk logs under the span of the request.
+        #[cfg(feature = "tracing")]
+        let walk = tracing::Instrument::in_current_span(walk);
+        tokio::spawn(walk);
+        let stream: DiffRootsResponseStream = Box::pin(futures::stream::unfold(
+            (rx, guard),
+            |(mut rx, guard)| async move { rx.recv().await.map(|response| (response, (rx, guard))) },
+        ));
+        Ok(Response::new(stream))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_describe_index(
+        &self,
+        request: Request<DescribeIndexRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<DescribeIndexResponse>, Status> {
+        let mut response = describe_index(request.get_ref().index);
+        if request.get_ref().contract_id.is_some() {
+            let _permit = self.acquire_permit(&request, false).await?;
+            let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+            let mut collection = self
+                .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+                .await?
+                .with_comment(request_id);
+            response.stored_depth = collection.get_stored_depth().await?;
+        }
+        Ok(Response::new(response))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_freeze_contract(
+        &self,
+        request: Request<FreezeContractRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<FreezeContractResponse>, Status> {
+        self.check_writable()?;
+        let _permit = self.acquire_permit(&request, true).await?;
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let request = request.into_inner();
+        let mode = match FreezeMode::from_i32(request.mode) {
+            Some(mode @ (FreezeMode::FreezeWrite | FreezeMode::FreezeFull)) => mode,
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "Invalid freeze mode, write or full expected, given {}",
+                    request.mode
+                )))
+            }
+        };
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        collection.set_freeze(Some((mode, &request.reason))).await?;
+        self.freezes
+            .lock()
+            .expect("Freezes lock")
+            .remove(&contract_id);
+        Ok(Response::new(FreezeContractResponse {}))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_unfreeze_contract(
+        &self,
+        request: Request<UnfreezeContractRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<UnfreezeContractResponse>, Status> {
+        self.check_writable()?;
+        let _permit = self.acquire_permit(&request, true).await?;
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        collection.set_freeze(None).await?;
+        self.freezes
+            .lock()
+            .expect("Freezes lock")
+            .remove(&contract_id);
+        Ok(Response::new(UnfreezeContractResponse {}))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_debug_verify(
+        &self,
+        request: Request<DebugVerifyRequest>,
+        request_id: &str,
+    ) -> std::result::Result<Response<DebugVerifyResponse>, Status> {
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, false, request_id).await?;
+        let _permit = self.acquire_permit(&request, false).await?;
+        let request = request.into_inner();
+        let proof: MerkleProof<Hash, MERKLE_TREE_HEIGHT> = request
+            .proof
+            .ok_or_else(|| Status::invalid_argument("Proof is required"))?
+            .try_into()?;
+        let steps = proof
+            .fold_steps(Hash::hash_children)
+            .map_err(|e| Error::InvalidArgument(format!("Proof malformed: {e}")))?;
+        let computed_root = steps.last().map_or(proof.source, |step| step.hash);
+
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_comment(request_id);
+        let root = collection.get_merkle_record(0, &proof.root).await?;
+        let root_known = root.is_some();
+        // Walk down the path of the stored tree, comparing each node with its recomputed hash.
+        // All the nodes above a wrong hash in the proof differ, so we keep the lowest one.
+        let mut divergent_index = None;
+        if let Some(mut node) = root {
+            if computed_root != proof.root {
+                divergent_index = Some(0);
+            }
+            let computed = steps
+                .iter()
+                .rev()
+                .skip(1)
+                .map(|step| (step.index, step.hash))
+                .chain(std::iter::once((proof.index, proof.source)));
+            for (index, hash) in computed {
+                let stored = if index % 2 == 1 {
+                    node.left
+                } else {
+                    node.right
+                };
+                if stored != hash {
+                    divergent_index = Some(index);
+                }
+                node = collection.must_get_merkle_record(index, &stored).await?;
+            }
+        }
+        Ok(Response::new(DebugVerifyResponse {
+            steps: steps.into_iter().map(Into::into).collect(),
+            computed_root: computed_root.into(),
+            valid: computed_root == proof.root,
+            root_known,
+            divergent_index,
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(request_id = %request_id))
+    )]
+    async fn handle_data_hash_record(
         &self,
         request: Request<DataHashRecordRequest>,
+        request_id: &str,
     ) -> std::result::Result<Response<DataHashRecordResponse>, Status> {
-        dbg!(&request);
+        let write = request.get_ref().mode == Some(DataHashRecordMode::ModeStore as i32);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        self.check_freeze(&contract_id, write, request_id).await?;
+        let _permit = self.acquire_permit(&request, write).await?;
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_comment(request_id);
         let record = match request.mode {
             Some(mode) if mode == DataHashRecordMode::ModeFetch as i32 => match request.hash {
                 Some(hash) => {
@@ -790,10 +2831,10 @@ impl KvPair for MongoKvPair {
                 _ => return Err(Status::invalid_argument("Hash is required for fetch mode")),
             },
             Some(mode) if mode == DataHashRecordMode::ModeStore as i32 => {
+                self.check_writable()?;
                 match (request.data, request.hash) {
                     (Some(data), Some(hash)) => {
                         let record = DataHashRecord::new(hash.try_into()?, data);
-                        dbg!(&record);
                         collection.insert_datahash_record(&record).await?;
                         record
                     }
@@ -817,3 +2858,179 @@ impl KvPair for MongoKvPair {
         }))
     }
 }
+
+#[tonic::async_trait]
+impl KvPair for MongoKvPair {
+    async fn get_root(
+        &self,
+        request: Request<GetRootRequest>,
+    ) -> std::result::Result<Response<GetRootResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_get_root(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn set_root(
+        &self,
+        request: Request<SetRootRequest>,
+    ) -> std::result::Result<Response<SetRootResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_set_root(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn get_leaf(
+        &self,
+        request: Request<GetLeafRequest>,
+    ) -> std::result::Result<Response<GetLeafResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_get_leaf(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn set_leaf(
+        &self,
+        request: Request<SetLeafRequest>,
+    ) -> std::result::Result<Response<SetLeafResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_set_leaf(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn get_range_proof(
+        &self,
+        request: Request<GetRangeProofRequest>,
+    ) -> std::result::Result<Response<GetRangeProofResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_get_range_proof(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn begin_read_session(
+        &self,
+        request: Request<BeginReadSessionRequest>,
+    ) -> std::result::Result<Response<BeginReadSessionResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_begin_read_session(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn get_non_leaf(
+        &self,
+        request: Request<GetNonLeafRequest>,
+    ) -> std::result::Result<Response<GetNonLeafResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_get_non_leaf(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn set_non_leaf(
+        &self,
+        request: Request<SetNonLeafRequest>,
+    ) -> std::result::Result<Response<SetNonLeafResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_set_non_leaf(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn poseidon_hash(
+        &self,
+        request: Request<PoseidonHashRequest>,
+    ) -> std::result::Result<Response<PoseidonHashResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_poseidon_hash(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn data_hash_record(
+        &self,
+        request: Request<DataHashRecordRequest>,
+    ) -> std::result::Result<Response<DataHashRecordResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_data_hash_record(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn compute_hash(
+        &self,
+        request: Request<ComputeHashRequest>,
+    ) -> std::result::Result<Response<ComputeHashResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_compute_hash(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn compute_pair_hash(
+        &self,
+        request: Request<ComputePairHashRequest>,
+    ) -> std::result::Result<Response<ComputePairHashResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_compute_pair_hash(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn freeze_contract(
+        &self,
+        request: Request<FreezeContractRequest>,
+    ) -> std::result::Result<Response<FreezeContractResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_freeze_contract(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn unfreeze_contract(
+        &self,
+        request: Request<UnfreezeContractRequest>,
+    ) -> std::result::Result<Response<UnfreezeContractResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_unfreeze_contract(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn debug_verify(
+        &self,
+        request: Request<DebugVerifyRequest>,
+    ) -> std::result::Result<Response<DebugVerifyResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_debug_verify(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn describe_index(
+        &self,
+        request: Request<DescribeIndexRequest>,
+    ) -> std::result::Result<Response<DescribeIndexResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_describe_index(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn get_server_info(
+        &self,
+        request: Request<GetServerInfoRequest>,
+    ) -> std::result::Result<Response<GetServerInfoResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_get_server_info(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    async fn get_hotspots(
+        &self,
+        request: Request<GetHotspotsRequest>,
+    ) -> std::result::Result<Response<GetHotspotsResponse>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_get_hotspots(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+
+    type DiffRootsStream = DiffRootsResponseStream;
+
+    async fn diff_roots(
+        &self,
+        request: Request<DiffRootsRequest>,
+    ) -> std::result::Result<Response<Self::DiffRootsStream>, Status> {
+        let request_id = get_request_id(&request);
+        let result = self.handle_diff_roots(request, &request_id).await;
+        attach_request_id(result, &request_id)
+    }
+}
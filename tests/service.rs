@@ -1,36 +1,91 @@
+use zkc_state_manager::client::index_error_details_of;
+use zkc_state_manager::client::Client;
+use zkc_state_manager::errors::Error;
+use zkc_state_manager::kvpair::hash_to_bson;
+use zkc_state_manager::kvpair::u64_to_bson;
+use zkc_state_manager::kvpair::ContractId;
+use zkc_state_manager::kvpair::DataHashRecord;
 use zkc_state_manager::kvpair::Hash;
 use zkc_state_manager::kvpair::LeafData;
+use zkc_state_manager::kvpair::MerkleRecord;
 use zkc_state_manager::kvpair::DEFAULT_HASH_VEC;
 use zkc_state_manager::kvpair::MERKLE_TREE_HEIGHT;
+use zkc_state_manager::merkle::LeafIndex;
+use zkc_state_manager::merkle::MerkleProof;
+use zkc_state_manager::merkle::MerkleRangeProof;
+use zkc_state_manager::merkle::NodeIndex;
+use zkc_state_manager::poseidon;
 use zkc_state_manager::proto::kv_pair_client::KvPairClient;
-use zkc_state_manager::proto::kv_pair_server::KvPairServer;
 use zkc_state_manager::proto::node::NodeData;
+use zkc_state_manager::proto::ComputeHashRequest;
+use zkc_state_manager::proto::ComputePairHashRequest;
 use zkc_state_manager::proto::DataHashRecordMode;
 use zkc_state_manager::proto::DataHashRecordRequest;
+use zkc_state_manager::proto::DebugVerifyRequest;
+use zkc_state_manager::proto::DebugVerifyResponse;
+use zkc_state_manager::proto::DescribeIndexRequest;
+use zkc_state_manager::proto::DiffRootsRequest;
+use zkc_state_manager::proto::DiffRootsResponse;
+use zkc_state_manager::proto::FreezeContractRequest;
+use zkc_state_manager::proto::FreezeMode;
+use zkc_state_manager::proto::GetHotspotsRequest;
 use zkc_state_manager::proto::GetLeafRequest;
 use zkc_state_manager::proto::GetLeafResponse;
+use zkc_state_manager::proto::GetNonLeafRequest;
+use zkc_state_manager::proto::GetRangeProofRequest;
 use zkc_state_manager::proto::GetRootRequest;
 use zkc_state_manager::proto::GetRootResponse;
+use zkc_state_manager::proto::GetServerInfoRequest;
+use zkc_state_manager::proto::HashScheme;
+use zkc_state_manager::proto::LeafAccesses;
 use zkc_state_manager::proto::NodeType;
 use zkc_state_manager::proto::PoseidonHashRequest;
 use zkc_state_manager::proto::PoseidonHashResponse;
 use zkc_state_manager::proto::ProofType;
 use zkc_state_manager::proto::SetLeafRequest;
 use zkc_state_manager::proto::SetLeafResponse;
+use zkc_state_manager::proto::UnfreezeContractRequest;
+use zkc_state_manager::service::kvpair_server;
+use zkc_state_manager::service::reflection_service;
+use zkc_state_manager::service::ApiKeyInterceptor;
+use zkc_state_manager::service::MerkleServiceBuilder;
+use zkc_state_manager::service::MongoCollection;
 use zkc_state_manager::service::MongoKvPair;
 use zkc_state_manager::service::MongoKvPairTestConfig;
+use zkc_state_manager::service::MongoPlacement;
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::{channel::oneshot, FutureExt};
+use mongodb::bson::Document;
+use prost::Message;
 use rand::{thread_rng, RngCore};
 use tempfile::NamedTempFile;
 use tokio::net::{UnixListener, UnixStream};
 use tokio_stream::wrappers::UnixListenerStream;
+use tonic::codec::CompressionEncoding;
+use tonic::service::Interceptor;
 use tonic::transport::{Channel, Endpoint, Server, Uri};
 use tonic::Request;
+use tonic_reflection::pb::server_reflection_client::ServerReflectionClient;
+use tonic_reflection::pb::server_reflection_request::MessageRequest;
+use tonic_reflection::pb::server_reflection_response::MessageResponse;
+use tonic_reflection::pb::ServerReflectionRequest;
 use tower::service_fn;
 
+// Create a server which uses a random contract id (thus a random collection).
+async fn new_test_server() -> (MongoKvPair, ContractId) {
+    let mut rng = thread_rng();
+    let mut contract_id = [0u8; 32];
+    rng.fill_bytes(&mut contract_id);
+    let test_config = MongoKvPairTestConfig {
+        contract_id: contract_id.into(),
+    };
+    let server = MongoKvPair::new_with_test_config(Some(test_config)).await;
+    (server, contract_id.into())
+}
+
 // Start a gRPC server in the background, returns the JoinHandle to the background task of this
 // server, a RPC client for this server and a channel sender which can be used to cancel the
 // executation of this gRPC server by sending a message `()` with this sender. This function
@@ -41,6 +96,26 @@ async fn start_server_get_client_and_cancellation_handler() -> (
     KvPairClient<Channel>,
     oneshot::Sender<()>,
 ) {
+    let (server, _) = new_test_server().await;
+    start_server(server).await
+}
+
+// Same as start_server_get_client_and_cancellation_handler, but with a given server.
+async fn start_server(
+    server: MongoKvPair,
+) -> (
+    tokio::task::JoinHandle<()>,
+    KvPairClient<Channel>,
+    oneshot::Sender<()>,
+) {
+    let (join_handler, channel, tx) = start_server_get_channel(server).await;
+    (join_handler, KvPairClient::new(channel), tx)
+}
+
+// Same as start_server, but returns the channel to the server, which also serves reflection.
+async fn start_server_get_channel(
+    server: MongoKvPair,
+) -> (tokio::task::JoinHandle<()>, Channel, oneshot::Sender<()>) {
     let (tx, rx) = oneshot::channel::<()>();
     let socket = NamedTempFile::new().unwrap();
     let socket = Arc::new(socket.into_temp_path());
@@ -49,17 +124,13 @@ async fn start_server_get_client_and_cancellation_handler() -> (
     let uds = UnixListener::bind(&*socket).unwrap();
     let stream = UnixListenerStream::new(uds);
 
-    let mut rng = thread_rng();
-    let mut contract_id = [0u8; 32];
-    rng.fill_bytes(&mut contract_id);
-    let test_config = MongoKvPairTestConfig {
-        contract_id: contract_id.into(),
-    };
-    let server = MongoKvPair::new_with_test_config(Some(test_config)).await;
-    let kvpair_server = KvPairServer::new(server.clone());
+    // Compressed as in production, the other tests checking that clients which don't advertise
+    // compression still get plain replies.
+    let kvpair_server = kvpair_server(server.clone(), true);
 
     let join_handler = tokio::spawn(async move {
         let result = Server::builder()
+            .add_service(reflection_service())
             .add_service(kvpair_server)
             .serve_with_incoming_shutdown(stream, rx.map(drop))
             .await;
@@ -84,9 +155,7 @@ async fn start_server_get_client_and_cancellation_handler() -> (
         .await
         .unwrap();
 
-    let client = KvPairClient::new(channel);
-
-    (join_handler, client, tx)
+    (join_handler, channel, tx)
 }
 
 async fn get_root(client: &mut KvPairClient<Channel>) -> GetRootResponse {
@@ -111,6 +180,9 @@ async fn get_leaf(
             hash: hash.map(|h| h.into()),
             proof_type: proof_type.into(),
             contract_id: None,
+            root: None,
+            read_session: None,
+            skip_data: false,
         }))
         .await
         .unwrap();
@@ -134,6 +206,9 @@ async fn set_leaf(
             proof_type,
             contract_id: None,
             hash: None,
+            force: false,
+            metadata: None,
+            expected_leaf_hash: None,
         }))
         .await
         .unwrap();
@@ -205,6 +280,9 @@ async fn test_set_leaf_hash_that_is_not_a_field_element() {
                 hash: Some([0xff; 32].to_vec()),
                 proof_type: ProofType::ProofEmpty.into(),
                 contract_id: None,
+                force: false,
+                metadata: None,
+                expected_leaf_hash: None,
             }))
             .await;
         dbg!(&response);
@@ -250,6 +328,44 @@ async fn test_set_and_get_leaf() {
     join_handler.await.unwrap()
 }
 
+#[tokio::test]
+async fn test_get_leaf_at_root() {
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let index = 2_u64.pow(MERKLE_TREE_HEIGHT.try_into().unwrap()) - 1;
+        let old_data: LeafData = [1_u8; 32].into();
+        set_leaf(client, index, old_data.clone(), ProofType::ProofEmpty).await;
+        let old_root = get_root(client).await.root;
+        set_leaf(client, index, [2_u8; 32].into(), ProofType::ProofEmpty).await;
+        assert_ne!(get_root(client).await.root, old_root);
+
+        let response = client
+            .get_leaf(Request::new(GetLeafRequest {
+                index,
+                hash: None,
+                proof_type: ProofType::ProofV0.into(),
+                contract_id: None,
+                root: Some(old_root.clone()),
+                read_session: None,
+                skip_data: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            response.node.unwrap().node_data,
+            Some(NodeData::Data(old_data.into()))
+        );
+        let proof =
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(response.proof.unwrap()).unwrap();
+        assert_eq!(proof.root, Hash::try_from(old_root.as_slice()).unwrap());
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
 #[tokio::test]
 async fn test_simple_set_and_get_leaf() {
     async fn get_leaf_hash(client: &mut KvPairClient<Channel>, index: u64) -> Vec<u8> {
@@ -260,6 +376,9 @@ async fn test_simple_set_and_get_leaf() {
                 hash: None,
                 proof_type,
                 contract_id: None,
+                root: None,
+                read_session: None,
+                skip_data: false,
             }))
             .await
             .unwrap();
@@ -289,6 +408,9 @@ async fn test_simple_set_and_get_leaf() {
                 proof_type,
                 contract_id: None,
                 hash: Some(leaf_hash.clone()),
+                force: false,
+                metadata: None,
+                expected_leaf_hash: None,
             }))
             .await
             .unwrap();
@@ -330,6 +452,69 @@ async fn test_poseidon_hash() {
     join_handler.await.unwrap()
 }
 
+#[tokio::test]
+async fn test_compute_hash() {
+    async fn compute_hash(
+        client: &mut KvPairClient<Channel>,
+        data: &[u8],
+        scheme: HashScheme,
+    ) -> Result<Hash, tonic::Status> {
+        let response = client
+            .compute_hash(Request::new(ComputeHashRequest {
+                data: data.to_vec(),
+                scheme: scheme.into(),
+            }))
+            .await?;
+        Ok(Hash::try_from(response.into_inner().hash).unwrap())
+    }
+
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let data = [1_u8; 64];
+        let expected: Hash = poseidon::hash(&data).unwrap().try_into().unwrap();
+        let hash = compute_hash(client, &data, HashScheme::HashSchemeFieldElements).await;
+        assert_eq!(hash.unwrap(), expected);
+        let expected: Hash = poseidon::hash_with_padding(&data)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let hash = compute_hash(client, &data, HashScheme::HashSchemePadded).await;
+        assert_eq!(hash.unwrap(), expected);
+        let expected = poseidon::hash_leaf_value(&data).unwrap();
+        let hash = compute_hash(client, &data, HashScheme::HashSchemeLeafValue).await;
+        assert_eq!(hash.unwrap(), expected);
+        let hash = compute_hash(client, &data[..32], HashScheme::HashSchemeMerkleLeaf).await;
+        assert_eq!(hash.unwrap(), Hash::hash_data(&data[..32]));
+
+        for (data, scheme) in [
+            (&data[..], HashScheme::HashSchemeUnspecified),
+            (&data[..], HashScheme::HashSchemeMerkleLeaf),
+            (&data[..31], HashScheme::HashSchemeFieldElements),
+        ] {
+            let status = compute_hash(client, data, scheme).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+
+        let (left, right) = (DEFAULT_HASH_VEC[1], DEFAULT_HASH_VEC[2]);
+        let response = client
+            .compute_pair_hash(Request::new(ComputePairHashRequest {
+                left: left.into(),
+                right: right.into(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            Hash::try_from(response.hash).unwrap(),
+            Hash::hash_children(&left, &right)
+        );
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
 #[tokio::test]
 async fn test_store_and_fetch_data_hash_record() {
     async fn test(client: &mut KvPairClient<Channel>) {
@@ -369,3 +554,1344 @@ async fn test_store_and_fetch_data_hash_record() {
     tx.send(()).unwrap();
     join_handler.await.unwrap()
 }
+
+#[tokio::test]
+async fn test_contract_placement() {
+    let mongodb_uri: String =
+        std::env::var("MONGODB_URI").unwrap_or("mongodb://localhost:27017".to_string());
+    let (server, contract_id) = new_test_server().await;
+    let database = format!("zkwasm-mongo-merkle-{}", hex::encode(&contract_id.0[..8]));
+    let placement = MongoPlacement {
+        uri: mongodb_uri.clone(),
+        database: database.clone(),
+    };
+    let server = server.with_placement(contract_id, placement).await.unwrap();
+    let (join_handler, mut client, tx) = start_server(server).await;
+
+    let index = 2_u64.pow(MERKLE_TREE_HEIGHT.try_into().unwrap()) - 1;
+    set_leaf(
+        &mut client,
+        index,
+        [42_u8; 32].into(),
+        ProofType::ProofEmpty,
+    )
+    .await;
+
+    let mongodb = mongodb::Client::with_uri_str(&mongodb_uri).await.unwrap();
+    let collection_name = format!("MERKLEDATA_{}", hex::encode(contract_id.0));
+    let count_records = |database: String| {
+        let collection = mongodb
+            .database(&database)
+            .collection::<Document>(&collection_name);
+        async move { collection.count_documents(None, None).await.unwrap() }
+    };
+    assert!(count_records(database.clone()).await > 0);
+    assert_eq!(
+        count_records(MongoCollection::<(), ()>::get_database_name()).await,
+        0
+    );
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap();
+    if std::env::var("KEEP_TEST_COLLECTIONS").is_err() {
+        mongodb.database(&database).drop(None).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_depth_mismatch() {
+    let mongodb_uri: String =
+        std::env::var("MONGODB_URI").unwrap_or("mongodb://localhost:27017".to_string());
+    let (server, contract_id) = new_test_server().await;
+    let (join_handler, mut client, tx) = start_server(server).await;
+
+    // Pretend that this contract was created by a server with another tree depth.
+    let mongodb = mongodb::Client::with_uri_str(&mongodb_uri).await.unwrap();
+    let collection_name = format!("MERKLEDATA_{}", hex::encode(contract_id.0));
+    mongodb
+        .database(&MongoCollection::<(), ()>::get_database_name())
+        .collection::<Document>(&collection_name)
+        .insert_one(
+            mongodb::bson::doc! {
+                "_id": MongoCollection::<MerkleRecord, DataHashRecord>::get_current_root_object_id(),
+                "depth": 20_i64,
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+    let response = client
+        .get_root(Request::new(GetRootRequest { contract_id: None }))
+        .await;
+    match response {
+        Err(status) => assert_eq!(status.code(), tonic::Code::FailedPrecondition),
+        _ => panic!("Should have returned error on depth mismatch"),
+    }
+    let response = client
+        .describe_index(Request::new(DescribeIndexRequest {
+            index: 0,
+            contract_id: Some(contract_id.0.to_vec()),
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.stored_depth, Some(20));
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_service_builder_rejects_unsupported_configurations() {
+    assert!(MerkleServiceBuilder::new().validate().is_ok());
+
+    // Rejected before connecting to the backend.
+    let invalid = [
+        (
+            MerkleServiceBuilder::new().depth(MERKLE_TREE_HEIGHT - 12),
+            "depth",
+        ),
+        (
+            MerkleServiceBuilder::new().cache_capacity(0),
+            "cache capacity",
+        ),
+        (
+            MerkleServiceBuilder::new().empty_leaf(DEFAULT_HASH_VEC[1]),
+            "empty leaf",
+        ),
+    ];
+    for (builder, expected) in invalid {
+        match builder.build().await {
+            Err(Error::InvalidArgument(message)) => {
+                assert!(message.contains(expected), "{message}")
+            }
+            other => panic!("Should have rejected the {expected}, got {other:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_self_check() {
+    let mongodb_uri: String =
+        std::env::var("MONGODB_URI").unwrap_or("mongodb://localhost:27017".to_string());
+    let (server, contract_id) = new_test_server().await;
+    let server = server.with_self_check(contract_id);
+    let (join_handler, client, tx) = start_server(server.clone()).await;
+    let mut client = Client::new(client);
+    let first = LeafIndex(0).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+    let second = LeafIndex(1).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+    let proof = client.set_leaf(first, vec![1; 32]).await.unwrap();
+    assert_eq!(server.self_check_failures(), 0);
+
+    // Corrupt the parent of both leaves to point to another first leaf, without changing its
+    // hash, as a bad cache or backend would.
+    let mongodb = mongodb::Client::with_uri_str(&mongodb_uri).await.unwrap();
+    let mut collection = MongoCollection::<MerkleRecord, DataHashRecord>::new(
+        mongodb,
+        &MongoCollection::<(), ()>::get_database_name(),
+        &contract_id,
+        false,
+    )
+    .await
+    .unwrap();
+    let other = Hash::hash_data(&[2; 32]);
+    collection
+        .insert_merkle_record(&MerkleRecord::new_leaf(first.0, other))
+        .await
+        .unwrap();
+    let parent = Hash::hash_children(&proof.source, &DEFAULT_HASH_VEC[0]);
+    collection
+        .update_one_merkle_record(
+            mongodb::bson::doc! {
+                "index": u64_to_bson((first.0 - 1) / 2),
+                "hash": hash_to_bson(&parent),
+            },
+            mongodb::bson::doc! { "$set": { "left": hash_to_bson(&other) } },
+            None,
+        )
+        .await
+        .unwrap();
+
+    // The write would silently change the first leaf in the new root, so it is refused.
+    let status = client.set_leaf(second, vec![3; 32]).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Internal);
+    assert!(status.message().contains("Self check failed"));
+    assert_eq!(server.self_check_failures(), 1);
+    assert_eq!(client.root().await.unwrap(), proof.root);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_diff_roots() {
+    async fn test(client: KvPairClient<Channel>) {
+        let mut client = Client::new(client).with_verification(true);
+        let index = |i: u64| LeafIndex(i).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        let empty_root = client.root().await.unwrap();
+        for i in [1, 5] {
+            client.set_leaf(index(i), vec![1; 32]).await.unwrap();
+        }
+        let old_root = client.root().await.unwrap();
+        for i in [9, 5] {
+            client.set_leaf(index(i), vec![2; 32]).await.unwrap();
+        }
+        let new_root = client.root().await.unwrap();
+
+        let (one, two) = (Hash::hash_data(&[1; 32]), Hash::hash_data(&[2; 32]));
+        assert_eq!(
+            client.diff_roots(old_root, new_root).await.unwrap(),
+            vec![(index(5), one, two), (index(9), DEFAULT_HASH_VEC[0], two)]
+        );
+        assert_eq!(
+            client.diff_roots(new_root, empty_root).await.unwrap(),
+            vec![
+                (index(1), one, DEFAULT_HASH_VEC[0]),
+                (index(5), two, DEFAULT_HASH_VEC[0]),
+                (index(9), two, DEFAULT_HASH_VEC[0])
+            ]
+        );
+        assert!(client
+            .diff_roots(new_root, new_root)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let status = client
+            .diff_roots(old_root, Hash::hash_data(&[3; 32]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_compression() {
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    let index = |i: u64| LeafIndex(i).to_node_index(MERKLE_TREE_HEIGHT).unwrap().0;
+    let empty_root = get_root(&mut client).await.root;
+    for i in 0..16 {
+        client
+            .set_leaf(Request::new(SetLeafRequest {
+                index: index(i),
+                data: Some(vec![i as u8; 64 * 1024]),
+                proof_type: ProofType::ProofEmpty.into(),
+                contract_id: None,
+                hash: None,
+                force: false,
+                expected_leaf_hash: None,
+                metadata: None,
+            }))
+            .await
+            .unwrap();
+    }
+    let new_root = get_root(&mut client).await.root;
+
+    async fn diff(
+        client: &mut KvPairClient<Channel>,
+        old_root: &[u8],
+        new_root: &[u8],
+    ) -> (Option<String>, Vec<DiffRootsResponse>) {
+        let response = client
+            .diff_roots(Request::new(DiffRootsRequest {
+                contract_id: None,
+                old_root: old_root.to_vec(),
+                new_root: new_root.to_vec(),
+                with_data: true,
+            }))
+            .await
+            .unwrap();
+        let encoding = response
+            .metadata()
+            .get("grpc-encoding")
+            .map(|value| value.to_str().unwrap().to_string());
+        let mut stream = response.into_inner();
+        let mut responses = vec![];
+        while let Some(response) = stream.message().await.unwrap() {
+            responses.push(response);
+        }
+        (encoding, responses)
+    }
+
+    // A client which doesn't advertise compression gets plain replies.
+    let (encoding, plain) = diff(&mut client, &empty_root, &new_root).await;
+    assert_eq!(encoding, None);
+    let changes: Vec<_> = plain
+        .iter()
+        .flat_map(|response| &response.changes)
+        .collect();
+    assert_eq!(changes.len(), 16);
+    for (i, change) in changes.iter().enumerate() {
+        assert_eq!(change.index, index(i as u64));
+        assert_eq!(change.new_data, Some(vec![i as u8; 64 * 1024]));
+    }
+
+    // A client which does gets the same, compressed, replies.
+    let mut compressed_client = client
+        .clone()
+        .send_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Gzip);
+    let (encoding, compressed) = diff(&mut compressed_client, &empty_root, &new_root).await;
+    assert_eq!(encoding.as_deref(), Some("gzip"));
+    assert_eq!(compressed, plain);
+    assert_eq!(get_root(&mut compressed_client).await.root, new_root);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_get_leaf_skip_data() {
+    // The bytes read by a client side proof walk of the leaf, which reads one sibling per level.
+    async fn proof_walk_bytes(
+        client: &mut KvPairClient<Channel>,
+        index: u64,
+        skip_data: bool,
+    ) -> usize {
+        let (_, proof) = Client::new(client.clone())
+            .get_leaf(NodeIndex(index))
+            .await
+            .unwrap();
+        let mut bytes = 0;
+        let mut child = index;
+        // The assist starts from the top of the tree.
+        for hash in proof.assist.iter().rev() {
+            let sibling = if child % 2 == 1 { child + 1 } else { child - 1 };
+            bytes += if child == index {
+                client
+                    .get_leaf(Request::new(GetLeafRequest {
+                        index: sibling,
+                        hash: Some((*hash).into()),
+                        proof_type: ProofType::ProofEmpty.into(),
+                        contract_id: None,
+                        root: None,
+                        read_session: None,
+                        skip_data,
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .encoded_len()
+            } else {
+                client
+                    .get_non_leaf(Request::new(GetNonLeafRequest {
+                        index: sibling,
+                        hash: (*hash).into(),
+                        contract_id: None,
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .encoded_len()
+            };
+            child = (child - 1) / 2;
+        }
+        bytes
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    let index = |i: u64| LeafIndex(i).to_node_index(MERKLE_TREE_HEIGHT).unwrap().0;
+    for i in [0, 1] {
+        client
+            .set_leaf(Request::new(SetLeafRequest {
+                index: index(i),
+                data: Some(vec![i as u8 + 1; 100 * 1024]),
+                proof_type: ProofType::ProofEmpty.into(),
+                contract_id: None,
+                hash: None,
+                force: false,
+                expected_leaf_hash: None,
+                metadata: None,
+            }))
+            .await
+            .unwrap();
+    }
+
+    let response = client
+        .get_leaf(Request::new(GetLeafRequest {
+            index: index(1),
+            hash: None,
+            proof_type: ProofType::ProofEmpty.into(),
+            contract_id: None,
+            root: None,
+            read_session: None,
+            skip_data: true,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let node = response.node.unwrap();
+    assert_eq!(node.node_data, None);
+    let expected: Hash = poseidon::hash(&[2; 100 * 1024])
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert_eq!(Hash::try_from(node.hash).unwrap(), expected);
+
+    // The sibling leaf holds the only data on the path, which the walk no longer reads.
+    let full = proof_walk_bytes(&mut client, index(0), false).await;
+    let meta_only = proof_walk_bytes(&mut client, index(0), true).await;
+    println!("Bytes per proof with 100 KiB leaves: {full} with the data, {meta_only} without");
+    assert!(full > 100 * 1024);
+    assert!(meta_only < 10 * 1024);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_write_coalescing() {
+    let (server, contract_id) = new_test_server().await;
+    let server = server.with_write_coalescing(contract_id, Duration::from_millis(500));
+    let (join_handler, client, tx) = start_server(server.clone()).await;
+    let client = Client::new(client).with_verification(true);
+    let index = LeafIndex(3).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+
+    // Ten updates of the same leaf in one window, then one expecting the leaf to be unchanged.
+    let updates = (1..=10_u8).map(|i| {
+        let mut client = client.clone();
+        async move {
+            tokio::time::sleep(Duration::from_millis(10 * i as u64)).await;
+            client.set_leaf(index, vec![i; 32]).await
+        }
+    });
+    let mut stale = client.clone();
+    let stale = async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        stale
+            .set_leaf_if(index, vec![11; 32], DEFAULT_HASH_VEC[0])
+            .await
+    };
+    let (proofs, stale) = futures::join!(futures::future::join_all(updates), stale);
+    assert_eq!(stale.unwrap_err().code(), tonic::Code::Aborted);
+
+    // A single root is written, with the last value, and all the updates are proved against it.
+    let mut client = client;
+    let root = client.root().await.unwrap();
+    let (data, _) = client.get_leaf(index).await.unwrap();
+    assert_eq!(data, vec![10; 32]);
+    for proof in proofs {
+        let proof = proof.unwrap();
+        assert_eq!(proof.root, root);
+        assert_eq!(proof.source, Hash::hash_data(&[10; 32]));
+    }
+    assert_eq!(server.coalesced_updates(), 9);
+
+    // The state is the same as with the last update alone.
+    let (other_join_handler, other, other_tx) =
+        start_server_get_client_and_cancellation_handler().await;
+    let mut other = Client::new(other);
+    let proof = other.set_leaf(index, vec![10; 32]).await.unwrap();
+    assert_eq!(proof.root, root);
+
+    other_tx.send(()).unwrap();
+    other_join_handler.await.unwrap();
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_request_id() {
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+
+    // A request id passed by the client is echoed back.
+    let mut request = Request::new(GetRootRequest { contract_id: None });
+    request
+        .metadata_mut()
+        .insert("x-request-id", "test-request-id".parse().unwrap());
+    let response = client.get_root(request).await.unwrap();
+    assert_eq!(
+        response.metadata().get("x-request-id").unwrap(),
+        "test-request-id"
+    );
+
+    // Otherwise a new one is generated.
+    let response = client
+        .get_root(Request::new(GetRootRequest { contract_id: None }))
+        .await
+        .unwrap();
+    assert!(response.metadata().get("x-request-id").is_some());
+
+    // Errors also carry the request id.
+    let mut request = Request::new(GetLeafRequest {
+        index: 0,
+        hash: None,
+        proof_type: ProofType::ProofEmpty.into(),
+        contract_id: None,
+        root: None,
+        read_session: None,
+        skip_data: false,
+    });
+    request
+        .metadata_mut()
+        .insert("x-request-id", "test-request-id".parse().unwrap());
+    let status = client.get_leaf(request).await.unwrap_err();
+    assert_eq!(
+        status.metadata().get("x-request-id").unwrap(),
+        "test-request-id"
+    );
+    assert!(status.message().contains("test-request-id"));
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_read_only() {
+    let (server, _) = new_test_server().await;
+    let (join_handler, mut client, tx) = start_server(server.with_read_only(true)).await;
+
+    get_root(&mut client).await;
+    let response = client
+        .set_leaf(Request::new(SetLeafRequest {
+            index: 2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1,
+            data: Some(vec![1; 32]),
+            proof_type: ProofType::ProofEmpty.into(),
+            contract_id: None,
+            hash: None,
+            force: false,
+            metadata: None,
+            expected_leaf_hash: None,
+        }))
+        .await;
+    match response {
+        Err(status) => assert_eq!(status.code(), tonic::Code::FailedPrecondition),
+        _ => panic!("Should have rejected mutating request in read-only mode"),
+    }
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_debug_verify() {
+    async fn debug_verify(
+        client: &mut KvPairClient<Channel>,
+        proof: &MerkleProof<Hash, MERKLE_TREE_HEIGHT>,
+    ) -> DebugVerifyResponse {
+        client
+            .debug_verify(Request::new(DebugVerifyRequest {
+                contract_id: None,
+                proof: Some(proof.try_into().unwrap()),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let index = 2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1;
+        let response = set_leaf(client, index, [1_u8; 32].into(), ProofType::ProofV0).await;
+        let proof =
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(response.proof.unwrap()).unwrap();
+
+        let response = debug_verify(client, &proof).await;
+        assert!(response.valid && response.root_known);
+        assert_eq!(response.steps.len(), MERKLE_TREE_HEIGHT);
+        assert_eq!(response.computed_root, Vec::<u8>::from(proof.root));
+        assert_eq!(response.divergent_index, None);
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.source = DEFAULT_HASH_VEC[1];
+        let response = debug_verify(client, &wrong_leaf).await;
+        assert!(!response.valid && response.root_known);
+        assert_eq!(response.divergent_index, Some(index));
+
+        let mut wrong_sibling = proof.clone();
+        wrong_sibling.assist[0] = DEFAULT_HASH_VEC[1];
+        let response = debug_verify(client, &wrong_sibling).await;
+        assert!(!response.valid);
+        // The sibling of node 1 is wrong, so only the root differs.
+        assert_eq!(response.divergent_index, Some(0));
+
+        let mut unknown_root = proof;
+        unknown_root.root = DEFAULT_HASH_VEC[1];
+        let response = debug_verify(client, &unknown_root).await;
+        assert!(!response.valid && !response.root_known);
+        assert_eq!(response.divergent_index, None);
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_set_unchanged_leaf() {
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let index = 2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1 + 7;
+        let first = set_leaf(client, index, [7_u8; 32].into(), ProofType::ProofV0).await;
+        let second = set_leaf(client, index, [7_u8; 32].into(), ProofType::ProofV0).await;
+        assert_eq!(first.proof, second.proof);
+        let proof =
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(second.proof.unwrap()).unwrap();
+        assert_eq!(
+            proof.root,
+            Hash::try_from(get_root(client).await.root).unwrap()
+        );
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_get_range_proof() {
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let first = 2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1;
+        // Starts at a right child and ends at a left child, so both frontiers are needed.
+        let (start, end) = (first + 1, first + 5);
+        let mut leaf_hashes = vec![];
+        for index in start..end {
+            let data = [(index - first) as u8; 32];
+            set_leaf(client, index, data.into(), ProofType::ProofEmpty).await;
+            let node = get_leaf(client, index, None, ProofType::ProofEmpty)
+                .await
+                .node
+                .unwrap();
+            leaf_hashes.push(Hash::try_from(node.hash).unwrap());
+        }
+
+        let response = client
+            .get_range_proof(Request::new(GetRangeProofRequest {
+                contract_id: None,
+                start,
+                end,
+                root: None,
+                read_session: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let proof = MerkleRangeProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(response.proof.unwrap())
+            .unwrap();
+        assert_eq!(
+            proof.root,
+            Hash::try_from(get_root(client).await.root).unwrap()
+        );
+        assert!(proof.verify(&leaf_hashes, Hash::hash_children).unwrap());
+        leaf_hashes.reverse();
+        assert!(!proof.verify(&leaf_hashes, Hash::hash_children).unwrap());
+
+        let status = client
+            .get_range_proof(Request::new(GetRangeProofRequest {
+                contract_id: None,
+                start,
+                end: start,
+                root: None,
+                read_session: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_client() {
+    async fn test(client: KvPairClient<Channel>) {
+        let mut client = Client::new(client).with_verification(true);
+        let index = LeafIndex(3).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        let proof = client.set_leaf(index, vec![3; 32]).await.unwrap();
+        assert_eq!(proof.index, index.0);
+        assert_eq!(proof.root, client.root().await.unwrap());
+
+        let (data, leaf_proof) = client.get_leaf(index).await.unwrap();
+        assert_eq!(data, vec![3; 32]);
+        assert_eq!(leaf_proof, proof);
+    }
+
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_set_leaf_expected_hash() {
+    async fn test(client: KvPairClient<Channel>) {
+        let mut writer = Client::new(client).with_verification(true);
+        let mut other = writer.clone();
+        let index = LeafIndex(17).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        writer.set_leaf(index, vec![17; 32]).await.unwrap();
+
+        // Both read the leaf, then the other writer updates it first.
+        let (_, read) = writer.get_leaf(index).await.unwrap();
+        let (_, other_read) = other.get_leaf(index).await.unwrap();
+        let updated = other
+            .set_leaf_if(index, vec![18; 32], other_read.source)
+            .await
+            .unwrap();
+
+        // The root the writer would compare and swap against is current, but its read of the
+        // leaf is stale, so the update is rejected instead of losing the other one.
+        assert_eq!(writer.root().await.unwrap(), updated.root);
+        let status = writer
+            .set_leaf_if(index, vec![19; 32], read.source)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        let current = status
+            .metadata()
+            .get("x-current-leaf-hash")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(current, hex::encode(updated.source.0));
+        let (data, _) = writer.get_leaf(index).await.unwrap();
+        assert_eq!(data, vec![18; 32]);
+
+        // Retrying with the current hash succeeds.
+        let proof = writer
+            .set_leaf_if(index, vec![19; 32], updated.source)
+            .await
+            .unwrap();
+        assert_eq!(proof.source, Hash::hash_data(&[19; 32]));
+    }
+
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_set_leaf_expected_hash_concurrently() {
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    let index = LeafIndex(23).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+    let mut writer = Client::new(client);
+    let (_, read) = writer.get_leaf(index).await.unwrap();
+
+    // All the writers read the same hash, so only the first update checked against it may pass.
+    let updates = (0..8).map(|i| {
+        let mut writer = writer.clone();
+        async move { writer.set_leaf_if(index, vec![i; 32], read.source).await }
+    });
+    let results = futures::future::join_all(updates).await;
+    let written: Vec<_> = results
+        .iter()
+        .enumerate()
+        .filter_map(|(i, result)| result.as_ref().ok().map(|_| i as u8))
+        .collect();
+    assert_eq!(written.len(), 1);
+    for status in results.iter().filter_map(|result| result.as_ref().err()) {
+        assert_eq!(status.code(), tonic::Code::Aborted);
+    }
+    let (data, _) = writer.get_leaf(index).await.unwrap();
+    assert_eq!(data, vec![written[0]; 32]);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_set_leaves_expected_hashes() {
+    let (server, contract_id) = new_test_server().await;
+    let (join_handler, client, tx) = start_server(server.clone()).await;
+    let mut client = Client::new(client);
+    let index = |i: u64| LeafIndex(i).to_node_index(MERKLE_TREE_HEIGHT).unwrap().0;
+    let leaf = |i: u64, value: u8| MerkleRecord::new_leaf(index(i), Hash::hash_data(&[value; 32]));
+    let empty = DEFAULT_HASH_VEC[0];
+
+    // The second update of leaf 1 expects the hash left by the first one.
+    let proofs = server
+        .set_leaves(
+            &contract_id,
+            &[
+                (leaf(1, 1), Some(empty)),
+                (leaf(2, 2), None),
+                (leaf(1, 3), Some(Hash::hash_data(&[1; 32]))),
+            ],
+        )
+        .await
+        .unwrap();
+    let root = client.root().await.unwrap();
+    assert_eq!(proofs.len(), 3);
+    for proof in &proofs {
+        assert_eq!(proof.root, root);
+    }
+    assert_eq!(proofs[0].source, Hash::hash_data(&[3; 32]));
+    assert_eq!(proofs[1].source, Hash::hash_data(&[2; 32]));
+
+    // A stale expectation rejects the whole batch.
+    let status = server
+        .set_leaves(
+            &contract_id,
+            &[(leaf(3, 4), Some(empty)), (leaf(2, 5), Some(empty))],
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+    let current = status
+        .metadata()
+        .get("x-current-leaf-hash")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert_eq!(current, hex::encode(Hash::hash_data(&[2; 32]).0));
+    assert_eq!(client.root().await.unwrap(), root);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_hotspots() {
+    async fn test(client: KvPairClient<Channel>) {
+        let mut client = Client::new(client).with_verification(true);
+        let hot = LeafIndex(21).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        let cold = LeafIndex(22).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        client.set_leaf(hot, vec![21; 32]).await.unwrap();
+        for _ in 0..3 {
+            client.get_leaf(hot).await.unwrap();
+        }
+        client.get_leaf(cold).await.unwrap();
+
+        let mut inner = client.into_inner();
+        let response = inner
+            .get_hotspots(Request::new(GetHotspotsRequest {
+                contract_id: None,
+                window_secs: 0,
+                limit: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.window_secs, 60);
+        assert_eq!(
+            response.leaves,
+            vec![
+                LeafAccesses {
+                    index: hot.0,
+                    count: 4
+                },
+                LeafAccesses {
+                    index: cold.0,
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(response.contracts.len(), 1);
+        assert_eq!(
+            (response.contracts[0].reads, response.contracts[0].writes),
+            (4, 1)
+        );
+
+        let response = inner
+            .get_hotspots(Request::new(GetHotspotsRequest {
+                contract_id: None,
+                window_secs: u32::MAX,
+                limit: 1,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.window_secs, 600);
+        assert_eq!(response.leaves.len(), 1);
+    }
+
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_leaf_metadata() {
+    async fn test(client: KvPairClient<Channel>) {
+        let mut client = Client::new(client).with_verification(true);
+        let index = LeafIndex(11).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        let without = client.set_leaf(index, vec![11; 32]).await.unwrap();
+        let (_, metadata, _) = client.get_leaf_with_metadata(index).await.unwrap();
+        assert_eq!(metadata, None);
+
+        // The metadata is not hashed, so the root is unchanged.
+        let with = client
+            .set_leaf_with_metadata(index, vec![11; 32], Some(b"owner".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(with, without);
+        let (data, metadata, proof) = client.get_leaf_with_metadata(index).await.unwrap();
+        assert_eq!(data, vec![11; 32]);
+        assert_eq!(metadata, Some(b"owner".to_vec()));
+        assert_eq!(proof, without);
+
+        // Updates without metadata keep the metadata.
+        client.set_leaf(index, vec![12; 32]).await.unwrap();
+        let (_, metadata, _) = client.get_leaf_with_metadata(index).await.unwrap();
+        assert_eq!(metadata, Some(b"owner".to_vec()));
+
+        let status = client
+            .set_leaf_with_metadata(index, vec![12; 32], Some(vec![0; 1025]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_read_session() {
+    async fn test(client: KvPairClient<Channel>) {
+        let mut client = Client::new(client).with_verification(true);
+        let index = LeafIndex(13).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        client.set_leaf(index, vec![13; 32]).await.unwrap();
+        let mut view = client.begin_read_session().await.unwrap();
+        let pinned_root = view.root().await.unwrap();
+
+        // Updates after the session started are not observed by its reads.
+        client.set_leaf(index, vec![14; 32]).await.unwrap();
+        assert_ne!(client.root().await.unwrap(), pinned_root);
+        let (data, proof) = view.get_leaf(index).await.unwrap();
+        assert_eq!(data, vec![13; 32]);
+        assert_eq!(proof.root, pinned_root);
+
+        let (data, root) = client
+            .read_session(|mut view| async move {
+                let (data, proof) = view.get_leaf(index).await?;
+                Ok((data, proof.root))
+            })
+            .await
+            .unwrap();
+        assert_eq!(data, vec![14; 32]);
+        assert_eq!(root, client.root().await.unwrap());
+
+        let mut inner = client.into_inner();
+        let status = inner
+            .get_leaf(Request::new(GetLeafRequest {
+                contract_id: None,
+                index: index.0,
+                hash: None,
+                proof_type: ProofType::ProofV0.into(),
+                root: None,
+                read_session: Some(vec![0; 16]),
+                skip_data: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    let (join_handler, client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_reflection() {
+    let (server, _) = new_test_server().await;
+    let (join_handler, channel, tx) = start_server_get_channel(server).await;
+
+    let mut client = ServerReflectionClient::new(channel);
+    let request = ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(MessageRequest::ListServices(String::new())),
+    };
+    let mut responses = client
+        .server_reflection_info(tokio_stream::iter(vec![request]))
+        .await
+        .unwrap()
+        .into_inner();
+    let services = match responses.message().await.unwrap().unwrap().message_response {
+        Some(MessageResponse::ListServicesResponse(response)) => response.service,
+        response => panic!("Unexpected reflection response {:?}", response),
+    };
+    let names: Vec<_> = services.into_iter().map(|service| service.name).collect();
+    assert!(names.contains(&"kvpair.KVPair".to_string()), "{:?}", names);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_health_check() {
+    let (server, _) = new_test_server().await;
+    let health = server.health_check().await.unwrap();
+    // The test server may be standalone, which has no lag, or a replica set which has just
+    // replicated the writes of the other tests.
+    if let Some(lag) = health.replication_lag {
+        assert!(lag < Duration::from_secs(60), "{lag:?}");
+    }
+}
+
+#[tokio::test]
+async fn test_get_server_info() {
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    let info = client
+        .get_server_info(Request::new(GetServerInfoRequest {}))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(!info.git_hash.is_empty());
+    assert!(info.proof_types.contains(&(ProofType::ProofV0 as i32)));
+    assert_eq!(info.backend, "mongodb");
+    assert_eq!(info.depth, MERKLE_TREE_HEIGHT as u32);
+    assert!(!info.read_only);
+    assert_eq!(info.skipped_unchanged_updates, 0);
+
+    // Setting a leaf to its current hash writes nothing, unless forced.
+    let index = 2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1;
+    for _ in 0..3 {
+        set_leaf(&mut client, index, [7_u8; 32].into(), ProofType::ProofEmpty).await;
+    }
+    let mut request = SetLeafRequest {
+        index,
+        data: Some(vec![7; 32]),
+        proof_type: ProofType::ProofEmpty.into(),
+        contract_id: None,
+        hash: None,
+        force: true,
+        expected_leaf_hash: None,
+        metadata: None,
+    };
+    client
+        .set_leaf(Request::new(request.clone()))
+        .await
+        .unwrap();
+    request.force = false;
+    client.set_leaf(Request::new(request)).await.unwrap();
+    let info = client
+        .get_server_info(Request::new(GetServerInfoRequest {}))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(info.skipped_unchanged_updates, 3);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_out_of_range_index() {
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let index = 2_u64.pow(MERKLE_TREE_HEIGHT as u32 + 1) - 1;
+        let status = client
+            .get_leaf(Request::new(GetLeafRequest {
+                index,
+                hash: None,
+                proof_type: ProofType::ProofV0.into(),
+                contract_id: None,
+                root: None,
+                read_session: None,
+                skip_data: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(
+            status.message().contains("leaves are"),
+            "{}",
+            status.message()
+        );
+        let details = index_error_details_of(&status).unwrap();
+        assert_eq!(details.index, index);
+        assert_eq!(details.depth, MERKLE_TREE_HEIGHT as u32);
+        assert_eq!(details.node_type, NodeType::NodeInvalid as i32);
+        assert_eq!(details.nearest_leaf_index, details.last_leaf_index);
+
+        let status = client
+            .set_leaf(Request::new(SetLeafRequest {
+                index: u64::MAX,
+                data: Some(vec![0; 32]),
+                proof_type: ProofType::ProofV0.into(),
+                contract_id: None,
+                hash: None,
+                force: false,
+                metadata: None,
+                expected_leaf_hash: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // An offset used as an index, off by the index of the first leaf.
+        let status = client
+            .set_leaf(Request::new(SetLeafRequest {
+                index: 5,
+                data: Some(vec![0; 32]),
+                proof_type: ProofType::ProofV0.into(),
+                contract_id: None,
+                hash: None,
+                force: false,
+                metadata: None,
+                expected_leaf_hash: None,
+            }))
+            .await
+            .unwrap_err();
+        let details = index_error_details_of(&status).unwrap();
+        assert_eq!(details.node_type, NodeType::NodeNonLeaf as i32);
+        assert_eq!(details.expected_node_type, NodeType::NodeLeaf as i32);
+        assert_eq!(
+            details.first_leaf_index,
+            2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1
+        );
+        assert_eq!(details.nearest_leaf_index, details.first_leaf_index);
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_describe_index() {
+    async fn test(client: &mut KvPairClient<Channel>) {
+        let index = 2_u64.pow(MERKLE_TREE_HEIGHT as u32) + 1;
+        let response = client
+            .describe_index(Request::new(DescribeIndexRequest {
+                index,
+                contract_id: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.node_type, NodeType::NodeLeaf as i32);
+        assert_eq!(response.depth, Some(MERKLE_TREE_HEIGHT as u32));
+        assert_eq!(response.offset, Some(2));
+        assert_eq!(response.stored_depth, None);
+
+        let response = client
+            .describe_index(Request::new(DescribeIndexRequest {
+                index: u64::MAX,
+                contract_id: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.node_type, NodeType::NodeInvalid as i32);
+        assert_eq!(response.depth, None);
+
+        let status = client
+            .get_leaf(Request::new(GetLeafRequest {
+                index,
+                hash: None,
+                proof_type: 42,
+                contract_id: None,
+                root: None,
+                read_session: None,
+                skip_data: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("42"), "{}", status.message());
+    }
+
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    test(&mut client).await;
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_concurrency_limits() {
+    let (server, _) = new_test_server().await;
+    // No read can ever get a permit, while writes are unbounded.
+    let server = server.with_concurrency_limits(Some(0), None);
+    let (join_handler, mut client, tx) = start_server(server).await;
+
+    let mut request = Request::new(GetRootRequest { contract_id: None });
+    request.set_timeout(Duration::from_millis(10));
+    match client.get_root(request).await {
+        Err(status) => assert_eq!(status.code(), tonic::Code::ResourceExhausted),
+        _ => panic!("Should have shed the request waiting past its deadline"),
+    }
+    set_leaf(
+        &mut client,
+        2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1,
+        [1_u8; 32].into(),
+        ProofType::ProofEmpty,
+    )
+    .await;
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_concurrency_limits_under_saturation() {
+    let (server, _) = new_test_server().await;
+    // A single write at a time, and no read at all.
+    let server = server.with_concurrency_limits(Some(0), Some(1));
+    let (join_handler, client, tx) = start_server(server.clone()).await;
+    let writer = Client::new(client.clone());
+
+    // The writes queue for the permit, and all of them are served.
+    let writes = (0..8).map(|i| {
+        let mut writer = writer.clone();
+        let index = LeafIndex(i).to_node_index(MERKLE_TREE_HEIGHT).unwrap();
+        async move { writer.set_leaf(index, vec![i as u8; 32]).await }
+    });
+    for result in futures::future::join_all(writes).await {
+        result.unwrap();
+    }
+    let stats = server.permit_wait();
+    assert_eq!(stats.acquired, 8);
+    assert_eq!(stats.shed, 0);
+    assert!(stats.max > Duration::ZERO && stats.max <= stats.total);
+
+    // The reads are shed at their deadline instead of waiting forever.
+    let mut reads = vec![];
+    for _ in 0..4 {
+        let mut client = client.clone();
+        reads.push(async move {
+            let mut request = Request::new(GetRootRequest { contract_id: None });
+            request.set_timeout(Duration::from_millis(50));
+            let start = Instant::now();
+            let status = client.get_root(request).await.unwrap_err();
+            (status, start.elapsed())
+        });
+    }
+    for (status, elapsed) in futures::future::join_all(reads).await {
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+    let stats = server.permit_wait();
+    assert_eq!(stats.acquired, 8);
+    assert_eq!(stats.shed, 4);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_bulk_hash_off_runtime() {
+    // The test runtime has a single thread, so a large hash computed inline would delay all
+    // the other requests until it is done.
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+
+    let mut bulk_client = client.clone();
+    let bulk = tokio::spawn(async move {
+        let start = Instant::now();
+        poseidon_hash(&mut bulk_client, vec![0; 32 * 20000]).await;
+        start.elapsed()
+    });
+    // Let the bulk request reach the server.
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut latencies = vec![];
+    for i in 0..50 {
+        let start = Instant::now();
+        client
+            .compute_pair_hash(Request::new(ComputePairHashRequest {
+                left: DEFAULT_HASH_VEC[i % 4].into(),
+                right: DEFAULT_HASH_VEC[i % 3].into(),
+            }))
+            .await
+            .unwrap();
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+    let p99 = latencies[latencies.len() * 99 / 100];
+    let bulk = bulk.await.unwrap();
+    assert!(p99 * 10 < bulk, "p99 {:?} while bulk took {:?}", p99, bulk);
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
+
+#[test]
+fn test_api_key_interceptor() {
+    fn request_with(key: &str, value: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request.metadata_mut().insert(key, value.parse().unwrap());
+        request
+    }
+
+    let mut open = ApiKeyInterceptor::new(None);
+    assert!(open.call(Request::new(())).is_ok());
+
+    let mut interceptor = ApiKeyInterceptor::new(Some("secret".to_string()));
+    assert!(interceptor
+        .call(request_with("authorization", "Bearer secret"))
+        .is_ok());
+    assert!(interceptor
+        .call(request_with("x-api-key", "secret"))
+        .is_ok());
+    for request in [
+        Request::new(()),
+        request_with("authorization", "Bearer wrong"),
+        request_with("authorization", "secret"),
+        request_with("x-api-key", "secre"),
+    ] {
+        let status = interceptor.call(request).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+}
+
+#[tokio::test]
+async fn test_freeze_contract() {
+    let (join_handler, mut client, tx) = start_server_get_client_and_cancellation_handler().await;
+    let index = 2_u64.pow(MERKLE_TREE_HEIGHT as u32) - 1;
+    let set_leaf_request = || {
+        Request::new(SetLeafRequest {
+            index,
+            data: Some(vec![1; 32]),
+            proof_type: ProofType::ProofEmpty.into(),
+            contract_id: None,
+            hash: None,
+            force: false,
+            metadata: None,
+            expected_leaf_hash: None,
+        })
+    };
+
+    client
+        .freeze_contract(Request::new(FreezeContractRequest {
+            contract_id: None,
+            mode: FreezeMode::FreezeWrite.into(),
+            reason: "maintenance".to_string(),
+        }))
+        .await
+        .unwrap();
+    get_root(&mut client).await;
+    let status = client.set_leaf(set_leaf_request()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert!(status.message().contains("maintenance"));
+
+    client
+        .freeze_contract(Request::new(FreezeContractRequest {
+            contract_id: None,
+            mode: FreezeMode::FreezeFull.into(),
+            reason: "incident".to_string(),
+        }))
+        .await
+        .unwrap();
+    let status = client
+        .get_root(Request::new(GetRootRequest { contract_id: None }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    client
+        .unfreeze_contract(Request::new(UnfreezeContractRequest { contract_id: None }))
+        .await
+        .unwrap();
+    get_root(&mut client).await;
+    client.set_leaf(set_leaf_request()).await.unwrap();
+
+    tx.send(()).unwrap();
+    join_handler.await.unwrap()
+}
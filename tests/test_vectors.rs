@@ -0,0 +1,89 @@
+//! Roots pinned for fixed sequences of leaf updates, so that any change to the hashers or to the
+//! tree math shows up as a failure here. The roots are visible on chain, so an expected value
+//! must never be updated to match a new output of this crate.
+
+use zkc_state_manager::kvpair::{Hash, DEFAULT_HASH_VEC};
+use zkc_state_manager::memory::MemoryMerkleTree;
+use zkc_state_manager::merkle::{MerkleTree, NodeIndex};
+
+const D: usize = 10;
+
+/// The leaf data of a value, its 32 bytes little endian representation.
+fn leaf_data(value: u64) -> [u8; 32] {
+    let mut data = [0; 32];
+    data[..8].copy_from_slice(&value.to_le_bytes());
+    data
+}
+
+fn hex_root(tree: &MemoryMerkleTree<D>) -> String {
+    hex::encode(tree.get_root_hash().0)
+}
+
+#[test]
+fn test_empty_root() {
+    let tree = MemoryMerkleTree::<D>::new();
+    assert_eq!(
+        hex_root(&tree),
+        "73d0659513683d40b5731e79a5746dbd28604950156bdbf515ab2867557af916"
+    );
+    assert_eq!(tree.get_root_hash(), DEFAULT_HASH_VEC[D]);
+}
+
+#[test]
+fn test_leaf_sequence_roots() {
+    // (leaf offset, value, root after the update)
+    let vectors = [
+        (
+            0,
+            1,
+            "78507ce5b9df40d66184847f5c3b514c2210bbc53889c7f6186d3c1ced99ce22",
+        ),
+        (
+            3,
+            2,
+            "5079f10fb6d71f05cad36e22e7086903dfcae1ae5993524ea0467b5b1cbb8403",
+        ),
+        (
+            1023,
+            3,
+            "f9140710964f0cdafe8d7f9b61ee48d71f32ab689c466e5d02e174106e023d1f",
+        ),
+        (
+            512,
+            4,
+            "9dcd5fd00848b4c7ed30087e65e41f76be2aff7d39adef6b6e531d3a04ff272e",
+        ),
+        // Overwriting a leaf.
+        (
+            3,
+            5,
+            "26e5f69b394700829896741b07b2d083f12c29f907d0f8688a24a417e2081418",
+        ),
+        // Zero data is the empty leaf, so setting an empty leaf to zero keeps the root.
+        (
+            1,
+            0,
+            "26e5f69b394700829896741b07b2d083f12c29f907d0f8688a24a417e2081418",
+        ),
+        (
+            0,
+            0,
+            "d64ae0b24820bacba3582cdbbaf5d4fed69940ea942b7daac6b8096b377c9d21",
+        ),
+    ];
+
+    let mut tree = MemoryMerkleTree::<D>::new();
+    for (offset, value, expected_root) in vectors {
+        let index = NodeIndex((1 << D) - 1 + offset);
+        let proof = tree
+            .update_leaf_data_with_proof(index, &leaf_data(value))
+            .unwrap();
+        assert_eq!(proof.source, Hash::hash_data(&leaf_data(value)));
+        assert_eq!(
+            hex_root(&tree),
+            expected_root,
+            "root after setting leaf {offset} to {value}"
+        );
+        assert!(tree.verify_proof(proof).unwrap());
+    }
+}